@@ -1,6 +1,240 @@
+use std::{env, fs, path::PathBuf};
+
+#[path = "build/image.rs"]
+mod image;
+
+#[allow(dead_code)]
+#[path = "src/config/schema.rs"]
+mod schema;
+
+/// Features that can't be enabled together, because they use the same pins or peripherals
+const CONFLICTS: [(&str, &str, &str); 9] = [
+    ("ld2410", "pzem", "both are connected to UART1"),
+    ("ld2410", "epaper", "the display uses GPIO4/GPIO5 of UART1"),
+    ("pzem", "epaper", "the display uses GPIO4/GPIO5 of UART1"),
+    ("thermocouple", "epaper", "both use GPIO0 as chip select"),
+    (
+        "supply",
+        "epaper",
+        "the supply voltage is measured on the display chip select pin GPIO0",
+    ),
+    ("ccs811", "fan", "the fan uses the CCS811 nWAKE pin GPIO10"),
+    (
+        "ccs811",
+        "rtd",
+        "the MAX31865 chip select is the CCS811 nWAKE pin GPIO10",
+    ),
+    (
+        "fan",
+        "rtd",
+        "the MAX31865 chip select is the fan PWM pin GPIO10",
+    ),
+    (
+        "supply",
+        "thermocouple",
+        "the supply voltage is measured on the MAX31855 chip select pin GPIO0",
+    ),
+];
+
+/// Features that can be combined with the `minimal` feature
+const MINIMAL_FEATURES: [&str; 2] = ["minimal", "temp_humi"];
+
+/// sdkconfig defaults of the `minimal` feature, e.g. without the TLS certificate bundle
+const MINIMAL_SDKCONFIG: &str = "sdkconfig.minimal";
+
+/// sdkconfig defaults of the `bthome` feature, with Bluetooth LE
+const BTHOME_SDKCONFIG: &str = "sdkconfig.bthome";
+
+/// Partition table, with a single app partition
+const PARTITION_TABLE: &str = "partitions.csv";
+
+/// Build-time config that is used if there's no config in NVS, overridden by `SENSILO_CONFIG`
+const DEFAULT_CONFIG_PATH: &str = "sensilo.toml";
+
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut errors = check_features();
+    if let Err(e) = embed_config() {
+        errors.push(e);
+    }
+    if !errors.is_empty() {
+        for error in errors {
+            eprintln!("Error: {}", error);
+        }
+        std::process::exit(1);
+    }
+    if enabled("minimal") {
+        report_image_size();
+    }
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
     Ok(())
 }
+
+/// Return an error for every combination of enabled features that can't be used together.
+fn check_features() -> Vec<String> {
+    let mut errors = Vec::new();
+    for (a, b, reason) in CONFLICTS {
+        if enabled(a) && enabled(b) {
+            errors.push(format!(
+                "The features \"{}\" and \"{}\" can't be combined: {}",
+                a, b, reason
+            ));
+        }
+    }
+    if enabled("buttons") && enabled("geiger") && enabled("s0") {
+        errors.push(
+            "The feature \"buttons\" can't be combined with both \"geiger\" and \"s0\": \
+             The buttons use their pulse input pins GPIO3 and GPIO1"
+                .to_string(),
+        );
+    }
+    if enabled("minimal") {
+        errors.extend(check_minimal());
+    }
+    if enabled("bthome") {
+        errors.extend(check_sdkconfig("bthome", BTHOME_SDKCONFIG));
+    }
+    errors
+}
+
+/// Return an error if the sdkconfig defaults of a feature are not used.
+fn check_sdkconfig(feature: &str, sdkconfig: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed=ESP_IDF_SDKCONFIG_DEFAULTS");
+    let defaults = env::var("ESP_IDF_SDKCONFIG_DEFAULTS").unwrap_or_default();
+    if defaults.split(';').any(|path| path.ends_with(sdkconfig)) {
+        return None;
+    }
+    Some(format!(
+        "The feature \"{}\" requires the sdkconfig defaults {} (set \
+         ESP_IDF_SDKCONFIG_DEFAULTS=\"sdkconfig.defaults;{}\")",
+        feature, sdkconfig, sdkconfig
+    ))
+}
+
+/// Return an error for every feature and setting that doesn't fit into the `minimal` build.
+fn check_minimal() -> Vec<String> {
+    let mut errors = Vec::new();
+    for (name, _) in env::vars() {
+        if let Some(feature) = name.strip_prefix("CARGO_FEATURE_") {
+            let feature = feature.to_lowercase();
+            if !MINIMAL_FEATURES.contains(&feature.as_str()) {
+                errors.push(format!(
+                    "The feature \"minimal\" can't be combined with \"{}\" (build with \
+                     --no-default-features --features minimal)",
+                    feature
+                ));
+            }
+        }
+    }
+
+    errors.extend(check_sdkconfig("minimal", MINIMAL_SDKCONFIG));
+
+    // The minimal build has no TLS certificate bundle
+    println!("cargo:rerun-if-env-changed=SENSILO_INFLUXDB_HOST");
+    let host = env::var("SENSILO_INFLUXDB_HOST").unwrap_or_default();
+    if host.starts_with("https://") {
+        errors.push(
+            "The feature \"minimal\" doesn't support HTTPS, SENSILO_INFLUXDB_HOST must start \
+             with http://"
+                .to_string(),
+        );
+    }
+    errors
+}
+
+/// Report the size of the image that was linked by the last build against the size of the app
+/// partition.
+///
+/// Note: The build script runs before the firmware is linked, so the reported image is the one
+/// of the previous build of the same profile.
+fn report_image_size() {
+    println!("cargo:rerun-if-changed={}", PARTITION_TABLE);
+    let budget = match fs::read_to_string(PARTITION_TABLE)
+        .ok()
+        .and_then(|csv| image::app_partition_size(&csv))
+    {
+        Some(budget) => budget,
+        None => {
+            println!(
+                "cargo:warning=No app partition found in {}",
+                PARTITION_TABLE
+            );
+            return;
+        }
+    };
+
+    // OUT_DIR is `target/<target>/<profile>/build/<package>-<hash>/out`
+    let elf = env::var_os("OUT_DIR")
+        .map(PathBuf::from)
+        .and_then(|out_dir| {
+            Some(
+                out_dir
+                    .ancestors()
+                    .nth(3)?
+                    .join(env::var("CARGO_PKG_NAME").ok()?),
+            )
+        });
+    match elf.map(|elf| image::usage(&elf)) {
+        Some(Ok(usage)) => println!(
+            "cargo:warning=Image size (last build): {} of {} KiB ({:.1} %), {} KiB static RAM",
+            usage.flash / 1024,
+            budget / 1024,
+            usage.flash as f64 / budget as f64 * 100.0,
+            usage.ram / 1024
+        ),
+        _ => println!(
+            "cargo:warning=App partition: {} KiB (no image of a previous build yet)",
+            budget / 1024
+        ),
+    }
+}
+
+/// Return whether a Cargo feature is enabled.
+fn enabled(feature: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase())).is_some()
+}
+
+/// Copy the build-time config to `$OUT_DIR/sensilo.toml`, from where it's embedded into the
+/// firmware. If there's no build-time config, the file is empty.
+///
+/// The config is checked against the schema here, so that typos and invalid types fail the
+/// build. The remaining validation (e.g. value ranges) happens when the config is loaded.
+fn embed_config() -> Result<(), String> {
+    println!("cargo:rerun-if-env-changed=SENSILO_CONFIG");
+    let explicit_path = env::var_os("SENSILO_CONFIG").map(PathBuf::from);
+    let path = explicit_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => {
+            check_config(&text)
+                .map_err(|e| format!("Invalid build-time config {}: {}", path.display(), e))?;
+            text
+        }
+        // The default path is optional
+        Err(_) if explicit_path.is_none() => String::new(),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").ok_or("OUT_DIR is not set")?);
+    fs::write(out_dir.join("sensilo.toml"), text)
+        .map_err(|e| format!("Could not write the build-time config: {}", e))
+}
+
+/// Check that a config has the current layout and matches the schema.
+fn check_config(text: &str) -> Result<(), String> {
+    let value: toml::Value = text.parse().map_err(|e| format!("{}", e))?;
+    let version = value
+        .get("version")
+        .and_then(|version| version.as_integer());
+    if version != Some(schema::VERSION.into()) {
+        return Err(format!(
+            "version: Must be the current version {}",
+            schema::VERSION
+        ));
+    }
+    toml::from_str::<schema::Config>(text).map_err(|e| format!("{}", e))?;
+    Ok(())
+}
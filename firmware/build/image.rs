@@ -0,0 +1,88 @@
+//! Size of the firmware image and of the app partition, for the size reports of the build script
+//! and of `cargo xtask size`.
+
+use std::{fs, io, ops::Range, path::Path};
+
+/// ELF program header type of loadable segments
+const PT_LOAD: u32 = 1;
+
+/// Address ranges of the internal RAM of the ESP32-C3 (DRAM, IRAM and RTC fast memory)
+const RAM: [Range<u32>; 3] = [
+    0x3fc8_0000..0x3fce_0000,
+    0x4037_c000..0x403e_0000,
+    0x5000_0000..0x5000_2000,
+];
+
+/// Memory usage of a firmware image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    /// Bytes that the segments take up in the flash image. All loadable segments are stored in
+    /// the image, also the ones that are copied to RAM at boot.
+    ///
+    /// The actual image is slightly larger, because of the image and segment headers and the
+    /// padding.
+    pub flash: u64,
+    /// Bytes of internal RAM that are used statically (data, bss and code that runs from RAM)
+    pub ram: u64,
+}
+
+/// Return the memory usage of an ELF file.
+pub fn usage(elf: &Path) -> io::Result<Usage> {
+    let data = fs::read(elf)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Not a 32-bit ELF file");
+    if data.len() < 0x34 || &data[..4] != b"\x7fELF" || data[4] != 1 {
+        return Err(invalid());
+    }
+    let phoff = read_u32(&data, 0x1c).ok_or_else(invalid)? as usize;
+    let phentsize = read_u16(&data, 0x2a).ok_or_else(invalid)? as usize;
+    let phnum = read_u16(&data, 0x2c).ok_or_else(invalid)? as usize;
+    let mut usage = Usage::default();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if read_u32(&data, header).ok_or_else(invalid)? != PT_LOAD {
+            continue;
+        }
+        let address = read_u32(&data, header + 8).ok_or_else(invalid)?;
+        usage.flash += u64::from(read_u32(&data, header + 16).ok_or_else(invalid)?);
+        if RAM.iter().any(|range| range.contains(&address)) {
+            usage.ram += u64::from(read_u32(&data, header + 20).ok_or_else(invalid)?);
+        }
+    }
+    Ok(usage)
+}
+
+/// Return the size of the first app partition in a partition table CSV file.
+pub fn app_partition_size(csv: &str) -> Option<u64> {
+    csv.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(',').map(str::trim).collect::<Vec<_>>())
+        .find(|columns| columns.get(1) == Some(&"app"))
+        .and_then(|columns| parse_size(columns.get(4)?))
+}
+
+/// Parse a partition size, e.g. `0x3F0000`, `4096`, `1M` or `64K`.
+fn parse_size(size: &str) -> Option<u64> {
+    if let Some(hex) = size.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    match size.strip_suffix(['K', 'k']) {
+        Some(kib) => kib.parse::<u64>().ok().map(|kib| kib * 1024),
+        None => match size.strip_suffix(['M', 'm']) {
+            Some(mib) => mib.parse::<u64>().ok().map(|mib| mib * 1024 * 1024),
+            None => size.parse().ok(),
+        },
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
@@ -0,0 +1,423 @@
+//! Local HTTP API.
+//!
+//! Endpoints:
+//!
+//! - `GET /api/v1/config`: Export the config as TOML (without secrets)
+//! - `PUT /api/v1/config`: Import a TOML config, applied after a restart
+//! - `GET /api/v1/outputs`: List the PWM outputs with their levels (`<name> <level>` per line)
+//! - `PUT /api/v1/outputs/<name>`: Set the level of a PWM output, the body is the level in percent
+//! - `GET /api/v1/log`: The persistent event log, oldest entry first (`<seq> <unix time or ->
+//!   <uptime>s <kind> <message>` per line)
+//! - `GET /api/v1/history?metric=<metric>&from=<time>&to=<time>&points=<n>`: Recent values of a
+//!   metric as JSON (`{"metric": "co2", "points": [[<time>, <value>], ...]}`). Times are Unix
+//!   timestamps in seconds. `from` and `to` default to the whole history, `points` (the maximum
+//!   number of points, the values are downsampled if there are more) defaults to 100.
+//! - `GET /api/v1/summary?hours=<n>`: Minimum, maximum and mean of every measured metric over the
+//!   last `hours` (default: 24) as JSON (`{"hours": 24, "metrics": {"co2": {"min": <value>,
+//!   "max": <value>, "mean": <value>}, ...}}`), as far as the history reaches back.
+//! - `POST /api/v1/backfill?from=<time>&to=<time>`: Resubmit the history values between two Unix
+//!   timestamps (default: the whole history) to InfluxDB, e.g. after an outage of the backend.
+//!   The values are submitted in the background, the response is sent immediately.
+//! - `GET /metrics`: The latest readings, sensor availability and error counts in the Prometheus
+//!   text format (see [`crate::prometheus`]), if `api.metrics` is set
+//! - `POST /api/v1/restart`: Restart the device, after a graceful shutdown (see
+//!   [`crate::shutdown`]). The response is sent immediately.
+//!
+//! If `api.token` is set in the config, requests must be authenticated with an
+//! `Authorization: Bearer <token>` header.
+
+use std::sync::{mpsc::Sender, Arc, Mutex};
+
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerResult, Request},
+        Method,
+    },
+    io::Write,
+};
+use esp_idf_svc::{
+    http::server::{Configuration as HttpServerConfiguration, EspHttpServer},
+    nvs::EspDefaultNvsPartition,
+};
+
+use crate::{
+    clock, config,
+    config::{Config, Metric},
+    eventlog,
+    history::History,
+    outputs::Outputs,
+    prometheus::Exporter,
+    Message,
+};
+
+/// Maximum size of a request body in bytes
+const MAX_BODY_SIZE: usize = 4096;
+
+/// Default maximum number of points returned by the history endpoint
+const DEFAULT_HISTORY_POINTS: usize = 100;
+
+/// Default time range of the summary endpoint in hours
+const DEFAULT_SUMMARY_HOURS: u32 = 24;
+
+/// Start the HTTP server. The server is stopped when the returned instance is dropped.
+pub fn start(
+    config: Arc<Config>,
+    nvs: EspDefaultNvsPartition,
+    outputs: Arc<Mutex<Outputs<'static>>>,
+    history: Arc<Mutex<History>>,
+    exporter: Arc<Mutex<Exporter>>,
+    sender: Sender<Message>,
+) -> anyhow::Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/api/v1/config", Method::Get, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        match config::export(&handler_config) {
+            Ok(text) => {
+                let mut response =
+                    request.into_response(200, None, &[("content-type", "application/toml")])?;
+                response.write_all(text.as_bytes())?;
+                Ok(())
+            }
+            Err(e) => respond(request, 500, &format!("{:#}", e)),
+        }
+    })?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/api/v1/config", Method::Put, move |mut request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let body = match read_body(&mut request)? {
+            Some(body) => body,
+            None => return respond(request, 413, "Request body too large"),
+        };
+        let text = match std::str::from_utf8(&body) {
+            Ok(text) => text,
+            Err(_) => return respond(request, 400, "Config is not valid UTF-8"),
+        };
+        match config::import(nvs.clone(), text, &handler_config) {
+            Ok(_) => respond(
+                request,
+                200,
+                "Config stored, restart the device to apply it",
+            ),
+            Err(e) => respond(request, 400, &format!("{:#}", e)),
+        }
+    })?;
+
+    let handler_config = config.clone();
+    let handler_outputs = outputs.clone();
+    server.fn_handler("/api/v1/outputs", Method::Get, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let outputs = handler_outputs
+            .lock()
+            .expect("Failed to lock outputs mutex");
+        let text: String = outputs
+            .iter()
+            .map(|output| format!("{} {:.1}\n", output.name(), output.level()))
+            .collect();
+        drop(outputs);
+        let mut response =
+            request.into_response(200, None, &[("content-type", "text/plain; charset=utf-8")])?;
+        response.write_all(text.as_bytes())?;
+        Ok(())
+    })?;
+
+    // The HTTP server has no wildcard matching by default, so there's a handler per output
+    for output in config.outputs.iter() {
+        let handler_config = config.clone();
+        let handler_outputs = outputs.clone();
+        let name = output.name.clone();
+        let uri = format!("/api/v1/outputs/{}", name);
+        server.fn_handler(&uri, Method::Put, move |mut request| {
+            if !authorized(&request, &handler_config) {
+                return respond(request, 401, "Unauthorized");
+            }
+            let body = match read_body(&mut request)? {
+                Some(body) => body,
+                None => return respond(request, 413, "Request body too large"),
+            };
+            let level = match std::str::from_utf8(&body)
+                .ok()
+                .and_then(|text| text.trim().parse::<f32>().ok())
+            {
+                Some(level) => level,
+                None => return respond(request, 400, "Body must be the level in percent"),
+            };
+            let result = handler_outputs
+                .lock()
+                .expect("Failed to lock outputs mutex")
+                .set_level(&name, level);
+            match result {
+                Ok(()) => respond(request, 200, "OK"),
+                Err(e) => respond(request, 400, &format!("{:#}", e)),
+            }
+        })?;
+    }
+
+    // Handlers are matched without the query string
+    let (handler_config, handler_history) = (config.clone(), history.clone());
+    server.fn_handler("/api/v1/summary", Method::Get, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let hours = match parse_summary_query(request.uri()) {
+            Ok(hours) => hours,
+            Err(message) => return respond(request, 400, &message),
+        };
+        let history = handler_history
+            .lock()
+            .expect("Failed to lock history mutex");
+        let summaries: Vec<String> = Metric::ALL
+            .into_iter()
+            .filter_map(|metric| {
+                let summary = history.summary(metric, u64::from(hours) * 3600)?;
+                Some(format!(
+                    "\"{}\":{{\"min\":{},\"max\":{},\"mean\":{}}}",
+                    metric.name(),
+                    summary.min,
+                    summary.max,
+                    summary.mean
+                ))
+            })
+            .collect();
+        drop(history);
+        let json = format!(
+            "{{\"hours\":{},\"metrics\":{{{}}}}}",
+            hours,
+            summaries.join(",")
+        );
+        let mut response =
+            request.into_response(200, None, &[("content-type", "application/json")])?;
+        response.write_all(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/api/v1/log", Method::Get, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let text: String = eventlog::entries()
+            .iter()
+            .map(|entry| format!("{}\n", entry))
+            .collect();
+        let mut response =
+            request.into_response(200, None, &[("content-type", "text/plain; charset=utf-8")])?;
+        response.write_all(text.as_bytes())?;
+        Ok(())
+    })?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/api/v1/history", Method::Get, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let query = match parse_history_query(request.uri()) {
+            Ok(query) => query,
+            Err(message) => return respond(request, 400, &message),
+        };
+        let points = history.lock().expect("Failed to lock history mutex").query(
+            query.metric,
+            query.from,
+            query.to,
+            query.points,
+        );
+        let points = match points {
+            Some(points) => points,
+            None => return respond(request, 503, "Clock is not synchronized yet"),
+        };
+        let values: Vec<String> = points
+            .iter()
+            .map(|(timestamp, value)| format!("[{},{}]", timestamp, value))
+            .collect();
+        let json = format!(
+            "{{\"metric\":\"{}\",\"points\":[{}]}}",
+            query.metric.name(),
+            values.join(",")
+        );
+        let mut response =
+            request.into_response(200, None, &[("content-type", "application/json")])?;
+        response.write_all(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    if config.api.metrics {
+        let handler_config = config.clone();
+        server.fn_handler("/metrics", Method::Get, move |request| {
+            if !authorized(&request, &handler_config) {
+                return respond(request, 401, "Unauthorized");
+            }
+            let text = exporter
+                .lock()
+                .expect("Failed to lock exporter mutex")
+                .render();
+            let mut response = request.into_response(
+                200,
+                None,
+                &[("content-type", "text/plain; version=0.0.4")],
+            )?;
+            response.write_all(text.as_bytes())?;
+            Ok(())
+        })?;
+    }
+
+    let handler_config = config.clone();
+    let handler_sender = sender.clone();
+    server.fn_handler("/api/v1/backfill", Method::Post, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        let (from, to) = match parse_backfill_query(request.uri()) {
+            Ok(range) => range,
+            Err(message) => return respond(request, 400, &message),
+        };
+        if clock::unix_time().is_none() {
+            return respond(request, 503, "Clock is not synchronized yet");
+        }
+        match handler_sender.send(Message::Backfill { from, to }) {
+            Ok(()) => respond(request, 202, "Backfill scheduled"),
+            Err(_) => respond(request, 500, "Main loop is not running"),
+        }
+    })?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/api/v1/restart", Method::Post, move |request| {
+        if !authorized(&request, &handler_config) {
+            return respond(request, 401, "Unauthorized");
+        }
+        match sender.send(Message::Restart) {
+            Ok(()) => respond(request, 202, "Restart scheduled"),
+            Err(_) => respond(request, 500, "Main loop is not running"),
+        }
+    })?;
+
+    Ok(server)
+}
+
+/// Parameters of a history query
+struct HistoryQuery {
+    metric: Metric,
+    from: u64,
+    to: u64,
+    points: usize,
+}
+
+/// Parse the query string of a history request.
+fn parse_history_query(uri: &str) -> Result<HistoryQuery, String> {
+    let mut metric = None;
+    let (mut from, mut to, mut points) = (0, u64::MAX, DEFAULT_HISTORY_POINTS);
+    for (key, value) in query_params(uri) {
+        let invalid = || format!("Invalid value for {}: {}", key, value);
+        match key {
+            "metric" => {
+                metric = Some(
+                    Metric::ALL
+                        .iter()
+                        .copied()
+                        .find(|metric| metric.name() == value)
+                        .ok_or_else(|| format!("Unknown metric: {}", value))?,
+                )
+            }
+            "from" => from = value.parse().map_err(|_| invalid())?,
+            "to" => to = value.parse().map_err(|_| invalid())?,
+            "points" => points = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+    }
+    Ok(HistoryQuery {
+        metric: metric.ok_or("Missing parameter: metric")?,
+        from,
+        to,
+        points,
+    })
+}
+
+/// Parse the query string of a summary request. Return the number of hours.
+fn parse_summary_query(uri: &str) -> Result<u32, String> {
+    let mut hours = DEFAULT_SUMMARY_HOURS;
+    for (key, value) in query_params(uri) {
+        match key {
+            "hours" => {
+                hours = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", key, value))?
+            }
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+    }
+    Ok(hours)
+}
+
+/// Parse the query string of a backfill request. Return the time range.
+fn parse_backfill_query(uri: &str) -> Result<(u64, u64), String> {
+    let (mut from, mut to) = (0, u64::MAX);
+    for (key, value) in query_params(uri) {
+        let invalid = || format!("Invalid value for {}: {}", key, value);
+        match key {
+            "from" => from = value.parse().map_err(|_| invalid())?,
+            "to" => to = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+    }
+    if from > to {
+        return Err("from must not be after to".into());
+    }
+    Ok((from, to))
+}
+
+/// Return the `key=value` pairs of the query string of a URI. The values are not URL-decoded,
+/// since only numbers and metric names are expected.
+fn query_params(uri: &str) -> impl Iterator<Item = (&str, &str)> {
+    let params = uri.split_once('?').map(|(_, params)| params).unwrap_or("");
+    params
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| param.split_once('=').unwrap_or((param, "")))
+}
+
+/// Return whether the request is authorized.
+fn authorized<C: Connection>(request: &Request<C>, config: &Config) -> bool {
+    match config.api.token {
+        Some(ref token) => {
+            request.header("authorization") == Some(format!("Bearer {}", token).as_str())
+        }
+        None => true,
+    }
+}
+
+/// Read the request body. Return `None` if it is larger than [`MAX_BODY_SIZE`].
+pub fn read_body<C: Connection>(request: &mut Request<C>) -> Result<Option<Vec<u8>>, C::Error> {
+    use embedded_svc::io::Read;
+
+    let mut body = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        let bytes_read = request.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(Some(body));
+        }
+        if body.len() + bytes_read > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buf[..bytes_read]);
+    }
+}
+
+/// Send a plain text response.
+fn respond<C: Connection>(request: Request<C>, status: u16, message: &str) -> HandlerResult {
+    let mut response = request.into_response(
+        status,
+        None,
+        &[("content-type", "text/plain; charset=utf-8")],
+    )?;
+    response.write_all(message.as_bytes())?;
+    response.write_all(b"\n")?;
+    Ok(())
+}
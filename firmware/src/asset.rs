@@ -0,0 +1,82 @@
+//! Serial number (asset tag) of the device.
+//!
+//! The serial number identifies the physical device, independently of its name, which may change
+//! when a device is moved to another room. It is reported in the `serial` tag of every line and
+//! shown on the display and on the serial console.
+//!
+//! The serial number is read from the user data eFuse block (BLOCK3) if it was burned, and from
+//! the `device` NVS namespace otherwise (written by the provisioning tool). The eFuse is
+//! permanent, while the NVS entry is lost if the NVS partition is erased.
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys::EspError;
+
+/// NVS namespace and key of the serial number
+const NAMESPACE: &str = "device";
+const KEY: &str = "serial";
+
+/// Maximum length of a serial number, the size of the user data eFuse block
+const MAX_LENGTH: usize = 32;
+
+/// Return the serial number, or `None` if the device has none.
+pub fn serial(partition: EspDefaultNvsPartition) -> Option<String> {
+    let (source, serial) = match read_efuse() {
+        Ok(Some(serial)) => ("eFuse", serial),
+        Ok(None) => match read_nvs(partition) {
+            Ok(serial) => ("NVS", serial?),
+            Err(e) => {
+                eprintln!("Serial: Could not read from NVS: {}", e);
+                return None;
+            }
+        },
+        Err(e) => {
+            eprintln!("Serial: Could not read eFuse: {}", e);
+            return None;
+        }
+    };
+    if !is_valid(&serial) {
+        eprintln!("Serial: Ignoring invalid serial number in {}", source);
+        return None;
+    }
+    Some(serial)
+}
+
+/// Read the serial number from the user data eFuse block, zero-padded ASCII. Returns `None` if
+/// the block was not burned.
+fn read_efuse() -> Result<Option<String>, EspError> {
+    let mut block = [0u8; MAX_LENGTH];
+    EspError::convert(unsafe {
+        esp_idf_sys::esp_efuse_read_block(
+            esp_idf_sys::esp_efuse_block_t_EFUSE_BLK3,
+            block.as_mut_ptr().cast(),
+            0,
+            MAX_LENGTH * 8,
+        )
+    })?;
+    let length = block
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(MAX_LENGTH);
+    if length == 0 {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&block[..length]).into_owned()))
+}
+
+fn read_nvs(partition: EspDefaultNvsPartition) -> Result<Option<String>, EspError> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = [0u8; MAX_LENGTH];
+    Ok(nvs
+        .get_raw(KEY, &mut buf)?
+        .map(|serial| String::from_utf8_lossy(serial).into_owned()))
+}
+
+/// Return whether a serial number can be used as tag value: Printable ASCII without spaces,
+/// commas, equal signs and backslashes.
+fn is_valid(serial: &str) -> bool {
+    !serial.is_empty()
+        && serial.len() <= MAX_LENGTH
+        && serial
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !b",=\\".contains(&byte))
+}
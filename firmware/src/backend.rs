@@ -0,0 +1,237 @@
+//! Fan-out of the measurement cycles to the submission backends.
+//!
+//! Every measurement cycle is submitted to all configured backends: InfluxDB (through the
+//! delivery queue, see [`crate::delivery`]), the MQTT broker, the JSON webhook and ESP-NOW.
+//! InfluxDB is a backend unless another one replaces it (`influxdb = false`, or ESP-NOW). Each
+//! backend handles its errors on its own, a failing backend doesn't keep the cycle from the
+//! others. The cycle counts as delivered (for the outage detection and the metrics) if all
+//! backends that were due accepted it.
+//!
+//! A backend with an `interval_secs` only gets a cycle once the interval has passed since its
+//! last one, e.g. MQTT every 10 s for live data and InfluxDB every 5 minutes. The cycles in
+//! between are skipped, except by InfluxDB, which collects their lines (with their timestamps)
+//! and submits them together with the next cycle that is due, as one batch. The annotations (see
+//! `sinks.annotations`) go the same way, only to InfluxDB.
+
+use std::{
+    mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{
+    config::Config,
+    delivery::{self, Queue},
+    json::JsonWebhook,
+    mqtt::Publisher,
+    reading::Reading,
+};
+
+/// Tolerance of the intervals, so that a backend with the interval of the measurements doesn't
+/// skip every other cycle because of jitter
+const TOLERANCE: Duration = Duration::from_secs(1);
+
+/// A measurement cycle
+pub struct Submission<'a> {
+    pub readings: &'a [Reading],
+    /// The readings and further lines (rule events, aggregates and diagnostics) in InfluxDB line
+    /// protocol format, without timestamps
+    pub lines: &'a [String],
+    /// Event log entries as `events` lines, with the timestamps of the entries. Only set if the
+    /// clock is synchronized.
+    pub annotations: &'a [String],
+    /// Unix time, `None` if the clock is not synchronized yet
+    pub timestamp: Option<u64>,
+}
+
+pub trait Backend {
+    /// Name of the backend, for log messages
+    fn name(&self) -> &'static str;
+
+    /// Submit a measurement cycle. Fail if the backend didn't accept it.
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()>;
+
+    /// Called with the cycles that are skipped because the interval of the backend didn't pass
+    /// yet.
+    fn skip(&mut self, _submission: &Submission) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called before a restart, e.g. to submit what's still pending.
+    fn shutdown(&mut self) {}
+}
+
+struct Entry {
+    backend: Box<dyn Backend>,
+    /// `None` for every cycle
+    interval: Option<Duration>,
+    /// Time of the last submission
+    last: Option<Instant>,
+}
+
+impl Entry {
+    fn is_due(&self, now: Instant) -> bool {
+        match (self.interval, self.last) {
+            (Some(interval), Some(last)) => now.duration_since(last) + TOLERANCE >= interval,
+            _ => true,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    backends: Vec<Entry>,
+}
+
+impl Dispatcher {
+    /// Add a backend that gets a cycle every `interval_secs` (every cycle if `None`).
+    pub fn add(&mut self, backend: Box<dyn Backend>, interval_secs: Option<u32>) {
+        self.backends.push(Entry {
+            backend,
+            interval: interval_secs.map(|secs| Duration::from_secs(secs.into())),
+            last: None,
+        });
+    }
+
+    /// Submit a measurement cycle to all backends that are due. Return whether all of them
+    /// accepted it.
+    pub fn submit(&mut self, submission: &Submission) -> bool {
+        let now = Instant::now();
+        let mut delivered = true;
+        for entry in self.backends.iter_mut() {
+            let due = entry.is_due(now);
+            let result = if due {
+                entry.last = Some(now);
+                entry.backend.submit(submission)
+            } else {
+                entry.backend.skip(submission)
+            };
+            if let Err(e) = result {
+                eprintln!(
+                    "Error: Could not submit to {}: {:#}",
+                    entry.backend.name(),
+                    e
+                );
+                delivered = false;
+            }
+        }
+        delivered
+    }
+
+    /// Shut all backends down, before a restart.
+    pub fn shutdown(&mut self) {
+        for entry in self.backends.iter_mut() {
+            entry.backend.shutdown();
+        }
+    }
+}
+
+/// InfluxDB, with at-least-once delivery
+pub struct InfluxDb {
+    queue: Queue,
+    config: Arc<Config>,
+    /// Timestamped lines of the skipped cycles
+    collected: Vec<String>,
+}
+
+impl InfluxDb {
+    pub fn new(queue: Queue, config: Arc<Config>) -> Self {
+        Self {
+            queue,
+            config,
+            collected: Vec::new(),
+        }
+    }
+
+    /// Submit the collected lines as one batch, if any.
+    fn submit_collected(&mut self) -> bool {
+        if self.collected.is_empty() {
+            return true;
+        }
+        let payload = mem::take(&mut self.collected).join("\n");
+        let config = &self.config;
+        self.queue
+            .submit_timestamped(payload, |payload| crate::submit_payload(payload, config))
+    }
+}
+
+impl Backend for InfluxDb {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        let delivered = match submission.timestamp {
+            Some(_) if !self.collected.is_empty() || !submission.annotations.is_empty() => {
+                self.skip(submission)?;
+                self.submit_collected()
+            }
+            _ => {
+                let config = &self.config;
+                self.queue
+                    .submit(submission.lines, submission.timestamp, |payload| {
+                        crate::submit_payload(payload, config)
+                    })
+            }
+        };
+        if !delivered {
+            bail!("Not all batches were delivered");
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        match submission.timestamp {
+            Some(timestamp) => {
+                self.collected.push(delivery::timestamped(
+                    &submission.lines.join("\n"),
+                    timestamp,
+                ));
+                self.collected.extend_from_slice(submission.annotations);
+                Ok(())
+            }
+            // Only cycles with timestamps can be submitted together, the others are submitted
+            // on their own (and wait for the clock synchronization in the delivery queue)
+            None => self.submit(submission),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.submit_collected();
+        let config = &self.config;
+        if !self
+            .queue
+            .flush(|payload| crate::submit_payload(payload, config))
+        {
+            eprintln!("Error: Pending batches are retried after the restart");
+        }
+    }
+}
+
+impl Backend for Publisher {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        self.publish(submission.readings)
+    }
+
+    fn shutdown(&mut self) {
+        if let Err(e) = self.publish_offline() {
+            eprintln!("Error: Could not publish to MQTT: {:#}", e);
+        }
+    }
+}
+
+impl Backend for JsonWebhook {
+    fn name(&self) -> &'static str {
+        "JSON webhook"
+    }
+
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        self.send(submission.timestamp, submission.readings)
+    }
+}
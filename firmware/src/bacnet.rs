@@ -0,0 +1,882 @@
+//! Read-only BACnet/IP device, for building automation systems.
+//!
+//! The device answers `Who-Is` with `I-Am` (and announces itself at startup), and serves
+//! `ReadProperty` and `ReadPropertyMultiple` requests. Every measured metric is an Analog Input
+//! object with the metric name as object name and the latest value as present value. The instance
+//! number of an Analog Input is the position of the metric in `Metric::ALL`, starting at 1. Objects
+//! can't be written, and segmentation is not supported.
+//!
+//! Messages from remote networks (through a BACnet router) are answered through the router, and
+//! messages forwarded by a BBMD are answered directly. The device is not a router itself.
+//!
+//! The encoding is implemented here, limited to the BVLL, NPDU and APDU features that are needed
+//! for the supported services.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    config::{Config, Metric},
+    history::History,
+    SENSILO_NAME, VERSION,
+};
+
+/// Stack size of the device thread
+const STACK_SIZE: usize = 8192;
+
+/// Maximum APDU length that is accepted, the maximum for BACnet/IP
+const MAX_APDU_LENGTH: usize = 1476;
+
+/// Vendor identifier. Sensilo has no identifier assigned by ASHRAE.
+const VENDOR_ID: u32 = 0;
+
+/// Protocol revision, the last revision without the mandatory `Property_List` property
+const PROTOCOL_REVISION: u32 = 12;
+
+/// Instance number that addresses the local device in `ReadProperty` requests
+const WILDCARD_INSTANCE: u32 = 4194303;
+
+// BVLL functions
+const BVLC_TYPE: u8 = 0x81;
+const BVLC_FORWARDED_NPDU: u8 = 0x04;
+const BVLC_ORIGINAL_UNICAST_NPDU: u8 = 0x0a;
+const BVLC_ORIGINAL_BROADCAST_NPDU: u8 = 0x0b;
+
+// NPDU control bits
+const NPDU_NETWORK_MESSAGE: u8 = 0x80;
+const NPDU_DESTINATION: u8 = 0x20;
+const NPDU_SOURCE: u8 = 0x08;
+
+// APDU types
+const APDU_CONFIRMED_REQUEST: u8 = 0x0;
+const APDU_UNCONFIRMED_REQUEST: u8 = 0x1;
+const APDU_COMPLEX_ACK: u8 = 0x3;
+const APDU_ERROR: u8 = 0x5;
+const APDU_REJECT: u8 = 0x6;
+const APDU_ABORT: u8 = 0x7;
+
+/// Segmented message flag of confirmed requests
+const SEGMENTED_MESSAGE: u8 = 0x08;
+
+// Services
+const SERVICE_I_AM: u8 = 0;
+const SERVICE_WHO_IS: u8 = 8;
+const SERVICE_READ_PROPERTY: u8 = 12;
+const SERVICE_READ_PROPERTY_MULTIPLE: u8 = 14;
+
+// Reject and abort reasons
+const REJECT_MISSING_REQUIRED_PARAMETER: u8 = 5;
+const REJECT_UNRECOGNIZED_SERVICE: u8 = 9;
+const ABORT_SEGMENTATION_NOT_SUPPORTED: u8 = 4;
+
+// Error classes and codes
+const ERROR_CLASS_OBJECT: u32 = 1;
+const ERROR_CLASS_PROPERTY: u32 = 2;
+const ERROR_UNKNOWN_OBJECT: u32 = 31;
+const ERROR_UNKNOWN_PROPERTY: u32 = 32;
+const ERROR_INVALID_ARRAY_INDEX: u32 = 42;
+const ERROR_PROPERTY_IS_NOT_AN_ARRAY: u32 = 50;
+
+// Application tags
+const TAG_BOOLEAN: u8 = 1;
+const TAG_UNSIGNED: u8 = 2;
+const TAG_REAL: u8 = 4;
+const TAG_CHARACTER_STRING: u8 = 7;
+const TAG_BIT_STRING: u8 = 8;
+const TAG_ENUMERATED: u8 = 9;
+const TAG_OBJECT_IDENTIFIER: u8 = 12;
+
+// Object types
+const OBJECT_ANALOG_INPUT: u32 = 0;
+const OBJECT_DEVICE: u32 = 8;
+
+/// Number of object types in the supported object types bit string
+const OBJECT_TYPES: usize = 56;
+
+/// Number of services in the supported services bit string
+const SERVICES: usize = 40;
+
+// Properties
+const PROP_ALL: u32 = 8;
+const PROP_APDU_TIMEOUT: u32 = 11;
+const PROP_APPLICATION_SOFTWARE_VERSION: u32 = 12;
+const PROP_DEVICE_ADDRESS_BINDING: u32 = 30;
+const PROP_EVENT_STATE: u32 = 36;
+const PROP_FIRMWARE_REVISION: u32 = 44;
+const PROP_MAX_APDU_LENGTH_ACCEPTED: u32 = 62;
+const PROP_MODEL_NAME: u32 = 70;
+const PROP_NUMBER_OF_APDU_RETRIES: u32 = 73;
+const PROP_OBJECT_IDENTIFIER: u32 = 75;
+const PROP_OBJECT_LIST: u32 = 76;
+const PROP_OBJECT_NAME: u32 = 77;
+const PROP_OBJECT_TYPE: u32 = 79;
+const PROP_OPTIONAL: u32 = 80;
+const PROP_OUT_OF_SERVICE: u32 = 81;
+const PROP_PRESENT_VALUE: u32 = 85;
+const PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED: u32 = 96;
+const PROP_PROTOCOL_SERVICES_SUPPORTED: u32 = 97;
+const PROP_PROTOCOL_VERSION: u32 = 98;
+const PROP_REQUIRED: u32 = 105;
+const PROP_SEGMENTATION_SUPPORTED: u32 = 107;
+const PROP_STATUS_FLAGS: u32 = 111;
+const PROP_SYSTEM_STATUS: u32 = 112;
+const PROP_UNITS: u32 = 117;
+const PROP_VENDOR_IDENTIFIER: u32 = 120;
+const PROP_VENDOR_NAME: u32 = 121;
+const PROP_PROTOCOL_REVISION: u32 = 139;
+const PROP_DATABASE_REVISION: u32 = 155;
+
+/// Properties of the device object
+const DEVICE_PROPERTIES: [u32; 20] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_SYSTEM_STATUS,
+    PROP_VENDOR_NAME,
+    PROP_VENDOR_IDENTIFIER,
+    PROP_MODEL_NAME,
+    PROP_FIRMWARE_REVISION,
+    PROP_APPLICATION_SOFTWARE_VERSION,
+    PROP_PROTOCOL_VERSION,
+    PROP_PROTOCOL_REVISION,
+    PROP_PROTOCOL_SERVICES_SUPPORTED,
+    PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED,
+    PROP_OBJECT_LIST,
+    PROP_MAX_APDU_LENGTH_ACCEPTED,
+    PROP_SEGMENTATION_SUPPORTED,
+    PROP_APDU_TIMEOUT,
+    PROP_NUMBER_OF_APDU_RETRIES,
+    PROP_DEVICE_ADDRESS_BINDING,
+    PROP_DATABASE_REVISION,
+];
+
+/// Properties of the analog input objects
+const ANALOG_INPUT_PROPERTIES: [u32; 8] = [
+    PROP_OBJECT_IDENTIFIER,
+    PROP_OBJECT_NAME,
+    PROP_OBJECT_TYPE,
+    PROP_PRESENT_VALUE,
+    PROP_STATUS_FLAGS,
+    PROP_EVENT_STATE,
+    PROP_OUT_OF_SERVICE,
+    PROP_UNITS,
+];
+
+/// Error class and code
+type Error = (u32, u32);
+
+/// An object of the device
+#[derive(Copy, Clone)]
+enum Object {
+    Device,
+    AnalogInput { metric: Metric, value: f32 },
+}
+
+/// Network address of the sender of a message on a remote network, as seen by the router
+struct RemoteAddress {
+    network: u16,
+    address: Vec<u8>,
+}
+
+/// Start the device thread.
+pub fn spawn(config: Arc<Config>, history: Arc<Mutex<History>>) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", config.bacnet.port))?;
+    socket.set_broadcast(true)?;
+    let device = Device {
+        instance: config.bacnet.device_instance.unwrap_or_default(),
+        config,
+        history,
+    };
+    thread::Builder::new()
+        .name("bacnet".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || device.run(socket))?;
+    Ok(())
+}
+
+struct Device {
+    config: Arc<Config>,
+    history: Arc<Mutex<History>>,
+    instance: u32,
+}
+
+impl Device {
+    fn run(&self, socket: UdpSocket) {
+        let broadcast = SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::BROADCAST,
+            self.config.bacnet.port,
+        ));
+
+        // Announce the device
+        let announcement = bvll(BVLC_ORIGINAL_BROADCAST_NPDU, &npdu(None, &self.i_am()));
+        if let Err(e) = socket.send_to(&announcement, broadcast) {
+            eprintln!("BACnet: ERROR: Could not send I-Am: {}", e);
+        }
+
+        let mut buf = [0u8; 1500];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("BACnet: ERROR: Could not receive message: {}", e);
+                    continue;
+                }
+            };
+            let (source, message) = match decode_bvll(&buf[..length], peer) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            let (remote, apdu) = match decode_npdu(message) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            let (apdu, broadcast_reply) = match self.handle(apdu) {
+                Some(reply) => reply,
+                None => continue,
+            };
+            let npdu = npdu(remote.as_ref(), &apdu);
+            let result = if broadcast_reply {
+                socket.send_to(&bvll(BVLC_ORIGINAL_BROADCAST_NPDU, &npdu), broadcast)
+            } else {
+                socket.send_to(&bvll(BVLC_ORIGINAL_UNICAST_NPDU, &npdu), source)
+            };
+            if let Err(e) = result {
+                eprintln!("BACnet: ERROR: Could not send reply: {}", e);
+            }
+        }
+    }
+
+    /// Handle an APDU. Return the reply and whether it must be broadcast, or `None` if there's no
+    /// reply.
+    fn handle(&self, apdu: &[u8]) -> Option<(Vec<u8>, bool)> {
+        match apdu.first()? >> 4 {
+            APDU_CONFIRMED_REQUEST => {
+                if apdu.len() < 4 {
+                    return None;
+                }
+                let invoke_id = apdu[2];
+                if apdu[0] & SEGMENTED_MESSAGE != 0 {
+                    return Some((abort(invoke_id, ABORT_SEGMENTATION_NOT_SUPPORTED), false));
+                }
+                let (service, parameters) = (apdu[3], &apdu[4..]);
+                let reply = match service {
+                    SERVICE_READ_PROPERTY => self.read_property(parameters),
+                    SERVICE_READ_PROPERTY_MULTIPLE => self.read_property_multiple(parameters),
+                    _ => Err(REJECT_UNRECOGNIZED_SERVICE),
+                };
+                let reply = match reply {
+                    Ok(Ok(body)) => {
+                        [&[APDU_COMPLEX_ACK << 4, invoke_id, service], &body[..]].concat()
+                    }
+                    Ok(Err((class, code))) => [
+                        &[APDU_ERROR << 4, invoke_id, service],
+                        &enumerated(class)[..],
+                        &enumerated(code)[..],
+                    ]
+                    .concat(),
+                    Err(reason) => vec![APDU_REJECT << 4, invoke_id, reason],
+                };
+                if reply.len() > max_apdu_length(apdu[1]) {
+                    return Some((abort(invoke_id, ABORT_SEGMENTATION_NOT_SUPPORTED), false));
+                }
+                Some((reply, false))
+            }
+            APDU_UNCONFIRMED_REQUEST if apdu.get(1) == Some(&SERVICE_WHO_IS) => {
+                // Optional range of device instances
+                let range = context_unsigned(&apdu[2..], 0)
+                    .and_then(|(low, rest)| Some((low, context_unsigned(rest, 1)?.0)));
+                match range {
+                    Some((low, high)) if !(low..=high).contains(&self.instance) => None,
+                    _ => Some((self.i_am(), true)),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the `I-Am` APDU.
+    fn i_am(&self) -> Vec<u8> {
+        [
+            &[APDU_UNCONFIRMED_REQUEST << 4, SERVICE_I_AM],
+            &object_identifier(OBJECT_DEVICE, self.instance)[..],
+            &unsigned(MAX_APDU_LENGTH as u32)[..],
+            // No segmentation
+            &enumerated(3)[..],
+            &unsigned(VENDOR_ID)[..],
+        ]
+        .concat()
+    }
+
+    /// Handle a `ReadProperty` request. Returns the body of the acknowledgement or the property
+    /// error, or the reject reason if the request is malformed.
+    fn read_property(&self, parameters: &[u8]) -> Result<Result<Vec<u8>, Error>, u8> {
+        let (object_id, rest) =
+            context_unsigned(parameters, 0).ok_or(REJECT_MISSING_REQUIRED_PARAMETER)?;
+        let (property, rest) =
+            context_unsigned(rest, 1).ok_or(REJECT_MISSING_REQUIRED_PARAMETER)?;
+        let index = context_unsigned(rest, 2).map(|(index, _)| index);
+
+        let objects = self.objects();
+        let value = match self.find(&objects, object_id) {
+            Some(object) => self.property(&objects, object, property, index),
+            None => Err((ERROR_CLASS_OBJECT, ERROR_UNKNOWN_OBJECT)),
+        };
+        Ok(value.map(|value| {
+            let mut body = context(0, &object_id.to_be_bytes());
+            body.extend(context(1, &minimal_bytes(property)));
+            if let Some(index) = index {
+                body.extend(context(2, &minimal_bytes(index)));
+            }
+            body.push(opening_tag(3));
+            body.extend(value);
+            body.push(closing_tag(3));
+            body
+        }))
+    }
+
+    /// Handle a `ReadPropertyMultiple` request. Returns the body of the acknowledgement, or the
+    /// reject reason if the request is malformed.
+    fn read_property_multiple(&self, parameters: &[u8]) -> Result<Result<Vec<u8>, Error>, u8> {
+        let objects = self.objects();
+        let mut body = Vec::new();
+        let mut rest = parameters;
+        while !rest.is_empty() {
+            let (object_id, r) =
+                context_unsigned(rest, 0).ok_or(REJECT_MISSING_REQUIRED_PARAMETER)?;
+            if r.first() != Some(&opening_tag(1)) {
+                return Err(REJECT_MISSING_REQUIRED_PARAMETER);
+            }
+            rest = &r[1..];
+            let object = self.find(&objects, object_id);
+
+            body.extend(context(0, &object_id.to_be_bytes()));
+            body.push(opening_tag(1));
+            while rest.first() != Some(&closing_tag(1)) {
+                let (property, r) =
+                    context_unsigned(rest, 0).ok_or(REJECT_MISSING_REQUIRED_PARAMETER)?;
+                let (index, r) = match context_unsigned(r, 1) {
+                    Some((index, r)) => (Some(index), r),
+                    None => (None, r),
+                };
+                rest = r;
+
+                // Special properties stand for a list of properties
+                let properties: Vec<u32> = match (object, property) {
+                    (Some(Object::Device), PROP_ALL | PROP_REQUIRED) => DEVICE_PROPERTIES.to_vec(),
+                    (Some(Object::AnalogInput { .. }), PROP_ALL | PROP_REQUIRED) => {
+                        ANALOG_INPUT_PROPERTIES.to_vec()
+                    }
+                    (Some(_), PROP_OPTIONAL) => Vec::new(),
+                    _ => vec![property],
+                };
+                for property in properties {
+                    let value = match object {
+                        Some(object) => self.property(&objects, object, property, index),
+                        None => Err((ERROR_CLASS_OBJECT, ERROR_UNKNOWN_OBJECT)),
+                    };
+                    body.extend(context(2, &minimal_bytes(property)));
+                    if let Some(index) = index {
+                        body.extend(context(3, &minimal_bytes(index)));
+                    }
+                    match value {
+                        Ok(value) => {
+                            body.push(opening_tag(4));
+                            body.extend(value);
+                            body.push(closing_tag(4));
+                        }
+                        Err((class, code)) => {
+                            body.push(opening_tag(5));
+                            body.extend(enumerated(class));
+                            body.extend(enumerated(code));
+                            body.push(closing_tag(5));
+                        }
+                    }
+                }
+            }
+            rest = &rest[1..];
+            body.push(closing_tag(1));
+        }
+        if body.is_empty() {
+            return Err(REJECT_MISSING_REQUIRED_PARAMETER);
+        }
+        Ok(Ok(body))
+    }
+
+    /// Return all objects, the device first, followed by the analog inputs of the measured metrics
+    /// (with their instance numbers).
+    fn objects(&self) -> Vec<(u32, Object)> {
+        let history = self.history.lock().expect("Failed to lock history mutex");
+        let mut objects = vec![(self.instance, Object::Device)];
+        for (i, metric) in Metric::ALL.into_iter().enumerate() {
+            if let Some(value) = history.latest(metric) {
+                objects.push((i as u32 + 1, Object::AnalogInput { metric, value }));
+            }
+        }
+        objects
+    }
+
+    /// Find the object with an object identifier.
+    fn find(&self, objects: &[(u32, Object)], object_id: u32) -> Option<Object> {
+        let (object_type, instance) = (object_id >> 22, object_id & 0x3fffff);
+        match object_type {
+            OBJECT_DEVICE if instance == self.instance || instance == WILDCARD_INSTANCE => {
+                Some(Object::Device)
+            }
+            OBJECT_ANALOG_INPUT => objects
+                .iter()
+                .find(|(i, object)| *i == instance && matches!(object, Object::AnalogInput { .. }))
+                .map(|(_, object)| *object),
+            _ => None,
+        }
+    }
+
+    /// Return the encoded value of a property.
+    fn property(
+        &self,
+        objects: &[(u32, Object)],
+        object: Object,
+        property: u32,
+        index: Option<u32>,
+    ) -> Result<Vec<u8>, Error> {
+        if index.is_some() && property != PROP_OBJECT_LIST {
+            return Err((ERROR_CLASS_PROPERTY, ERROR_PROPERTY_IS_NOT_AN_ARRAY));
+        }
+        let name = self.config.name.as_deref().unwrap_or(SENSILO_NAME);
+        let value = match (object, property) {
+            (Object::Device, PROP_OBJECT_IDENTIFIER) => {
+                object_identifier(OBJECT_DEVICE, self.instance)
+            }
+            (Object::Device, PROP_OBJECT_NAME) => character_string(name),
+            (Object::Device, PROP_OBJECT_TYPE) => enumerated(OBJECT_DEVICE),
+            // Operational
+            (Object::Device, PROP_SYSTEM_STATUS) => enumerated(0),
+            (Object::Device, PROP_VENDOR_NAME) => character_string("Sensilo"),
+            (Object::Device, PROP_VENDOR_IDENTIFIER) => unsigned(VENDOR_ID),
+            (Object::Device, PROP_MODEL_NAME) => character_string("Sensilo"),
+            (Object::Device, PROP_FIRMWARE_REVISION)
+            | (Object::Device, PROP_APPLICATION_SOFTWARE_VERSION) => character_string(VERSION),
+            (Object::Device, PROP_PROTOCOL_VERSION) => unsigned(1),
+            (Object::Device, PROP_PROTOCOL_REVISION) => unsigned(PROTOCOL_REVISION),
+            (Object::Device, PROP_PROTOCOL_SERVICES_SUPPORTED) => bit_string(
+                SERVICES,
+                &[
+                    SERVICE_READ_PROPERTY,
+                    SERVICE_READ_PROPERTY_MULTIPLE,
+                    // Bit of Who-Is in the supported services
+                    34,
+                ],
+            ),
+            (Object::Device, PROP_PROTOCOL_OBJECT_TYPES_SUPPORTED) => bit_string(
+                OBJECT_TYPES,
+                &[OBJECT_ANALOG_INPUT as u8, OBJECT_DEVICE as u8],
+            ),
+            (Object::Device, PROP_OBJECT_LIST) => {
+                let mut ids = objects.iter().map(|(instance, object)| match object {
+                    Object::Device => object_identifier(OBJECT_DEVICE, *instance),
+                    Object::AnalogInput { .. } => object_identifier(OBJECT_ANALOG_INPUT, *instance),
+                });
+                match index {
+                    None => ids.flatten().collect(),
+                    Some(0) => unsigned(objects.len() as u32),
+                    Some(index) => ids
+                        .nth(index as usize - 1)
+                        .ok_or((ERROR_CLASS_PROPERTY, ERROR_INVALID_ARRAY_INDEX))?,
+                }
+            }
+            (Object::Device, PROP_MAX_APDU_LENGTH_ACCEPTED) => unsigned(MAX_APDU_LENGTH as u32),
+            // No segmentation
+            (Object::Device, PROP_SEGMENTATION_SUPPORTED) => enumerated(3),
+            (Object::Device, PROP_APDU_TIMEOUT) => unsigned(3000),
+            (Object::Device, PROP_NUMBER_OF_APDU_RETRIES) => unsigned(3),
+            // Empty list
+            (Object::Device, PROP_DEVICE_ADDRESS_BINDING) => Vec::new(),
+            // Changes whenever an object is added, objects are never removed until restart
+            (Object::Device, PROP_DATABASE_REVISION) => unsigned(objects.len() as u32),
+
+            (Object::AnalogInput { metric, .. }, PROP_OBJECT_IDENTIFIER) => {
+                let instance = Metric::ALL
+                    .iter()
+                    .position(|m| *m == metric)
+                    .unwrap_or_default() as u32
+                    + 1;
+                object_identifier(OBJECT_ANALOG_INPUT, instance)
+            }
+            (Object::AnalogInput { metric, .. }, PROP_OBJECT_NAME) => {
+                character_string(metric.name())
+            }
+            (Object::AnalogInput { .. }, PROP_OBJECT_TYPE) => enumerated(OBJECT_ANALOG_INPUT),
+            (Object::AnalogInput { value, .. }, PROP_PRESENT_VALUE) => real(value),
+            // In alarm, fault, overridden, out of service
+            (Object::AnalogInput { .. }, PROP_STATUS_FLAGS) => bit_string(4, &[]),
+            // Normal
+            (Object::AnalogInput { .. }, PROP_EVENT_STATE) => enumerated(0),
+            (Object::AnalogInput { .. }, PROP_OUT_OF_SERVICE) => boolean(false),
+            (Object::AnalogInput { metric, .. }, PROP_UNITS) => enumerated(units(metric)),
+            _ => return Err((ERROR_CLASS_PROPERTY, ERROR_UNKNOWN_PROPERTY)),
+        };
+        Ok(value)
+    }
+}
+
+/// Return the BACnet engineering units of a metric.
+fn units(metric: Metric) -> u32 {
+    match metric {
+        Metric::Temperature | Metric::Thermocouple | Metric::Rtd => 62, // Degrees Celsius
+        Metric::Humidity => 29,                                         // Percent relative humidity
+        Metric::Illuminance => 37,                                      // Luxes
+        Metric::Co2 | Metric::Co2eq => 96,                              // Parts per million
+        Metric::Tvoc | Metric::Hcho => 97,                              // Parts per billion
+        Metric::Pressure | Metric::SeaLevelPressure => 133,             // Hectopascals
+        Metric::Occupancy => 98,                                        // Percent
+        Metric::Power => 47,                                            // Watts
+        // There's no unit for the dose rate
+        Metric::Aqi | Metric::DoseRate => 95, // No units
+    }
+}
+
+/// Decode a BVLL message. Return the address of the sender and the NPDU.
+fn decode_bvll(data: &[u8], peer: SocketAddr) -> Option<(SocketAddr, &[u8])> {
+    if data.len() < 4 || data[0] != BVLC_TYPE {
+        return None;
+    }
+    if usize::from(u16::from_be_bytes([data[2], data[3]])) != data.len() {
+        return None;
+    }
+    match data[1] {
+        BVLC_ORIGINAL_UNICAST_NPDU | BVLC_ORIGINAL_BROADCAST_NPDU => Some((peer, &data[4..])),
+        // Forwarded by a BBMD, with the address of the original sender
+        BVLC_FORWARDED_NPDU if data.len() >= 10 => {
+            let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            let port = u16::from_be_bytes([data[8], data[9]]);
+            Some((SocketAddr::V4(SocketAddrV4::new(ip, port)), &data[10..]))
+        }
+        _ => None,
+    }
+}
+
+/// Encode a BVLL message.
+fn bvll(function: u8, npdu: &[u8]) -> Vec<u8> {
+    let length = (npdu.len() + 4) as u16;
+    let mut out = vec![BVLC_TYPE, function];
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(npdu);
+    out
+}
+
+/// Decode an NPDU. Return the address of the sender if it's on a remote network, and the APDU.
+/// Network layer messages and messages for other networks are ignored.
+fn decode_npdu(data: &[u8]) -> Option<(Option<RemoteAddress>, &[u8])> {
+    if data.len() < 2 || data[0] != 1 {
+        return None;
+    }
+    let control = data[1];
+    if control & NPDU_NETWORK_MESSAGE != 0 {
+        return None;
+    }
+    let mut rest = &data[2..];
+    let has_destination = control & NPDU_DESTINATION != 0;
+    if has_destination {
+        let network = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+        // Only global broadcasts are for this device, it's not a router
+        if network != 0xffff {
+            return None;
+        }
+        let length = usize::from(*rest.get(2)?);
+        rest = rest.get(3 + length..)?;
+    }
+    let mut remote = None;
+    if control & NPDU_SOURCE != 0 {
+        let network = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+        let length = usize::from(*rest.get(2)?);
+        let address = rest.get(3..3 + length)?.to_vec();
+        remote = Some(RemoteAddress { network, address });
+        rest = &rest[3 + length..];
+    }
+    if has_destination {
+        // Hop count
+        rest = rest.get(1..)?;
+    }
+    Some((remote, rest))
+}
+
+/// Encode an NPDU, addressed to a device on a remote network if `remote` is set.
+fn npdu(remote: Option<&RemoteAddress>, apdu: &[u8]) -> Vec<u8> {
+    let mut out = vec![1];
+    match remote {
+        Some(remote) => {
+            out.push(NPDU_DESTINATION);
+            out.extend_from_slice(&remote.network.to_be_bytes());
+            out.push(remote.address.len() as u8);
+            out.extend_from_slice(&remote.address);
+            // Hop count
+            out.push(255);
+        }
+        None => out.push(0),
+    }
+    out.extend_from_slice(apdu);
+    out
+}
+
+/// Return the maximum APDU length from the encoded value in a confirmed request.
+fn max_apdu_length(encoded: u8) -> usize {
+    match encoded & 0x0f {
+        0 => 50,
+        1 => 128,
+        2 => 206,
+        3 => 480,
+        4 => 1024,
+        _ => MAX_APDU_LENGTH,
+    }
+}
+
+fn abort(invoke_id: u8, reason: u8) -> Vec<u8> {
+    // Sent by the server
+    vec![APDU_ABORT << 4 | 0x01, invoke_id, reason]
+}
+
+/// Decode a context tagged unsigned value (or object identifier) with the tag number.
+fn context_unsigned(data: &[u8], number: u8) -> Option<(u32, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let length = usize::from(tag & 0x07);
+    if tag >> 4 != number || tag & 0x08 == 0 || length == 0 || length > 4 || rest.len() < length {
+        return None;
+    }
+    let value = rest[..length]
+        .iter()
+        .fold(0, |value, byte| value << 8 | u32::from(*byte));
+    Some((value, &rest[length..]))
+}
+
+/// Encode the header of a tag. The `first` byte contains the tag number and class.
+fn tag(first: u8, length: usize) -> Vec<u8> {
+    if length < 5 {
+        vec![first | length as u8]
+    } else if length < 254 {
+        vec![first | 5, length as u8]
+    } else {
+        vec![first | 5, 254, (length >> 8) as u8, length as u8]
+    }
+}
+
+fn application(number: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = tag(number << 4, content.len());
+    out.extend_from_slice(content);
+    out
+}
+
+fn context(number: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = tag(number << 4 | 0x08, content.len());
+    out.extend_from_slice(content);
+    out
+}
+
+fn opening_tag(number: u8) -> u8 {
+    number << 4 | 0x0e
+}
+
+fn closing_tag(number: u8) -> u8 {
+    number << 4 | 0x0f
+}
+
+/// Return the big endian bytes of a value without leading zeros (at least one byte).
+fn minimal_bytes(value: u32) -> Vec<u8> {
+    let skip = (value.leading_zeros() / 8).min(3) as usize;
+    value.to_be_bytes()[skip..].to_vec()
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    // The value is encoded in the length field
+    vec![TAG_BOOLEAN << 4 | value as u8]
+}
+
+fn unsigned(value: u32) -> Vec<u8> {
+    application(TAG_UNSIGNED, &minimal_bytes(value))
+}
+
+fn enumerated(value: u32) -> Vec<u8> {
+    application(TAG_ENUMERATED, &minimal_bytes(value))
+}
+
+fn real(value: f32) -> Vec<u8> {
+    application(TAG_REAL, &value.to_be_bytes())
+}
+
+fn character_string(value: &str) -> Vec<u8> {
+    // Character set: UTF-8
+    let content = [&[0], value.as_bytes()].concat();
+    application(TAG_CHARACTER_STRING, &content)
+}
+
+/// Encode a bit string of `length` bits, with the bits in `set` set to 1.
+fn bit_string(length: usize, set: &[u8]) -> Vec<u8> {
+    // Number of unused bits in the last byte
+    let unused = (8 - length % 8) % 8;
+    let mut content = vec![0; (length + unused) / 8 + 1];
+    content[0] = unused as u8;
+    for bit in set.iter().map(|bit| usize::from(*bit)) {
+        content[1 + bit / 8] |= 0x80 >> (bit % 8);
+    }
+    application(TAG_BIT_STRING, &content)
+}
+
+fn object_identifier(object_type: u32, instance: u32) -> Vec<u8> {
+    application(
+        TAG_OBJECT_IDENTIFIER,
+        &(object_type << 22 | instance).to_be_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    const INSTANCE: u32 = 1234;
+
+    /// `ReadProperty` of the object name of the device, with the invoke ID 1
+    const READ_OBJECT_NAME: [u8; 11] = [
+        0x00, 0x05, 0x01, 0x0c, 0x0c, 0x02, 0x00, 0x04, 0xd2, 0x19, 0x4d,
+    ];
+
+    /// `ReadPropertyMultiple` of the object name of the device, with the invoke ID 1
+    const READ_MULTIPLE_OBJECT_NAME: [u8; 13] = [
+        0x00, 0x05, 0x01, 0x0e, 0x0c, 0x02, 0x00, 0x04, 0xd2, 0x1e, 0x09, 0x4d, 0x1f,
+    ];
+
+    fn device() -> Device {
+        Device {
+            config: Arc::new(Config::default()),
+            history: Arc::new(Mutex::new(History::new(&config::History::default()))),
+            instance: INSTANCE,
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 10), 47808))
+    }
+
+    #[test]
+    fn who_is_is_answered() {
+        let device = device();
+        assert_eq!(
+            device.handle(&[0x10, SERVICE_WHO_IS]),
+            Some((device.i_am(), true))
+        );
+        // Range of device instances without this device
+        assert_eq!(
+            device.handle(&[0x10, SERVICE_WHO_IS, 0x09, 0x00, 0x19, 0x0a]),
+            None
+        );
+    }
+
+    #[test]
+    fn read_property_is_acknowledged() {
+        let (reply, broadcast) = device().handle(&READ_OBJECT_NAME).unwrap();
+        assert!(!broadcast);
+        assert_eq!(
+            reply[..3],
+            [APDU_COMPLEX_ACK << 4, 0x01, SERVICE_READ_PROPERTY]
+        );
+        assert!(reply.ends_with(&[closing_tag(3)]));
+    }
+
+    #[test]
+    fn truncated_read_property_is_rejected() {
+        let device = device();
+        for length in 0..4 {
+            assert_eq!(device.handle(&READ_OBJECT_NAME[..length]), None);
+        }
+        for length in 4..READ_OBJECT_NAME.len() {
+            assert_eq!(
+                device.handle(&READ_OBJECT_NAME[..length]),
+                Some((
+                    vec![APDU_REJECT << 4, 0x01, REJECT_MISSING_REQUIRED_PARAMETER],
+                    false
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_read_property_multiple_is_rejected() {
+        let device = device();
+        for length in 4..READ_MULTIPLE_OBJECT_NAME.len() {
+            assert_eq!(
+                device.handle(&READ_MULTIPLE_OBJECT_NAME[..length]),
+                Some((
+                    vec![APDU_REJECT << 4, 0x01, REJECT_MISSING_REQUIRED_PARAMETER],
+                    false
+                ))
+            );
+        }
+        let (reply, _) = device.handle(&READ_MULTIPLE_OBJECT_NAME).unwrap();
+        assert_eq!(
+            reply[..3],
+            [APDU_COMPLEX_ACK << 4, 0x01, SERVICE_READ_PROPERTY_MULTIPLE]
+        );
+    }
+
+    #[test]
+    fn segmented_request_is_aborted() {
+        let mut request = READ_OBJECT_NAME;
+        request[0] |= SEGMENTED_MESSAGE;
+        assert_eq!(
+            device().handle(&request),
+            Some((abort(0x01, ABORT_SEGMENTATION_NOT_SUPPORTED), false))
+        );
+    }
+
+    #[test]
+    fn malformed_bvll_is_dropped() {
+        let message = bvll(BVLC_ORIGINAL_UNICAST_NPDU, &npdu(None, &READ_OBJECT_NAME));
+        assert_eq!(decode_bvll(&message, peer()), Some((peer(), &message[4..])));
+        for length in 0..message.len() {
+            assert!(decode_bvll(&message[..length], peer()).is_none());
+        }
+        // Forwarded message without the complete address of the original sender
+        assert!(decode_bvll(
+            &[BVLC_TYPE, BVLC_FORWARDED_NPDU, 0x00, 0x08, 1, 2, 3, 4],
+            peer()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn truncated_npdu_is_dropped() {
+        // From network 5, address 7
+        let message = [
+            &[0x01, NPDU_SOURCE, 0x00, 0x05, 0x01, 0x07][..],
+            &READ_OBJECT_NAME,
+        ]
+        .concat();
+        let (remote, apdu) = decode_npdu(&message).unwrap();
+        let remote = remote.unwrap();
+        assert_eq!((remote.network, remote.address), (5, vec![7]));
+        assert_eq!(apdu, READ_OBJECT_NAME);
+        for length in 0..6 {
+            assert!(decode_npdu(&message[..length]).is_none());
+        }
+        // Global broadcast without the hop count
+        assert!(decode_npdu(&[0x01, NPDU_DESTINATION, 0xff, 0xff, 0x00]).is_none());
+    }
+
+    #[test]
+    fn values_are_encoded() {
+        assert_eq!(minimal_bytes(0), [0x00]);
+        assert_eq!(minimal_bytes(0x1234), [0x12, 0x34]);
+        assert_eq!(unsigned(1476), [0x22, 0x05, 0xc4]);
+        assert_eq!(bit_string(4, &[]), [0x82, 0x04, 0x00]);
+        assert_eq!(
+            object_identifier(OBJECT_DEVICE, INSTANCE),
+            [0xc4, 0x02, 0x00, 0x04, 0xd2]
+        );
+        // Extended length
+        let long = character_string(&"x".repeat(300));
+        assert_eq!(long[..4], [0x75, 254, 0x01, 0x2d]);
+        assert_eq!(long.len(), 4 + 301);
+    }
+}
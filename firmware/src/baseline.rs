@@ -0,0 +1,92 @@
+//! Persistence of gas sensor algorithm baselines in NVS.
+//!
+//! MOX gas sensors need a long time (hours to days) to learn their baseline. To avoid starting
+//! from scratch after every reboot, the baseline is stored in NVS periodically and restored once
+//! after the sensor has warmed up.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+
+/// NVS namespace used for all baselines
+const NAMESPACE: &str = "baseline";
+
+pub struct BaselinePersistence {
+    nvs: EspNvs<NvsDefault>,
+    key: &'static str,
+    started: Instant,
+    warmup: Duration,
+    save_interval: Duration,
+    restored: bool,
+    last_save: Instant,
+}
+
+impl BaselinePersistence {
+    /// Create a new instance. The `key` must be unique per sensor type.
+    ///
+    /// The stored baseline will be restored once `warmup` has elapsed, and the current baseline
+    /// will be saved every `save_interval`.
+    pub fn new(
+        partition: EspDefaultNvsPartition,
+        key: &'static str,
+        warmup: Duration,
+        save_interval: Duration,
+    ) -> Result<Self, EspError> {
+        let now = Instant::now();
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+            key,
+            started: now,
+            warmup,
+            save_interval,
+            restored: false,
+            last_save: now,
+        })
+    }
+
+    /// If the warm-up period has elapsed and the baseline has not yet been restored, return the
+    /// stored baseline (if any). This returns `Some` at most once.
+    pub fn take_restore<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.restored || self.started.elapsed() < self.warmup {
+            return None;
+        }
+        self.restored = true;
+        let mut buf = [0; N];
+        match self.nvs.get_raw(self.key, &mut buf) {
+            Ok(Some(stored)) if stored.len() == N => Some(buf),
+            Ok(Some(_)) => {
+                eprintln!(
+                    "Baseline: Ignoring stored {} baseline with invalid length",
+                    self.key
+                );
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Baseline: Could not read {} baseline: {}", self.key, e);
+                None
+            }
+        }
+    }
+
+    /// Return whether the stored baseline was restored (or there was none), i.e. whether the
+    /// current baseline is based on the learned one and can be saved.
+    pub fn is_restored(&self) -> bool {
+        self.restored
+    }
+
+    /// Return whether the current baseline should be saved.
+    pub fn save_due(&self) -> bool {
+        self.last_save.elapsed() >= self.save_interval
+    }
+
+    /// Save the baseline to NVS.
+    pub fn save(&mut self, baseline: &[u8]) {
+        self.last_save = Instant::now();
+        match self.nvs.set_raw(self.key, baseline) {
+            Ok(_) => println!("Baseline: Saved {} baseline {:02x?}", self.key, baseline),
+            Err(e) => eprintln!("Baseline: Could not save {} baseline: {}", self.key, e),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! UDP datagrams with the latest readings, for consumers on the LAN without a backend.
+//!
+//! Every measurement cycle, a JSON object with the device name, the serial number (if any), the
+//! Unix time (if the clock is synchronized) and the values of all measured metrics is sent to
+//! the configured address, by default as broadcast to the whole LAN:
+//!
+//! ```json
+//! {"name":"livingroom","serial":"SN-0042","timestamp":1700000000,"values":{"co2":812}}
+//! ```
+//!
+//! Names and serial numbers never need escaping, since they are valid tag values.
+
+use std::{
+    io,
+    net::{SocketAddrV4, UdpSocket},
+};
+
+use crate::config::{self, Metric};
+
+pub struct Broadcaster {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+}
+
+impl Broadcaster {
+    pub fn new(config: &config::Udp) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            destination: SocketAddrV4::new(config.address, config.port),
+        })
+    }
+
+    /// Send the values of the metrics.
+    pub fn send(
+        &self,
+        name: &str,
+        serial: Option<&str>,
+        timestamp: Option<u64>,
+        values: &[(Metric, f32)],
+    ) -> io::Result<()> {
+        let mut json = format!("{{\"name\":\"{}\"", name);
+        if let Some(serial) = serial {
+            json.push_str(&format!(",\"serial\":\"{}\"", serial));
+        }
+        if let Some(timestamp) = timestamp {
+            json.push_str(&format!(",\"timestamp\":{}", timestamp));
+        }
+        let values: Vec<String> = values
+            .iter()
+            // JSON has no representation for NaN and infinity
+            .filter(|(_, value)| value.is_finite())
+            .map(|(metric, value)| format!("\"{}\":{}", metric.name(), value))
+            .collect();
+        json.push_str(&format!(",\"values\":{{{}}}}}", values.join(",")));
+        self.socket.send_to(json.as_bytes(), self.destination)?;
+        Ok(())
+    }
+}
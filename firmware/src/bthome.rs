@@ -0,0 +1,196 @@
+//! BTHome advertisements via Bluetooth LE, so that nearby Home Assistant instances (or their
+//! Bluetooth proxies) pick up the readings passively, without any configuration of the device.
+//!
+//! With `sinks.bthome`, the latest readings are advertised in the BTHome v2 format (unencrypted),
+//! every `advertising_ms`, and the advertisement is updated every measurement cycle. The readings
+//! of these metrics are advertised, as BTHome objects in the order of their IDs:
+//!
+//! | Metric        | Object                         |
+//! |---------------|--------------------------------|
+//! | `temperature` | 0x02, sint16, 0.01 °C          |
+//! | `humidity`    | 0x03, uint16, 0.01 %           |
+//! | `pressure`    | 0x04, uint24, 0.01 hPa         |
+//! | `illuminance` | 0x05, uint24, 0.01 lx          |
+//! | `power`       | 0x0b, uint24, 0.01 W           |
+//! | `co2`         | 0x12, uint16, ppm              |
+//!
+//! The packet ID (object 0x00) is incremented with every update, so that Home Assistant ignores
+//! repeated advertisements of the same readings. The device name is sent in the scan response.
+//!
+//! The NimBLE host runs in its own task and only advertises (see `sdkconfig.bthome`). WiFi and
+//! Bluetooth share the radio, so the advertisements work alongside the other sinks, or instead of
+//! them with `influxdb = false`.
+
+use std::ffi::c_void;
+
+use anyhow::bail;
+use esp_idf_sys::EspError;
+
+use crate::{
+    backend::{Backend, Submission},
+    config::{self, Metric},
+    reading::Reading,
+};
+
+/// Maximum size of a legacy advertisement (and scan response)
+const MAX_ADVERTISEMENT_SIZE: usize = 31;
+
+/// BTHome service UUID
+const SERVICE_UUID: u16 = 0xfcd2;
+
+/// BTHome device information: Version 2, not encrypted, sent regularly
+const DEVICE_INFO: u8 = 0x40;
+
+/// Object IDs of the metrics, with their factor and size in bytes, in the order of the IDs
+const OBJECTS: [(Metric, u8, f32, usize); 6] = [
+    (Metric::Temperature, 0x02, 100.0, 2),
+    (Metric::Humidity, 0x03, 100.0, 2),
+    (Metric::Pressure, 0x04, 100.0, 3),
+    (Metric::Illuminance, 0x05, 100.0, 3),
+    (Metric::Power, 0x0b, 100.0, 3),
+    (Metric::Co2, 0x12, 1.0, 2),
+];
+
+pub struct Advertiser {
+    name: String,
+    /// Advertising interval in units of 0.625 ms
+    interval: u16,
+    packet_id: u8,
+}
+
+impl Advertiser {
+    /// Start the Bluetooth controller and the NimBLE host. Advertising starts with the first
+    /// update.
+    pub fn new(config: &config::BtHome, name: &str) -> Result<Self, EspError> {
+        unsafe {
+            EspError::convert(esp_idf_sys::esp_nimble_hci_and_controller_init())?;
+            esp_idf_sys::nimble_port_init();
+            esp_idf_sys::nimble_port_freertos_init(Some(host_task));
+        }
+        Ok(Self {
+            name: name.into(),
+            // Validated when loading the config, at most 16384 units
+            interval: (config.advertising_ms * 8 / 5) as u16,
+            packet_id: 0,
+        })
+    }
+
+    /// Advertise the readings, replacing the previous ones.
+    pub fn update(&mut self, readings: &[Reading]) -> anyhow::Result<()> {
+        if unsafe { esp_idf_sys::ble_hs_synced() } == 0 {
+            bail!("Bluetooth host not ready yet");
+        }
+        self.packet_id = self.packet_id.wrapping_add(1);
+        let data = advertisement(self.packet_id, readings);
+        check(
+            unsafe { esp_idf_sys::ble_gap_adv_set_data(data.as_ptr(), data.len() as i32) },
+            "Could not set advertisement",
+        )?;
+        if unsafe { esp_idf_sys::ble_gap_adv_active() } == 0 {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    /// Start advertising, with the name in the scan response.
+    fn start(&self) -> anyhow::Result<()> {
+        let name = &self.name.as_bytes()[..self.name.len().min(MAX_ADVERTISEMENT_SIZE - 2)];
+        // Complete local name
+        let mut response = vec![name.len() as u8 + 1, 0x09];
+        response.extend_from_slice(name);
+        check(
+            unsafe {
+                esp_idf_sys::ble_gap_adv_rsp_set_data(response.as_ptr(), response.len() as i32)
+            },
+            "Could not set scan response",
+        )?;
+        let params = esp_idf_sys::ble_gap_adv_params {
+            conn_mode: esp_idf_sys::BLE_GAP_CONN_MODE_NON as u8,
+            disc_mode: esp_idf_sys::BLE_GAP_DISC_MODE_GEN as u8,
+            itvl_min: self.interval,
+            itvl_max: self.interval,
+            ..Default::default()
+        };
+        check(
+            unsafe {
+                esp_idf_sys::ble_gap_adv_start(
+                    esp_idf_sys::BLE_OWN_ADDR_PUBLIC as u8,
+                    std::ptr::null(),
+                    // BLE_HS_FOREVER
+                    i32::MAX,
+                    &params,
+                    None,
+                    std::ptr::null_mut(),
+                )
+            },
+            "Could not start advertising",
+        )?;
+        println!("Bluetooth: Advertising BTHome readings");
+        Ok(())
+    }
+}
+
+impl Backend for Advertiser {
+    fn name(&self) -> &'static str {
+        "BTHome"
+    }
+
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        self.update(submission.readings)
+    }
+}
+
+/// Runs the NimBLE host until it's stopped.
+unsafe extern "C" fn host_task(_: *mut c_void) {
+    esp_idf_sys::nimble_port_run();
+    esp_idf_sys::nimble_port_freertos_deinit();
+}
+
+/// Fail with `message` if a NimBLE function returned an error code.
+fn check(rc: i32, message: &str) -> anyhow::Result<()> {
+    if rc != 0 {
+        bail!("{} (NimBLE error {})", message, rc);
+    }
+    Ok(())
+}
+
+/// Return the advertisement with the flags and the BTHome service data. Objects that don't fit
+/// are left out.
+fn advertisement(packet_id: u8, readings: &[Reading]) -> Vec<u8> {
+    // General discoverable, BR/EDR not supported
+    let mut data = vec![0x02, 0x01, 0x06];
+    let mut service_data = SERVICE_UUID.to_le_bytes().to_vec();
+    service_data.extend_from_slice(&[DEVICE_INFO, 0x00, packet_id]);
+    // Header of the service data: Length and type
+    let mut available = MAX_ADVERTISEMENT_SIZE - data.len() - 2 - service_data.len();
+    for (metric, id, factor, size) in OBJECTS {
+        let reading = match readings.iter().find(|r| r.metric == Some(metric)) {
+            Some(reading) => reading,
+            None => continue,
+        };
+        if 1 + size > available {
+            continue;
+        }
+        available -= 1 + size;
+        let value = (reading.value.as_f32() * factor).round();
+        service_data.push(id);
+        match (metric, size) {
+            (Metric::Temperature, _) => {
+                let value = value.clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                service_data.extend_from_slice(&value.to_le_bytes());
+            }
+            (_, 2) => {
+                let value = value.clamp(0.0, u16::MAX as f32) as u16;
+                service_data.extend_from_slice(&value.to_le_bytes());
+            }
+            _ => {
+                let value = value.clamp(0.0, 0xff_ffff as f32) as u32;
+                service_data.extend_from_slice(&value.to_le_bytes()[..3]);
+            }
+        }
+    }
+    // Service data with a 16-bit UUID
+    data.extend_from_slice(&[service_data.len() as u8 + 1, 0x16]);
+    data.extend_from_slice(&service_data);
+    data
+}
@@ -0,0 +1,71 @@
+//! Energy budget of the device, measured with an INA219 channel on its own supply
+//! (`sensors.ina219.self_channel`).
+//!
+//! The power is sampled every [`SAMPLE_INTERVAL`] in a periodic timer task, so that the short
+//! peaks while transmitting (e.g. during a TLS handshake) are included, and integrated to the
+//! energy. Every measurement cycle, the energy since the previous cycle (i.e. of one full cycle,
+//! including waiting and the submission of the previous cycle) is submitted as
+//! `budget,sensor_type=ina219,channel=<channel> cycle_mwh=...,average_mw=...,cycle_secs=...`.
+//!
+//! Together with the `diagnostics` line, this shows what the measurement interval, the reused
+//! InfluxDB connection or a low power profile save.
+
+use std::time::{Duration, Instant};
+
+/// Interval at which the power is sampled
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Energy consumed during a measurement cycle
+#[derive(Debug, Copy, Clone)]
+pub struct Cycle {
+    /// Energy in mWh
+    pub energy: f32,
+    /// Average power in mW
+    pub average_power: f32,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+pub struct EnergyBudget {
+    /// Energy since the start of the cycle in Ws
+    energy: f64,
+    /// Time and power in W of the last sample
+    last_sample: Option<(Instant, f32)>,
+    /// Start of the cycle, `None` until the first sample
+    cycle_start: Option<Instant>,
+}
+
+impl EnergyBudget {
+    /// Add a power sample in W.
+    pub fn sample(&mut self, power: f32) {
+        let now = Instant::now();
+        match self.last_sample {
+            Some((at, last_power)) => {
+                // Trapezoidal rule
+                let secs = now.duration_since(at).as_secs_f64();
+                self.energy += secs * (f64::from(last_power) + f64::from(power)) / 2.0;
+            }
+            None => self.cycle_start = Some(now),
+        }
+        self.last_sample = Some((now, power));
+    }
+
+    /// Return the energy since the previous call (or since the first sample) and start a new
+    /// cycle. Return `None` if there was no sample yet.
+    pub fn take(&mut self) -> Option<Cycle> {
+        let now = Instant::now();
+        let duration = now.duration_since(self.cycle_start?);
+        let energy = self.energy;
+        self.energy = 0.0;
+        self.cycle_start = Some(now);
+        if duration.is_zero() {
+            return None;
+        }
+        Some(Cycle {
+            // 1 mWh = 3.6 Ws
+            energy: (energy / 3.6) as f32,
+            average_power: (energy * 1000.0 / duration.as_secs_f64()) as f32,
+            duration,
+        })
+    }
+}
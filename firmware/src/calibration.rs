@@ -0,0 +1,263 @@
+//! Calibration log of the sensors, with reminders, for deployments whose readings must be
+//! traceable (e.g. CO₂ sensors that are recalibrated against a reference once a year).
+//!
+//! The date of the last calibration of every sensor in `calibration.sensors` is stored in NVS. It
+//! is recorded with the `calibration set` console command, through MQTT (see [`crate::mqtt`]) and
+//! by a forced recalibration of the SCD4x (`scd4x frc`), and every calibration is appended to the
+//! event log. Every measurement cycle, the days since the last calibration are submitted as
+//! `calibration,sensor_type=<sensor> days=<days>u,due=<bool>` (only `due=true` for sensors that
+//! were never calibrated).
+//!
+//! A sensor is due once more than `interval_days` passed since its last calibration, or if it was
+//! never calibrated: A warning is printed and shown on the display, and with `notify`, an ntfy
+//! notification is published, at most once per day.
+//!
+//! Like the event log, the calibration log is global, so that the console and the MQTT client
+//! can record calibrations.
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+
+use crate::{
+    clock, config, eventlog,
+    reading::{Reading, Unit},
+};
+
+/// NVS namespace of the calibration dates, with the name of the sensor as key
+const NAMESPACE: &str = "calibration";
+
+/// Seconds per day
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+struct Log {
+    nvs: EspNvs<NvsDefault>,
+    interval_days: u32,
+    /// The tracked sensors with the day of their last calibration (days since the Unix epoch),
+    /// `None` if they were never calibrated
+    sensors: Vec<(&'static str, Option<u32>)>,
+    /// Day of the last reminder
+    reminded: Option<u32>,
+}
+
+/// Calibration status of a tracked sensor
+pub struct Status {
+    pub sensor: &'static str,
+    /// Day of the last calibration (days since the Unix epoch), `None` if never calibrated
+    pub day: Option<u32>,
+    /// Days since the last calibration, `None` if never calibrated or if the clock is not
+    /// synchronized
+    pub days_since: Option<u32>,
+    pub due: bool,
+}
+
+/// Open the log with the tracked sensors of the config. Without tracked sensors, the log stays
+/// disabled.
+pub fn open(
+    partition: EspDefaultNvsPartition,
+    config: &config::Calibration,
+) -> Result<(), EspError> {
+    if config.sensors.is_empty() {
+        return Ok(());
+    }
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut sensors = Vec::new();
+    for name in &config.sensors {
+        // Validated when loading the config
+        let sensor = match config::SENSOR_NAMES.iter().find(|sensor| *sensor == name) {
+            Some(sensor) => *sensor,
+            None => continue,
+        };
+        let mut buf = [0; 4];
+        let day = match nvs.get_raw(sensor, &mut buf)? {
+            Some(&[a, b, c, d]) => Some(u32::from_le_bytes([a, b, c, d])),
+            _ => None,
+        };
+        sensors.push((sensor, day));
+    }
+    *LOG.lock().expect("Failed to lock calibration log mutex") = Some(Log {
+        nvs,
+        interval_days: config.interval_days,
+        sensors,
+        reminded: None,
+    });
+    Ok(())
+}
+
+/// Return whether the log is enabled, i.e. whether any sensors are tracked.
+pub fn is_enabled() -> bool {
+    LOG.lock()
+        .expect("Failed to lock calibration log mutex")
+        .is_some()
+}
+
+/// Return whether the calibration of `sensor` is tracked.
+// Only used by the SCD4x recalibration
+#[cfg_attr(not(feature = "scd4x"), allow(dead_code))]
+pub fn is_tracked(sensor: &str) -> bool {
+    match LOG
+        .lock()
+        .expect("Failed to lock calibration log mutex")
+        .as_ref()
+    {
+        Some(log) => log.sensors.iter().any(|(name, _)| *name == sensor),
+        None => false,
+    }
+}
+
+/// Record a calibration of `sensor` on `day` (days since the Unix epoch), or today if `None`.
+pub fn record(sensor: &str, day: Option<u32>) -> anyhow::Result<()> {
+    let today = today();
+    let day = match (day, today) {
+        (Some(day), Some(today)) if day > today => bail!("{} is in the future", format_date(day)),
+        (Some(day), _) => day,
+        (None, Some(today)) => today,
+        (None, None) => bail!("The clock is not synchronized, specify the date"),
+    };
+    {
+        let mut log = LOG.lock().expect("Failed to lock calibration log mutex");
+        let log = log
+            .as_mut()
+            .ok_or_else(|| anyhow!("No sensors are tracked (calibration.sensors)"))?;
+        let entry = log
+            .sensors
+            .iter_mut()
+            .find(|(name, _)| *name == sensor)
+            .ok_or_else(|| anyhow!("{} is not tracked (calibration.sensors)", sensor))?;
+        log.nvs
+            .set_raw(entry.0, &day.to_le_bytes())
+            .map_err(|e| anyhow!("Could not store the calibration: {}", e))?;
+        entry.1 = Some(day);
+    }
+    eventlog::record(
+        eventlog::Kind::Calibration,
+        &format!("{}: Calibrated on {}", sensor, format_date(day)),
+    );
+    Ok(())
+}
+
+/// Return the calibration status of the tracked sensors.
+pub fn status() -> Vec<Status> {
+    let today = today();
+    let log = LOG.lock().expect("Failed to lock calibration log mutex");
+    let log = match log.as_ref() {
+        Some(log) => log,
+        None => return Vec::new(),
+    };
+    log.sensors
+        .iter()
+        .map(|&(sensor, day)| {
+            let days_since = day.zip(today).map(|(day, today)| today.saturating_sub(day));
+            Status {
+                sensor,
+                day,
+                days_since,
+                due: day.is_none() || matches!(days_since, Some(days) if days > log.interval_days),
+            }
+        })
+        .collect()
+}
+
+/// Return the readings of the tracked sensors. Sensors that were calibrated are left out while
+/// the clock is not synchronized.
+pub fn readings() -> Vec<Reading> {
+    let mut readings = Vec::new();
+    for status in status() {
+        if let Some(days) = status.days_since {
+            readings.push(
+                Reading::unsigned("calibration", "days", days, Unit::Day).sensor(status.sensor),
+            );
+        }
+        if status.days_since.is_some() || status.day.is_none() {
+            readings.push(Reading::bool("calibration", "due", status.due).sensor(status.sensor));
+        }
+    }
+    readings
+}
+
+/// Return the reminder of the sensors that are due, at most once per day. Returns `None` if no
+/// sensor is due, if there was a reminder today already or if the clock is not synchronized.
+pub fn reminder() -> Option<String> {
+    let today = today()?;
+    let due: Vec<String> = status()
+        .into_iter()
+        .filter(|status| status.due)
+        .map(|status| match status.day {
+            Some(day) => format!("{} (last on {})", status.sensor, format_date(day)),
+            None => format!("{} (never)", status.sensor),
+        })
+        .collect();
+    if due.is_empty() {
+        return None;
+    }
+    let mut log = LOG.lock().expect("Failed to lock calibration log mutex");
+    let log = log.as_mut()?;
+    if log.reminded == Some(today) {
+        return None;
+    }
+    log.reminded = Some(today);
+    Some(format!("Calibration due: {}", due.join(", ")))
+}
+
+/// Return today as days since the Unix epoch, or `None` if the clock is not synchronized.
+fn today() -> Option<u32> {
+    clock::unix_time().map(|secs| (secs / DAY_SECS) as u32)
+}
+
+/// Parse a date in the format `YYYY-MM-DD` to days since the Unix epoch.
+pub fn parse_date(text: &str) -> Option<u32> {
+    let mut parts = text.split('-');
+    let year: u32 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some()
+        || !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+    {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    // Rejects days that the month doesn't have, e.g. February 30 becomes March 1 or 2
+    (civil_from_days(days) == (year, month, day)).then_some(days)
+}
+
+/// Format days since the Unix epoch as `YYYY-MM-DD`.
+pub fn format_date(days: u32) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Return the days since the Unix epoch of a date (see Howard Hinnant's `days_from_civil`).
+fn days_from_civil(year: u32, month: u32, day: u32) -> u32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Return the date of days since the Unix epoch (see Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: u32) -> (u32, u32, u32) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
@@ -0,0 +1,15 @@
+//! Sensor instances, tagged with their measurement point.
+//!
+//! Sensor types that may be attached more than once are stored as a list of [`Channel`]s in
+//! [`Sensors`]. So far, that's the INA219 (at different I²C addresses), all other sensors are
+//! used once. The tag is submitted as `channel` tag, so that the instances can be told apart. The
+//! MAX31855 and MAX31865 are single channels, for their tag.
+//!
+//! [`Sensors`]: crate::Sensors
+
+/// A sensor, tagged with the name of its measurement point
+pub struct Channel<S> {
+    pub sensor: S,
+    /// Value of the `channel` tag
+    pub tag: String,
+}
@@ -0,0 +1,164 @@
+//! Wall clock time, synchronized via SNTP.
+//!
+//! There's no RTC with a backup battery, so the clock starts at the Unix epoch after every boot
+//! and is only valid once SNTP has synchronized it.
+//!
+//! SNTP uses the configured NTP servers (or the ESP-IDF defaults from `pool.ntp.org`), which are
+//! tried in order. After every synchronization, the correction of the clock is recorded, i.e. how
+//! far the clock drifted since the previous synchronization.
+//!
+//! The local time uses the time zone set with [`set_timezone`] (UTC by default), including the
+//! daylight saving time transitions. It is calculated by newlib, from the POSIX TZ string.
+
+use std::{
+    ffi::CString,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use esp_idf_svc::sntp::{EspSntp, SntpConf};
+
+/// Unix time before which the clock is considered not synchronized (2022-01-01)
+const MIN_VALID_TIME: u64 = 1_640_995_200;
+
+/// Time zone if none is configured
+pub const DEFAULT_TIMEZONE: &str = "UTC0";
+
+/// Maximum number of NTP servers, must match `CONFIG_LWIP_SNTP_MAX_SERVERS` in
+/// `sdkconfig.defaults`
+pub const MAX_NTP_SERVERS: usize = 3;
+
+/// The last SNTP synchronization, set from the SNTP callback
+static LAST_SYNC: Mutex<Option<LastSync>> = Mutex::new(None);
+
+struct LastSync {
+    /// Unix time in µs after the synchronization
+    unix_us: i64,
+    /// Uptime in µs at the synchronization
+    uptime_us: i64,
+    /// Correction of the clock in µs, `None` after the first synchronization
+    offset_us: Option<i64>,
+}
+
+/// Status of the time synchronization
+pub struct SyncStatus {
+    /// Seconds since the last synchronization
+    pub age_secs: u64,
+    /// Correction of the clock at the last synchronization in ms, `None` after the first one
+    pub offset_ms: Option<i64>,
+}
+
+/// Return the current Unix time in seconds, or `None` if the clock is not synchronized yet.
+pub fn unix_time() -> Option<u64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if secs < MIN_VALID_TIME {
+        return None;
+    }
+    Some(secs)
+}
+
+/// Start the time synchronization with the NTP `servers`, or with the ESP-IDF defaults if there
+/// are none. If there are fewer servers than [`MAX_NTP_SERVERS`], they are repeated.
+pub fn start_sntp(servers: &[String]) -> anyhow::Result<EspSntp> {
+    let mut conf = SntpConf::default();
+    if !servers.is_empty() {
+        for (slot, server) in conf.servers.iter_mut().zip(servers.iter().cycle()) {
+            *slot = server;
+        }
+    }
+    let sntp = EspSntp::new(&conf)?;
+    unsafe { esp_idf_sys::sntp_set_time_sync_notification_cb(Some(on_sync)) };
+    Ok(sntp)
+}
+
+/// Called by SNTP after the clock was set to `tv`.
+unsafe extern "C" fn on_sync(tv: *mut esp_idf_sys::timeval) {
+    let tv = &*tv;
+    // `time_t` is 32 bits with ESP-IDF 4.4
+    #[allow(clippy::useless_conversion)]
+    let unix_us = i64::from(tv.tv_sec) * 1_000_000 + i64::from(tv.tv_usec);
+    let uptime_us = esp_idf_sys::esp_timer_get_time();
+    if let Ok(mut last_sync) = LAST_SYNC.lock() {
+        // Where the clock would be without this synchronization
+        let offset_us = last_sync
+            .as_ref()
+            .map(|last| unix_us - (last.unix_us + (uptime_us - last.uptime_us)));
+        *last_sync = Some(LastSync {
+            unix_us,
+            uptime_us,
+            offset_us,
+        });
+    }
+}
+
+/// Return the status of the time synchronization, or `None` if the clock was not synchronized
+/// yet.
+pub fn sync_status() -> Option<SyncStatus> {
+    let uptime_us = unsafe { esp_idf_sys::esp_timer_get_time() };
+    let last_sync = LAST_SYNC.lock().expect("Failed to lock sync mutex");
+    last_sync.as_ref().map(|last| SyncStatus {
+        age_secs: ((uptime_us - last.uptime_us) / 1_000_000) as u64,
+        offset_ms: last.offset_us.map(|offset| offset / 1000),
+    })
+}
+
+/// Set the time zone of the local time, as POSIX TZ string (e.g. `CET-1CEST,M3.5.0,M10.5.0/3`).
+pub fn set_timezone(tz: &str) {
+    std::env::set_var("TZ", tz);
+    unsafe { esp_idf_sys::tzset() };
+}
+
+/// Return whether `tz` starts like a POSIX TZ string: A name of at least three letters (or any
+/// name in `<>`), followed by the offset to UTC. The daylight saving time rules are not checked.
+pub fn is_valid_timezone(tz: &str) -> bool {
+    if !tz.is_ascii() || tz.contains(char::is_whitespace) {
+        return false;
+    }
+    let rest = if let Some(quoted) = tz.strip_prefix('<') {
+        match quoted.find('>') {
+            Some(end) if end >= 3 => &quoted[end + 1..],
+            _ => return false,
+        }
+    } else {
+        let end = tz
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(tz.len());
+        if end < 3 {
+            return false;
+        }
+        &tz[end..]
+    };
+    let rest = rest.strip_prefix(['+', '-']).unwrap_or(rest);
+    rest.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Return the current local time, or `None` if the clock is not synchronized yet.
+fn local_time() -> Option<esp_idf_sys::tm> {
+    let secs = unix_time()? as esp_idf_sys::time_t;
+    let mut tm = esp_idf_sys::tm::default();
+    if unsafe { esp_idf_sys::localtime_r(&secs, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(tm)
+}
+
+/// Return the current local time of day in minutes since midnight, or `None` if the clock is not
+/// synchronized yet.
+pub fn minute_of_day() -> Option<u32> {
+    local_time().map(|tm| (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+/// Format the current local time with `strftime` (e.g. `%H:%M %Z`), or return `None` if the
+/// clock is not synchronized yet.
+pub fn format_local_time(format: &str) -> Option<String> {
+    let tm = local_time()?;
+    let format = CString::new(format).ok()?;
+    let mut buf = [0u8; 32];
+    let len = unsafe {
+        esp_idf_sys::strftime(buf.as_mut_ptr() as *mut _, buf.len(), format.as_ptr(), &tm)
+    };
+    if len == 0 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
@@ -0,0 +1,546 @@
+//! Commands on the serial console, e.g. for calibrating sensors on site.
+//!
+//! Commands are read line by line from stdin (the USB serial console) in a background thread.
+//! Enter `help` for a list of commands.
+//!
+//! Devices can be provisioned on a bench through the console, with the same firmware for all of
+//! them: Settings are changed one by one with `config set <key> <value>` (e.g. `set wifi.ssid
+//! MyNetwork`), every change is validated and stored in NVS right away, and applied with
+//! `restart`.
+
+use std::{
+    io::{self, Read},
+    str::FromStr,
+    sync::{mpsc::Sender, Arc},
+    thread,
+    time::Duration,
+};
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+#[cfg(any(feature = "gas", feature = "scd4x"))]
+use std::sync::Mutex;
+
+#[cfg(feature = "scd4x")]
+use crate::delay::GeneralPurposeDelay;
+#[cfg(feature = "faults")]
+use crate::faults;
+#[cfg(any(feature = "gas", feature = "scd4x"))]
+use crate::Sensors;
+use crate::{
+    calibration, clock, config, config::Config, eventlog, profile::ActiveProfile, stats, Message,
+    SENSILO_NAME, VERSION,
+};
+
+/// Stack size of the console thread
+const STACK_SIZE: usize = 8192;
+
+/// Interval at which stdin is polled (reads don't block on the ESP-IDF console)
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Maximum length of a command line, enough for setting an API token
+const MAX_LINE_LENGTH: usize = 256;
+
+/// Maximum length of a line of an imported config, e.g. with a webhook body
+const MAX_IMPORT_LINE_LENGTH: usize = 1024;
+
+/// Markers around an exported config. An import is terminated with the end marker.
+const CONFIG_BEGIN: &str = "-----BEGIN SENSILO CONFIG-----";
+const CONFIG_END: &str = "-----END SENSILO CONFIG-----";
+
+const HELP: &str = "\
+Commands:
+  help                          Show this help
+  info                          Show the name, serial number and firmware version
+  sgp30 baseline                Show the current SGP30 baseline
+  sgp30 baseline <co2eq> <tvoc> Set the SGP30 baseline
+  sgp30 clean-air               Restart the SGP30 algorithm, assuming clean air
+  scd4x frc <ppm>               Recalibrate the SCD4x to the specified CO₂ concentration
+  calibration                   Show the calibration dates of the tracked sensors
+  calibration set <sensor> [<date>]
+                                Record a calibration today or on a date (YYYY-MM-DD)
+  profile                       Show the active and the available profiles
+  profile set <name>            Switch to the specified profile (until restart)
+  profile clear                 Switch to the base settings (until restart)
+  config show                   Show the config (without secrets), including stored changes
+  config set <key> <value>      Change a setting (e.g. wifi.ssid), applied after a restart
+  config unset <key>            Reset a setting to its default, applied after a restart
+  config export                 Print the config (without secrets)
+  config import                 Import a config (paste it, followed by the end marker)
+  tasks                         List the tasks with their free stack (high-water mark)
+  heap                          Show the heap usage and fragmentation
+  log                           Show the persistent event log
+  restart                       Restart the device (after submitting the pending measurements)
+  fault                         Show the injected faults (only with the faults feature)
+  fault i2c|http [<count>]      Fail I²C transactions or HTTP requests (until cleared)
+  fault wifi                    Disconnect WiFi in the next measurement cycle
+  fault lock <ms> [<count>]     Hold the sensors mutex longer in every cycle (until cleared)
+  fault clear                   Stop injecting faults
+
+Aliases: show config, set <key> <value>, unset <key>, reboot";
+
+/// A console command
+enum Command {
+    Help,
+    Info,
+    #[cfg(feature = "gas")]
+    Sgp30GetBaseline,
+    #[cfg(feature = "gas")]
+    Sgp30SetBaseline {
+        co2eq: u16,
+        tvoc: u16,
+    },
+    #[cfg(feature = "gas")]
+    Sgp30CleanAir,
+    #[cfg(feature = "scd4x")]
+    Scd4xForcedRecalibration {
+        target_ppm: u16,
+    },
+    CalibrationShow,
+    CalibrationSet {
+        sensor: String,
+        /// Days since the Unix epoch, `None` for today
+        day: Option<u32>,
+    },
+    ProfileShow,
+    ProfileSet {
+        name: String,
+    },
+    ProfileClear,
+    ConfigShow,
+    ConfigSet {
+        key: String,
+        /// `None` to reset the setting to its default
+        value: Option<String>,
+    },
+    ConfigExport,
+    ConfigImport,
+    Tasks,
+    Heap,
+    Log,
+    Restart,
+    #[cfg(feature = "faults")]
+    Fault {
+        args: Vec<String>,
+    },
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            ["help"] => Ok(Self::Help),
+            ["info"] => Ok(Self::Info),
+            #[cfg(feature = "gas")]
+            ["sgp30", "baseline"] => Ok(Self::Sgp30GetBaseline),
+            #[cfg(feature = "gas")]
+            ["sgp30", "baseline", co2eq, tvoc] => Ok(Self::Sgp30SetBaseline {
+                co2eq: parse_arg("co2eq", co2eq)?,
+                tvoc: parse_arg("tvoc", tvoc)?,
+            }),
+            #[cfg(feature = "gas")]
+            ["sgp30", "clean-air"] => Ok(Self::Sgp30CleanAir),
+            #[cfg(not(feature = "gas"))]
+            ["sgp30", ..] => Err("The SGP30 is not enabled in this firmware".into()),
+            #[cfg(feature = "scd4x")]
+            ["scd4x", "frc", target_ppm] => Ok(Self::Scd4xForcedRecalibration {
+                target_ppm: parse_arg("ppm", target_ppm)?,
+            }),
+            #[cfg(not(feature = "scd4x"))]
+            ["scd4x", ..] => Err("The SCD4x is not enabled in this firmware".into()),
+            ["calibration"] => Ok(Self::CalibrationShow),
+            ["calibration", "set", sensor] => Ok(Self::CalibrationSet {
+                sensor: sensor.to_string(),
+                day: None,
+            }),
+            ["calibration", "set", sensor, date] => Ok(Self::CalibrationSet {
+                sensor: sensor.to_string(),
+                day: Some(
+                    calibration::parse_date(date)
+                        .ok_or_else(|| format!("Invalid date: {:?} (expected YYYY-MM-DD)", date))?,
+                ),
+            }),
+            ["profile"] => Ok(Self::ProfileShow),
+            ["profile", "set", name] => Ok(Self::ProfileSet {
+                name: name.to_string(),
+            }),
+            ["profile", "clear"] => Ok(Self::ProfileClear),
+            ["config", "show"] | ["show", "config"] => Ok(Self::ConfigShow),
+            ["config", "set", key, _, ..] => Ok(Self::ConfigSet {
+                key: key.to_string(),
+                value: Some(rest(line, 3).into()),
+            }),
+            ["set", key, _, ..] => Ok(Self::ConfigSet {
+                key: key.to_string(),
+                value: Some(rest(line, 2).into()),
+            }),
+            ["config", "unset", key] | ["unset", key] => Ok(Self::ConfigSet {
+                key: key.to_string(),
+                value: None,
+            }),
+            ["config", "export"] => Ok(Self::ConfigExport),
+            ["config", "import"] => Ok(Self::ConfigImport),
+            ["tasks"] => Ok(Self::Tasks),
+            ["heap"] => Ok(Self::Heap),
+            ["log"] => Ok(Self::Log),
+            ["restart"] | ["reboot"] => Ok(Self::Restart),
+            #[cfg(feature = "faults")]
+            ["fault", args @ ..] => Ok(Self::Fault {
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+            }),
+            #[cfg(not(feature = "faults"))]
+            ["fault", ..] => Err("Fault injection is not enabled in this firmware".into()),
+            _ => Err(format!(
+                "Unknown command: {:?} (enter \"help\" for help)",
+                line
+            )),
+        }
+    }
+}
+
+/// Return the rest of a line after the first `words` words, e.g. a value with spaces.
+fn rest(line: &str, words: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..words {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+#[cfg(any(feature = "gas", feature = "scd4x"))]
+fn parse_arg<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {:?}", name, value))
+}
+
+/// Start the console thread.
+pub fn spawn(
+    #[cfg(any(feature = "gas", feature = "scd4x"))] sensors: Arc<Mutex<Sensors<'static>>>,
+    config: Arc<Config>,
+    serial: Option<String>,
+    profile: ActiveProfile,
+    nvs: EspDefaultNvsPartition,
+    sender: Sender<Message>,
+) -> io::Result<()> {
+    let mut console = Console {
+        #[cfg(any(feature = "gas", feature = "scd4x"))]
+        sensors,
+        config,
+        serial,
+        profile,
+        nvs,
+        sender,
+        pending: None,
+        import: None,
+    };
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || console.run())?;
+    Ok(())
+}
+
+struct Console {
+    /// The sensors that can be calibrated
+    #[cfg(any(feature = "gas", feature = "scd4x"))]
+    sensors: Arc<Mutex<Sensors<'static>>>,
+    config: Arc<Config>,
+    serial: Option<String>,
+    profile: ActiveProfile,
+    nvs: EspDefaultNvsPartition,
+    /// Channel to the main loop, for restart requests
+    sender: Sender<Message>,
+    /// The config that was stored in NVS but is not applied yet, if any
+    pending: Option<Config>,
+    /// The config that is currently being imported, if any
+    import: Option<String>,
+}
+
+impl Console {
+    /// Return the config that is stored in NVS, i.e. the changed one if there are changes that
+    /// are not applied yet.
+    fn stored_config(&self) -> &Config {
+        self.pending.as_ref().unwrap_or(&self.config)
+    }
+
+    fn run(&mut self) {
+        let mut stdin = io::stdin();
+        let mut line = Vec::with_capacity(MAX_LINE_LENGTH);
+        // Whether the current line is longer than the maximum length
+        let mut too_long = false;
+        let mut buf = [0; 32];
+        loop {
+            let bytes_read = match stdin.read(&mut buf) {
+                Ok(bytes_read) => bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+                Err(e) => {
+                    eprintln!("Console: ERROR: Could not read from stdin: {}", e);
+                    0
+                }
+            };
+            if bytes_read == 0 {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            for byte in &buf[..bytes_read] {
+                match byte {
+                    b'\r' | b'\n' if too_long => {
+                        self.reject_line();
+                        too_long = false;
+                        line.clear();
+                    }
+                    b'\r' | b'\n' => {
+                        if let Ok(text) = std::str::from_utf8(&line) {
+                            let text = text.trim();
+                            if !text.is_empty() {
+                                self.handle_line(text);
+                            }
+                        }
+                        line.clear();
+                    }
+                    _ if line.len() < self.max_line_length() => line.push(*byte),
+                    _ => too_long = true,
+                }
+            }
+        }
+    }
+
+    /// Return the maximum length of a line, which is longer while importing a config.
+    fn max_line_length(&self) -> usize {
+        if self.import.is_some() {
+            MAX_IMPORT_LINE_LENGTH
+        } else {
+            MAX_LINE_LENGTH
+        }
+    }
+
+    /// Reject a line that is longer than the maximum length, instead of running a truncated
+    /// command. An import is aborted, so that the config isn't silently changed.
+    fn reject_line(&mut self) {
+        eprintln!(
+            "> Error: Line longer than {} characters",
+            self.max_line_length()
+        );
+        if self.import.take().is_some() {
+            eprintln!("> Error: Config import aborted");
+        }
+    }
+
+    fn handle_line(&mut self, line: &str) {
+        // While importing a config, collect all lines until the end marker
+        if let Some(ref mut import) = self.import {
+            match line {
+                CONFIG_BEGIN => {}
+                CONFIG_END => {
+                    let text = self.import.take().unwrap_or_default();
+                    match config::import(self.nvs.clone(), &text, self.stored_config()) {
+                        Ok(config) => {
+                            println!("> Config stored, restart the device to apply it");
+                            self.pending = Some(config);
+                        }
+                        Err(e) => eprintln!("> Error: {:#}", e),
+                    }
+                }
+                _ => {
+                    import.push_str(line);
+                    import.push('\n');
+                }
+            }
+            return;
+        }
+
+        match line.parse() {
+            Ok(command) => {
+                if let Err(e) = self.execute(command) {
+                    eprintln!("> Error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("> {}", e),
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), String> {
+        match command {
+            Command::Help => println!("{}", HELP),
+            Command::Info => {
+                println!(
+                    "> Name: {}",
+                    self.config.name.as_deref().unwrap_or(SENSILO_NAME)
+                );
+                println!(
+                    "> Serial number: {}",
+                    self.serial.as_deref().unwrap_or("(none)")
+                );
+                println!("> Firmware version: {}", VERSION);
+                println!("> Config hash: {}", config::hash(&self.config));
+                match clock::sync_status() {
+                    Some(status) => println!(
+                        "> Time: Synchronized {} s ago, offset {}",
+                        status.age_secs,
+                        status
+                            .offset_ms
+                            .map(|offset| format!("{} ms", offset))
+                            .unwrap_or_else(|| "unknown".into())
+                    ),
+                    None => println!("> Time: Not synchronized"),
+                }
+            }
+            #[cfg(feature = "gas")]
+            Command::Sgp30GetBaseline => {
+                let mut sensors = self.sensors.lock().expect("Failed to lock sensors mutex");
+                let (sgp30, _) = sensors.gas.as_mut().ok_or("SGP30 not available")?;
+                let baseline = sgp30.get_baseline().map_err(|e| format!("{:?}", e))?;
+                println!(
+                    "> SGP30 baseline: co2eq={} tvoc={}",
+                    baseline.co2eq, baseline.tvoc
+                );
+            }
+            #[cfg(feature = "gas")]
+            Command::Sgp30SetBaseline { co2eq, tvoc } => {
+                let mut sensors = self.sensors.lock().expect("Failed to lock sensors mutex");
+                let (sgp30, _) = sensors.gas.as_mut().ok_or("SGP30 not available")?;
+                sgp30
+                    .set_baseline(&sgp30::Baseline { co2eq, tvoc })
+                    .map_err(|e| format!("{:?}", e))?;
+                println!("> SGP30 baseline set to co2eq={} tvoc={}", co2eq, tvoc);
+            }
+            #[cfg(feature = "gas")]
+            Command::Sgp30CleanAir => {
+                let mut sensors = self.sensors.lock().expect("Failed to lock sensors mutex");
+                let (sgp30, _) = sensors.gas.as_mut().ok_or("SGP30 not available")?;
+                sgp30.init().map_err(|e| format!("{:?}", e))?;
+                println!("> SGP30 algorithm restarted, the current air is assumed to be clean");
+            }
+            #[cfg(feature = "scd4x")]
+            Command::Scd4xForcedRecalibration { target_ppm } => {
+                let mut sensors = self.sensors.lock().expect("Failed to lock sensors mutex");
+                let scd = sensors.co2.as_mut().ok_or("SCD4x not available")?;
+                let correction = scd
+                    .forced_recalibration(target_ppm, &mut GeneralPurposeDelay)
+                    .map_err(|e| format!("{:?}", e))?;
+                println!(
+                    "> SCD4x recalibrated to {} PPM (correction: {} PPM)",
+                    target_ppm, correction
+                );
+                if calibration::is_tracked("scd4x") {
+                    match calibration::record("scd4x", None) {
+                        Ok(()) => println!("> Calibration recorded"),
+                        Err(e) => eprintln!("> Error: Could not record calibration: {:#}", e),
+                    }
+                }
+            }
+            Command::CalibrationShow => {
+                let status = calibration::status();
+                if status.is_empty() {
+                    println!("> No sensors are tracked (calibration.sensors)");
+                }
+                for status in status {
+                    let date = status
+                        .day
+                        .map(calibration::format_date)
+                        .unwrap_or_else(|| "never".into());
+                    let days = status
+                        .days_since
+                        .map(|days| format!(" ({} days ago)", days))
+                        .unwrap_or_default();
+                    let due = if status.due { ", due" } else { "" };
+                    println!("> {}: {}{}{}", status.sensor, date, days, due);
+                }
+            }
+            Command::CalibrationSet { sensor, day } => {
+                calibration::record(&sensor, day).map_err(|e| format!("{:#}", e))?;
+                println!("> Calibration of {} recorded", sensor);
+            }
+            Command::ProfileShow => {
+                println!(
+                    "> Active profile: {}",
+                    self.profile.name().as_deref().unwrap_or("(none)")
+                );
+                let names: Vec<&str> = self.profile.names().collect();
+                println!("> Available profiles: {}", names.join(", "));
+            }
+            Command::ProfileSet { name } => {
+                self.profile
+                    .set(Some(&name))
+                    .map_err(|e| format!("{:#}", e))?;
+                println!("> Switched to profile {}", name);
+            }
+            Command::ProfileClear => {
+                self.profile.set(None).map_err(|e| format!("{:#}", e))?;
+                println!("> Switched to the base settings");
+            }
+            Command::ConfigShow => {
+                let text = config::export(self.stored_config()).map_err(|e| format!("{:#}", e))?;
+                for line in text.lines() {
+                    println!("> {}", line);
+                }
+                if self.pending.is_some() {
+                    println!("> Changes are stored, restart the device to apply them");
+                }
+            }
+            Command::ConfigSet { key, value } => {
+                let changed = config::set(self.stored_config(), &key, value.as_deref())
+                    .map_err(|e| format!("{}: {:#}", key, e))?;
+                config::save(self.nvs.clone(), &changed).map_err(|e| format!("{:#}", e))?;
+                self.pending = Some(changed);
+                match value {
+                    Some(_) => println!("> {} set, restart the device to apply it", key),
+                    None => println!(
+                        "> {} reset to its default, restart the device to apply it",
+                        key
+                    ),
+                }
+            }
+            Command::ConfigExport => {
+                let text = config::export(&self.config).map_err(|e| format!("{:#}", e))?;
+                println!("{}\n{}{}", CONFIG_BEGIN, text, CONFIG_END);
+            }
+            Command::ConfigImport => {
+                println!("> Paste the config, then enter {}", CONFIG_END);
+                self.import = Some(String::new());
+            }
+            Command::Tasks => {
+                println!(
+                    "> {:<16} {:<10} {:>4} {:>10}",
+                    "Task", "State", "Prio", "Free stack"
+                );
+                for task in stats::tasks() {
+                    println!(
+                        "> {:<16} {:<10} {:>4} {:>10}",
+                        task.name, task.state, task.priority, task.stack_high_water_mark
+                    );
+                }
+            }
+            Command::Heap => {
+                let heap = stats::heap();
+                println!("> Total:              {:>7} bytes", heap.total);
+                println!("> Free:               {:>7} bytes", heap.free);
+                println!("> Minimum free:       {:>7} bytes", heap.minimum_free);
+                println!("> Largest free block: {:>7} bytes", heap.largest_free_block);
+                println!("> Fragmentation:      {:>7.1} %", heap.fragmentation());
+            }
+            Command::Log => {
+                for entry in eventlog::entries() {
+                    println!("> {}", entry);
+                }
+            }
+            Command::Restart => {
+                self.sender
+                    .send(Message::Restart)
+                    .map_err(|_| "Main loop is not running")?;
+                println!("> Restarting after the current measurement cycle");
+            }
+            #[cfg(feature = "faults")]
+            Command::Fault { args } => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                let status = faults::command(&args).map_err(|e| format!("{:#}", e))?;
+                println!("> {}", status);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1677 @@
+//! Runtime configuration, loaded from NVS at boot.
+//!
+//! The config is a TOML document (see [`schema`] and `config.example.toml`), stored as a blob
+//! in the `config` NVS namespace under the key `toml`. It is validated strictly: If the config
+//! cannot be parsed or contains invalid values, it is rejected as a whole and the defaults are
+//! used instead.
+//!
+//! If there's no config in NVS, the build-time config (`sensilo.toml`, embedded by the build
+//! script) is used, or the defaults if there was none. A config that is imported at runtime takes
+//! precedence.
+//!
+//! Configs with an older layout are migrated to the current [`VERSION`] when loaded, and the
+//! migrated config is stored back to NVS.
+//!
+//! The device name, the WiFi credentials and the InfluxDB server used to be compiled into the
+//! firmware from the `SENSILO_*` environment variables (see `.env`). Firmware that is still built
+//! with them migrates them into the config if it doesn't set them yet, and stores the config in
+//! NVS, so that they are kept when a firmware without them (e.g. the same binary for all devices)
+//! is flashed later.
+//!
+//! The config can be exported and imported (e.g. for backups or for cloning it to a new device).
+//! Secrets are not exported, on import the secrets of the current config are kept. Partial configs
+//! (e.g. from the remote config, see `remote.rs`) are merged into the current config instead.
+
+use anyhow::{bail, Context};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use crate::{clock, eventlog, history, knx, outputs, roaming, webhook};
+
+mod schema;
+
+pub use schema::*;
+
+/// NVS namespace of the config
+const NAMESPACE: &str = "config";
+
+/// NVS key of the TOML config blob
+const KEY: &str = "toml";
+
+/// Maximum size of the config in bytes
+const MAX_SIZE: usize = 4096;
+
+/// Build-time config, empty if there was none
+const BUILD_TIME_CONFIG: &str = include_str!(concat!(env!("OUT_DIR"), "/sensilo.toml"));
+
+/// Settings from the environment at build time, migrated into the config (see
+/// [`migrate_build_time_settings`]). Unset or empty if the firmware was built without them.
+const BUILD_TIME_NAME: Option<&str> = option_env!("SENSILO_NAME");
+const BUILD_TIME_WIFI_SSID: Option<&str> = option_env!("SENSILO_WIFI_SSID");
+const BUILD_TIME_WIFI_PASSWORD: Option<&str> = option_env!("SENSILO_WIFI_PASSWORD");
+const BUILD_TIME_INFLUXDB_HOST: Option<&str> = option_env!("SENSILO_INFLUXDB_HOST");
+const BUILD_TIME_INFLUXDB_ORG: Option<&str> = option_env!("SENSILO_INFLUXDB_ORG");
+const BUILD_TIME_INFLUXDB_BUCKET: Option<&str> = option_env!("SENSILO_INFLUXDB_BUCKET");
+const BUILD_TIME_INFLUXDB_API_TOKEN: Option<&str> = option_env!("SENSILO_INFLUXDB_API_TOKEN");
+
+/// Tags that are set by the firmware itself and cannot be overridden
+const RESERVED_TAGS: [&str; 9] = [
+    "name",
+    "serial",
+    "fw_version",
+    "config_hash",
+    "sensor_type",
+    "channel",
+    "rule",
+    "metric",
+    "simulated",
+];
+
+/// Names of the sensors, as used in the `sensors` list of a profile
+pub const SENSOR_NAMES: [&str; 19] = [
+    "shtc3", "veml7700", "tsl2591", "sgp30", "sdp8xx", "bmp390", "ens160", "ccs811", "sfa30",
+    "as7341", "apds9960", "ld2410", "geiger", "s0", "pzem004t", "ina219", "max31855", "max31865",
+    "scd4x",
+];
+
+/// Migrations of older config layouts. The migration at index `n` migrates a config from version
+/// `n + 1` to version `n + 2`.
+const MIGRATIONS: [fn(&mut toml::Value); VERSION as usize - 1] = [migrate_v1_to_v2];
+
+/// Load the config from NVS. If there's no config, or if it is invalid, the build-time config or
+/// the defaults are used. Settings from the environment at build time are migrated into the config
+/// (see [`migrate_build_time_settings`]).
+pub fn load(partition: EspDefaultNvsPartition) -> Config {
+    let config = match read(partition.clone()) {
+        Ok(Some(config)) => {
+            println!("Config: Loaded from NVS");
+            config
+        }
+        Ok(None) => {
+            println!("Config: No config in NVS");
+            fallback()
+        }
+        Err(e) => {
+            eprintln!("Config: ERROR: {:#}", e);
+            // Don't replace a config that is only invalid for this firmware, but still apply the
+            // build-time settings
+            let mut config = fallback();
+            migrate_build_time_settings(&mut config);
+            return config;
+        }
+    };
+    let mut migrated = config.clone();
+    if !migrate_build_time_settings(&mut migrated) {
+        return config;
+    }
+    if let Err(e) = validate(&migrated) {
+        eprintln!("Config: ERROR: Build-time settings: {:#}", e);
+        return config;
+    }
+    match save(partition, &migrated) {
+        Ok(()) => println!("Config: Stored the build-time settings in NVS"),
+        Err(e) => eprintln!(
+            "Config: ERROR: Could not store the build-time settings: {:#}",
+            e
+        ),
+    }
+    migrated
+}
+
+/// Validate a config and store it in NVS. It is applied after a restart.
+pub fn save(partition: EspDefaultNvsPartition, config: &Config) -> anyhow::Result<()> {
+    validate(config).context("Invalid config")?;
+    store(&mut open(partition)?, config)?;
+    eventlog::record(
+        eventlog::Kind::Config,
+        &format!("Config {} stored", hash(config)),
+    );
+    Ok(())
+}
+
+/// Return a build-time setting, `None` if it's unset or empty.
+fn build_time(setting: Option<&'static str>) -> Option<&'static str> {
+    setting.filter(|value| !value.is_empty())
+}
+
+/// Take the settings from the environment at build time (device name, WiFi credentials and
+/// InfluxDB server) that are not set in the config yet. Return whether anything was taken.
+fn migrate_build_time_settings(config: &mut Config) -> bool {
+    let mut migrated = false;
+    if let (None, Some(name)) = (&config.name, build_time(BUILD_TIME_NAME)) {
+        config.name = Some(name.into());
+        migrated = true;
+    }
+    if let (None, Some(ssid)) = (&config.wifi.ssid, build_time(BUILD_TIME_WIFI_SSID)) {
+        config.wifi.ssid = Some(ssid.into());
+        config.wifi.password = build_time(BUILD_TIME_WIFI_PASSWORD).map(Into::into);
+        migrated = true;
+    }
+    let api_token = build_time(BUILD_TIME_INFLUXDB_API_TOKEN).map(String::from);
+    match config.sinks.influxdb {
+        // The API token used to default to the build-time one
+        Some(ref mut influxdb) => {
+            if influxdb.api == InfluxDbApi::V2
+                && influxdb.api_token.is_none()
+                && api_token.is_some()
+            {
+                influxdb.api_token = api_token;
+                migrated = true;
+            }
+        }
+        None => {
+            if let Some(host) = build_time(BUILD_TIME_INFLUXDB_HOST) {
+                config.sinks.influxdb = Some(InfluxDb {
+                    host: host.into(),
+                    api: InfluxDbApi::V2,
+                    org: BUILD_TIME_INFLUXDB_ORG.unwrap_or_default().into(),
+                    bucket: BUILD_TIME_INFLUXDB_BUCKET.unwrap_or_default().into(),
+                    api_token,
+                    username: None,
+                    password: None,
+                    interval_secs: None,
+                });
+                migrated = true;
+            }
+        }
+    }
+    migrated
+}
+
+/// Return the build-time config, or the defaults if there's none or if it is invalid.
+fn fallback() -> Config {
+    if BUILD_TIME_CONFIG.is_empty() {
+        println!("Config: Using defaults");
+        return Config::default();
+    }
+    match parse(BUILD_TIME_CONFIG) {
+        Ok((config, _)) => {
+            println!("Config: Using the build-time config");
+            config
+        }
+        Err(e) => {
+            eprintln!("Config: ERROR: Build-time config: {:#}", e);
+            eprintln!("Config: Using defaults");
+            Config::default()
+        }
+    }
+}
+
+fn open(partition: EspDefaultNvsPartition) -> anyhow::Result<EspNvs<NvsDefault>> {
+    EspNvs::new(partition, NAMESPACE, true).context("Could not open NVS namespace")
+}
+
+fn read(partition: EspDefaultNvsPartition) -> anyhow::Result<Option<Config>> {
+    let mut nvs = open(partition)?;
+    let len = match nvs.len(KEY).context("Could not read config size")? {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    if len > MAX_SIZE {
+        bail!("Config is too large ({} bytes, max {})", len, MAX_SIZE);
+    }
+    let mut buf = vec![0; len];
+    let raw = match nvs
+        .get_raw(KEY, &mut buf)
+        .context("Could not read config")?
+    {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let text = std::str::from_utf8(raw).context("Config is not valid UTF-8")?;
+    let (config, migrated_from) = parse(text)?;
+    if let Some(version) = migrated_from {
+        println!("Config: Migrated from version {} to {}", version, VERSION);
+        store(&mut nvs, &config).context("Could not store migrated config")?;
+    }
+    Ok(Some(config))
+}
+
+fn store(nvs: &mut EspNvs<NvsDefault>, config: &Config) -> anyhow::Result<()> {
+    let text = serialize(config)?;
+    if text.len() > MAX_SIZE {
+        bail!(
+            "Config is too large ({} bytes, max {})",
+            text.len(),
+            MAX_SIZE
+        );
+    }
+    nvs.set_raw(KEY, text.as_bytes())
+        .context("Could not write config")?;
+    Ok(())
+}
+
+/// Parse, migrate and validate a TOML config. If the config was migrated, the original version is
+/// returned as well.
+fn parse(text: &str) -> anyhow::Result<(Config, Option<u32>)> {
+    let mut value = text.parse::<toml::Value>().context("Invalid config")?;
+    let version = match value.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .context("Invalid config: version: Must be a positive integer")?,
+    };
+    if version > VERSION {
+        bail!(
+            "Invalid config: version: Version {} is not supported by this firmware (max {})",
+            version,
+            VERSION
+        );
+    }
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut value);
+    }
+    if let Some(table) = value.as_table_mut() {
+        table.insert("version".into(), toml::Value::Integer(VERSION.into()));
+    }
+
+    let config: Config = value.try_into().context("Invalid config")?;
+    validate(&config).context("Invalid config")?;
+    Ok((config, Some(version).filter(|version| *version != VERSION)))
+}
+
+/// Export the config as TOML, without secrets.
+pub fn export(config: &Config) -> anyhow::Result<String> {
+    let mut config = config.clone();
+    remove_secrets(&mut config);
+    serialize(&config)
+}
+
+/// Remove all secrets from a config.
+fn remove_secrets(config: &mut Config) {
+    config.api.token = None;
+    config.snmp.community = None;
+    config.esphome.password = None;
+    config.remote_config.token = None;
+    config.wifi.password = None;
+    for network in config.wifi.networks.iter_mut() {
+        network.password = None;
+    }
+    config.wifi.portal_password = None;
+    if let Some(ref mut influxdb) = config.sinks.influxdb {
+        influxdb.api_token = None;
+        influxdb.password = None;
+    }
+    if let Some(ref mut ntfy) = config.sinks.ntfy {
+        ntfy.token = None;
+    }
+    if let Some(ref mut mqtt) = config.sinks.mqtt {
+        mqtt.password = None;
+    }
+}
+
+/// Serialize a config as TOML.
+///
+/// The config is converted to a `toml::Value` first, which emits the plain values of a table before
+/// its tables. Serializing the structs directly fails if a plain value follows a table (e.g. an
+/// empty `outputs` array after the `sinks` table).
+fn serialize(config: &Config) -> anyhow::Result<String> {
+    let value = toml::Value::try_from(config).context("Could not serialize config")?;
+    toml::to_string(&value).context("Could not serialize config")
+}
+
+/// Return a short hash of the config, submitted as `config_hash` tag, so that devices whose config
+/// differs from the intended template can be found easily.
+///
+/// Secrets and the settings that identify the device (the name and the custom tags) are not
+/// included, so all devices that were configured from the same template have the same hash.
+pub fn hash(config: &Config) -> String {
+    let mut config = config.clone();
+    config.name = None;
+    config.tags.clear();
+    let text = export(&config).unwrap_or_default();
+
+    // 32 bit FNV-1a
+    let hash = text.bytes().fold(0x811c9dc5_u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    format!("{:08x}", hash)
+}
+
+/// Import a TOML config and store it in NVS. Secrets that are missing in the imported config are
+/// taken from the `current` config, if they are for the same server or network. The imported
+/// config is applied after a restart, it is returned as well.
+pub fn import(
+    partition: EspDefaultNvsPartition,
+    text: &str,
+    current: &Config,
+) -> anyhow::Result<Config> {
+    let (mut config, _) = parse(text)?;
+    restore_secrets(&mut config, current);
+    save(partition, &config)?;
+    Ok(config)
+}
+
+/// Take the secrets that are missing in an imported config from the `current` config.
+fn restore_secrets(config: &mut Config, current: &Config) {
+    if config.api.token.is_none() {
+        config.api.token = current.api.token.clone();
+    }
+    if config.snmp.community.is_none() {
+        config.snmp.community = current.snmp.community.clone();
+    }
+    if config.esphome.password.is_none() {
+        config.esphome.password = current.esphome.password.clone();
+    }
+    // Only for the same URL
+    if config.remote_config.token.is_none() && config.remote_config.url == current.remote_config.url
+    {
+        config.remote_config.token = current.remote_config.token.clone();
+    }
+    // Only for the same network, an open network has no password
+    if config.wifi.password.is_none() && config.wifi.ssid == current.wifi.ssid {
+        config.wifi.password = current.wifi.password.clone();
+    }
+    for network in config.wifi.networks.iter_mut() {
+        if network.password.is_none() {
+            network.password = current
+                .wifi
+                .networks
+                .iter()
+                .find(|current| current.ssid == network.ssid)
+                .and_then(|current| current.password.clone());
+        }
+    }
+    if config.wifi.portal_password.is_none() {
+        config.wifi.portal_password = current.wifi.portal_password.clone();
+    }
+    // Only for the same server, so that they aren't sent to another one
+    if let Some(ref mut influxdb) = config.sinks.influxdb {
+        if let Some(current) = current
+            .sinks
+            .influxdb
+            .as_ref()
+            .filter(|current| current.host == influxdb.host)
+        {
+            if influxdb.api_token.is_none() {
+                influxdb.api_token = current.api_token.clone();
+            }
+            if influxdb.password.is_none() {
+                influxdb.password = current.password.clone();
+            }
+        }
+    }
+    if let Some(ref mut ntfy) = config.sinks.ntfy {
+        if ntfy.token.is_none() {
+            ntfy.token = current
+                .sinks
+                .ntfy
+                .as_ref()
+                .filter(|current| current.server == ntfy.server)
+                .and_then(|current| current.token.clone());
+        }
+    }
+    if let Some(ref mut mqtt) = config.sinks.mqtt {
+        if mqtt.password.is_none() {
+            mqtt.password = current
+                .sinks
+                .mqtt
+                .as_ref()
+                .filter(|current| current.url == mqtt.url)
+                .and_then(|current| current.password.clone());
+        }
+    }
+}
+
+/// Merge a partial config (a TOML table with only some of the settings, e.g. `[intervals]` and
+/// `[tags]`) into the `current` config and validate the result. Tables are merged, all other
+/// settings (including arrays, e.g. the rules) are replaced, so settings can be changed, but not
+/// removed. Return the merged config with the dotted keys of the settings that changed.
+///
+/// Secrets that are not part of the partial config are kept like on [`import`], only if they are
+/// for the same server or network, so that a document can't send them to another host.
+///
+/// The merged config is not stored, see [`save`].
+pub fn merge(current: &Config, partial: toml::Value) -> anyhow::Result<(Config, Vec<String>)> {
+    let mut partial = match partial {
+        toml::Value::Table(table) => table,
+        _ => bail!("Invalid config: Not a table"),
+    };
+    match partial.remove("version") {
+        None => {}
+        Some(toml::Value::Integer(version)) if version == i64::from(VERSION) => {}
+        Some(_) => bail!(
+            "Invalid config: version: Must be {}, partial configs are not migrated",
+            VERSION
+        ),
+    }
+    let before = toml::Value::try_from(current).context("Could not serialize config")?;
+    let mut merged = current.clone();
+    remove_secrets(&mut merged);
+    let mut merged = toml::Value::try_from(&merged).context("Could not serialize config")?;
+    merge_value(&mut merged, toml::Value::Table(partial));
+    let mut config: Config = merged.try_into().context("Invalid config")?;
+    restore_secrets(&mut config, current);
+    validate(&config).context("Invalid config")?;
+
+    // Compare the serialized configs, so that a setting that is set to its current value doesn't
+    // count as changed
+    let after = toml::Value::try_from(&config).context("Could not serialize config")?;
+    let mut changed = Vec::new();
+    diff("", &before, &after, &mut changed);
+    Ok((config, changed))
+}
+
+/// Merge a value into a table of a config: Tables are merged, other values are replaced.
+fn merge_value(target: &mut toml::Value, value: toml::Value) {
+    match (target, value) {
+        (toml::Value::Table(target), toml::Value::Table(table)) => {
+            for (key, value) in table {
+                match target.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, value) => *target = value,
+    }
+}
+
+/// Collect the dotted keys of the settings that differ between two serialized configs.
+fn diff(key: &str, before: &toml::Value, after: &toml::Value, changed: &mut Vec<String>) {
+    match (before, after) {
+        (toml::Value::Table(before), toml::Value::Table(after)) => {
+            let added = after.keys().filter(|name| !before.contains_key(*name));
+            for name in before.keys().chain(added) {
+                let key = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}.{}", key, name)
+                };
+                match (before.get(name), after.get(name)) {
+                    (Some(before), Some(after)) => diff(&key, before, after, changed),
+                    _ => changed.push(key),
+                }
+            }
+        }
+        _ if before != after => changed.push(key.into()),
+        _ => {}
+    }
+}
+
+/// Set a single setting by its dotted key (e.g. `wifi.ssid`), or unset it with `None` so that its
+/// default is used, and return the changed config. The value is parsed as a TOML value (e.g.
+/// `30`, `true`, `["shtc3"]` or `{ host = "...", org = "...", bucket = "..." }`), and is taken as
+/// a string if it isn't one or if the setting expects a string, so that strings don't need quotes.
+///
+/// The changed config is not stored, see [`save`].
+pub fn set(config: &Config, key: &str, value: Option<&str>) -> anyhow::Result<Config> {
+    let path: Vec<&str> = key.split('.').collect();
+    if path.iter().any(|part| part.is_empty()) || key == "version" {
+        bail!("Invalid key: {:?}", key);
+    }
+    // The parsed value first, then the value as string
+    let mut candidates = Vec::new();
+    match value {
+        Some(value) => {
+            let parsed = format!("value = {}", value)
+                .parse::<toml::Value>()
+                .ok()
+                .and_then(|table| table.get("value").cloned());
+            let text = toml::Value::String(value.into());
+            if let Some(parsed) = parsed.filter(|parsed| *parsed != text) {
+                candidates.push(Some(parsed));
+            }
+            candidates.push(Some(text));
+        }
+        None => candidates.push(None),
+    }
+
+    let (last, parents) = path.split_last().context("Invalid key")?;
+    let mut error = None;
+    for candidate in candidates {
+        let mut root = toml::Value::try_from(config).context("Could not serialize config")?;
+        let mut table = root.as_table_mut().context("Could not serialize config")?;
+        for (i, part) in parents.iter().enumerate() {
+            if !table.contains_key(*part) {
+                if candidate.is_none() {
+                    // Unset already
+                    return Ok(config.clone());
+                }
+                table.insert(part.to_string(), toml::Value::Table(Default::default()));
+            }
+            table = table
+                .get_mut(*part)
+                .and_then(toml::Value::as_table_mut)
+                .with_context(|| format!("{}: Not a table", path[..=i].join(".")))?;
+        }
+        match candidate {
+            Some(candidate) => table.insert(last.to_string(), candidate),
+            None => table.remove(*last),
+        };
+        match root.try_into::<Config>() {
+            Ok(changed) => return Ok(changed),
+            Err(e) => error = error.or(Some(e)),
+        }
+    }
+    // There's at least one candidate
+    Err(error.expect("No candidate")).context("Invalid config")
+}
+
+/// Version 2: The tag of INA219 channels is set with `channel`, like for all other sensors.
+fn migrate_v1_to_v2(config: &mut toml::Value) {
+    let channels = config
+        .get_mut("sensors")
+        .and_then(|sensors| sensors.get_mut("ina219"))
+        .and_then(|ina219| ina219.get_mut("channels"))
+        .and_then(toml::Value::as_array_mut);
+    for channel in channels.into_iter().flatten() {
+        if let Some(channel) = channel.as_table_mut() {
+            if let Some(tag) = channel.remove("tag") {
+                channel.insert("channel".into(), tag);
+            }
+        }
+    }
+}
+
+/// Validate the values that cannot be checked by the schema alone. The error message names the
+/// offending field.
+fn validate(config: &Config) -> anyhow::Result<()> {
+    if let Some(ref name) = config.name {
+        validate_tag_value("name", name)?;
+    }
+    if let Some(ref timezone) = config.timezone {
+        if !clock::is_valid_timezone(timezone) {
+            bail!("timezone: Invalid POSIX TZ string {:?}", timezone);
+        }
+    }
+    if config.time.servers.len() > clock::MAX_NTP_SERVERS {
+        bail!(
+            "time.servers: At most {} servers are supported",
+            clock::MAX_NTP_SERVERS
+        );
+    }
+    for (i, server) in config.time.servers.iter().enumerate() {
+        if server.is_empty() || server.contains(char::is_whitespace) {
+            bail!("time.servers[{}]: Invalid server {:?}", i, server);
+        }
+    }
+    if !matches!(config.mains_frequency, 50 | 60) {
+        bail!(
+            "mains_frequency: Must be 50 or 60, not {}",
+            config.mains_frequency
+        );
+    }
+    for (key, value) in config.tags.iter() {
+        validate_tag_value(&format!("tags.{}", key), key)?;
+        validate_tag_value(&format!("tags.{}", key), value)?;
+        if RESERVED_TAGS.contains(&key.as_str()) {
+            bail!(
+                "tags.{}: Tag is set by the firmware and cannot be overridden",
+                key
+            );
+        }
+    }
+
+    let intervals = &config.intervals;
+    if intervals.measurement_secs == 0 {
+        bail!("intervals.measurement_secs: Must be greater than 0");
+    }
+    if intervals.presence_poll_ms == 0 {
+        bail!("intervals.presence_poll_ms: Must be greater than 0");
+    }
+    if intervals.occupied_measurement_secs == Some(0) {
+        bail!("intervals.occupied_measurement_secs: Must be greater than 0");
+    }
+    if intervals.vacant_measurement_secs == Some(0) {
+        bail!("intervals.vacant_measurement_secs: Must be greater than 0");
+    }
+    if let Some(ref ssid) = config.wifi.ssid {
+        if ssid.is_empty() || ssid.len() > 32 {
+            bail!("wifi.ssid: Must be 1 to 32 bytes long");
+        }
+    }
+    if let Some(ref password) = config.wifi.password {
+        if config.wifi.ssid.is_none() {
+            bail!("wifi.password: Requires ssid");
+        }
+        if !(8..=64).contains(&password.len()) {
+            bail!("wifi.password: Must be 8 to 64 characters long (WPA2)");
+        }
+    }
+    if !config.wifi.networks.is_empty() && config.wifi.ssid.is_none() {
+        bail!("wifi.networks: Requires ssid");
+    }
+    for (i, network) in config.wifi.networks.iter().enumerate() {
+        if network.ssid.is_empty() || network.ssid.len() > 32 {
+            bail!("wifi.networks[{}].ssid: Must be 1 to 32 bytes long", i);
+        }
+        if config.wifi.ssid.as_ref() == Some(&network.ssid)
+            || config.wifi.networks[..i]
+                .iter()
+                .any(|other| other.ssid == network.ssid)
+        {
+            bail!(
+                "wifi.networks[{}].ssid: Duplicate network {:?}",
+                i,
+                network.ssid
+            );
+        }
+        if let Some(ref password) = network.password {
+            if !(8..=64).contains(&password.len()) {
+                bail!(
+                    "wifi.networks[{}].password: Must be 8 to 64 characters long (WPA2)",
+                    i
+                );
+            }
+        }
+    }
+    if let Some(ref password) = config.wifi.portal_password {
+        if !(8..=64).contains(&password.len()) {
+            bail!("wifi.portal_password: Must be 8 to 64 characters long (WPA2)");
+        }
+    }
+    if let Some(ref country) = config.wifi.country {
+        if country.len() != 2
+            || !country
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            bail!("wifi.country: Invalid country code {:?}", country);
+        }
+    }
+    if let Some(power) = config.wifi.max_tx_power_dbm {
+        if !(2.0..=20.0).contains(&power) {
+            bail!("wifi.max_tx_power_dbm: Must be between 2 and 20");
+        }
+    }
+    if let Some(ref bssid) = config.wifi.bssid {
+        if roaming::parse_bssid(bssid).is_none() {
+            bail!("wifi.bssid: Invalid BSSID {:?}", bssid);
+        }
+        if config.wifi.roaming {
+            bail!("wifi.roaming: Can't be combined with wifi.bssid");
+        }
+    }
+    if config.wifi.roaming_threshold_dbm >= 0 {
+        bail!("wifi.roaming_threshold_dbm: Must be negative");
+    }
+    if config.outage.failures == 0 {
+        bail!("outage.failures: Must be greater than 0");
+    }
+    if let Some(ref led) = config.outage.led {
+        if !config.outputs.iter().any(|o| &o.name == led) {
+            bail!("outage.led: Unknown output {:?}", led);
+        }
+    }
+    match config.outage.portal_after {
+        Some(0) => bail!("outage.portal_after: Must be greater than 0"),
+        // The portal is not opened without a password once an SSID is configured (see `portal.rs`)
+        Some(_) if !config.wifi.portal || config.wifi.portal_password.is_none() => {
+            bail!("outage.portal_after: Requires wifi.portal and wifi.portal_password")
+        }
+        _ => {}
+    }
+    if config.history.resolution_secs == 0 {
+        bail!("history.resolution_secs: Must be greater than 0");
+    }
+    let capacity = config.history.capacity();
+    if capacity == 0 {
+        bail!("history.retention_hours: Must be at least history.resolution_secs");
+    }
+    if capacity > history::MAX_CAPACITY {
+        bail!(
+            "history: At most {} slots are supported, retention_hours * 3600 / resolution_secs \
+             is {}",
+            history::MAX_CAPACITY,
+            capacity
+        );
+    }
+
+    if let Some(ref profile) = config.profile {
+        if !config.profiles.contains_key(profile) {
+            bail!("profile: Unknown profile {:?}", profile);
+        }
+    }
+    for (name, profile) in config.profiles.iter() {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!(
+                "profiles.{:?}: Name must not be empty or contain whitespace",
+                name
+            );
+        }
+        if profile.measurement_secs == Some(0) {
+            bail!("profiles.{}.measurement_secs: Must be greater than 0", name);
+        }
+        if profile.presence_poll_ms == Some(0) {
+            bail!("profiles.{}.presence_poll_ms: Must be greater than 0", name);
+        }
+        if profile.occupied_measurement_secs == Some(0) {
+            bail!(
+                "profiles.{}.occupied_measurement_secs: Must be greater than 0",
+                name
+            );
+        }
+        if profile.vacant_measurement_secs == Some(0) {
+            bail!(
+                "profiles.{}.vacant_measurement_secs: Must be greater than 0",
+                name
+            );
+        }
+        for sensor in profile.sensors.iter().flatten() {
+            if !SENSOR_NAMES.contains(&sensor.as_str()) {
+                bail!(
+                    "profiles.{}.sensors: Unknown sensor {:?} (known sensors: {})",
+                    name,
+                    sensor,
+                    SENSOR_NAMES.join(", ")
+                );
+            }
+        }
+    }
+
+    if matches!(config.api.token, Some(ref token) if token.is_empty()) {
+        bail!("api.token: Must not be empty");
+    }
+    if config.snmp.enabled {
+        match config.snmp.community {
+            Some(ref community) if community.is_empty() => {
+                bail!("snmp.community: Must not be empty")
+            }
+            Some(_) => {}
+            None => bail!("snmp.community: Must be set if SNMP is enabled"),
+        }
+    }
+    if config.bacnet.enabled {
+        match config.bacnet.device_instance {
+            // 4194303 is reserved for addressing the local device
+            Some(instance) if instance >= 4194303 => {
+                bail!("bacnet.device_instance: Must be less than 4194303")
+            }
+            Some(_) => {}
+            None => bail!("bacnet.device_instance: Must be set if BACnet is enabled"),
+        }
+    }
+    if matches!(config.esphome.password, Some(ref password) if password.is_empty()) {
+        bail!("esphome.password: Must not be empty");
+    }
+    let remote = &config.remote_config;
+    if let Some(ref url) = remote.url {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!(
+                "remote_config.url: Must start with http:// or https://, not {:?}",
+                url
+            );
+        }
+    } else if remote.token.is_some() {
+        bail!("remote_config.token: Requires url");
+    }
+    if matches!(remote.token, Some(ref token) if token.is_empty()) {
+        bail!("remote_config.token: Must not be empty");
+    }
+    if remote.interval_secs < 60 {
+        bail!("remote_config.interval_secs: Must be at least 60");
+    }
+    if remote.mqtt && config.sinks.mqtt.is_none() {
+        bail!("remote_config.mqtt: Requires sinks.mqtt");
+    }
+    if config.modbus.enabled && config.modbus.registers.is_empty() {
+        bail!("modbus.registers: Must not be empty if Modbus is enabled");
+    }
+    for (i, register) in config.modbus.registers.iter().enumerate() {
+        if !register.scale().is_normal() {
+            bail!("modbus.registers[{}].scale: Must be a non-zero number", i);
+        }
+        let end = u32::from(register.address) + u32::from(register.format().registers());
+        if end > 0x10000 {
+            bail!("modbus.registers[{}].address: Out of range", i);
+        }
+        let overlapping = config.modbus.registers[..i].iter().any(|other| {
+            let other_end = u32::from(other.address) + u32::from(other.format().registers());
+            u32::from(register.address) < other_end && u32::from(other.address) < end
+        });
+        if overlapping {
+            bail!("modbus.registers[{}]: Overlaps another register", i);
+        }
+    }
+
+    if let Some(ref influxdb) = config.sinks.influxdb {
+        if !influxdb.host.starts_with("http://") && !influxdb.host.starts_with("https://") {
+            bail!(
+                "sinks.influxdb.host: Must start with http:// or https://, not {:?}",
+                influxdb.host
+            );
+        }
+        match influxdb.api {
+            InfluxDbApi::V2 => {
+                if influxdb.org.is_empty() {
+                    bail!("sinks.influxdb.org: Must be set for the v2 API");
+                }
+                if influxdb.username.is_some() || influxdb.password.is_some() {
+                    bail!("sinks.influxdb.username/password: Only used by the v1 API");
+                }
+            }
+            InfluxDbApi::V1 => {
+                if influxdb.api_token.is_some() {
+                    bail!("sinks.influxdb.api_token: Only used by the v2 API");
+                }
+                if influxdb.password.is_some() && influxdb.username.is_none() {
+                    bail!("sinks.influxdb.password: Requires a username");
+                }
+            }
+        }
+        if influxdb.bucket.is_empty() {
+            bail!("sinks.influxdb.bucket: Must not be empty");
+        }
+    }
+    for (i, route) in config.sinks.routes.iter().enumerate() {
+        let field = format!("sinks.routes[{}]", i);
+        if route.measurements.is_empty() {
+            bail!("{}.measurements: Must not be empty", field);
+        }
+        for measurement in route.measurements.iter() {
+            validate_tag_value(&format!("{}.measurements", field), measurement)?;
+        }
+        if route.bucket.is_empty() {
+            bail!("{}.bucket: Must not be empty", field);
+        }
+        if matches!(route.org, Some(ref org) if org.is_empty()) {
+            bail!("{}.org: Must not be empty", field);
+        }
+    }
+    if let Some(ref downsampling) = config.sinks.downsampling {
+        if downsampling.interval_secs < config.intervals.measurement_secs {
+            bail!(
+                "sinks.downsampling.interval_secs: Must not be shorter than the measurement \
+                interval ({}s)",
+                config.intervals.measurement_secs
+            );
+        }
+        validate_tag_value("sinks.downsampling.measurement", &downsampling.measurement)?;
+    }
+    if matches!(config.sinks.udp, Some(ref udp) if udp.port == 0) {
+        bail!("sinks.udp.port: Must not be 0");
+    }
+    if let Some(ref annotations) = config.sinks.annotations {
+        if annotations.kinds.is_empty() {
+            bail!("sinks.annotations.kinds: Must not be empty");
+        }
+    }
+    if let Some(ref config) = config.sinks.knx {
+        if knx::parse_individual_address(&config.individual_address).is_none() {
+            bail!(
+                "sinks.knx.individual_address: Invalid address {:?}",
+                config.individual_address
+            );
+        }
+        if config.telegrams.is_empty() {
+            bail!("sinks.knx.telegrams: Must not be empty");
+        }
+        for (i, telegram) in config.telegrams.iter().enumerate() {
+            if knx::parse_group_address(&telegram.group_address).is_none() {
+                bail!(
+                    "sinks.knx.telegrams[{}].group_address: Invalid address {:?}",
+                    i,
+                    telegram.group_address
+                );
+            }
+        }
+    }
+    if let Some(ref ntfy) = config.sinks.ntfy {
+        if let Some(ref server) = ntfy.server {
+            if !server.starts_with("http://") && !server.starts_with("https://") {
+                bail!(
+                    "sinks.ntfy.server: Must start with http:// or https://, not {:?}",
+                    server
+                );
+            }
+        }
+        if ntfy.topic.is_empty() || ntfy.topic.contains('/') {
+            bail!("sinks.ntfy.topic: Must not be empty or contain '/'");
+        }
+        if matches!(ntfy.priority, Some(priority) if !(1..=5).contains(&priority)) {
+            bail!("sinks.ntfy.priority: Must be between 1 and 5");
+        }
+    }
+    if let Some(ref mqtt) = config.sinks.mqtt {
+        if !mqtt.url.starts_with("mqtt://") && !mqtt.url.starts_with("mqtts://") {
+            bail!(
+                "sinks.mqtt.url: Must start with mqtt:// or mqtts://, not {:?}",
+                mqtt.url
+            );
+        }
+        if mqtt.topic_prefix.is_empty()
+            || mqtt.topic_prefix.starts_with('/')
+            || mqtt.topic_prefix.ends_with('/')
+            || mqtt.topic_prefix.contains(['+', '#'])
+        {
+            bail!("sinks.mqtt.topic_prefix: Must not be empty, start or end with '/' or contain wildcards");
+        }
+        if mqtt.discovery_prefix.is_empty() || mqtt.discovery_prefix.contains(['/', '+', '#']) {
+            bail!("sinks.mqtt.discovery_prefix: Must not be empty or contain '/' or wildcards");
+        }
+        if let Some(ref name) = config.name {
+            if name.contains(['/', '+', '#']) {
+                bail!(
+                    "name: Must not contain '/' or wildcards, since it's part of the MQTT topics"
+                );
+            }
+        }
+        if matches!(mqtt.client_id, Some(ref client_id) if client_id.is_empty()) {
+            bail!("sinks.mqtt.client_id: Must not be empty");
+        }
+        if mqtt.qos > 1 {
+            bail!("sinks.mqtt.qos: Must be 0 or 1, not {}", mqtt.qos);
+        }
+    }
+    if let Some(ref webhook) = config.sinks.webhook {
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            bail!(
+                "sinks.webhook.url: Must start with http:// or https://, not {:?}",
+                webhook.url
+            );
+        }
+        if webhook.headers.keys().any(|name| name.is_empty()) {
+            bail!("sinks.webhook.headers: Names must not be empty");
+        }
+    }
+    for (field, interval_secs) in [
+        (
+            "sinks.influxdb.interval_secs",
+            config.sinks.influxdb.as_ref().and_then(|s| s.interval_secs),
+        ),
+        (
+            "sinks.mqtt.interval_secs",
+            config.sinks.mqtt.as_ref().and_then(|s| s.interval_secs),
+        ),
+        (
+            "sinks.webhook.interval_secs",
+            config.sinks.webhook.as_ref().and_then(|s| s.interval_secs),
+        ),
+        (
+            "sinks.espnow.interval_secs",
+            config.sinks.espnow.as_ref().and_then(|s| s.interval_secs),
+        ),
+        (
+            "sinks.bthome.interval_secs",
+            config.sinks.bthome.as_ref().and_then(|s| s.interval_secs),
+        ),
+    ] {
+        if interval_secs == Some(0) {
+            bail!("{}: Must be greater than 0", field);
+        }
+    }
+    if let Some(ref bthome) = config.sinks.bthome {
+        if !cfg!(feature = "bthome") {
+            bail!("sinks.bthome: Requires the bthome feature");
+        }
+        if !(20..=10240).contains(&bthome.advertising_ms) {
+            bail!(
+                "sinks.bthome.advertising_ms: Must be between 20 and 10240, not {}",
+                bthome.advertising_ms
+            );
+        }
+    }
+    if let Some(ref espnow) = config.sinks.espnow {
+        if roaming::parse_bssid(&espnow.gateway).is_none() {
+            bail!(
+                "sinks.espnow.gateway: Invalid MAC address {:?}",
+                espnow.gateway
+            );
+        }
+        if !(1..=13).contains(&espnow.channel) {
+            bail!(
+                "sinks.espnow.channel: Must be between 1 and 13, not {}",
+                espnow.channel
+            );
+        }
+        // Without association, there's no IP connectivity
+        let sinks = &config.sinks;
+        for (field, configured) in [
+            ("sinks.influxdb", sinks.influxdb.is_some()),
+            ("sinks.ntfy", sinks.ntfy.is_some()),
+            ("sinks.routes", !sinks.routes.is_empty()),
+            ("sinks.downsampling", sinks.downsampling.is_some()),
+            ("sinks.udp", sinks.udp.is_some()),
+            ("sinks.knx", sinks.knx.is_some()),
+            ("sinks.annotations", sinks.annotations.is_some()),
+            ("sinks.mqtt", sinks.mqtt.is_some()),
+            ("sinks.webhook", sinks.webhook.is_some()),
+            ("api.enabled", config.api.enabled),
+            ("snmp.enabled", config.snmp.enabled),
+            ("bacnet.enabled", config.bacnet.enabled),
+            ("modbus.enabled", config.modbus.enabled),
+            ("esphome.enabled", config.esphome.enabled),
+            ("mdns.enabled", config.mdns.enabled),
+            ("remote_config", config.remote_config.is_enabled()),
+            ("wifi.roaming", config.wifi.roaming),
+            ("wifi.networks", !config.wifi.networks.is_empty()),
+        ] {
+            if configured {
+                bail!("{}: Not available with sinks.espnow", field);
+            }
+        }
+        for (i, rule) in config.rules.iter().enumerate() {
+            if rule
+                .then
+                .iter()
+                .chain(rule.otherwise.iter())
+                .any(|action| !matches!(action, RuleAction::Output { .. }))
+            {
+                bail!(
+                    "rules[{}]: Only output actions are available with sinks.espnow",
+                    i
+                );
+            }
+        }
+    }
+
+    let sensors = &config.sensors;
+    if !(100..=60_000).contains(&sensors.read_timeout_ms) {
+        bail!("sensors.read_timeout_ms: Must be between 100 and 60000");
+    }
+    if !matches!(sensors.bmp390.oversampling, 1 | 2 | 4 | 8 | 16 | 32) {
+        bail!(
+            "sensors.bmp390.oversampling: Must be 1, 2, 4, 8, 16 or 32, not {}",
+            sensors.bmp390.oversampling
+        );
+    }
+    if let Some(on_lux) = sensors.veml7700.lights_on_lux {
+        if on_lux <= 0.0 {
+            bail!("sensors.veml7700.lights_on_lux: Must be greater than 0");
+        }
+        if matches!(sensors.veml7700.lights_off_lux, Some(off_lux) if !(0.0..on_lux).contains(&off_lux))
+        {
+            bail!("sensors.veml7700.lights_off_lux: Must be between 0 and lights_on_lux");
+        }
+    } else if sensors.veml7700.lights_off_lux.is_some() {
+        bail!("sensors.veml7700.lights_off_lux: Requires lights_on_lux");
+    }
+    if sensors.as7341.lux_factor <= 0.0 {
+        bail!("sensors.as7341.lux_factor: Must be greater than 0");
+    }
+    if sensors.geiger.usvh_per_cpm <= 0.0 {
+        bail!("sensors.geiger.usvh_per_cpm: Must be greater than 0");
+    }
+    if sensors.s0.impulses_per_kwh == 0 {
+        bail!("sensors.s0.impulses_per_kwh: Must be greater than 0");
+    }
+    validate_tag_value("sensors.max31855.channel", &sensors.max31855.channel)?;
+    validate_tag_value("sensors.max31865.channel", &sensors.max31865.channel)?;
+    if !(2..=4).contains(&sensors.max31865.wires) {
+        bail!(
+            "sensors.max31865.wires: Must be 2, 3 or 4, not {}",
+            sensors.max31865.wires
+        );
+    }
+    if matches!(sensors.max31865.rref, Some(rref) if rref <= 0.0) {
+        bail!("sensors.max31865.rref: Must be greater than 0");
+    }
+    if sensors.ina219.shunt_milliohm <= 0.0 {
+        bail!("sensors.ina219.shunt_milliohm: Must be greater than 0");
+    }
+    if sensors.supply.divider < 1.0 {
+        bail!("sensors.supply.divider: Must be at least 1");
+    }
+    for (i, channel) in sensors.ina219.channels.iter().enumerate() {
+        if !(0x40..=0x4f).contains(&channel.address) {
+            bail!(
+                "sensors.ina219.channels[{}].address: Must be in the range 0x40-0x4f, not 0x{:02x}",
+                i,
+                channel.address
+            );
+        }
+        if sensors.ina219.channels[..i]
+            .iter()
+            .any(|other| other.address == channel.address)
+        {
+            bail!(
+                "sensors.ina219.channels[{}].address: Duplicate address 0x{:02x}",
+                i,
+                channel.address
+            );
+        }
+        validate_tag_value(
+            &format!("sensors.ina219.channels[{}].channel", i),
+            &channel.tag(),
+        )?;
+    }
+    if let Some(ref tag) = sensors.ina219.self_channel {
+        if !sensors
+            .ina219
+            .channels
+            .iter()
+            .any(|channel| channel.tag() == *tag)
+        {
+            bail!("sensors.ina219.self_channel: Unknown channel {:?}", tag);
+        }
+    }
+
+    let display = &config.display;
+    for (i, page) in display.pages.iter().enumerate() {
+        if page.rows.is_empty() {
+            bail!("display.pages[{}].rows: Must not be empty", i);
+        }
+        for (j, row) in page.rows.iter().enumerate() {
+            if let Some(unit) = row.unit {
+                if !supports_unit(row.metric, unit) {
+                    bail!(
+                        "display.pages[{}].rows[{}].unit: {:?} cannot be shown in {:?}",
+                        i,
+                        j,
+                        row.metric,
+                        unit
+                    );
+                }
+            }
+        }
+    }
+
+    if config.buttons.long_press_ms == 0 {
+        bail!("buttons.long_press_ms: Must be greater than 0");
+    }
+
+    let ventilation = &config.ventilation;
+    if ventilation.kp < 0.0 || ventilation.ki < 0.0 {
+        bail!("ventilation.kp/ki: Must not be negative");
+    }
+    if !(0.0..=100.0).contains(&ventilation.min_output)
+        || !(0.0..=100.0).contains(&ventilation.max_output)
+        || ventilation.min_output > ventilation.max_output
+    {
+        bail!("ventilation.min_output/max_output: Must be between 0 and 100, with min <= max");
+    }
+    if ventilation.pwm_frequency == 0 {
+        bail!("ventilation.pwm_frequency: Must be greater than 0");
+    }
+
+    let safe_mode = &config.safe_mode;
+    if safe_mode.brownouts == 0 {
+        bail!("safe_mode.brownouts: Must be greater than 0");
+    }
+    if safe_mode.low_readings == 0 {
+        bail!("safe_mode.low_readings: Must be greater than 0");
+    }
+    match (safe_mode.min_voltage, safe_mode.resume_voltage) {
+        (Some(min), _) if min <= 0.0 => bail!("safe_mode.min_voltage: Must be greater than 0"),
+        (Some(min), Some(resume)) if resume < min => {
+            bail!("safe_mode.resume_voltage: Must be at least min_voltage")
+        }
+        (None, Some(_)) => bail!("safe_mode.resume_voltage: Requires min_voltage"),
+        _ => {}
+    }
+    if let Some(ref profile) = safe_mode.profile {
+        if !config.profiles.contains_key(profile) {
+            bail!("safe_mode.profile: Unknown profile {:?}", profile);
+        }
+    }
+
+    let cpu = &config.cpu;
+    if ![80, 160].contains(&cpu.max_freq_mhz) {
+        bail!(
+            "cpu.max_freq_mhz: Must be 80 or 160 (the maximum of the ESP32-C3), not {}",
+            cpu.max_freq_mhz
+        );
+    }
+    let scaling = match cpu.min_freq_mhz {
+        Some(min) if ![10, 20, 40, 80, 160].contains(&min) || min > cpu.max_freq_mhz => bail!(
+            "cpu.min_freq_mhz: Must be 10, 20, 40, 80 or 160, and at most max_freq_mhz, not {}",
+            min
+        ),
+        Some(min) => min < cpu.max_freq_mhz,
+        None => false,
+    };
+    if (scaling || cpu.light_sleep) && (!config.outputs.is_empty() || cfg!(feature = "fan")) {
+        bail!(
+            "cpu.min_freq_mhz/light_sleep: Not available with PWM outputs or the fan, since \
+            their frequency depends on the APB clock"
+        );
+    }
+    if cpu.light_sleep && cfg!(any(feature = "geiger", feature = "s0")) {
+        bail!("cpu.light_sleep: Not available with pulse inputs, pulses would be missed");
+    }
+    // The CPU is scaled up while a task runs, but the slower wake-ups from the minimum frequency
+    // add up over the many small steps of a TLS handshake, which must finish within the 10 s
+    // timeout of the HTTP client
+    if let (Some(min), Some(field)) = (cpu.min_freq_mhz, tls_connection(config)) {
+        if min < 40 {
+            bail!(
+                "cpu.min_freq_mhz: Must be at least 40 with TLS connections ({}), not {}",
+                field,
+                min
+            );
+        }
+    }
+
+    let calibration = &config.calibration;
+    for (i, sensor) in calibration.sensors.iter().enumerate() {
+        if !SENSOR_NAMES.contains(&sensor.as_str()) {
+            bail!(
+                "calibration.sensors: Unknown sensor {:?} (known sensors: {})",
+                sensor,
+                SENSOR_NAMES.join(", ")
+            );
+        }
+        if calibration.sensors[..i].contains(sensor) {
+            bail!("calibration.sensors: Duplicate sensor {:?}", sensor);
+        }
+    }
+    if calibration.interval_days == 0 {
+        bail!("calibration.interval_days: Must not be 0");
+    }
+    if calibration.notify && config.sinks.ntfy.is_none() {
+        bail!("calibration.notify: ntfy is not configured in sinks.ntfy");
+    }
+
+    let simulation = &config.simulation;
+    if simulation.enabled && simulation.metrics.is_empty() {
+        bail!("simulation.metrics: Must not be empty if the simulation is enabled");
+    }
+    for (i, simulated) in simulation.metrics.iter().enumerate() {
+        if simulation.metrics[..i]
+            .iter()
+            .any(|other| other.metric() == simulated.metric())
+        {
+            bail!(
+                "simulation.metrics[{}]: Duplicate metric {}",
+                i,
+                simulated.metric().name()
+            );
+        }
+        match *simulated {
+            SimulatedMetric::Sine { min, max, .. }
+            | SimulatedMetric::RandomWalk { min, max, .. }
+                if min >= max =>
+            {
+                bail!("simulation.metrics[{}]: min must be less than max", i);
+            }
+            SimulatedMetric::Sine { period_secs: 0, .. } => {
+                bail!("simulation.metrics[{}].period_secs: Must not be 0", i);
+            }
+            SimulatedMetric::RandomWalk { step, .. } if step <= 0.0 => {
+                bail!("simulation.metrics[{}].step: Must be greater than 0", i);
+            }
+            SimulatedMetric::Replay { ref values, .. } if values.is_empty() => {
+                bail!("simulation.metrics[{}].values: Must not be empty", i);
+            }
+            _ => {}
+        }
+    }
+
+    if config.outputs.len() > outputs::MAX_OUTPUTS {
+        bail!(
+            "outputs: At most {} outputs are supported",
+            outputs::MAX_OUTPUTS
+        );
+    }
+    for (i, output) in config.outputs.iter().enumerate() {
+        if output.name.is_empty()
+            || !output
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "outputs[{}].name: Must not be empty and only contain A-Z, a-z, 0-9, - and _",
+                i
+            );
+        }
+        if config.outputs[..i]
+            .iter()
+            .any(|other| other.name == output.name)
+        {
+            bail!("outputs[{}].name: Duplicate name {:?}", i, output.name);
+        }
+        if !pin_is_free(output.pin) {
+            bail!("outputs[{}].pin: GPIO{} is not available", i, output.pin);
+        }
+        if config.outputs[..i]
+            .iter()
+            .any(|other| other.pin == output.pin)
+        {
+            bail!(
+                "outputs[{}].pin: GPIO{} is used by another output",
+                i,
+                output.pin
+            );
+        }
+        if output.frequency == Some(0) {
+            bail!("outputs[{}].frequency: Must be greater than 0", i);
+        }
+        let min_duty = output.min_duty.unwrap_or(0.0);
+        let max_duty = output.max_duty.unwrap_or(100.0);
+        if !(0.0..=100.0).contains(&min_duty)
+            || !(0.0..=100.0).contains(&max_duty)
+            || min_duty > max_duty
+        {
+            bail!(
+                "outputs[{}].min_duty/max_duty: Must be between 0 and 100, with min <= max",
+                i
+            );
+        }
+    }
+
+    for (i, rule) in config.rules.iter().enumerate() {
+        validate_tag_value(&format!("rules[{}].name", i), &rule.name)?;
+        if config.rules[..i]
+            .iter()
+            .any(|other| other.name == rule.name)
+        {
+            bail!("rules[{}].name: Duplicate name {:?}", i, rule.name);
+        }
+        validate_condition(&format!("rules[{}].when", i), &rule.when)?;
+        let actions = rule.then.iter().map(|action| ("then", action));
+        let actions = actions.chain(rule.otherwise.iter().map(|action| ("else", action)));
+        for (field, action) in actions {
+            match action {
+                RuleAction::Output { output, level } => {
+                    if !config.outputs.iter().any(|o| &o.name == output) {
+                        bail!("rules[{}].{}: Unknown output {:?}", i, field, output);
+                    }
+                    if !(0.0..=100.0).contains(level) {
+                        bail!("rules[{}].{}: Level must be between 0 and 100", i, field);
+                    }
+                }
+                RuleAction::Webhook { webhook, body, .. } => {
+                    if !webhook.starts_with("http://") && !webhook.starts_with("https://") {
+                        bail!(
+                            "rules[{}].{}: Webhook URL must start with http(s)://",
+                            i,
+                            field
+                        );
+                    }
+                    validate_template(&format!("rules[{}].{}", i, field), body)?;
+                }
+                RuleAction::Ntfy {
+                    ntfy,
+                    title,
+                    priority,
+                } => {
+                    if config.sinks.ntfy.is_none() {
+                        bail!(
+                            "rules[{}].{}: ntfy is not configured in sinks.ntfy",
+                            i,
+                            field
+                        );
+                    }
+                    if matches!(priority, Some(priority) if !(1..=5).contains(priority)) {
+                        bail!("rules[{}].{}: Priority must be between 1 and 5", i, field);
+                    }
+                    validate_template(&format!("rules[{}].{}", i, field), ntfy)?;
+                    if let Some(title) = title {
+                        validate_template(&format!("rules[{}].{}", i, field), title)?;
+                    }
+                }
+            }
+        }
+    }
+
+    if cfg!(feature = "minimal") {
+        validate_minimal(config)?;
+    }
+
+    Ok(())
+}
+
+/// Validate that the config only uses what's part of the minimal build: No network services and
+/// no HTTPS, since the TLS certificate bundle is stripped.
+fn validate_minimal(config: &Config) -> anyhow::Result<()> {
+    for (field, enabled) in [
+        ("api", config.api.enabled),
+        ("snmp", config.snmp.enabled),
+        ("bacnet", config.bacnet.enabled),
+        ("modbus", config.modbus.enabled),
+        ("esphome", config.esphome.enabled),
+        ("mdns", config.mdns.enabled),
+    ] {
+        if enabled {
+            bail!("{}.enabled: Not available in the minimal build", field);
+        }
+    }
+    if let Some(field) = tls_connection(config) {
+        bail!("{}: HTTPS/TLS is not available in the minimal build", field);
+    }
+    Ok(())
+}
+
+/// Return the field of the first connection that uses HTTPS or TLS, if any.
+fn tls_connection(config: &Config) -> Option<String> {
+    if let Some(ref influxdb) = config.sinks.influxdb {
+        if influxdb.host.starts_with("https://") {
+            return Some("sinks.influxdb.host".into());
+        }
+    }
+    if let Some(ref ntfy) = config.sinks.ntfy {
+        // The default server uses HTTPS
+        if !matches!(ntfy.server, Some(ref server) if server.starts_with("http://")) {
+            return Some("sinks.ntfy.server".into());
+        }
+    }
+    if let Some(ref mqtt) = config.sinks.mqtt {
+        if mqtt.url.starts_with("mqtts://") {
+            return Some("sinks.mqtt.url".into());
+        }
+    }
+    if let Some(ref webhook) = config.sinks.webhook {
+        if webhook.url.starts_with("https://") {
+            return Some("sinks.webhook.url".into());
+        }
+    }
+    if matches!(config.remote_config.url, Some(ref url) if url.starts_with("https://")) {
+        return Some("remote_config.url".into());
+    }
+    for (i, rule) in config.rules.iter().enumerate() {
+        for action in rule.then.iter().chain(rule.otherwise.iter()) {
+            if matches!(action, RuleAction::Webhook { webhook, .. } if webhook.starts_with("https://"))
+            {
+                return Some(format!("rules[{}]", i));
+            }
+        }
+    }
+    None
+}
+
+/// Validate that a template only contains known placeholders.
+fn validate_template(field: &str, template: &str) -> anyhow::Result<()> {
+    if let Some(name) = webhook::placeholders(template)
+        .iter()
+        .find(|name| !webhook::VARIABLES.contains(name))
+    {
+        bail!("{}: Unknown placeholder {:?}", field, name);
+    }
+    Ok(())
+}
+
+/// Validate a rule condition.
+fn validate_condition(field: &str, condition: &Condition) -> anyhow::Result<()> {
+    match condition {
+        Condition::All { all: conditions } | Condition::Any { any: conditions } => {
+            if conditions.is_empty() {
+                bail!("{}: Must not be empty", field);
+            }
+            for (i, condition) in conditions.iter().enumerate() {
+                validate_condition(&format!("{}[{}]", field, i), condition)?;
+            }
+        }
+        Condition::Metric { above, below, .. } => {
+            if above.is_none() && below.is_none() {
+                bail!("{}: Either above or below must be set", field);
+            }
+        }
+        Condition::Time { from, to } => {
+            for time in [from, to] {
+                if parse_time_of_day(time).is_none() {
+                    bail!("{}: Invalid time {:?}, must be HH:MM", field, time);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a time of day in the format `HH:MM`. Return the minutes since midnight.
+pub fn parse_time_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours >= 24 || minutes >= 60 {
+        return None;
+    }
+    Some(hours * 60 + minutes)
+}
+
+/// Return whether a GPIO can be used for an output, because it's not used by an enabled feature.
+///
+/// GPIO6/GPIO7 (I²C), GPIO11-17 (flash), GPIO18/GPIO19 (USB D-/D+) and GPIO20/GPIO21 (serial
+/// console) are always used.
+fn pin_is_free(pin: u8) -> bool {
+    let used = match pin {
+        0 => cfg!(feature = "thermocouple") || cfg!(feature = "epaper"),
+        1 => cfg!(feature = "s0") || cfg!(feature = "buttons"),
+        2 | 8 | 9 => {
+            cfg!(feature = "thermocouple") || cfg!(feature = "rtd") || cfg!(feature = "epaper")
+        }
+        3 => cfg!(feature = "geiger") || cfg!(feature = "buttons"),
+        4 | 5 => cfg!(feature = "ld2410") || cfg!(feature = "pzem") || cfg!(feature = "epaper"),
+        10 => cfg!(feature = "ccs811") || cfg!(feature = "fan") || cfg!(feature = "rtd"),
+        _ => true,
+    };
+    !used
+}
+
+/// Return whether a value of the metric can be converted to the unit.
+fn supports_unit(metric: Metric, unit: Unit) -> bool {
+    match metric {
+        Metric::Temperature | Metric::Thermocouple | Metric::Rtd => {
+            matches!(unit, Unit::Celsius | Unit::Fahrenheit)
+        }
+        Metric::Pressure | Metric::SeaLevelPressure => {
+            matches!(unit, Unit::Hpa | Unit::Inhg | Unit::Mmhg)
+        }
+        _ => false,
+    }
+}
+
+/// Ensure that a tag key or value can be submitted in InfluxDB line protocol without escaping.
+fn validate_tag_value(field: &str, value: &str) -> anyhow::Result<()> {
+    if value.is_empty() {
+        bail!("{}: Must not be empty", field);
+    }
+    if let Some(c) = value
+        .chars()
+        .find(|c| matches!(c, ',' | '=' | ' ' | '"' | '\\'))
+    {
+        bail!("{}: Must not contain {:?} (in {:?})", field, c, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_outputs() -> Config {
+        let mut config = Config::default();
+        config.outputs.push(Output {
+            name: "fan".into(),
+            pin: 7,
+            frequency: Some(25000),
+            min_duty: Some(20.0),
+            max_duty: None,
+        });
+        config.outputs.push(Output {
+            name: "led".into(),
+            pin: 6,
+            frequency: None,
+            min_duty: None,
+            max_duty: None,
+        });
+        config
+    }
+
+    #[test]
+    fn store_round_trip() {
+        let config = config_with_outputs();
+        let text = serialize(&config).unwrap();
+        let stored: Config = toml::from_str(&text).unwrap();
+        assert_eq!(stored.outputs.len(), 2);
+        assert_eq!(stored.outputs[0].name, "fan");
+        assert_eq!(stored.outputs[0].frequency, Some(25000));
+        assert_eq!(stored.outputs[1].pin, 6);
+        assert_eq!(serialize(&stored).unwrap(), text);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut config = config_with_outputs();
+        config.api.token = Some("secret".into());
+        let text = export(&config).unwrap();
+        assert!(!text.contains("secret"));
+        let exported: Config = toml::from_str(&text).unwrap();
+        assert_eq!(exported.outputs.len(), 2);
+        assert_eq!(exported.api.token, None);
+        assert_eq!(export(&exported).unwrap(), text);
+    }
+
+    fn config_with_influxdb(host: &str, api_token: Option<&str>) -> Config {
+        let mut config = Config::default();
+        config.sinks.influxdb = Some(InfluxDb {
+            host: host.into(),
+            api: InfluxDbApi::V2,
+            org: "org".into(),
+            bucket: "bucket".into(),
+            api_token: api_token.map(Into::into),
+            username: None,
+            password: None,
+            interval_secs: None,
+        });
+        config
+    }
+
+    #[test]
+    fn import_keeps_secrets_for_same_host() {
+        let current = config_with_influxdb("https://influx.example.com", Some("secret"));
+        let mut config = config_with_influxdb("https://influx.example.com", None);
+        restore_secrets(&mut config, &current);
+        let influxdb = config.sinks.influxdb.unwrap();
+        assert_eq!(influxdb.api_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn import_drops_secrets_for_other_host() {
+        let mut current = config_with_influxdb("https://influx.example.com", Some("secret"));
+        if let Some(ref mut influxdb) = current.sinks.influxdb {
+            influxdb.password = Some("password".into());
+        }
+        let mut config = config_with_influxdb("https://attacker.example.com", None);
+        restore_secrets(&mut config, &current);
+        let influxdb = config.sinks.influxdb.unwrap();
+        assert_eq!(influxdb.api_token, None);
+        assert_eq!(influxdb.password, None);
+    }
+
+    #[test]
+    fn merge_keeps_secrets_for_same_host() {
+        let current = config_with_influxdb("https://influx.example.com", Some("secret"));
+        let partial = "[sinks.influxdb]\nbucket = \"other\"".parse().unwrap();
+        let (config, changed) = merge(&current, partial).unwrap();
+        let influxdb = config.sinks.influxdb.unwrap();
+        assert_eq!(influxdb.api_token.as_deref(), Some("secret"));
+        assert_eq!(changed, ["sinks.influxdb.bucket"]);
+    }
+
+    #[test]
+    fn merge_drops_secrets_for_other_host() {
+        let mut current = config_with_influxdb("https://influx.example.com", Some("secret"));
+        current.remote_config.url = Some("https://config.example.com/sensilo.toml".into());
+        current.remote_config.token = Some("token".into());
+        let partial = concat!(
+            "[sinks.influxdb]\n",
+            "host = \"https://attacker.example.com\"\n",
+            "[remote_config]\n",
+            "url = \"https://attacker.example.com/sensilo.toml\"\n",
+        );
+        let (config, _) = merge(&current, partial.parse().unwrap()).unwrap();
+        let influxdb = config.sinks.influxdb.unwrap();
+        assert_eq!(influxdb.api_token, None);
+        assert_eq!(influxdb.password, None);
+        assert_eq!(config.remote_config.token, None);
+    }
+
+    #[test]
+    fn hash_depends_on_config() {
+        assert_ne!(hash(&Config::default()), hash(&config_with_outputs()));
+    }
+}
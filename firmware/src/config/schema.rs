@@ -0,0 +1,1440 @@
+//! Config file schema.
+//!
+//! All fields are optional, missing fields are set to their defaults. Unknown fields are
+//! rejected, to catch typos.
+//!
+//! When changing the layout in an incompatible way, increment [`VERSION`] and add a migration.
+//!
+//! This file is compiled into the build script (to check the build-time config) and into the
+//! provisioning tool (`../provision`) as well, so it must only depend on `std` and `serde`.
+
+use std::{collections::BTreeMap, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+/// Current version of the config layout
+pub const VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Version of the config layout
+    pub version: u32,
+    /// Name of the device (value of the `name` tag), default "sensilo"
+    pub name: Option<String>,
+    /// Altitude in meters above sea level, used for sea-level pressure reduction and for the
+    /// pressure compensation of CO₂ sensors (if there's no pressure sensor)
+    pub altitude: Option<f32>,
+    /// Mains frequency in Hz (50 or 60), used to filter out mains noise
+    pub mains_frequency: u8,
+    /// Time zone of the rules and the display clock as POSIX TZ string, e.g.
+    /// "CET-1CEST,M3.5.0,M10.5.0/3" (default: UTC)
+    pub timezone: Option<String>,
+    /// Name of the profile that is active at boot, `None` to use the base settings
+    pub profile: Option<String>,
+    /// Additional tags that are added to every submitted line
+    pub tags: BTreeMap<String, String>,
+    pub intervals: Intervals,
+    pub history: History,
+    pub outage: Outage,
+    pub wifi: Wifi,
+    pub time: Time,
+    /// Named measurement profiles
+    pub profiles: BTreeMap<String, Profile>,
+    pub api: Api,
+    pub snmp: Snmp,
+    pub bacnet: Bacnet,
+    pub modbus: Modbus,
+    pub esphome: Esphome,
+    pub mdns: Mdns,
+    pub remote_config: RemoteConfig,
+    pub sinks: Sinks,
+    pub sensors: Sensors,
+    pub display: Display,
+    pub buttons: Buttons,
+    pub ventilation: Ventilation,
+    pub safe_mode: SafeMode,
+    pub cpu: Cpu,
+    pub calibration: Calibration,
+    pub simulation: Simulation,
+    /// PWM outputs, controlled through the local HTTP API
+    pub outputs: Vec<Output>,
+    /// Rules, evaluated once per measurement cycle
+    pub rules: Vec<Rule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            name: None,
+            altitude: None,
+            mains_frequency: 50,
+            timezone: None,
+            profile: None,
+            tags: BTreeMap::new(),
+            intervals: Intervals::default(),
+            history: History::default(),
+            outage: Outage::default(),
+            wifi: Wifi::default(),
+            time: Time::default(),
+            profiles: BTreeMap::new(),
+            api: Api::default(),
+            snmp: Snmp::default(),
+            bacnet: Bacnet::default(),
+            modbus: Modbus::default(),
+            esphome: Esphome::default(),
+            mdns: Mdns::default(),
+            remote_config: RemoteConfig::default(),
+            sinks: Sinks::default(),
+            sensors: Sensors::default(),
+            display: Display::default(),
+            buttons: Buttons::default(),
+            ventilation: Ventilation::default(),
+            safe_mode: SafeMode::default(),
+            cpu: Cpu::default(),
+            calibration: Calibration::default(),
+            simulation: Simulation::default(),
+            outputs: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Intervals {
+    /// Interval between two measurement submissions in seconds
+    pub measurement_secs: u32,
+    /// Interval at which the presence sensors are polled in milliseconds
+    pub presence_poll_ms: u32,
+    /// Measurement interval in seconds while somebody is present (if there's a presence sensor)
+    pub occupied_measurement_secs: Option<u32>,
+    /// Measurement interval in seconds while nobody is present (if there's a presence sensor)
+    pub vacant_measurement_secs: Option<u32>,
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Self {
+            measurement_secs: 30,
+            presence_poll_ms: 250,
+            occupied_measurement_secs: None,
+            vacant_measurement_secs: None,
+        }
+    }
+}
+
+/// In-RAM history of the measurements
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct History {
+    /// Length of a time slot in seconds, the values measured during a slot are averaged
+    pub resolution_secs: u32,
+    /// How long the values are kept in hours
+    pub retention_hours: u32,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            resolution_secs: 60,
+            retention_hours: 24,
+        }
+    }
+}
+
+impl History {
+    /// Return the number of slots per metric.
+    pub fn capacity(&self) -> usize {
+        (self.retention_hours as usize * 3600) / self.resolution_secs.max(1) as usize
+    }
+}
+
+/// Detection of network outages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Outage {
+    /// Number of failed submissions in a row after which the device is offline
+    pub failures: u32,
+    /// Whether the history of an outage is resubmitted once the device is back online
+    pub backfill: bool,
+    /// Name of a PWM output (e.g. with an LED) that is turned on while the device is offline
+    pub led: Option<String>,
+    /// Number of failed WiFi reconnections while offline after which the provisioning portal is
+    /// opened (default: never)
+    pub portal_after: Option<u32>,
+}
+
+impl Default for Outage {
+    fn default() -> Self {
+        Self {
+            failures: 3,
+            backfill: true,
+            led: None,
+            portal_after: None,
+        }
+    }
+}
+
+/// WiFi settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Wifi {
+    /// SSID of the access point
+    pub ssid: Option<String>,
+    /// Password of the access point (secret), `None` for an open network
+    pub password: Option<String>,
+    /// Further networks in the order of priority, e.g. at home and at the office. The network that
+    /// worked last is tried first (see `networks.rs`).
+    pub networks: Vec<WifiNetwork>,
+    /// ISO 3166 country code, determines the allowed channels and TX power, e.g. "CH" (default:
+    /// "01", worldwide safe mode)
+    pub country: Option<String>,
+    /// Maximum TX power in dBm, between 2 and 20 (default: the maximum of the country)
+    pub max_tx_power_dbm: Option<f32>,
+    /// Only connect to the access point of `ssid` with this BSSID (see `roaming.rs`), e.g.
+    /// "aa:bb:cc:dd:ee:ff" (default: any)
+    pub bssid: Option<String>,
+    /// Connect to the strongest access point, and look for a stronger one if the signal is weak
+    pub roaming: bool,
+    /// Signal strength in dBm below which a stronger access point is looked for
+    pub roaming_threshold_dbm: i8,
+    /// Open the provisioning portal (see `portal.rs`) if no SSID is configured or if the access
+    /// point can't be reached
+    pub portal: bool,
+    /// Password of the portal access point (secret), `None` for an open access point. Without it,
+    /// the portal is only opened if no SSID is configured.
+    pub portal_password: Option<String>,
+}
+
+impl Default for Wifi {
+    fn default() -> Self {
+        Self {
+            ssid: None,
+            password: None,
+            networks: Vec::new(),
+            country: None,
+            max_tx_power_dbm: None,
+            bssid: None,
+            roaming: false,
+            roaming_threshold_dbm: -75,
+            portal: true,
+            portal_password: None,
+        }
+    }
+}
+
+/// A further WiFi network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiNetwork {
+    /// SSID of the access point
+    pub ssid: String,
+    /// Password of the access point (secret), `None` for an open network
+    pub password: Option<String>,
+}
+
+/// Time synchronization via SNTP
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Time {
+    /// NTP servers (host names or IP addresses), tried in order (default: `pool.ntp.org`)
+    pub servers: Vec<String>,
+}
+
+/// A named measurement profile. Settings that are not set are taken from the base config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Interval between two measurement submissions in seconds
+    pub measurement_secs: Option<u32>,
+    /// Interval at which the presence sensors are polled in milliseconds
+    pub presence_poll_ms: Option<u32>,
+    /// Measurement interval in seconds while somebody is present
+    pub occupied_measurement_secs: Option<u32>,
+    /// Measurement interval in seconds while nobody is present
+    pub vacant_measurement_secs: Option<u32>,
+    /// Sensors that are read, all sensors if not set
+    pub sensors: Option<Vec<String>>,
+    /// Whether the low power measurement modes of the sensors are used (currently the SHTC3)
+    pub low_power: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Api {
+    /// Whether the local HTTP API is enabled
+    pub enabled: bool,
+    /// If set, requests must be authenticated with `Authorization: Bearer <token>` (secret)
+    pub token: Option<String>,
+    /// Whether the Prometheus metrics are served at `/metrics`
+    pub metrics: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Snmp {
+    /// Whether the SNMP v2c agent is enabled
+    pub enabled: bool,
+    /// Community (secret), required if the agent is enabled
+    pub community: Option<String>,
+    pub port: u16,
+    /// Private enterprise number of the OID subtree (default: 32473, reserved for documentation)
+    pub enterprise: u32,
+}
+
+impl Default for Snmp {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            community: None,
+            port: 161,
+            enterprise: 32473,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bacnet {
+    /// Whether the BACnet/IP device is enabled
+    pub enabled: bool,
+    /// Device instance number, unique within the BACnet network, required if the device is enabled
+    pub device_instance: Option<u32>,
+    pub port: u16,
+}
+
+impl Default for Bacnet {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            device_instance: None,
+            port: 47808,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Modbus {
+    /// Whether the Modbus TCP server is enabled
+    pub enabled: bool,
+    pub port: u16,
+    /// Input registers, at least one is required if the server is enabled
+    pub registers: Vec<Register>,
+}
+
+impl Default for Modbus {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 502,
+            registers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Esphome {
+    /// Whether the ESPHome native API is enabled
+    pub enabled: bool,
+    /// API password (secret), if clients must authenticate
+    pub password: Option<String>,
+    pub port: u16,
+}
+
+impl Default for Esphome {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            password: None,
+            port: 6053,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mdns {
+    /// Whether the device is announced via mDNS as `sensilo-<name>.local`, with its services
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// URL of a partial config (TOML or JSON) that is merged into the config (see `remote.rs`)
+    pub url: Option<String>,
+    /// If set, the config is fetched with `Authorization: Bearer <token>` (secret)
+    pub token: Option<String>,
+    /// Interval between two fetches of the URL in seconds
+    pub interval_secs: u32,
+    /// Whether partial configs are received from `<topic_prefix>/<name>/config/set` (requires
+    /// `sinks.mqtt`)
+    pub mqtt: bool,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            token: None,
+            interval_secs: 3600,
+            mqtt: false,
+        }
+    }
+}
+
+impl RemoteConfig {
+    /// Return whether partial configs are fetched or received.
+    pub fn is_enabled(&self) -> bool {
+        self.url.is_some() || self.mqtt
+    }
+}
+
+/// Input register (or registers, depending on the format) with the value of a metric
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Register {
+    /// Address of the (first) register, starting at 0
+    pub address: u16,
+    pub metric: Metric,
+    /// Factor the value is multiplied with before it's rounded (default: 1)
+    pub scale: Option<f32>,
+    /// Format of the value (default: int16)
+    pub format: Option<RegisterFormat>,
+}
+
+impl Register {
+    pub fn scale(&self) -> f32 {
+        self.scale.unwrap_or(1.0)
+    }
+
+    pub fn format(&self) -> RegisterFormat {
+        self.format.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegisterFormat {
+    /// Signed 16 bit integer
+    #[default]
+    Int16,
+    /// Unsigned 16 bit integer
+    Uint16,
+    /// Signed 32 bit integer in two registers, the high word first
+    Int32,
+}
+
+impl RegisterFormat {
+    /// Return the number of registers.
+    pub fn registers(self) -> u16 {
+        match self {
+            RegisterFormat::Int16 | RegisterFormat::Uint16 => 1,
+            RegisterFormat::Int32 => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sinks {
+    /// InfluxDB server
+    pub influxdb: Option<InfluxDb>,
+    /// ntfy server and topic for push notifications
+    pub ntfy: Option<Ntfy>,
+    /// Routing of measurements to other InfluxDB buckets. The first matching route is used,
+    /// measurements without a matching route are written to the bucket of the InfluxDB server.
+    pub routes: Vec<Route>,
+    /// Additional stream with aggregates of the metrics, e.g. for long-term storage
+    pub downsampling: Option<Downsampling>,
+    /// UDP datagram with the latest readings, sent every measurement cycle
+    pub udp: Option<Udp>,
+    /// KNXnet/IP group telegrams with the latest readings, sent every measurement cycle
+    pub knx: Option<Knx>,
+    /// Entries of the event log as `events` lines, e.g. for Grafana annotations
+    pub annotations: Option<Annotations>,
+    /// MQTT broker to which the readings are published, every measurement cycle
+    pub mqtt: Option<Mqtt>,
+    /// URL to which a JSON document with the readings is POSTed, every measurement cycle
+    pub webhook: Option<JsonWebhook>,
+    /// Gateway to which the readings are sent via ESP-NOW, instead of associating with WiFi
+    pub espnow: Option<EspNow>,
+    /// BTHome advertisements with the latest readings, with the `bthome` feature
+    pub bthome: Option<BtHome>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxDb {
+    pub host: String,
+    /// Write API of the server
+    #[serde(default)]
+    pub api: InfluxDbApi,
+    /// Organization (v2 API only)
+    #[serde(default)]
+    pub org: String,
+    /// Bucket, or database of the v1 API (optionally with the retention policy, `<db>/<rp>`)
+    pub bucket: String,
+    /// API token (secret, v2 API only)
+    pub api_token: Option<String>,
+    /// Username of the v1 API (default: no authentication)
+    pub username: Option<String>,
+    /// Password of the v1 API (secret)
+    pub password: Option<String>,
+    /// Interval between two submissions in seconds, the cycles in between are submitted together (default:
+    /// every measurement cycle)
+    pub interval_secs: Option<u32>,
+}
+
+/// Write API of an InfluxDB server
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InfluxDbApi {
+    /// `/api/v2/write` of InfluxDB 2.x and 3.x, with org, bucket and API token
+    #[default]
+    V2,
+    /// `/write` of InfluxDB 1.x and compatible ingesters, with database and optional basic
+    /// authentication
+    V1,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Names of the measurements (e.g. `diagnostics`), i.e. the first field of the lines
+    pub measurements: Vec<String>,
+    pub bucket: String,
+    /// Organization of the bucket (default: the organization of the InfluxDB server)
+    pub org: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Downsampling {
+    /// Length of the aggregation window in seconds
+    pub interval_secs: u32,
+    /// Name of the measurement of the aggregates
+    pub measurement: String,
+}
+
+impl Default for Downsampling {
+    fn default() -> Self {
+        Self {
+            interval_secs: 300,
+            measurement: "downsampled".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Udp {
+    /// Destination address, the broadcast address to reach the whole LAN
+    pub address: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Default for Udp {
+    fn default() -> Self {
+        Self {
+            address: Ipv4Addr::BROADCAST,
+            port: 4210,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Knx {
+    /// Individual address of the device (`area.line.device`)
+    pub individual_address: String,
+    /// KNXnet/IP routing multicast address
+    pub multicast_address: Ipv4Addr,
+    pub port: u16,
+    /// Telegrams, one per metric and group address
+    pub telegrams: Vec<KnxTelegram>,
+}
+
+impl Default for Knx {
+    fn default() -> Self {
+        Self {
+            individual_address: "15.15.255".into(),
+            multicast_address: Ipv4Addr::new(224, 0, 23, 12),
+            port: 3671,
+            telegrams: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KnxTelegram {
+    pub metric: Metric,
+    /// Group address (`main/middle/sub` or `main/sub`)
+    pub group_address: String,
+    pub dpt: Dpt,
+}
+
+/// KNX datapoint type, the encoding of a value
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Dpt {
+    /// DPT 5.001, 0-100 % as one byte
+    #[serde(rename = "5.001")]
+    Scaling,
+    /// DPT 7, unsigned 16 bit integer
+    #[serde(rename = "7")]
+    Unsigned16,
+    /// DPT 9, 2-byte float (e.g. 9.001 temperature, 9.004 lux, 9.007 humidity, 9.008 ppm)
+    #[serde(rename = "9")]
+    Float16,
+    /// DPT 14, 4-byte IEEE 754 float
+    #[serde(rename = "14")]
+    Float32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Annotations {
+    /// Kinds of the event log entries that are submitted
+    pub kinds: Vec<EventKind>,
+}
+
+impl Default for Annotations {
+    fn default() -> Self {
+        Self {
+            kinds: vec![EventKind::Boot, EventKind::Config],
+        }
+    }
+}
+
+/// Kind of an event log entry (see `eventlog.rs`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Boot,
+    Config,
+    Rule,
+    Sensor,
+    Watchdog,
+    Power,
+    Calibration,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 7] = [
+        EventKind::Boot,
+        EventKind::Config,
+        EventKind::Rule,
+        EventKind::Sensor,
+        EventKind::Watchdog,
+        EventKind::Power,
+        EventKind::Calibration,
+    ];
+
+    /// Return the name of the kind, as used in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Boot => "boot",
+            EventKind::Config => "config",
+            EventKind::Rule => "rule",
+            EventKind::Sensor => "sensor",
+            EventKind::Watchdog => "watchdog",
+            EventKind::Power => "power",
+            EventKind::Calibration => "calibration",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mqtt {
+    /// Broker URL, `mqtt://` or `mqtts://` (TLS), e.g. `mqtts://broker.example.com:8883`
+    pub url: String,
+    pub username: Option<String>,
+    /// Password (secret)
+    pub password: Option<String>,
+    /// Client ID (default: the device name)
+    pub client_id: Option<String>,
+    /// First level of the topics, `<topic_prefix>/<name>/...`
+    pub topic_prefix: String,
+    /// Quality of service of the readings, 0 (at most once) or 1 (at least once)
+    pub qos: u8,
+    /// Whether the broker keeps the latest reading of every topic for new subscribers
+    pub retain: bool,
+    /// Whether the measurements are also written to InfluxDB. If not, MQTT replaces InfluxDB,
+    /// i.e. events, annotations and backfills are not submitted at all.
+    pub influxdb: bool,
+    /// Whether the sensors are announced with Home Assistant MQTT discovery
+    pub discovery: bool,
+    /// First level of the discovery topics, as configured in Home Assistant
+    pub discovery_prefix: String,
+    /// Interval between two submissions in seconds, the cycles in between are skipped (default:
+    /// every measurement cycle)
+    pub interval_secs: Option<u32>,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            username: None,
+            password: None,
+            client_id: None,
+            topic_prefix: "sensilo".into(),
+            qos: 0,
+            retain: false,
+            influxdb: true,
+            discovery: true,
+            discovery_prefix: "homeassistant".into(),
+            interval_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsonWebhook {
+    /// URL, `http://` or `https://`
+    pub url: String,
+    /// Additional request headers, e.g. `Authorization`
+    pub headers: BTreeMap<String, String>,
+    /// Whether the measurements are also written to InfluxDB. If not, the webhook replaces
+    /// InfluxDB, i.e. events, annotations and backfills are not submitted at all.
+    pub influxdb: bool,
+    /// Interval between two submissions in seconds, the cycles in between are skipped (default:
+    /// every measurement cycle)
+    pub interval_secs: Option<u32>,
+}
+
+impl Default for JsonWebhook {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            headers: BTreeMap::new(),
+            influxdb: true,
+            interval_secs: None,
+        }
+    }
+}
+
+/// BTHome advertisements (see `bthome.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BtHome {
+    /// Advertising interval in milliseconds, 20-10240
+    pub advertising_ms: u32,
+    /// Whether the measurements are also written to InfluxDB. If not, BTHome replaces InfluxDB,
+    /// i.e. events, annotations and backfills are not submitted at all.
+    pub influxdb: bool,
+    /// Interval between two updates of the advertisement in seconds, the cycles in between are
+    /// skipped (default: every measurement cycle)
+    pub interval_secs: Option<u32>,
+}
+
+impl Default for BtHome {
+    fn default() -> Self {
+        Self {
+            advertising_ms: 1000,
+            influxdb: true,
+            interval_secs: None,
+        }
+    }
+}
+
+/// ESP-NOW sender mode (see `espnow.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EspNow {
+    /// MAC address of the gateway, e.g. "aa:bb:cc:dd:ee:ff"
+    pub gateway: String,
+    /// WiFi channel of the gateway, 1-13
+    pub channel: u8,
+    /// Interval between two submissions in seconds, the cycles in between are skipped (default:
+    /// every measurement cycle)
+    pub interval_secs: Option<u32>,
+}
+
+impl Default for EspNow {
+    fn default() -> Self {
+        Self {
+            gateway: String::new(),
+            channel: 1,
+            interval_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ntfy {
+    /// Server URL (default: `https://ntfy.sh`)
+    pub server: Option<String>,
+    pub topic: String,
+    /// Default message priority, 1 (min) to 5 (max) (default: the server default, 3)
+    pub priority: Option<u8>,
+    /// Access token (secret)
+    pub token: Option<String>,
+    /// Whether a message is published when the device starts
+    #[serde(default)]
+    pub notify_startup: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sensors {
+    /// Whether the readings are tagged with the serial number of their sensor (`sensor_serial`)
+    pub serial_tags: bool,
+    /// Time budget of a sensor read in milliseconds (see `deadline.rs`)
+    pub read_timeout_ms: u32,
+    pub shtc3: Warmup,
+    pub veml7700: Veml7700,
+    pub bmp390: Bmp390,
+    pub as7341: As7341,
+    pub apds9960: Apds9960,
+    pub ld2410: Ld2410,
+    pub geiger: Geiger,
+    pub s0: S0,
+    pub max31855: Max31855,
+    pub max31865: Max31865,
+    pub ina219: Ina219,
+    pub supply: Supply,
+}
+
+impl Default for Sensors {
+    fn default() -> Self {
+        Self {
+            serial_tags: false,
+            read_timeout_ms: 2000,
+            shtc3: Warmup::default(),
+            veml7700: Veml7700::default(),
+            bmp390: Bmp390::default(),
+            as7341: As7341::default(),
+            apds9960: Apds9960::default(),
+            ld2410: Ld2410::default(),
+            geiger: Geiger::default(),
+            s0: S0::default(),
+            max31855: Max31855::default(),
+            max31865: Max31865::default(),
+            ina219: Ina219::default(),
+            supply: Supply::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Warmup {
+    /// Number of samples to discard after the sensor was powered up
+    pub discard_samples: u8,
+}
+
+impl Default for Warmup {
+    fn default() -> Self {
+        Self { discard_samples: 1 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Veml7700 {
+    /// Number of samples to discard after the sensor was powered up
+    pub discard_samples: u8,
+    /// Illuminance in lux above which the lights are considered on, enables the `lights` events
+    /// (see `lights.rs`)
+    pub lights_on_lux: Option<f32>,
+    /// Illuminance in lux below which the lights are considered off (default: half of
+    /// `lights_on_lux`)
+    pub lights_off_lux: Option<f32>,
+}
+
+impl Default for Veml7700 {
+    fn default() -> Self {
+        Self {
+            discard_samples: 1,
+            lights_on_lux: None,
+            lights_off_lux: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bmp390 {
+    /// Pressure oversampling (1, 2, 4, 8, 16 or 32)
+    pub oversampling: u8,
+}
+
+impl Default for Bmp390 {
+    fn default() -> Self {
+        Self { oversampling: 8 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct As7341 {
+    /// Calibration factor for the illuminance derived from the spectral channels
+    pub lux_factor: f32,
+}
+
+impl Default for As7341 {
+    fn default() -> Self {
+        Self { lux_factor: 1.0 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Apds9960 {
+    /// Proximity level (0-255) at or above which somebody is considered present
+    pub presence_threshold: u8,
+    /// How long nobody must be detected until presence ends, in seconds
+    pub hold_time_secs: u32,
+}
+
+impl Default for Apds9960 {
+    fn default() -> Self {
+        Self {
+            presence_threshold: 50,
+            hold_time_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ld2410 {
+    /// How long nobody must be detected until presence ends, in seconds (the radar already
+    /// debounces internally)
+    pub hold_time_secs: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Geiger {
+    /// Conversion factor from CPM to µSv/h, depends on the tube
+    pub usvh_per_cpm: f32,
+    /// Dead time of the tube in µs
+    pub dead_time_us: u32,
+}
+
+impl Default for Geiger {
+    fn default() -> Self {
+        // SBM-20
+        Self {
+            usvh_per_cpm: 0.0057,
+            dead_time_us: 190,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S0 {
+    /// Impulses per kWh, as printed on the meter
+    pub impulses_per_kwh: u32,
+}
+
+impl Default for S0 {
+    fn default() -> Self {
+        Self {
+            impulses_per_kwh: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Max31855 {
+    /// Value of the channel tag
+    pub channel: String,
+}
+
+impl Default for Max31855 {
+    fn default() -> Self {
+        Self {
+            channel: "thermocouple".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Max31865 {
+    /// Value of the channel tag
+    pub channel: String,
+    pub rtd: Rtd,
+    /// Reference resistor in Ω (default 430 for PT100, 4300 for PT1000)
+    pub rref: Option<f32>,
+    /// Number of wires (2, 3 or 4)
+    pub wires: u8,
+}
+
+impl Default for Max31865 {
+    fn default() -> Self {
+        Self {
+            channel: "rtd".into(),
+            rtd: Rtd::default(),
+            rref: None,
+            wires: 4,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rtd {
+    #[default]
+    Pt100,
+    Pt1000,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ina219 {
+    /// Shunt resistance in mΩ
+    pub shunt_milliohm: f32,
+    pub channels: Vec<I2cChannel>,
+    /// Tag of the channel that measures the supply of the device itself, for the energy budget
+    pub self_channel: Option<String>,
+}
+
+impl Default for Ina219 {
+    fn default() -> Self {
+        Self {
+            shunt_milliohm: 100.0,
+            // A0 and A1 connected to GND
+            channels: vec![I2cChannel {
+                address: 0x40,
+                channel: None,
+            }],
+            self_channel: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Supply {
+    /// Ratio of the voltage divider in front of the ADC pin (supply voltage / pin voltage)
+    pub divider: f32,
+}
+
+impl Default for Supply {
+    fn default() -> Self {
+        // Two equal resistors
+        Self { divider: 2.0 }
+    }
+}
+
+/// A sensor instance at a specific I²C address
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct I2cChannel {
+    /// I²C address (0x40-0x4f for the INA219, depending on A0/A1)
+    pub address: u8,
+    /// Value of the channel tag (default: the address)
+    pub channel: Option<String>,
+}
+
+impl I2cChannel {
+    /// Return the value of the channel tag.
+    pub fn tag(&self) -> String {
+        self.channel
+            .clone()
+            .unwrap_or_else(|| format!("0x{:02x}", self.address))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Display {
+    /// Tag of the INA219 channel that measures the battery, shown as battery level
+    pub battery_channel: Option<String>,
+    /// Whether a sparkline with the recent trend is shown next to every value
+    pub sparklines: bool,
+    /// Pages, shown one after the other. If empty, a default page with the most common metrics
+    /// is shown.
+    pub pages: Vec<Page>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self {
+            battery_channel: None,
+            sparklines: true,
+            pages: Vec::new(),
+        }
+    }
+}
+
+/// A display page
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Page {
+    /// Title, shown in the header instead of the device name
+    pub title: Option<String>,
+    /// Rows, from top to bottom
+    pub rows: Vec<Row>,
+}
+
+/// A row on a display page, showing one metric
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Row {
+    pub metric: Metric,
+    /// Label (default: depends on the metric)
+    pub label: Option<String>,
+    /// Unit the value is converted to (default: the unit of the metric)
+    pub unit: Option<Unit>,
+    /// Number of decimals (default: depends on the metric)
+    pub decimals: Option<u8>,
+}
+
+/// A measured metric, e.g. shown on the display
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// Temperature (SHTC3)
+    Temperature,
+    /// Relative humidity (SHTC3)
+    Humidity,
+    /// Illuminance (TSL2591 or VEML7700)
+    Illuminance,
+    /// CO₂ (SCD4x)
+    Co2,
+    /// CO₂ equivalent (SGP30, ENS160 or CCS811)
+    Co2eq,
+    /// TVOC (SGP30, ENS160 or CCS811)
+    Tvoc,
+    /// Station pressure (BMP390)
+    Pressure,
+    /// Pressure reduced to sea level (BMP390)
+    SeaLevelPressure,
+    /// Air quality index (ENS160)
+    Aqi,
+    /// Formaldehyde (SFA30)
+    Hcho,
+    /// Share of time somebody was present (APDS9960 or LD2410)
+    Occupancy,
+    /// Dose rate (Geiger counter)
+    DoseRate,
+    /// Electrical power (PZEM-004T or S0)
+    Power,
+    /// Thermocouple temperature (MAX31855)
+    Thermocouple,
+    /// RTD temperature (MAX31865)
+    Rtd,
+}
+
+impl Metric {
+    /// All metrics
+    pub const ALL: [Metric; 15] = [
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Illuminance,
+        Metric::Co2,
+        Metric::Co2eq,
+        Metric::Tvoc,
+        Metric::Pressure,
+        Metric::SeaLevelPressure,
+        Metric::Aqi,
+        Metric::Hcho,
+        Metric::Occupancy,
+        Metric::DoseRate,
+        Metric::Power,
+        Metric::Thermocouple,
+        Metric::Rtd,
+    ];
+
+    /// Return the name of the metric, as used in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::Temperature => "temperature",
+            Metric::Humidity => "humidity",
+            Metric::Illuminance => "illuminance",
+            Metric::Co2 => "co2",
+            Metric::Co2eq => "co2eq",
+            Metric::Tvoc => "tvoc",
+            Metric::Pressure => "pressure",
+            Metric::SeaLevelPressure => "sea_level_pressure",
+            Metric::Aqi => "aqi",
+            Metric::Hcho => "hcho",
+            Metric::Occupancy => "occupancy",
+            Metric::DoseRate => "dose_rate",
+            Metric::Power => "power",
+            Metric::Thermocouple => "thermocouple",
+            Metric::Rtd => "rtd",
+        }
+    }
+}
+
+/// A unit that values can be converted to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    /// Degrees Celsius (temperatures)
+    Celsius,
+    /// Degrees Fahrenheit (temperatures)
+    Fahrenheit,
+    /// Hectopascal (pressures)
+    Hpa,
+    /// Inches of mercury (pressures)
+    Inhg,
+    /// Millimeters of mercury (pressures)
+    Mmhg,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Buttons {
+    /// Presses that are held at least this long (in milliseconds) are long presses
+    pub long_press_ms: u32,
+}
+
+impl Default for Buttons {
+    fn default() -> Self {
+        Self {
+            long_press_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ventilation {
+    /// Metric that is controlled, e.g. `co2` or `humidity`
+    pub metric: Metric,
+    /// Target value of the metric
+    pub setpoint: f32,
+    /// Proportional gain in percent fan speed per unit of the metric
+    pub kp: f32,
+    /// Integral gain in percent fan speed per unit of the metric and second
+    pub ki: f32,
+    /// Minimal fan speed in percent
+    pub min_output: f32,
+    /// Maximal fan speed in percent
+    pub max_output: f32,
+    /// PWM frequency in Hz
+    pub pwm_frequency: u32,
+}
+
+impl Default for Ventilation {
+    fn default() -> Self {
+        Self {
+            metric: Metric::Co2,
+            setpoint: 800.0,
+            kp: 0.1,
+            ki: 0.0005,
+            min_output: 0.0,
+            max_output: 100.0,
+            pwm_frequency: 25_000,
+        }
+    }
+}
+
+/// Low-voltage safe mode (see `power.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafeMode {
+    /// Whether the safe mode is entered on brown-outs and low supply voltage
+    pub enabled: bool,
+    /// Number of brown-out resets in a row after which the device starts in the safe mode
+    pub brownouts: u8,
+    /// Supply voltage in V below which the safe mode is entered (with the `supply` feature)
+    pub min_voltage: Option<f32>,
+    /// Supply voltage in V at or above which the safe mode is left (default: `min_voltage`)
+    pub resume_voltage: Option<f32>,
+    /// Number of supply voltage readings in a row that must be below `min_voltage` (or at or
+    /// above `resume_voltage`) to enter (or leave) the safe mode
+    pub low_readings: u8,
+    /// Hours after which the device restarts normally, if it entered the safe mode because of
+    /// brown-outs (0: never)
+    pub retry_hours: u32,
+    /// Name of the profile that is active in the safe mode (default: the regular profile)
+    pub profile: Option<String>,
+}
+
+impl Default for SafeMode {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            brownouts: 2,
+            min_voltage: None,
+            resume_voltage: None,
+            low_readings: 3,
+            retry_hours: 12,
+            profile: None,
+        }
+    }
+}
+
+/// CPU frequency and automatic light sleep (see `cpu.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cpu {
+    /// Maximum CPU frequency in MHz, 80 or 160
+    pub max_freq_mhz: u32,
+    /// Frequency in MHz to which the CPU is scaled down while idle, 10, 20, 40, 80 or 160
+    /// (default: the maximum, i.e. no frequency scaling)
+    pub min_freq_mhz: Option<u32>,
+    /// Whether the chip enters light sleep automatically while idle
+    pub light_sleep: bool,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self {
+            max_freq_mhz: 160,
+            min_freq_mhz: None,
+            light_sleep: false,
+        }
+    }
+}
+
+/// Calibration log of the sensors (see `calibration.rs`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    /// Sensors whose calibration dates are tracked, by their name (see `SENSOR_NAMES`)
+    pub sensors: Vec<String>,
+    /// Days after a calibration until the sensor is due again
+    pub interval_days: u32,
+    /// Whether an ntfy notification is published when a sensor is due for calibration
+    pub notify: bool,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            sensors: Vec::new(),
+            interval_days: 365,
+            notify: false,
+        }
+    }
+}
+
+/// Simulated readings instead of the sensors, for testing the sinks (see `simulation.rs`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Simulation {
+    /// Whether the sensors are replaced by the simulated metrics
+    pub enabled: bool,
+    pub metrics: Vec<SimulatedMetric>,
+}
+
+/// A simulated metric, submitted like the readings of its sensor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum SimulatedMetric {
+    /// A sine wave between `min` and `max`
+    Sine {
+        metric: Metric,
+        min: f32,
+        max: f32,
+        period_secs: u32,
+    },
+    /// A random walk between `min` and `max`, by at most `step` per measurement cycle
+    RandomWalk {
+        metric: Metric,
+        min: f32,
+        max: f32,
+        step: f32,
+    },
+    /// Recorded values, one per measurement cycle, replayed in a loop
+    Replay { metric: Metric, values: Vec<f32> },
+}
+
+impl SimulatedMetric {
+    pub fn metric(&self) -> Metric {
+        match *self {
+            SimulatedMetric::Sine { metric, .. }
+            | SimulatedMetric::RandomWalk { metric, .. }
+            | SimulatedMetric::Replay { metric, .. } => metric,
+        }
+    }
+}
+
+/// A PWM output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Output {
+    /// Name, used in the API path
+    pub name: String,
+    /// GPIO number, must not be used by an enabled feature
+    pub pin: u8,
+    /// PWM frequency in Hz (default: 1000)
+    pub frequency: Option<u32>,
+    /// Duty cycle in percent at the lowest level above 0 (default: 0)
+    pub min_duty: Option<f32>,
+    /// Duty cycle in percent at level 100 (default: 100)
+    pub max_duty: Option<f32>,
+}
+
+/// A rule: When the condition becomes true, the `then` actions are run, when it becomes false
+/// again, the `else` actions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Name, used as the value of the `rule` tag
+    pub name: String,
+    pub when: Condition,
+    /// Actions that are run when the condition becomes true
+    #[serde(default)]
+    pub then: Vec<RuleAction>,
+    /// Actions that are run when the condition becomes false
+    #[serde(default, rename = "else")]
+    pub otherwise: Vec<RuleAction>,
+}
+
+/// A rule condition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Condition {
+    /// True if all conditions are true
+    All { all: Vec<Condition> },
+    /// True if any condition is true
+    Any { any: Vec<Condition> },
+    /// True if a metric is above and/or below a threshold, for at least `for_secs` seconds
+    Metric {
+        metric: Metric,
+        above: Option<f32>,
+        below: Option<f32>,
+        #[serde(default)]
+        for_secs: u32,
+    },
+    /// True between two times of day (local time, `HH:MM`). If `from` is after `to`, the time window
+    /// spans midnight.
+    Time { from: String, to: String },
+}
+
+/// An action that is run by a rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum RuleAction {
+    /// Set the level of a PWM output in percent
+    Output { output: String, level: f32 },
+    /// Send an HTTP POST request to the URL, with a templated body (see `webhook.rs`)
+    Webhook {
+        webhook: String,
+        body: String,
+        /// Content type of the body (default: `application/json`)
+        content_type: Option<String>,
+        /// Additional headers, e.g. for authentication. Note: Unlike the secrets, the headers and
+        /// the URL are included in config exports.
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// Publish a message with a templated text through ntfy (see `sinks.ntfy`)
+    Ntfy {
+        ntfy: String,
+        /// Title (templated, default: the rule name)
+        title: Option<String>,
+        /// Priority (default: `sinks.ntfy.priority`)
+        priority: Option<u8>,
+    },
+}
@@ -0,0 +1,141 @@
+//! Detection of network outages.
+//!
+//! The device is online until `outage.failures` submissions in a row have failed. Then it's
+//! offline: The display shows "Offline", the `outage.led` output is turned on, and WiFi is
+//! reconnected if the connection (or the IP address) was lost, with a backoff from
+//! [`MIN_RECONNECT_INTERVAL`] to [`MAX_RECONNECT_INTERVAL`]. After `outage.portal_after` failed
+//! reconnections, the provisioning portal is opened, which restarts the device when it times out.
+//! The measurements continue meanwhile, they are kept in the delivery queue and the history. The
+//! first successful submission brings the device back online. Then the history since the first
+//! failure can be resubmitted, since the delivery queue only holds the most recent batches.
+
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Interval of the first reconnection attempt after going offline
+pub const MIN_RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum interval between two reconnection attempts, the interval doubles after every attempt
+pub const MAX_RECONNECT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+enum State {
+    Online {
+        /// Number of failed submissions in a row
+        failures: u32,
+        /// Unix time of the first failed submission, if the clock was synchronized
+        first_failure: Option<u64>,
+    },
+    Offline {
+        /// Unix time of the first failed submission, if the clock was synchronized
+        since: Option<u64>,
+        next_reconnect: Instant,
+        reconnect_interval: Duration,
+        /// Number of WiFi reconnections since the device went offline
+        reconnects: u32,
+    },
+}
+
+/// A change of the state
+pub enum Transition {
+    Offline,
+    /// Back online, the outage started at the Unix time `since` (if known)
+    Online {
+        since: Option<u64>,
+    },
+}
+
+pub struct Connectivity {
+    max_failures: u32,
+    state: State,
+}
+
+impl Connectivity {
+    pub fn new(config: &config::Outage) -> Self {
+        Self {
+            max_failures: config.failures,
+            state: State::Online {
+                failures: 0,
+                first_failure: None,
+            },
+        }
+    }
+
+    /// Return whether the device is offline.
+    pub fn is_offline(&self) -> bool {
+        matches!(self.state, State::Offline { .. })
+    }
+
+    /// Record the result of a submission at the Unix time `now` (if the clock is synchronized).
+    /// Return the transition, if the state changed.
+    pub fn record(&mut self, delivered: bool, now: Option<u64>) -> Option<Transition> {
+        match (&mut self.state, delivered) {
+            (State::Online { failures, .. }, true) => {
+                *failures = 0;
+                None
+            }
+            (
+                State::Online {
+                    failures,
+                    first_failure,
+                },
+                false,
+            ) => {
+                if *failures == 0 {
+                    *first_failure = now;
+                }
+                *failures += 1;
+                if *failures < self.max_failures {
+                    return None;
+                }
+                self.state = State::Offline {
+                    since: *first_failure,
+                    next_reconnect: Instant::now() + MIN_RECONNECT_INTERVAL,
+                    reconnect_interval: MIN_RECONNECT_INTERVAL,
+                    reconnects: 0,
+                };
+                Some(Transition::Offline)
+            }
+            (State::Offline { since, .. }, true) => {
+                let since = *since;
+                self.state = State::Online {
+                    failures: 0,
+                    first_failure: None,
+                };
+                Some(Transition::Online { since })
+            }
+            (State::Offline { .. }, false) => None,
+        }
+    }
+
+    /// Return whether a reconnection attempt is due. Every call that returns `true` schedules the
+    /// next attempt.
+    pub fn reconnect_due(&mut self) -> bool {
+        match self.state {
+            State::Offline {
+                ref mut next_reconnect,
+                ref mut reconnect_interval,
+                ..
+            } if Instant::now() >= *next_reconnect => {
+                *reconnect_interval = (*reconnect_interval * 2).min(MAX_RECONNECT_INTERVAL);
+                *next_reconnect = Instant::now() + *reconnect_interval;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record a WiFi reconnection while offline. Return the number of reconnections since the
+    /// device went offline, including this one.
+    pub fn record_reconnect(&mut self) -> u32 {
+        match self.state {
+            State::Offline {
+                ref mut reconnects, ..
+            } => {
+                *reconnects += 1;
+                *reconnects
+            }
+            State::Online { .. } => 0,
+        }
+    }
+}
@@ -0,0 +1,28 @@
+//! CPU frequency and automatic light sleep, through the power management of ESP-IDF.
+//!
+//! The CPU runs at `cpu.max_freq_mhz` while a task runs and is scaled down to `cpu.min_freq_mhz`
+//! while all tasks are idle (dynamic frequency scaling). 80 MHz is plenty for reading the sensors
+//! and submitting the readings, and it reduces both the power consumption and the self-heating
+//! that the temperature sensors pick up. The TLS handshakes take about twice as long as at
+//! 160 MHz though, which shows in `connect_ms` of the diagnostics line.
+//!
+//! With `cpu.light_sleep`, the chip enters light sleep while idle, and WiFi stays connected with
+//! modem sleep. Input on the serial console may be lost while the chip sleeps.
+
+use std::ffi::c_void;
+
+use esp_idf_sys::EspError;
+
+use crate::config;
+
+/// Apply the CPU config. Without it, the CPU runs at 160 MHz (see `sdkconfig.defaults`).
+pub fn configure(config: &config::Cpu) -> Result<(), EspError> {
+    let pm_config = esp_idf_sys::esp_pm_config_esp32c3_t {
+        max_freq_mhz: config.max_freq_mhz as i32,
+        min_freq_mhz: config.min_freq_mhz.unwrap_or(config.max_freq_mhz) as i32,
+        light_sleep_enable: config.light_sleep,
+    };
+    EspError::convert(unsafe {
+        esp_idf_sys::esp_pm_configure(&pm_config as *const _ as *const c_void)
+    })
+}
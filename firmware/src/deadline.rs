@@ -0,0 +1,122 @@
+//! Time budgets of the sensor reads, so that a misbehaving sensor (e.g. one that holds the clock
+//! line low) can't stall the measurement cycle past the measurement interval.
+//!
+//! Every read of a sensor in the main loop gets a budget of `sensors.read_timeout_ms`. Once it's
+//! used up, the remaining I²C transactions of the read fail right away with a timeout, instead of
+//! each waiting for the I²C hardware timeout, so the read fails like any other failed read. A
+//! single transaction can't be interrupted, but it's bounded by the hardware timeout. Reads of the
+//! UART and SPI sensors are not cut short, but they're measured as well.
+//!
+//! Reads that took longer than their budget are reported as `<sensor>_timeout_ms` in the
+//! `diagnostics` line, with the duration of the read.
+//!
+//! The deadline is kept per thread, so that it only applies to the reads of the main loop and not
+//! to those of the timer tasks, which share the I²C bus.
+
+use std::{
+    cell::{Cell, RefCell},
+    time::{Duration, Instant},
+};
+
+use embedded_hal_0_2::blocking::i2c::{Read as I2cRead, Write, WriteRead};
+use esp_idf_sys::{EspError, ESP_ERR_TIMEOUT};
+
+thread_local! {
+    /// Deadline of the current read of this thread
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+
+    /// Sensors whose reads took longer than their budget, with the duration of the read
+    static TIMEOUTS: RefCell<Vec<(&'static str, Duration)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A read of a sensor with a time budget, which ends when the read is dropped
+// Unused in builds without sensors
+#[allow(dead_code)]
+pub struct Read {
+    sensor: &'static str,
+    start: Instant,
+    budget: Duration,
+}
+
+/// Start a read of `sensor` (by its name in the config) with a time budget.
+#[allow(dead_code)]
+pub fn start(sensor: &'static str, budget: Duration) -> Read {
+    let start = Instant::now();
+    DEADLINE.with(|deadline| deadline.set(Some(start + budget)));
+    Read {
+        sensor,
+        start,
+        budget,
+    }
+}
+
+impl Drop for Read {
+    fn drop(&mut self) {
+        DEADLINE.with(|deadline| deadline.set(None));
+        let elapsed = self.start.elapsed();
+        if elapsed > self.budget {
+            eprintln!(
+                "{}: ERROR: Read took {}ms, more than its budget of {}ms",
+                self.sensor,
+                elapsed.as_millis(),
+                self.budget.as_millis()
+            );
+            TIMEOUTS.with(|timeouts| timeouts.borrow_mut().push((self.sensor, elapsed)));
+        }
+    }
+}
+
+/// Return the sensors whose reads took longer than their budget since the last call, with the
+/// duration of the read.
+pub fn take_timeouts() -> Vec<(&'static str, Duration)> {
+    TIMEOUTS.with(|timeouts| timeouts.take())
+}
+
+/// An I²C bus whose transactions fail once the deadline of the current read passed
+pub struct I2c<I>(pub I);
+
+impl<I> I2c<I> {
+    /// Fail if the deadline passed.
+    fn check(&self) -> Result<(), EspError> {
+        let expired = DEADLINE.with(|deadline| match deadline.get() {
+            Some(deadline) => Instant::now() > deadline,
+            None => false,
+        });
+        if expired {
+            return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+        }
+        Ok(())
+    }
+}
+
+impl<I: I2cRead<Error = EspError>> I2cRead for I2c<I> {
+    type Error = EspError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.read(address, buffer)
+    }
+}
+
+impl<I: Write<Error = EspError>> Write for I2c<I> {
+    type Error = EspError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.write(address, bytes)
+    }
+}
+
+impl<I: WriteRead<Error = EspError>> WriteRead for I2c<I> {
+    type Error = EspError;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.write_read(address, bytes, buffer)
+    }
+}
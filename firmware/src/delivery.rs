@@ -0,0 +1,389 @@
+//! At-least-once delivery of the submitted lines.
+//!
+//! Every submission (the lines of one measurement cycle) is a batch with a sequence number. A
+//! batch is only dropped when the sink confirmed it. Batches that could not be delivered are
+//! stored in NVS, so that they survive reboots, and are retried in order before the next batch.
+//!
+//! Retried batches may be delivered twice (e.g. if the response of the sink was lost). Since every
+//! line carries the timestamp of its measurement, a duplicate overwrites the same point in
+//! InfluxDB instead of adding a new one. If the sink only confirmed a part of a batch (e.g. the
+//! lines of one of several buckets, see `sinks.routes`), only the rest is retried (see
+//! [`Failure`]).
+//!
+//! Batches of measurements before the clock is synchronized (e.g. right after a boot, or while the
+//! NTP servers are unreachable) carry the uptime of their measurement instead, and wait in RAM.
+//! Once SNTP succeeds, they are timestamped from the uptime and delivered like the other batches,
+//! so that the buffered points land at the time of their measurement instead of clumping at the
+//! time of the submission. If the clock is still not synchronized after [`SYNC_WAIT`], they are
+//! submitted once without timestamps (the sink uses its own time), and they are lost on a reboot,
+//! since the uptime doesn't survive it.
+//!
+//! The batches are stored in an NVS partition of their own (`nvs_log`, see `partitions.csv`), so
+//! that they don't take up the space of the config. Without it (e.g. if the device was flashed with
+//! another partition table), the batches are only kept in RAM.
+//!
+//! The queue is bounded by [`MAX_BATCHES`]. If it is full, the oldest batch is dropped with an
+//! error message. Longer outages can be recovered from the history (see the backfill endpoint of
+//! the HTTP API).
+
+use std::{collections::VecDeque, time::Duration};
+
+use esp_idf_svc::nvs::{
+    EspCustomNvsPartition, EspDefaultNvsPartition, EspNvs, NvsCustom, NvsPartitionId,
+};
+use esp_idf_sys::EspError;
+
+use crate::clock;
+
+/// NVS namespace of the stored batches
+const NAMESPACE: &str = "delivery";
+
+/// Maximum number of pending batches. A batch is stored in the NVS slot `seq % MAX_BATCHES`.
+///
+/// Together with [`MAX_STORED_SIZE`], this is sized from the NVS budget in `partitions.csv`.
+const MAX_BATCHES: usize = 8;
+
+/// Maximum size of a stored batch in bytes, larger batches are only kept in RAM
+const MAX_STORED_SIZE: usize = 2048;
+
+/// How long a batch without timestamps waits for the clock synchronization, before it's submitted
+/// without them
+const SYNC_WAIT: Duration = Duration::from_secs(300);
+
+/// Error of the `send` function of the queue
+pub struct Failure {
+    pub error: anyhow::Error,
+    /// The lines of the payload that were not delivered, if the sink confirmed the others. `None`
+    /// if nothing was delivered.
+    pub pending: Option<String>,
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(error: anyhow::Error) -> Self {
+        Self {
+            error,
+            pending: None,
+        }
+    }
+}
+
+/// Lines that were not confirmed by the sink yet
+struct Batch {
+    seq: u32,
+    payload: String,
+    stored: bool,
+    /// Uptime in µs at the measurement, as long as the lines have no timestamps
+    uptime_us: Option<i64>,
+}
+
+impl Batch {
+    /// Whether the batch waits for the clock synchronization at `now_us` (uptime)
+    fn waits(&self, now_us: i64) -> bool {
+        matches!(self.uptime_us, Some(uptime_us) if now_us - uptime_us < SYNC_WAIT.as_micros() as i64)
+    }
+}
+
+pub struct Queue {
+    /// `None` without the NVS partition, the batches are only kept in RAM then
+    nvs: Option<EspNvs<NvsCustom>>,
+    /// Pending batches, oldest first
+    batches: VecDeque<Batch>,
+    next_seq: u32,
+}
+
+impl Queue {
+    /// Create a new instance, restoring the pending batches from the NVS `partition` of the queue.
+    ///
+    /// Older firmware stored the batches in the default NVS partition. They are moved to
+    /// `partition` if it has none yet, otherwise they are dropped.
+    pub fn new(
+        partition: Option<EspCustomNvsPartition>,
+        default_partition: EspDefaultNvsPartition,
+    ) -> Result<Self, EspError> {
+        let nvs = match partition {
+            Some(partition) => EspNvs::new(partition, NAMESPACE, true)?,
+            None => {
+                return Ok(Self {
+                    nvs: None,
+                    batches: VecDeque::new(),
+                    next_seq: 0,
+                })
+            }
+        };
+        let mut batches = read(&nvs);
+        if !batches.is_empty() {
+            println!("  Restored {} undelivered batches", batches.len());
+        }
+        // Read-only, so that the namespace isn't created if it doesn't exist
+        let legacy = EspNvs::new(default_partition.clone(), NAMESPACE, false)
+            .map(|legacy| read(&legacy))
+            .unwrap_or_default();
+        let has_legacy = !legacy.is_empty();
+        if has_legacy && batches.is_empty() {
+            println!(
+                "  Moving {} undelivered batches from the default NVS partition",
+                legacy.len()
+            );
+            batches = legacy;
+            for batch in batches.iter_mut() {
+                batch.stored = false;
+            }
+        } else if has_legacy {
+            eprintln!(
+                "Delivery: Dropping {} batches of an older firmware",
+                legacy.len()
+            );
+        }
+        let next_seq = batches.last().map(|batch| batch.seq + 1).unwrap_or(0);
+        let mut queue = Self {
+            nvs: Some(nvs),
+            batches: batches.into(),
+            next_seq,
+        };
+        if has_legacy {
+            for i in 0..queue.batches.len() {
+                if !queue.batches[i].stored {
+                    queue.store(i);
+                }
+            }
+            remove_legacy(default_partition);
+        }
+        Ok(queue)
+    }
+
+    /// Submit a batch of lines with `send`, after the pending batches. If `timestamp` (Unix time)
+    /// is set, it's appended to every line, otherwise the batch waits for the clock
+    /// synchronization. The batch is kept until it was delivered.
+    ///
+    /// `send` must only return `Ok` if the sink confirmed the payload. Returns whether all batches
+    /// were delivered, except for the ones that wait for the clock synchronization.
+    pub fn submit(
+        &mut self,
+        lines: &[String],
+        timestamp: Option<u64>,
+        send: impl FnMut(&str) -> Result<(), Failure>,
+    ) -> bool {
+        match timestamp {
+            Some(timestamp) => self.push(timestamped(&lines.join("\n"), timestamp), None),
+            None => {
+                let uptime_us = unsafe { esp_idf_sys::esp_timer_get_time() };
+                self.push(lines.join("\n"), Some(uptime_us));
+            }
+        }
+        self.deliver(send)
+    }
+
+    /// Submit a batch of lines that already have timestamps (e.g. of several measurement cycles,
+    /// see [`timestamped`]) with `send`, like [`Queue::submit`].
+    pub fn submit_timestamped(
+        &mut self,
+        payload: String,
+        send: impl FnMut(&str) -> Result<(), Failure>,
+    ) -> bool {
+        self.push(payload, None);
+        self.deliver(send)
+    }
+
+    /// Try to deliver the pending batches once more, e.g. before a restart. Batches that wait for
+    /// the clock synchronization are submitted without timestamps. Returns whether all batches
+    /// were delivered.
+    pub fn flush(&mut self, send: impl FnMut(&str) -> Result<(), Failure>) -> bool {
+        if self.batches.is_empty() {
+            return true;
+        }
+        println!("-> Submitting {} pending batches", self.batches.len());
+        // The uptime doesn't survive the restart
+        if let Some(now) = clock::unix_time() {
+            self.correct_timestamps(now, unsafe { esp_idf_sys::esp_timer_get_time() });
+        }
+        for batch in self.batches.iter_mut() {
+            batch.uptime_us = None;
+        }
+        self.deliver(send)
+    }
+
+    /// Deliver the pending batches in order and store the ones that are still pending.
+    fn deliver(&mut self, mut send: impl FnMut(&str) -> Result<(), Failure>) -> bool {
+        let now_us = unsafe { esp_idf_sys::esp_timer_get_time() };
+        if let Some(now) = clock::unix_time() {
+            self.correct_timestamps(now, now_us);
+        }
+
+        // Stop at the first failure, or at the first batch that waits for the clock
+        while let Some(batch) = self.batches.front() {
+            if batch.waits(now_us) {
+                println!(
+                    "Delivery: {} batches wait for the clock synchronization",
+                    self.batches.len()
+                );
+                return true;
+            }
+            if batch.uptime_us.is_some() {
+                println!("-> Submitting batch {} without timestamps", batch.seq);
+            } else {
+                println!("-> Submitting batch {}", batch.seq);
+            }
+            if let Err(failure) = send(&batch.payload) {
+                eprintln!(
+                    "Error: Could not submit batch {} ({} pending): {}",
+                    batch.seq,
+                    self.batches.len(),
+                    failure.error
+                );
+                // Only the rest is retried, it replaces the stored batch below
+                if let Some(pending) = failure.pending {
+                    let batch = self.batches.front_mut().expect("Queue is empty");
+                    batch.payload = pending;
+                    batch.stored = false;
+                }
+                break;
+            }
+            let batch = self.batches.pop_front().expect("Queue is empty");
+            if batch.stored {
+                self.remove(&batch);
+            }
+        }
+
+        // Store the batches that are still pending, so that they survive a reboot
+        for i in 0..self.batches.len() {
+            if !self.batches[i].stored && self.batches[i].uptime_us.is_none() {
+                self.store(i);
+            }
+        }
+        self.batches.is_empty()
+    }
+
+    /// Timestamp the batches without timestamps from the uptime of their measurement, with the
+    /// current Unix time `now` at the uptime `now_us`.
+    fn correct_timestamps(&mut self, now: u64, now_us: i64) {
+        for batch in self.batches.iter_mut() {
+            if let Some(uptime_us) = batch.uptime_us.take() {
+                let age_secs = ((now_us - uptime_us) / 1_000_000) as u64;
+                println!(
+                    "Delivery: Timestamping batch {} from {} s ago",
+                    batch.seq, age_secs
+                );
+                batch.payload = timestamped(&batch.payload, now.saturating_sub(age_secs));
+            }
+        }
+    }
+
+    /// Add a batch. If the queue is full, the oldest batch is dropped.
+    fn push(&mut self, payload: String, uptime_us: Option<i64>) {
+        if self.batches.len() == MAX_BATCHES {
+            let dropped = self.batches.pop_front().expect("Queue is empty");
+            eprintln!(
+                "Error: Delivery queue is full, dropping batch {}",
+                dropped.seq
+            );
+            if dropped.stored {
+                self.remove(&dropped);
+            }
+        }
+        self.batches.push_back(Batch {
+            seq: self.next_seq,
+            payload,
+            stored: false,
+            uptime_us,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Store the batch at `index` in NVS.
+    fn store(&mut self, index: usize) {
+        let nvs = match self.nvs {
+            Some(ref mut nvs) => nvs,
+            None => return,
+        };
+        let batch = &mut self.batches[index];
+        if batch.payload.len() > MAX_STORED_SIZE {
+            eprintln!(
+                "Delivery: Batch {} is too large to be stored, it's lost on reboot",
+                batch.seq
+            );
+            return;
+        }
+        let mut blob = Vec::with_capacity(4 + batch.payload.len());
+        blob.extend_from_slice(&batch.seq.to_le_bytes());
+        blob.extend_from_slice(batch.payload.as_bytes());
+        match nvs.set_raw(&key(slot(batch.seq)), &blob) {
+            Ok(_) => batch.stored = true,
+            Err(e) => eprintln!("Delivery: Could not store batch {}: {}", batch.seq, e),
+        }
+    }
+
+    /// Remove a delivered batch from NVS.
+    fn remove(&mut self, batch: &Batch) {
+        if let Some(ref mut nvs) = self.nvs {
+            if let Err(e) = nvs.remove(&key(slot(batch.seq))) {
+                eprintln!("Delivery: Could not remove batch {}: {}", batch.seq, e);
+            }
+        }
+    }
+}
+
+/// Read the stored batches, oldest first.
+fn read<T: NvsPartitionId>(nvs: &EspNvs<T>) -> Vec<Batch> {
+    let mut batches = Vec::new();
+    let mut buf = vec![0; 4 + MAX_STORED_SIZE];
+    for slot in 0..MAX_BATCHES {
+        let stored = match nvs.get_raw(&key(slot), &mut buf) {
+            Ok(Some(stored)) if stored.len() > 4 => stored,
+            Ok(_) => continue,
+            Err(e) => {
+                eprintln!("Delivery: Could not read batch slot {}: {}", slot, e);
+                continue;
+            }
+        };
+        let seq = u32::from_le_bytes([stored[0], stored[1], stored[2], stored[3]]);
+        match std::str::from_utf8(&stored[4..]) {
+            Ok(payload) => batches.push(Batch {
+                seq,
+                payload: payload.to_string(),
+                stored: true,
+                uptime_us: None,
+            }),
+            Err(_) => eprintln!("Delivery: Ignoring invalid batch in slot {}", slot),
+        }
+    }
+    batches.sort_by_key(|batch| batch.seq);
+    batches
+}
+
+/// Remove the batches of an older firmware from the default NVS partition.
+fn remove_legacy(partition: EspDefaultNvsPartition) {
+    let mut nvs = match EspNvs::new(partition, NAMESPACE, true) {
+        Ok(nvs) => nvs,
+        Err(e) => {
+            eprintln!(
+                "Delivery: Could not remove the batches of an older firmware: {}",
+                e
+            );
+            return;
+        }
+    };
+    for slot in 0..MAX_BATCHES {
+        if let Err(e) = nvs.remove(&key(slot)) {
+            eprintln!("Delivery: Could not remove batch slot {}: {}", slot, e);
+        }
+    }
+}
+
+/// Return the lines of a payload with the Unix time `timestamp` appended to every line.
+pub fn timestamped(payload: &str, timestamp: u64) -> String {
+    let lines: Vec<String> = payload
+        .lines()
+        .map(|line| format!("{} {}", line, timestamp))
+        .collect();
+    lines.join("\n")
+}
+
+/// Return the NVS slot of a batch.
+fn slot(seq: u32) -> usize {
+    seq as usize % MAX_BATCHES
+}
+
+/// Return the NVS key of a slot.
+fn key(slot: usize) -> String {
+    format!("batch{}", slot)
+}
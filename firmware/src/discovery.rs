@@ -0,0 +1,107 @@
+//! Home Assistant MQTT discovery, so that the device and its sensors show up in Home Assistant
+//! without any configuration there.
+//!
+//! For every metric that is measured, a retained config message is published to
+//! `<discovery_prefix>/sensor/<node_id>/<metric>/config`, the first time the metric is published
+//! after connecting. The node ID is derived from the device name (e.g. `livingroom` or
+//! `sensilo_2` for `Sensilo 2`), so the entities are named e.g. `sensor.livingroom_temperature`.
+//! The entities use the availability topic of the device, so they are shown as unavailable when
+//! the broker loses the connection.
+//!
+//! Only the primary readings of the metrics are announced, with the same names, units and device
+//! classes as the ESPHome API.
+
+use std::fmt::Write;
+
+use crate::{config::Metric, esphome, VERSION};
+
+pub struct Discovery {
+    prefix: String,
+    node_id: String,
+    /// JSON object of the device, the same in every config message
+    device: String,
+    availability_topic: String,
+    /// Metrics whose config was published since connecting
+    announced: Vec<Metric>,
+}
+
+impl Discovery {
+    pub fn new(prefix: &str, name: &str, availability_topic: &str) -> Self {
+        let node_id = node_id(name);
+        let device = format!(
+            "{{\"identifiers\":[\"sensilo_{}\"],\"name\":\"{}\",\"manufacturer\":\"Sensilo\",\
+            \"model\":\"Sensilo v2\",\"sw_version\":\"{}\"}}",
+            node_id, name, VERSION
+        );
+        Self {
+            prefix: prefix.into(),
+            node_id,
+            device,
+            availability_topic: availability_topic.into(),
+            announced: Vec::new(),
+        }
+    }
+
+    /// Forget the announced metrics, so that their configs are published again (e.g. after a
+    /// reconnect, in case the broker lost the retained messages).
+    pub fn reset(&mut self) {
+        self.announced.clear();
+    }
+
+    /// Return the topic and payload of the config of `metric` if it wasn't announced yet, and
+    /// mark it as announced. `state_topic` is the topic of its readings.
+    pub fn announce(&mut self, metric: Metric, state_topic: &str) -> Option<(String, String)> {
+        if self.announced.contains(&metric) {
+            return None;
+        }
+        self.announced.push(metric);
+        Some((
+            format!(
+                "{}/sensor/{}/{}/config",
+                self.prefix,
+                self.node_id,
+                metric.name()
+            ),
+            self.config(metric, state_topic),
+        ))
+    }
+
+    /// Return the config message of a metric, without the device information.
+    fn config(&self, metric: Metric, state_topic: &str) -> String {
+        let (label, unit, device_class, decimals) = esphome::entity(metric);
+        let mut json = format!(
+            "{{\"name\":\"{}\",\"unique_id\":\"sensilo_{}_{}\",\"object_id\":\"{}_{}\",\
+            \"state_topic\":\"{}\",\"availability_topic\":\"{}\",\"state_class\":\"measurement\",\
+            \"suggested_display_precision\":{}",
+            label,
+            self.node_id,
+            metric.name(),
+            self.node_id,
+            metric.name(),
+            state_topic,
+            self.availability_topic,
+            decimals
+        );
+        // Writing to a string cannot fail
+        if !unit.is_empty() {
+            let _ = write!(json, ",\"unit_of_measurement\":\"{}\"", unit);
+        }
+        if !device_class.is_empty() {
+            let _ = write!(json, ",\"device_class\":\"{}\"", device_class);
+        }
+        let _ = write!(json, ",\"device\":{}}}", self.device);
+        json
+    }
+}
+
+/// Return the node ID of a device name: Lowercase, with `_` instead of anything but ASCII letters
+/// and digits.
+fn node_id(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect()
+}
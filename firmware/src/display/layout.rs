@@ -0,0 +1,153 @@
+//! Data-driven page layout.
+//!
+//! The pages, the metrics on every page, their labels and units are defined in the config (see
+//! [`config::Page`]). Labels, units and decimals that are not set default to sensible values
+//! for the metric. Without configured pages, a default page with the most common metrics is
+//! shown, leaving out the metrics that are not measured by the sensors of the device.
+
+use crate::{
+    config::{self, Metric, Unit},
+    history::History,
+};
+
+use super::{Page, Row};
+
+/// Metrics on the default page
+const DEFAULT_METRICS: [Metric; 6] = [
+    Metric::Temperature,
+    Metric::Humidity,
+    Metric::Co2,
+    Metric::Co2eq,
+    Metric::Pressure,
+    Metric::Illuminance,
+];
+
+/// Shown instead of the value if a metric is not available
+const UNAVAILABLE: &str = "--";
+
+/// Build the configured pages, reading the metrics with `value`. If there are no configured
+/// pages, the default page is returned. If sparklines are enabled, the trends are taken from the
+/// `history`.
+pub fn pages(
+    config: &config::Display,
+    value: impl Fn(Metric) -> Option<f32>,
+    history: &History,
+) -> Vec<Page> {
+    let build_row = |row: &config::Row, value: Option<f32>| {
+        let (label, value) = format_row(row, value);
+        let trend = if config.sparklines {
+            history.values(row.metric).collect()
+        } else {
+            Vec::new()
+        };
+        Row {
+            label,
+            value,
+            trend,
+        }
+    };
+    if config.pages.is_empty() {
+        let rows = DEFAULT_METRICS
+            .iter()
+            .filter_map(|metric| {
+                let row = config::Row {
+                    metric: *metric,
+                    label: None,
+                    unit: None,
+                    decimals: None,
+                };
+                value(*metric).map(|value| build_row(&row, Some(value)))
+            })
+            .collect();
+        return vec![Page { title: None, rows }];
+    }
+    config
+        .pages
+        .iter()
+        .map(|page| Page {
+            title: page.title.clone(),
+            rows: page
+                .rows
+                .iter()
+                .map(|row| build_row(row, value(row.metric)))
+                .collect(),
+        })
+        .collect()
+}
+
+/// Format a row as label and value (with unit).
+fn format_row(row: &config::Row, value: Option<f32>) -> (String, String) {
+    let (label, base_unit, decimals) = defaults(row.metric);
+    let label = row.label.clone().unwrap_or_else(|| label.into());
+    let value = match value {
+        Some(value) => {
+            let decimals = row.decimals.map(usize::from).unwrap_or(decimals);
+            let (value, symbol) = match (base_unit, row.unit.or(base_unit)) {
+                (Some(from), Some(to)) => (convert(value, from, to), unit_symbol(to)),
+                _ => (value, metric_symbol(row.metric)),
+            };
+            match symbol {
+                "" => format!("{:.*}", decimals, value),
+                symbol => format!("{:.*} {}", decimals, value, symbol),
+            }
+        }
+        None => UNAVAILABLE.into(),
+    };
+    (label, value)
+}
+
+/// Return the default label, unit (if the metric can be converted) and number of decimals of a
+/// metric.
+fn defaults(metric: Metric) -> (&'static str, Option<Unit>, usize) {
+    match metric {
+        Metric::Temperature => ("Temp", Some(Unit::Celsius), 1),
+        Metric::Humidity => ("Humi", None, 0),
+        Metric::Illuminance => ("Light", None, 0),
+        Metric::Co2 => ("CO2", None, 0),
+        Metric::Co2eq => ("CO2eq", None, 0),
+        Metric::Tvoc => ("TVOC", None, 0),
+        Metric::Pressure => ("Press", Some(Unit::Hpa), 0),
+        Metric::SeaLevelPressure => ("Press", Some(Unit::Hpa), 0),
+        Metric::Aqi => ("AQI", None, 0),
+        Metric::Hcho => ("HCHO", None, 0),
+        Metric::Occupancy => ("Occup", None, 0),
+        Metric::DoseRate => ("Dose", None, 2),
+        Metric::Power => ("Power", None, 0),
+        Metric::Thermocouple => ("TC", Some(Unit::Celsius), 0),
+        Metric::Rtd => ("RTD", Some(Unit::Celsius), 1),
+    }
+}
+
+/// Return the symbol of a unit.
+fn unit_symbol(unit: Unit) -> &'static str {
+    match unit {
+        Unit::Celsius => "°C",
+        Unit::Fahrenheit => "°F",
+        Unit::Hpa => "hPa",
+        Unit::Inhg => "inHg",
+        Unit::Mmhg => "mmHg",
+    }
+}
+
+/// Return the unit symbol of a metric that cannot be converted.
+fn metric_symbol(metric: Metric) -> &'static str {
+    match metric {
+        Metric::Humidity | Metric::Occupancy => "%",
+        Metric::Illuminance => "lx",
+        Metric::Co2 | Metric::Co2eq => "ppm",
+        Metric::Tvoc | Metric::Hcho => "ppb",
+        Metric::DoseRate => "uSv/h",
+        Metric::Power => "W",
+        _ => "",
+    }
+}
+
+/// Convert a value between units of the same quantity.
+fn convert(value: f32, from: Unit, to: Unit) -> f32 {
+    match (from, to) {
+        (Unit::Celsius, Unit::Fahrenheit) => value * 1.8 + 32.0,
+        (Unit::Hpa, Unit::Inhg) => value * 0.029_53,
+        (Unit::Hpa, Unit::Mmhg) => value * 0.750_06,
+        _ => value,
+    }
+}
@@ -0,0 +1,318 @@
+//! E-paper status display for battery units.
+//!
+//! Shows the latest readings, the battery level and the time of the last update on an SSD1680
+//! panel in landscape orientation. The display is refreshed once per measurement cycle. Every
+//! refresh redraws the whole screen from the current [`Summary`], so no display state needs to
+//! survive a deep sleep.
+//!
+//! The readings are arranged in pages (see [`layout`]). If there's more than one page, the next
+//! page is shown on every refresh. The pages can also be turned with the buttons (see
+//! [`crate::input`]). Next to every value, a sparkline shows its recent trend.
+//!
+//! The screen is drawn with [`embedded_graphics`] into a [`Frame`] buffer, which is then sent to
+//! the panel.
+
+use std::convert::Infallible;
+
+use embedded_graphics::{
+    mono_font::{iso_8859_1::FONT_10X20, iso_8859_1::FONT_6X10, MonoTextStyle},
+    prelude::*,
+    primitives::{Line, Polyline, PrimitiveStyle},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use embedded_hal_0_2::{
+    blocking::{delay::DelayMs, spi::Write},
+    digital::v2::{InputPin, OutputPin},
+};
+
+use crate::{
+    clock,
+    drivers::ssd1680::{self, Ssd1680},
+};
+
+pub mod layout;
+
+/// Screen width in landscape orientation
+const SCREEN_WIDTH: i32 = ssd1680::HEIGHT as i32;
+
+/// Screen height in landscape orientation
+const SCREEN_HEIGHT: i32 = ssd1680::WIDTH as i32;
+
+/// Margin around the screen content in pixels
+const MARGIN: i32 = 2;
+
+/// Y coordinate of the line below the header
+const HEADER_LINE: i32 = 12;
+
+/// Y coordinate of the first reading
+const ROWS_TOP: i32 = HEADER_LINE + 3;
+
+/// Vertical distance between two readings in pixels
+const ROW_HEIGHT: i32 = 21;
+
+/// X coordinate of the sparklines, right of the labels
+const SPARKLINE_LEFT: i32 = 48;
+
+/// Horizontal space between a sparkline and its value
+const SPARKLINE_GAP: i32 = 8;
+
+/// Battery voltage at 0 % and 100 % (single LiPo cell)
+const BATTERY_EMPTY_V: f32 = 3.3;
+const BATTERY_FULL_V: f32 = 4.2;
+
+/// The content of the screen
+pub struct Summary {
+    /// Name of the device, shown in the header of pages without title
+    pub name: String,
+    /// Serial number of the device, shown next to the name
+    pub serial: Option<String>,
+    /// Pages with readings
+    pub pages: Vec<Page>,
+    /// Battery voltage in V, if known
+    pub battery_voltage: Option<f32>,
+    /// Whether the device is offline (see [`crate::connectivity`])
+    pub offline: bool,
+    /// Whether a sensor is due for calibration (see [`crate::calibration`])
+    pub calibration_due: bool,
+}
+
+/// A page with readings
+pub struct Page {
+    /// Title, shown in the header instead of the device name
+    pub title: Option<String>,
+    /// Readings in display order. Readings that don't fit on the screen are left out.
+    pub rows: Vec<Row>,
+}
+
+/// A reading on a page
+pub struct Row {
+    pub label: String,
+    /// Formatted value with unit
+    pub value: String,
+    /// Recent values, oldest first. If there are less than two values, no sparkline is shown.
+    pub trend: Vec<f32>,
+}
+
+/// Frame buffer in landscape orientation
+struct Frame {
+    buffer: Box<[u8; ssd1680::BUFFER_SIZE]>,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Self {
+            buffer: Box::new([0xff; ssd1680::BUFFER_SIZE]),
+        }
+    }
+}
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if !(0..SCREEN_WIDTH).contains(&point.x) || !(0..SCREEN_HEIGHT).contains(&point.y) {
+                continue;
+            }
+            // The panel is rotated by 90°: The landscape X axis runs along the gate lines. A set
+            // bit is a white pixel.
+            let column = ssd1680::WIDTH - 1 - point.y as usize;
+            let index = point.x as usize * ssd1680::ROW_SIZE + column / 8;
+            let mask = 0x80 >> (column % 8);
+            match color {
+                BinaryColor::On => self.buffer[index] &= !mask,
+                BinaryColor::Off => self.buffer[index] |= mask,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Display<SPI, DC, BUSY> {
+    driver: Ssd1680<SPI, DC, BUSY>,
+    frame: Frame,
+    /// Index of the page that is shown, `None` before the first refresh
+    page: Option<usize>,
+}
+
+impl<SPI, SE, DC, BUSY, PE> Display<SPI, DC, BUSY>
+where
+    SPI: Write<u8, Error = SE>,
+    DC: OutputPin<Error = PE>,
+    BUSY: InputPin<Error = PE>,
+{
+    pub fn new(driver: Ssd1680<SPI, DC, BUSY>) -> Self {
+        Self {
+            driver,
+            frame: Frame::new(),
+            page: None,
+        }
+    }
+
+    /// Redraw the screen with the next page. This blocks for a few seconds, until the panel is
+    /// refreshed.
+    pub fn show(
+        &mut self,
+        summary: &Summary,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), ssd1680::Error<SE, PE>> {
+        let count = summary.pages.len().max(1);
+        self.page = Some(self.page.map_or(0, |page| (page + 1) % count));
+        self.refresh(summary, delay)
+    }
+
+    /// Redraw the screen with the previous page, see [`Self::show`].
+    pub fn show_previous(
+        &mut self,
+        summary: &Summary,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), ssd1680::Error<SE, PE>> {
+        let count = summary.pages.len().max(1);
+        self.page = Some(
+            self.page
+                .map_or(0, |page| (page % count + count - 1) % count),
+        );
+        self.refresh(summary, delay)
+    }
+
+    fn refresh(
+        &mut self,
+        summary: &Summary,
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), ssd1680::Error<SE, PE>> {
+        let page = self.page.and_then(|page| summary.pages.get(page));
+        self.frame.buffer.fill(0xff);
+        draw(&mut self.frame, summary, page).unwrap_or_else(|e| match e {});
+        self.driver.update(&self.frame.buffer, delay)
+    }
+}
+
+/// Draw a page, with the header.
+fn draw<D>(target: &mut D, summary: &Summary, page: Option<&Page>) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let small = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let large = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+    let top_left = TextStyleBuilder::new().baseline(Baseline::Top).build();
+    let top_right = TextStyleBuilder::new()
+        .baseline(Baseline::Top)
+        .alignment(Alignment::Right)
+        .build();
+    let middle_left = TextStyleBuilder::new().baseline(Baseline::Middle).build();
+    let middle_right = TextStyleBuilder::new()
+        .baseline(Baseline::Middle)
+        .alignment(Alignment::Right)
+        .build();
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 1);
+
+    // Header: Title or name on the left, connectivity, battery level and time on the right
+    let title = match (page.and_then(|page| page.title.as_ref()), &summary.serial) {
+        (Some(title), _) => title.clone(),
+        (None, Some(serial)) => format!("{} #{}", summary.name, serial),
+        (None, None) => summary.name.clone(),
+    };
+    Text::with_text_style(&title, Point::new(MARGIN, MARGIN), small, top_left).draw(target)?;
+    let mut status = Vec::new();
+    if summary.offline {
+        status.push("Offline".to_string());
+    }
+    if summary.calibration_due {
+        status.push("Cal due".to_string());
+    }
+    if let Some(voltage) = summary.battery_voltage {
+        status.push(format!("Bat {}%", battery_level(voltage)));
+    }
+    status.push(current_time().unwrap_or_else(|| "--:--".into()));
+    Text::with_text_style(
+        &status.join("  "),
+        Point::new(SCREEN_WIDTH - MARGIN, MARGIN),
+        small,
+        top_right,
+    )
+    .draw(target)?;
+    Line::new(
+        Point::new(0, HEADER_LINE),
+        Point::new(SCREEN_WIDTH - 1, HEADER_LINE),
+    )
+    .into_styled(stroke)
+    .draw(target)?;
+
+    // Readings: Label on the left, value on the right, sparkline in between
+    let rows = page.map(|page| page.rows.as_slice()).unwrap_or_default();
+    let max_rows = ((SCREEN_HEIGHT - ROWS_TOP) / ROW_HEIGHT) as usize;
+    for (i, row) in rows.iter().take(max_rows).enumerate() {
+        let top = ROWS_TOP + i as i32 * ROW_HEIGHT;
+        let middle = top + ROW_HEIGHT / 2;
+        Text::with_text_style(&row.label, Point::new(MARGIN, middle), small, middle_left)
+            .draw(target)?;
+        Text::with_text_style(
+            &row.value,
+            Point::new(SCREEN_WIDTH - MARGIN, middle),
+            large,
+            middle_right,
+        )
+        .draw(target)?;
+
+        let value_width = row.value.chars().count() as i32 * FONT_10X20.character_size.width as i32;
+        let right = SCREEN_WIDTH - MARGIN - value_width - SPARKLINE_GAP;
+        let points = sparkline(
+            &row.trend,
+            SPARKLINE_LEFT,
+            right,
+            top + 2,
+            top + ROW_HEIGHT - 3,
+        );
+        if points.len() >= 2 {
+            Polyline::new(&points).into_styled(stroke).draw(target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the points of a sparkline with the most recent values that fit into the box between
+/// `left`/`right` and `top`/`bottom`, one pixel per value. The range of the values is scaled to
+/// the height of the box.
+fn sparkline(values: &[f32], left: i32, right: i32, top: i32, bottom: i32) -> Vec<Point> {
+    let width = (right - left + 1).max(0) as usize;
+    let values = &values[values.len().saturating_sub(width)..];
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            // A constant value is drawn as a line in the middle
+            let relative = if range > f32::EPSILON {
+                (value - min) / range
+            } else {
+                0.5
+            };
+            let y = bottom - (relative * (bottom - top) as f32).round() as i32;
+            Point::new(left + i as i32, y)
+        })
+        .collect()
+}
+
+/// Estimate the battery level in percent from the cell voltage.
+fn battery_level(voltage: f32) -> u8 {
+    let level = (voltage - BATTERY_EMPTY_V) / (BATTERY_FULL_V - BATTERY_EMPTY_V) * 100.0;
+    level.clamp(0.0, 100.0).round() as u8
+}
+
+/// Return the current local time as text, or `None` if the clock is not synchronized yet.
+fn current_time() -> Option<String> {
+    clock::format_local_time("%H:%M %Z")
+}
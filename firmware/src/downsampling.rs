@@ -0,0 +1,84 @@
+//! Downsampled stream of the metrics, for long-term storage.
+//!
+//! The values of every metric are aggregated over a fixed window (e.g. 5 minutes). At the end of
+//! the window, one line per metric with the mean, minimum, maximum and number of values is
+//! submitted in addition to the raw measurements. With a route (see `sinks.routes`), the
+//! aggregates can be written to a bucket with a long retention period, without server-side
+//! downsampling tasks.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Downsampling, Metric},
+    lineproto::{Line, TagSet},
+};
+
+/// Aggregate of the values of a metric in a window
+struct Aggregate {
+    sum: f64,
+    min: f32,
+    max: f32,
+    count: u32,
+}
+
+pub struct Downsampler {
+    measurement: String,
+    interval: Duration,
+    /// Start of the current window
+    window_start: Instant,
+    aggregates: BTreeMap<Metric, Aggregate>,
+}
+
+impl Downsampler {
+    pub fn new(config: &Downsampling) -> Self {
+        Self {
+            measurement: config.measurement.clone(),
+            interval: Duration::from_secs(config.interval_secs.into()),
+            window_start: Instant::now(),
+            aggregates: BTreeMap::new(),
+        }
+    }
+
+    /// Add a value to the current window.
+    pub fn record(&mut self, metric: Metric, value: f32) {
+        let aggregate = self.aggregates.entry(metric).or_insert(Aggregate {
+            sum: 0.0,
+            min: value,
+            max: value,
+            count: 0,
+        });
+        aggregate.sum += f64::from(value);
+        aggregate.min = aggregate.min.min(value);
+        aggregate.max = aggregate.max.max(value);
+        aggregate.count += 1;
+    }
+
+    /// If the current window has ended, return the aggregates as
+    /// `<measurement>,metric=<metric>,<tags> mean=..,min=..,max=..,count=..u` lines and start a
+    /// new window.
+    pub fn take_lines(&mut self, tags: &TagSet) -> Option<Vec<String>> {
+        if self.window_start.elapsed() < self.interval {
+            return None;
+        }
+        self.window_start = Instant::now();
+        let aggregates = std::mem::take(&mut self.aggregates);
+        Some(
+            aggregates
+                .into_iter()
+                .map(|(metric, aggregate)| {
+                    Line::build(&self.measurement)
+                        .tag("metric", metric.name())
+                        .tags(tags)
+                        .field("mean", (aggregate.sum / f64::from(aggregate.count)) as f32)
+                        .field("min", aggregate.min)
+                        .field("max", aggregate.max)
+                        .field("count", aggregate.count)
+                        .into_string()
+                })
+                .collect(),
+        )
+    }
+}
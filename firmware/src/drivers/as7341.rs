@@ -0,0 +1,238 @@
+//! Driver for the ams AS7341 11-channel spectral sensor.
+//!
+//! The sensor only has six ADCs, so the eight visible channels are measured in two passes with
+//! different SMUX (sensor multiplexer) configurations.
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayMs,
+    i2c::{Write, WriteRead},
+};
+
+/// I²C address of the AS7341
+pub const ADDRESS: u8 = 0x39;
+
+/// Expected value of the ID register (upper 6 bits)
+const ID: u8 = 0b0010_0100;
+
+mod reg {
+    pub const ENABLE: u8 = 0x80;
+    pub const ATIME: u8 = 0x81;
+    pub const ID: u8 = 0x92;
+    pub const CH0_DATA_L: u8 = 0x95;
+    pub const STATUS2: u8 = 0xa3;
+    pub const CFG1: u8 = 0xaa;
+    pub const CFG6: u8 = 0xaf;
+    pub const ASTEP_L: u8 = 0xca;
+}
+
+mod enable {
+    pub const PON: u8 = 1 << 0;
+    pub const SP_EN: u8 = 1 << 1;
+    pub const SMUXEN: u8 = 1 << 4;
+}
+
+/// AVALID flag in the STATUS2 register
+const AVALID: u8 = 1 << 6;
+
+/// SMUX configuration connecting F1-F4, Clear and NIR to the six ADCs
+const SMUX_F1_F4: [u8; 20] = [
+    0x30, 0x01, 0x00, 0x00, 0x00, 0x42, 0x00, 0x00, 0x50, 0x00, //
+    0x00, 0x00, 0x20, 0x04, 0x00, 0x30, 0x01, 0x50, 0x00, 0x06,
+];
+
+/// SMUX configuration connecting F5-F8, Clear and NIR to the six ADCs
+const SMUX_F5_F8: [u8; 20] = [
+    0x00, 0x00, 0x00, 0x40, 0x02, 0x00, 0x10, 0x03, 0x50, 0x10, //
+    0x03, 0x00, 0x00, 0x00, 0x24, 0x00, 0x00, 0x50, 0x00, 0x06,
+];
+
+/// Integration time: (ATIME + 1) × (ASTEP + 1) × 2.78 µs ≈ 50 ms
+const ATIME: u8 = 29;
+const ASTEP: u16 = 599;
+
+/// Gain setting 16x (register value 5, starting at 0.5x for 0)
+const GAIN: u8 = 5;
+const GAIN_FACTOR: f32 = 16.0;
+
+/// Peak wavelengths of the channels F1-F8 in nm
+pub const WAVELENGTHS: [u16; 8] = [415, 445, 480, 515, 555, 590, 630, 680];
+
+/// CIE 1931 2° colour matching functions (x̄, ȳ, z̄), sampled at the channel peak wavelengths
+const CIE_XYZ: [(f32, f32, f32); 8] = [
+    (0.0776, 0.0022, 0.3713),
+    (0.3481, 0.0298, 1.7826),
+    (0.0956, 0.1390, 0.8130),
+    (0.0291, 0.6082, 0.1117),
+    (0.5121, 1.0000, 0.0058),
+    (1.0263, 0.7570, 0.0011),
+    (0.6424, 0.2650, 0.0001),
+    (0.0468, 0.0170, 0.0000),
+];
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// The ID does not match an AS7341
+    InvalidId(u8),
+    /// The SMUX configuration or the measurement did not complete in time
+    Timeout,
+}
+
+/// Raw channel counts of a full measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Channels F1 (415 nm) to F8 (680 nm)
+    pub channels: [u16; 8],
+    /// Clear channel
+    pub clear: u16,
+    /// Near infrared channel
+    pub nir: u16,
+}
+
+impl Measurement {
+    /// Return the channel counts normalized by gain and integration time.
+    fn basic_counts(&self) -> [f32; 8] {
+        let integration_time_ms = (ATIME as f32 + 1.0) * (ASTEP as f32 + 1.0) * 0.00278;
+        self.channels
+            .map(|count| count as f32 / (GAIN_FACTOR * integration_time_ms))
+    }
+
+    /// Approximate the CIE 1931 XYZ tristimulus values from the eight visible channels.
+    fn xyz(&self) -> (f32, f32, f32) {
+        self.basic_counts()
+            .iter()
+            .zip(CIE_XYZ.iter())
+            .fold((0.0, 0.0, 0.0), |(x, y, z), (count, (xb, yb, zb))| {
+                (x + count * xb, y + count * yb, z + count * zb)
+            })
+    }
+
+    /// Estimate the illuminance in lux.
+    ///
+    /// This is a rough approximation (the photopic response is only sampled at eight points),
+    /// scaled by a calibration factor that must be determined with a reference lux meter.
+    pub fn lux(&self, calibration_factor: f32) -> f32 {
+        self.xyz().1 * calibration_factor
+    }
+
+    /// Estimate the correlated color temperature in Kelvin (McCamy's approximation).
+    ///
+    /// Returns `None` if it's too dark to determine the chromaticity.
+    pub fn cct(&self) -> Option<f32> {
+        let (x, y, z) = self.xyz();
+        let sum = x + y + z;
+        if sum <= f32::EPSILON {
+            return None;
+        }
+        let (cx, cy) = (x / sum, y / sum);
+        let n = (cx - 0.3320) / (0.1858 - cy);
+        Some(449.0 * n.powi(3) + 3525.0 * n.powi(2) + 6823.3 * n + 5520.33)
+    }
+}
+
+pub struct As7341<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C, E> As7341<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(ADDRESS, &[register, value])
+            .map_err(Error::I2c)
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c
+            .write_read(ADDRESS, &[register], buf)
+            .map_err(Error::I2c)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut buf = [0];
+        self.read_registers(register, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Verify the ID, power on the sensor and configure gain and integration time.
+    pub fn init(&mut self) -> Result<(), Error<E>> {
+        let id = self.read_register(reg::ID)? & 0b1111_1100;
+        if id != ID {
+            return Err(Error::InvalidId(id));
+        }
+        self.write_register(reg::ENABLE, enable::PON)?;
+        self.write_register(reg::ATIME, ATIME)?;
+        let [astep_l, astep_h] = ASTEP.to_le_bytes();
+        self.i2c
+            .write(ADDRESS, &[reg::ASTEP_L, astep_l, astep_h])
+            .map_err(Error::I2c)?;
+        self.write_register(reg::CFG1, GAIN)
+    }
+
+    /// Poll a register until `done` returns true, for at most `timeout_ms` milliseconds.
+    fn wait_for<D: DelayMs<u16>>(
+        &mut self,
+        delay: &mut D,
+        register: u8,
+        timeout_ms: u16,
+        done: impl Fn(u8) -> bool,
+    ) -> Result<(), Error<E>> {
+        for _ in 0..timeout_ms / 5 {
+            if done(self.read_register(register)?) {
+                return Ok(());
+            }
+            delay.delay_ms(5);
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Apply a SMUX configuration, run a single measurement and return the six ADC values.
+    fn measure_with_smux<D: DelayMs<u16>>(
+        &mut self,
+        delay: &mut D,
+        smux: &[u8; 20],
+    ) -> Result<[u16; 6], Error<E>> {
+        // Write SMUX configuration from RAM to the SMUX chain
+        self.write_register(reg::ENABLE, enable::PON)?;
+        self.write_register(reg::CFG6, 0b10 << 3)?;
+        let mut buf = [0; 21];
+        buf[1..].copy_from_slice(smux);
+        self.i2c.write(ADDRESS, &buf).map_err(Error::I2c)?;
+        self.write_register(reg::ENABLE, enable::PON | enable::SMUXEN)?;
+        self.wait_for(delay, reg::ENABLE, 100, |value| value & enable::SMUXEN == 0)?;
+
+        // Start measurement and wait for valid data
+        self.write_register(reg::ENABLE, enable::PON | enable::SP_EN)?;
+        self.wait_for(delay, reg::STATUS2, 500, |value| value & AVALID != 0)?;
+        let mut data = [0; 12];
+        self.read_registers(reg::CH0_DATA_L, &mut data)?;
+        self.write_register(reg::ENABLE, enable::PON)?;
+
+        let mut values = [0; 6];
+        for (value, bytes) in values.iter_mut().zip(data.chunks_exact(2)) {
+            *value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(values)
+    }
+
+    /// Measure all channels (two passes of ~50 ms each).
+    pub fn measure<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>> {
+        let low = self.measure_with_smux(delay, &SMUX_F1_F4)?;
+        let high = self.measure_with_smux(delay, &SMUX_F5_F8)?;
+        Ok(Measurement {
+            channels: [
+                low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3],
+            ],
+            clear: high[4],
+            nir: high[5],
+        })
+    }
+}
@@ -0,0 +1,248 @@
+//! Driver for the Bosch BMP390 barometric pressure sensor.
+//!
+//! The sensor is operated in forced mode: Every call to [`Bmp390::measure`] triggers a single
+//! conversion and returns the compensated result.
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayUs,
+    i2c::{Write, WriteRead},
+};
+
+/// I²C address with SDO pulled high (the BMP390 uses 0x76 with SDO pulled low)
+pub const ADDRESS_SDO_HIGH: u8 = 0x77;
+
+/// Expected value of the chip ID register
+const CHIP_ID: u8 = 0x60;
+
+mod reg {
+    pub const CHIP_ID: u8 = 0x00;
+    pub const STATUS: u8 = 0x03;
+    pub const DATA: u8 = 0x04;
+    pub const PWR_CTRL: u8 = 0x1b;
+    pub const OSR: u8 = 0x1c;
+    pub const CONFIG: u8 = 0x1f;
+    pub const CALIBRATION: u8 = 0x31;
+    pub const CMD: u8 = 0x7e;
+}
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// The chip ID does not match a BMP390
+    InvalidChipId(u8),
+    /// The conversion did not complete in time
+    Timeout,
+}
+
+/// Pressure oversampling setting
+#[derive(Debug, Copy, Clone)]
+pub enum Oversampling {
+    X1 = 0,
+    X2 = 1,
+    X4 = 2,
+    X8 = 3,
+    X16 = 4,
+    X32 = 5,
+}
+
+impl Oversampling {
+    /// Return the oversampling setting for the specified factor, if valid.
+    pub fn from_factor(factor: u8) -> Option<Self> {
+        match factor {
+            1 => Some(Self::X1),
+            2 => Some(Self::X2),
+            4 => Some(Self::X4),
+            8 => Some(Self::X8),
+            16 => Some(Self::X16),
+            32 => Some(Self::X32),
+            _ => None,
+        }
+    }
+
+    fn factor(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Calibration coefficients, converted to floating point according to the datasheet
+#[derive(Debug, Default)]
+struct Calibration {
+    t1: f64,
+    t2: f64,
+    t3: f64,
+    p1: f64,
+    p2: f64,
+    p3: f64,
+    p4: f64,
+    p5: f64,
+    p6: f64,
+    p7: f64,
+    p8: f64,
+    p9: f64,
+    p10: f64,
+    p11: f64,
+}
+
+impl Calibration {
+    fn from_registers(buf: &[u8; 21]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]) as f64;
+        let i16_at = |i: usize| i16::from_le_bytes([buf[i], buf[i + 1]]) as f64;
+        let i8_at = |i: usize| buf[i] as i8 as f64;
+        Self {
+            t1: u16_at(0) * 2f64.powi(8),
+            t2: u16_at(2) / 2f64.powi(30),
+            t3: i8_at(4) / 2f64.powi(48),
+            p1: (i16_at(5) - 2f64.powi(14)) / 2f64.powi(20),
+            p2: (i16_at(7) - 2f64.powi(14)) / 2f64.powi(29),
+            p3: i8_at(9) / 2f64.powi(32),
+            p4: i8_at(10) / 2f64.powi(37),
+            p5: u16_at(11) * 2f64.powi(3),
+            p6: u16_at(13) / 2f64.powi(6),
+            p7: i8_at(15) / 2f64.powi(8),
+            p8: i8_at(16) / 2f64.powi(15),
+            p9: i16_at(17) / 2f64.powi(48),
+            p10: i8_at(19) / 2f64.powi(48),
+            p11: i8_at(20) / 2f64.powi(65),
+        }
+    }
+
+    /// Return the compensated temperature in °C.
+    fn compensate_temperature(&self, raw: u32) -> f64 {
+        let d1 = raw as f64 - self.t1;
+        let d2 = d1 * self.t2;
+        d2 + d1 * d1 * self.t3
+    }
+
+    /// Return the compensated pressure in Pa.
+    fn compensate_pressure(&self, raw: u32, t: f64) -> f64 {
+        let p = raw as f64;
+        let out1 = self.p5 + self.p6 * t + self.p7 * t * t + self.p8 * t * t * t;
+        let out2 = p * (self.p1 + self.p2 * t + self.p3 * t * t + self.p4 * t * t * t);
+        let out3 = p * p * (self.p9 + self.p10 * t) + p * p * p * self.p11;
+        out1 + out2 + out3
+    }
+}
+
+/// A single pressure measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Pressure in Pa
+    pub pressure: f32,
+    /// Sensor temperature in °C
+    pub temperature: f32,
+}
+
+pub struct Bmp390<I2C> {
+    i2c: I2C,
+    address: u8,
+    oversampling: Oversampling,
+    calibration: Calibration,
+}
+
+impl<I2C, E> Bmp390<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8, oversampling: Oversampling) -> Self {
+        Self {
+            i2c,
+            address,
+            oversampling,
+            calibration: Calibration::default(),
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(self.address, &[register, value])
+            .map_err(Error::I2c)
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .map_err(Error::I2c)
+    }
+
+    /// Reset the sensor, verify the chip ID, read the calibration coefficients and apply the
+    /// oversampling configuration.
+    pub fn init<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<(), Error<E>> {
+        self.write_register(reg::CMD, 0xb6)?;
+        delay.delay_us(2_000);
+
+        let mut chip_id = [0];
+        self.read_registers(reg::CHIP_ID, &mut chip_id)?;
+        if chip_id[0] != CHIP_ID {
+            return Err(Error::InvalidChipId(chip_id[0]));
+        }
+
+        let mut buf = [0; 21];
+        self.read_registers(reg::CALIBRATION, &mut buf)?;
+        self.calibration = Calibration::from_registers(&buf);
+
+        // Temperature oversampling x1 is sufficient for pressure compensation unless the pressure
+        // is heavily oversampled (see section 3.5 of the datasheet)
+        let osr_t = match self.oversampling {
+            Oversampling::X16 | Oversampling::X32 => Oversampling::X2,
+            _ => Oversampling::X1,
+        };
+        self.write_register(reg::OSR, (osr_t as u8) << 3 | self.oversampling as u8)?;
+
+        // IIR filter coefficient 3, to suppress short-term disturbances (e.g. slamming doors)
+        self.write_register(reg::CONFIG, 0b010 << 1)?;
+
+        Ok(())
+    }
+
+    /// Trigger a forced mode conversion and return the compensated result.
+    pub fn measure<D: DelayUs<u32>>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>> {
+        // Enable pressure and temperature, forced mode
+        self.write_register(reg::PWR_CTRL, 0b01 << 4 | 0b11)?;
+
+        // Wait for the conversion time (see section 3.9.2 of the datasheet)
+        let osr_t = if self.oversampling.factor() >= 16 {
+            2
+        } else {
+            1
+        };
+        let conversion_time_us = 234 + 392 + self.oversampling.factor() * 2020 + 163 + osr_t * 2020;
+        delay.delay_us(conversion_time_us);
+
+        // Poll for data ready
+        let mut status = [0];
+        let mut ready = false;
+        for _ in 0..10 {
+            self.read_registers(reg::STATUS, &mut status)?;
+            if status[0] & 0b0110_0000 == 0b0110_0000 {
+                ready = true;
+                break;
+            }
+            delay.delay_us(1_000);
+        }
+        if !ready {
+            return Err(Error::Timeout);
+        }
+
+        let mut buf = [0; 6];
+        self.read_registers(reg::DATA, &mut buf)?;
+        let raw_pressure = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]);
+        let raw_temperature = u32::from_le_bytes([buf[3], buf[4], buf[5], 0]);
+        let temperature = self.calibration.compensate_temperature(raw_temperature);
+        let pressure = self
+            .calibration
+            .compensate_pressure(raw_pressure, temperature);
+        Ok(Measurement {
+            pressure: pressure as f32,
+            temperature: temperature as f32,
+        })
+    }
+}
+
+/// Reduce the station pressure to sea level, using the international barometric formula.
+///
+/// The altitude is specified in meters above sea level.
+pub fn sea_level_pressure(pressure: f32, altitude: f32) -> f32 {
+    pressure / (1.0 - altitude / 44_330.0).powf(5.255)
+}
@@ -0,0 +1,165 @@
+//! Driver for the ams CCS811 digital metal-oxide gas sensor.
+//!
+//! The nWAKE pin is pulled low for the duration of every I²C transaction and released
+//! afterwards, so the sensor interface can sleep in between.
+
+use embedded_hal_0_2::{
+    blocking::{
+        delay::DelayUs,
+        i2c::{Write, WriteRead},
+    },
+    digital::v2::OutputPin,
+};
+
+/// I²C address with ADDR pulled low (the CCS811 uses 0x5b with ADDR pulled high)
+pub const ADDRESS_ADDR_LOW: u8 = 0x5a;
+
+/// Expected value of the hardware ID register
+const HW_ID: u8 = 0x81;
+
+mod reg {
+    pub const STATUS: u8 = 0x00;
+    pub const MEAS_MODE: u8 = 0x01;
+    pub const ALG_RESULT_DATA: u8 = 0x02;
+    pub const ENV_DATA: u8 = 0x05;
+    pub const BASELINE: u8 = 0x11;
+    pub const HW_ID: u8 = 0x20;
+    pub const ERROR_ID: u8 = 0xe0;
+    pub const APP_START: u8 = 0xf4;
+}
+
+mod status {
+    pub const ERROR: u8 = 1 << 0;
+    pub const DATA_READY: u8 = 1 << 3;
+    pub const APP_VALID: u8 = 1 << 4;
+    pub const FW_MODE: u8 = 1 << 7;
+}
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E, PE> {
+    /// I²C bus error
+    I2c(E),
+    /// Error while setting the nWAKE pin
+    Pin(PE),
+    /// The hardware ID does not match a CCS811
+    InvalidHardwareId(u8),
+    /// No valid application firmware is loaded
+    NoValidApp,
+    /// The sensor reported an error (contents of the ERROR_ID register)
+    Device(u8),
+    /// No new data is available
+    NotReady,
+}
+
+/// A single air quality measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// CO₂ equivalent concentration in PPM
+    pub co2eq_ppm: u16,
+    /// TVOC concentration in PPB
+    pub tvoc_ppb: u16,
+}
+
+pub struct Ccs811<I2C, WAKE> {
+    i2c: I2C,
+    address: u8,
+    n_wake: WAKE,
+}
+
+impl<I2C, E, WAKE, PE> Ccs811<I2C, WAKE>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    WAKE: OutputPin<Error = PE>,
+{
+    pub fn new(i2c: I2C, address: u8, n_wake: WAKE) -> Self {
+        Self {
+            i2c,
+            address,
+            n_wake,
+        }
+    }
+
+    /// Assert nWAKE, run the I²C transaction and release nWAKE again.
+    fn with_wake<T>(
+        &mut self,
+        f: impl FnOnce(&mut I2C, u8) -> Result<T, E>,
+    ) -> Result<T, Error<E, PE>> {
+        self.n_wake.set_low().map_err(Error::Pin)?;
+        // Note: nWAKE must be asserted at least 50 µs before the transaction. The pin toggle and
+        // the I²C driver setup take longer than that.
+        let result = f(&mut self.i2c, self.address).map_err(Error::I2c);
+        self.n_wake.set_high().map_err(Error::Pin)?;
+        result
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error<E, PE>> {
+        self.with_wake(|i2c, address| i2c.write_read(address, &[register], buf))
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error<E, PE>> {
+        self.with_wake(|i2c, address| i2c.write(address, data))
+    }
+
+    fn status(&mut self) -> Result<u8, Error<E, PE>> {
+        let mut status = [0];
+        self.read_registers(reg::STATUS, &mut status)?;
+        if status[0] & status::ERROR != 0 {
+            let mut error_id = [0];
+            self.read_registers(reg::ERROR_ID, &mut error_id)?;
+            return Err(Error::Device(error_id[0]));
+        }
+        Ok(status[0])
+    }
+
+    /// Verify the hardware ID, start the application firmware and enable constant power mode
+    /// with a measurement every second.
+    pub fn init<D: DelayUs<u16>>(&mut self, delay: &mut D) -> Result<(), Error<E, PE>> {
+        let mut hw_id = [0];
+        self.read_registers(reg::HW_ID, &mut hw_id)?;
+        if hw_id[0] != HW_ID {
+            return Err(Error::InvalidHardwareId(hw_id[0]));
+        }
+        if self.status()? & status::APP_VALID == 0 {
+            return Err(Error::NoValidApp);
+        }
+        if self.status()? & status::FW_MODE == 0 {
+            self.write(&[reg::APP_START])?;
+            delay.delay_us(1_000);
+        }
+        // Drive mode 1: Measurement every second
+        self.write(&[reg::MEAS_MODE, 0b001 << 4])
+    }
+
+    /// Feed ambient temperature (in °C) and relative humidity (in %) for compensation.
+    pub fn set_environment(&mut self, temperature: f32, humidity: f32) -> Result<(), Error<E, PE>> {
+        let humi = ((humidity * 512.0) as u16).to_be_bytes();
+        let temp = (((temperature + 25.0) * 512.0) as u16).to_be_bytes();
+        self.write(&[reg::ENV_DATA, humi[0], humi[1], temp[0], temp[1]])
+    }
+
+    /// Read the latest measurement.
+    pub fn measure(&mut self) -> Result<Measurement, Error<E, PE>> {
+        if self.status()? & status::DATA_READY == 0 {
+            return Err(Error::NotReady);
+        }
+        let mut buf = [0; 4];
+        self.read_registers(reg::ALG_RESULT_DATA, &mut buf)?;
+        Ok(Measurement {
+            co2eq_ppm: u16::from_be_bytes([buf[0], buf[1]]),
+            tvoc_ppb: u16::from_be_bytes([buf[2], buf[3]]),
+        })
+    }
+
+    /// Read the current baseline of the algorithm.
+    pub fn baseline(&mut self) -> Result<[u8; 2], Error<E, PE>> {
+        let mut buf = [0; 2];
+        self.read_registers(reg::BASELINE, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Restore a baseline previously read with [`Ccs811::baseline`].
+    pub fn set_baseline(&mut self, baseline: [u8; 2]) -> Result<(), Error<E, PE>> {
+        self.write(&[reg::BASELINE, baseline[0], baseline[1]])
+    }
+}
@@ -0,0 +1,143 @@
+//! Driver for the ScioSense ENS160 digital metal-oxide multi-gas sensor.
+
+use embedded_hal_0_2::blocking::i2c::{Write, WriteRead};
+
+/// I²C address with ADDR pulled low (the ENS160 uses 0x53 with ADDR pulled high)
+pub const ADDRESS_ADDR_LOW: u8 = 0x52;
+
+/// Expected value of the part ID register
+const PART_ID: u16 = 0x0160;
+
+mod reg {
+    pub const PART_ID: u8 = 0x00;
+    pub const OPMODE: u8 = 0x10;
+    pub const TEMP_IN: u8 = 0x13;
+    pub const DEVICE_STATUS: u8 = 0x20;
+    pub const DATA_AQI: u8 = 0x21;
+}
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// The part ID does not match an ENS160
+    InvalidPartId(u16),
+    /// The sensor reported an error (e.g. an invalid operating mode)
+    Device,
+}
+
+/// Operating mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OperatingMode {
+    Idle = 0x01,
+    Standard = 0x02,
+    Reset = 0xf0,
+}
+
+/// Validity of the measurement data, as reported by the sensor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Validity {
+    /// Normal operation
+    Normal,
+    /// Warm-up phase (first 3 minutes after power-on)
+    WarmUp,
+    /// Initial start-up phase (first hour of operation ever)
+    InitialStartUp,
+    /// Invalid output
+    Invalid,
+}
+
+impl From<u8> for Validity {
+    fn from(status: u8) -> Self {
+        match (status >> 2) & 0b11 {
+            0 => Self::Normal,
+            1 => Self::WarmUp,
+            2 => Self::InitialStartUp,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+/// A single air quality measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Air quality index according to UBA (1 = excellent, 5 = unhealthy)
+    pub aqi: u8,
+    /// TVOC concentration in PPB
+    pub tvoc_ppb: u16,
+    /// CO₂ equivalent concentration in PPM
+    pub co2eq_ppm: u16,
+    /// Validity of the data
+    pub validity: Validity,
+}
+
+pub struct Ens160<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Ens160<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c
+            .write_read(self.address, &[register], buf)
+            .map_err(Error::I2c)
+    }
+
+    /// Read the part ID and verify that it matches an ENS160.
+    pub fn part_id(&mut self) -> Result<u16, Error<E>> {
+        let mut buf = [0; 2];
+        self.read_registers(reg::PART_ID, &mut buf)?;
+        let part_id = u16::from_le_bytes(buf);
+        if part_id != PART_ID {
+            return Err(Error::InvalidPartId(part_id));
+        }
+        Ok(part_id)
+    }
+
+    /// Switch the operating mode.
+    ///
+    /// Note: After a reset, the sensor needs about 10 ms before accepting new commands. Standard
+    /// mode can only be entered from idle mode.
+    pub fn set_operating_mode(&mut self, mode: OperatingMode) -> Result<(), Error<E>> {
+        self.i2c
+            .write(self.address, &[reg::OPMODE, mode as u8])
+            .map_err(Error::I2c)
+    }
+
+    /// Feed ambient temperature (in °C) and relative humidity (in %) for compensation.
+    pub fn set_compensation(&mut self, temperature: f32, humidity: f32) -> Result<(), Error<E>> {
+        let temp = (((temperature + 273.15) * 64.0) as u16).to_le_bytes();
+        let humi = ((humidity * 512.0) as u16).to_le_bytes();
+        self.i2c
+            .write(
+                self.address,
+                &[reg::TEMP_IN, temp[0], temp[1], humi[0], humi[1]],
+            )
+            .map_err(Error::I2c)
+    }
+
+    /// Read the latest measurement.
+    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        let mut status = [0];
+        self.read_registers(reg::DEVICE_STATUS, &mut status)?;
+        if status[0] & 0b0100_0000 != 0 {
+            return Err(Error::Device);
+        }
+        let mut buf = [0; 5];
+        self.read_registers(reg::DATA_AQI, &mut buf)?;
+        Ok(Measurement {
+            aqi: buf[0] & 0b111,
+            tvoc_ppb: u16::from_le_bytes([buf[1], buf[2]]),
+            co2eq_ppm: u16::from_le_bytes([buf[3], buf[4]]),
+            validity: Validity::from(status[0]),
+        })
+    }
+}
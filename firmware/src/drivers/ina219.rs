@@ -0,0 +1,92 @@
+//! Driver for the TI INA219 current/power monitor.
+//!
+//! The calibration register is not used: The current is calculated from the shunt voltage and the
+//! known shunt resistance, which works for any shunt without recalculating the calibration value.
+
+use embedded_hal_0_2::blocking::i2c::{Write, WriteRead};
+
+mod reg {
+    pub const CONFIG: u8 = 0x00;
+    pub const SHUNT_VOLTAGE: u8 = 0x01;
+    pub const BUS_VOLTAGE: u8 = 0x02;
+}
+
+/// Configuration: 32 V bus range, ±320 mV shunt range, 12 bit ADCs with 128 samples averaging,
+/// continuous shunt and bus measurement
+const CONFIG: u16 = 0b0011_1111_1111_1111;
+
+/// Shunt voltage register value at the end of the ±320 mV range
+const SHUNT_FULL_SCALE: i16 = 32000;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// The shunt voltage is out of range
+    Overflow,
+}
+
+/// A single measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Bus voltage in V
+    pub voltage: f32,
+    /// Current in A
+    pub current: f32,
+    /// Power in W
+    pub power: f32,
+}
+
+pub struct Ina219<I2C> {
+    i2c: I2C,
+    address: u8,
+    /// Shunt resistance in Ω
+    shunt: f32,
+}
+
+impl<I2C, E> Ina219<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8, shunt: f32) -> Self {
+        Self {
+            i2c,
+            address,
+            shunt,
+        }
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u16, Error<E>> {
+        let mut buf = [0; 2];
+        self.i2c
+            .write_read(self.address, &[register], &mut buf)
+            .map_err(Error::I2c)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    /// Write the configuration.
+    pub fn init(&mut self) -> Result<(), Error<E>> {
+        let [msb, lsb] = CONFIG.to_be_bytes();
+        self.i2c
+            .write(self.address, &[reg::CONFIG, msb, lsb])
+            .map_err(Error::I2c)
+    }
+
+    /// Read the latest measurement.
+    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        // Shunt voltage: Signed, 10 µV per LSB. Bus voltage: Bits 15..3, 4 mV per LSB.
+        let shunt_raw = self.read_register(reg::SHUNT_VOLTAGE)? as i16;
+        if shunt_raw.saturating_abs() >= SHUNT_FULL_SCALE {
+            return Err(Error::Overflow);
+        }
+        let shunt_voltage = shunt_raw as f32 * 10e-6;
+        let voltage = (self.read_register(reg::BUS_VOLTAGE)? >> 3) as f32 * 4e-3;
+        let current = shunt_voltage / self.shunt;
+        Ok(Measurement {
+            voltage,
+            current,
+            power: voltage * current,
+        })
+    }
+}
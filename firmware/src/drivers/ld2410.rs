@@ -0,0 +1,191 @@
+//! Driver for the HLK-LD2410 24 GHz mmWave presence radar.
+//!
+//! The radar continuously sends report frames over UART (256000 baud, 8N1). The driver only
+//! parses the basic target reports, the engineering mode and the configuration commands are not
+//! supported.
+
+use embedded_hal_0_2::serial::Read;
+
+/// Baud rate of the radar's UART (factory default)
+pub const BAUD_RATE: u32 = 256_000;
+
+/// Report frame header
+const HEADER: [u8; 4] = [0xf4, 0xf3, 0xf2, 0xf1];
+
+/// Report frame footer
+const FOOTER: [u8; 4] = [0xf8, 0xf7, 0xf6, 0xf5];
+
+/// Maximum accepted length of the intra-frame data
+const MAX_DATA_LEN: usize = 64;
+
+/// Data type of a basic target report
+const DATA_TYPE_BASIC: u8 = 0x02;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// UART error
+    Serial(E),
+}
+
+/// Target state as reported by the radar
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TargetState {
+    /// Nobody is present
+    None,
+    /// A moving target was detected
+    Moving,
+    /// A still (e.g. sitting) target was detected
+    Still,
+    /// A moving and a still target were detected
+    MovingAndStill,
+}
+
+impl TargetState {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Self::None),
+            0x01 => Some(Self::Moving),
+            0x02 => Some(Self::Still),
+            0x03 => Some(Self::MovingAndStill),
+            _ => None,
+        }
+    }
+
+    /// Return whether somebody is present.
+    pub fn is_present(self) -> bool {
+        self != Self::None
+    }
+
+    /// Return whether a moving target was detected.
+    pub fn is_moving(self) -> bool {
+        matches!(self, Self::Moving | Self::MovingAndStill)
+    }
+
+    /// Return whether a still target was detected.
+    pub fn is_still(self) -> bool {
+        matches!(self, Self::Still | Self::MovingAndStill)
+    }
+}
+
+/// A basic target report
+#[derive(Debug, Copy, Clone)]
+pub struct Report {
+    pub target_state: TargetState,
+    /// Distance of the moving target in cm
+    pub moving_distance: u16,
+    /// Energy of the moving target (0-100)
+    pub moving_energy: u8,
+    /// Distance of the still target in cm
+    pub still_distance: u16,
+    /// Energy of the still target (0-100)
+    pub still_energy: u8,
+    /// Detection distance in cm
+    pub detection_distance: u16,
+}
+
+impl Report {
+    /// Parse the intra-frame data of a basic target report.
+    fn parse(data: &[u8]) -> Option<Self> {
+        // Data type, head (0xaa), 9 bytes target data, tail (0x55), check (0x00)
+        if data.len() != 13 || data[0] != DATA_TYPE_BASIC || data[1] != 0xaa || data[11] != 0x55 {
+            return None;
+        }
+        let target = &data[2..11];
+        Some(Self {
+            target_state: TargetState::from_byte(target[0])?,
+            moving_distance: u16::from_le_bytes([target[1], target[2]]),
+            moving_energy: target[3],
+            still_distance: u16::from_le_bytes([target[4], target[5]]),
+            still_energy: target[6],
+            detection_distance: u16::from_le_bytes([target[7], target[8]]),
+        })
+    }
+}
+
+pub struct Ld2410<UART> {
+    uart: UART,
+    /// Bytes of the frame that is currently being received
+    frame: Vec<u8>,
+    /// Most recently received report
+    latest: Option<Report>,
+}
+
+impl<UART, E> Ld2410<UART>
+where
+    UART: Read<u8, Error = E>,
+{
+    pub fn new(uart: UART) -> Self {
+        Self {
+            uart,
+            frame: Vec::with_capacity(HEADER.len() + 2 + MAX_DATA_LEN + FOOTER.len()),
+            latest: None,
+        }
+    }
+
+    /// Process all bytes that are available on the UART. Return the most recent report, if a new
+    /// report was received.
+    pub fn poll(&mut self) -> Result<Option<Report>, Error<E>> {
+        let mut received = None;
+        loop {
+            match self.uart.read() {
+                Ok(byte) => {
+                    if let Some(report) = self.feed(byte) {
+                        received = Some(report);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
+            }
+        }
+        if received.is_some() {
+            self.latest = received;
+        }
+        Ok(received)
+    }
+
+    /// Return the most recently received report.
+    pub fn latest(&self) -> Option<Report> {
+        self.latest
+    }
+
+    /// Add a byte to the current frame. Return a report if the frame is complete.
+    fn feed(&mut self, byte: u8) -> Option<Report> {
+        let len = self.frame.len();
+
+        // Wait for the header
+        if len < HEADER.len() {
+            if byte == HEADER[len] {
+                self.frame.push(byte);
+            } else {
+                self.frame.clear();
+                if byte == HEADER[0] {
+                    self.frame.push(byte);
+                }
+            }
+            return None;
+        }
+
+        self.frame.push(byte);
+        if self.frame.len() < HEADER.len() + 2 {
+            return None;
+        }
+        let data_len = u16::from_le_bytes([self.frame[4], self.frame[5]]) as usize;
+        if data_len > MAX_DATA_LEN {
+            self.frame.clear();
+            return None;
+        }
+        let frame_len = HEADER.len() + 2 + data_len + FOOTER.len();
+        if self.frame.len() < frame_len {
+            return None;
+        }
+
+        let report = if self.frame[frame_len - FOOTER.len()..] == FOOTER {
+            Report::parse(&self.frame[HEADER.len() + 2..frame_len - FOOTER.len()])
+        } else {
+            None
+        };
+        self.frame.clear();
+        report
+    }
+}
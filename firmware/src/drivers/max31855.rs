@@ -0,0 +1,67 @@
+//! Driver for the Maxim MAX31855 thermocouple-to-digital converter (K-type).
+//!
+//! The converter is read-only: Every transaction returns a 32 bit frame with the thermocouple
+//! temperature, the cold junction temperature and the fault flags. SPI mode 0, max. 5 MHz.
+
+use embedded_hal_0_2::blocking::spi::Transfer;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// SPI bus error
+    Spi(E),
+    /// The thermocouple is not connected
+    OpenCircuit,
+    /// The thermocouple is short-circuited to GND
+    ShortToGnd,
+    /// The thermocouple is short-circuited to VCC
+    ShortToVcc,
+}
+
+/// A single temperature measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Thermocouple temperature in °C
+    pub temperature: f32,
+    /// Cold junction (internal) temperature in °C
+    pub internal_temperature: f32,
+}
+
+pub struct Max31855<SPI> {
+    spi: SPI,
+}
+
+impl<SPI, E> Max31855<SPI>
+where
+    SPI: Transfer<u8, Error = E>,
+{
+    pub fn new(spi: SPI) -> Self {
+        Self { spi }
+    }
+
+    /// Read the temperatures.
+    pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        let mut buf = [0; 4];
+        self.spi.transfer(&mut buf).map_err(Error::Spi)?;
+        let frame = u32::from_be_bytes(buf);
+
+        // Fault bit, details in D2..D0
+        if frame & (1 << 16) != 0 {
+            return Err(if frame & 0b001 != 0 {
+                Error::OpenCircuit
+            } else if frame & 0b010 != 0 {
+                Error::ShortToGnd
+            } else {
+                Error::ShortToVcc
+            });
+        }
+
+        // D31..D18: 14 bit signed, 0.25 °C per LSB. D15..D4: 12 bit signed, 0.0625 °C per LSB.
+        let thermocouple = (frame as i32) >> 18;
+        let internal = ((frame as i32) << 16) >> 20;
+        Ok(Measurement {
+            temperature: thermocouple as f32 * 0.25,
+            internal_temperature: internal as f32 * 0.0625,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+//! Driver for the Maxim MAX31865 RTD-to-digital converter (PT100/PT1000).
+//!
+//! The converter is used in one-shot mode: The bias voltage is only switched on for the
+//! measurement, to avoid self-heating of the RTD. SPI mode 1 or 3, max. 5 MHz.
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayMs,
+    spi::{Transfer, Write},
+};
+
+mod reg {
+    pub const CONFIG: u8 = 0x00;
+    pub const RTD_MSB: u8 = 0x01;
+    pub const FAULT_STATUS: u8 = 0x07;
+}
+
+/// Register addresses must have the MSB set for writes
+const WRITE: u8 = 0x80;
+
+mod config {
+    pub const VBIAS: u8 = 1 << 7;
+    pub const ONE_SHOT: u8 = 1 << 5;
+    pub const THREE_WIRE: u8 = 1 << 4;
+    pub const FAULT_CLEAR: u8 = 1 << 1;
+    pub const FILTER_50HZ: u8 = 1 << 0;
+}
+
+/// Callendar-Van Dusen coefficients for platinum RTDs (IEC 60751)
+const A: f32 = 3.9083e-3;
+const B: f32 = -5.775e-7;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// SPI bus error
+    Spi(E),
+    /// The converter detected a fault (content of the fault status register)
+    Fault(u8),
+}
+
+/// RTD type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rtd {
+    Pt100,
+    Pt1000,
+}
+
+impl Rtd {
+    /// Return the nominal resistance at 0 °C in Ω.
+    fn r0(self) -> f32 {
+        match self {
+            Self::Pt100 => 100.0,
+            Self::Pt1000 => 1000.0,
+        }
+    }
+
+    /// Return the reference resistor value of the common breakout boards in Ω.
+    pub fn default_reference(self) -> f32 {
+        match self {
+            Self::Pt100 => 430.0,
+            Self::Pt1000 => 4300.0,
+        }
+    }
+}
+
+/// Wiring of the RTD
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Wiring {
+    /// 2-wire or 4-wire
+    TwoOrFourWire,
+    ThreeWire,
+}
+
+/// Mains frequency to reject with the notch filter
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Filter {
+    Hz50,
+    Hz60,
+}
+
+/// A single temperature measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Temperature in °C
+    pub temperature: f32,
+    /// RTD resistance in Ω
+    pub resistance: f32,
+}
+
+pub struct Max31865<SPI> {
+    spi: SPI,
+    rtd: Rtd,
+    /// Reference resistor in Ω (e.g. 430 Ω for PT100, 4300 Ω for PT1000)
+    reference: f32,
+    /// Base configuration (wiring and filter)
+    config: u8,
+}
+
+impl<SPI, E> Max31865<SPI>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+{
+    pub fn new(spi: SPI, rtd: Rtd, reference: f32, wiring: Wiring, filter: Filter) -> Self {
+        let mut config = 0;
+        if wiring == Wiring::ThreeWire {
+            config |= config::THREE_WIRE;
+        }
+        if filter == Filter::Hz50 {
+            config |= config::FILTER_50HZ;
+        }
+        Self {
+            spi,
+            rtd,
+            reference,
+            config,
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.spi
+            .write(&[register | WRITE, value])
+            .map_err(Error::Spi)
+    }
+
+    fn read_registers<const N: usize>(&mut self, register: u8) -> Result<[u8; N], Error<E>> {
+        let mut buf = [0; 8];
+        buf[0] = register;
+        self.spi.transfer(&mut buf[..N + 1]).map_err(Error::Spi)?;
+        let mut values = [0; N];
+        values.copy_from_slice(&buf[1..N + 1]);
+        Ok(values)
+    }
+
+    /// Write the base configuration and clear any faults.
+    pub fn init(&mut self) -> Result<(), Error<E>> {
+        self.write_register(reg::CONFIG, self.config | config::FAULT_CLEAR)
+    }
+
+    /// Run a one-shot measurement. This takes about 75 ms.
+    pub fn measure<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>> {
+        // Switch on bias voltage and let the input filter settle
+        self.write_register(reg::CONFIG, self.config | config::VBIAS)?;
+        delay.delay_ms(10);
+
+        // Start conversion
+        self.write_register(reg::CONFIG, self.config | config::VBIAS | config::ONE_SHOT)?;
+        delay.delay_ms(65);
+
+        let [msb, lsb] = self.read_registers(reg::RTD_MSB)?;
+        self.write_register(reg::CONFIG, self.config)?;
+
+        // LSB bit 0 is the fault flag
+        if lsb & 1 != 0 {
+            let [fault] = self.read_registers(reg::FAULT_STATUS)?;
+            self.init()?;
+            return Err(Error::Fault(fault));
+        }
+
+        let code = u16::from_be_bytes([msb, lsb]) >> 1;
+        let resistance = code as f32 * self.reference / 32768.0;
+        Ok(Measurement {
+            temperature: self.temperature(resistance),
+            resistance,
+        })
+    }
+
+    /// Convert the RTD resistance to a temperature.
+    fn temperature(&self, resistance: f32) -> f32 {
+        let r0 = self.rtd.r0();
+
+        // Callendar-Van Dusen equation, solved for T (exact for T >= 0 °C)
+        let t = (-A + (A * A - 4.0 * B * (1.0 - resistance / r0)).sqrt()) / (2.0 * B);
+        if t >= 0.0 {
+            return t;
+        }
+
+        // Below 0 °C, use a polynomial approximation (normalized to PT100)
+        let r = resistance / r0 * 100.0;
+        -242.02 + 2.2228 * r + 2.5859e-3 * r.powi(2) - 4.8260e-6 * r.powi(3) - 2.8183e-8 * r.powi(4)
+            + 1.5243e-10 * r.powi(5)
+    }
+}
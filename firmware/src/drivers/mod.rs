@@ -0,0 +1,33 @@
+//! In-tree drivers for sensors and displays that don't have a usable crate on crates.io (yet).
+//!
+//! The sensor drivers are only compiled with the feature of their sensor.
+
+#[cfg(feature = "spectral")]
+pub mod as7341;
+#[cfg(feature = "pressure")]
+pub mod bmp390;
+#[cfg(feature = "ccs811")]
+pub mod ccs811;
+#[cfg(feature = "ens160")]
+pub mod ens160;
+#[cfg(feature = "ina219")]
+pub mod ina219;
+#[cfg(feature = "ld2410")]
+pub mod ld2410;
+#[cfg(feature = "thermocouple")]
+pub mod max31855;
+#[cfg(feature = "rtd")]
+pub mod max31865;
+#[cfg(feature = "pzem")]
+pub mod pzem004t;
+#[cfg(feature = "scd4x")]
+pub mod scd4x;
+#[cfg(feature = "diff_pressure")]
+pub mod sdp8xx;
+#[cfg(any(feature = "diff_pressure", feature = "hcho", feature = "scd4x"))]
+pub mod sensirion;
+#[cfg(feature = "hcho")]
+pub mod sfa30;
+pub mod ssd1680;
+#[cfg(feature = "tsl2591")]
+pub mod tsl2591;
@@ -0,0 +1,155 @@
+//! Driver for the Peacefair PZEM-004T v3 energy monitor.
+//!
+//! The module talks Modbus RTU over UART (9600 baud, 8N1). All measured values are read from the
+//! input registers with a single request.
+
+use embedded_hal_0_2::{
+    blocking::delay::DelayMs,
+    serial::{Read, Write},
+};
+
+/// Baud rate of the module's UART
+pub const BAUD_RATE: u32 = 9600;
+
+/// General Modbus address, every PZEM-004T responds to it (only one device on the bus!)
+pub const ADDRESS_GENERAL: u8 = 0xf8;
+
+/// Modbus function code "Read Input Registers"
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+/// Number of input registers containing measurements
+const REGISTER_COUNT: usize = 10;
+
+/// Length of the response: Address, function, byte count, registers, CRC
+const RESPONSE_LEN: usize = 3 + 2 * REGISTER_COUNT + 2;
+
+/// Timeout for the response in ms
+const TIMEOUT_MS: u16 = 200;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// UART error
+    Serial(E),
+    /// No (complete) response was received in time
+    Timeout,
+    /// CRC checksum validation failed
+    Crc,
+    /// The module returned a Modbus exception or an unexpected response
+    InvalidResponse,
+}
+
+/// A single measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Voltage in V
+    pub voltage: f32,
+    /// Current in A
+    pub current: f32,
+    /// Active power in W
+    pub power: f32,
+    /// Cumulative energy in kWh (as counted by the module)
+    pub energy: f32,
+    /// Frequency in Hz
+    pub frequency: f32,
+    /// Power factor
+    pub power_factor: f32,
+}
+
+/// Calculate the Modbus CRC-16.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xa001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub struct Pzem004t<UART> {
+    uart: UART,
+    address: u8,
+}
+
+impl<UART, E> Pzem004t<UART>
+where
+    UART: Read<u8, Error = E> + Write<u8, Error = E>,
+{
+    pub fn new(uart: UART, address: u8) -> Self {
+        Self { uart, address }
+    }
+
+    /// Read all measurements.
+    pub fn measure<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>> {
+        // Discard stale bytes
+        while self.uart.read().is_ok() {}
+
+        // Send request
+        let mut request = [
+            self.address,
+            READ_INPUT_REGISTERS,
+            0x00,
+            0x00,
+            0x00,
+            REGISTER_COUNT as u8,
+            0,
+            0,
+        ];
+        let [crc_l, crc_h] = crc16(&request[..6]).to_le_bytes();
+        request[6] = crc_l;
+        request[7] = crc_h;
+        for byte in request {
+            nb::block!(self.uart.write(byte)).map_err(Error::Serial)?;
+        }
+        nb::block!(self.uart.flush()).map_err(Error::Serial)?;
+
+        // Receive response
+        let mut response = [0; RESPONSE_LEN];
+        let mut received = 0;
+        let mut waited_ms = 0;
+        while received < RESPONSE_LEN {
+            match self.uart.read() {
+                Ok(byte) => {
+                    response[received] = byte;
+                    received += 1;
+                }
+                Err(nb::Error::WouldBlock) if waited_ms < TIMEOUT_MS => {
+                    delay.delay_ms(1);
+                    waited_ms += 1;
+                }
+                Err(nb::Error::WouldBlock) => return Err(Error::Timeout),
+                Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
+            }
+            // An exception response only has 5 bytes, stop early
+            if received == 5 && response[1] & 0x80 != 0 {
+                return Err(Error::InvalidResponse);
+            }
+        }
+        let crc = u16::from_le_bytes([response[RESPONSE_LEN - 2], response[RESPONSE_LEN - 1]]);
+        if crc16(&response[..RESPONSE_LEN - 2]) != crc {
+            return Err(Error::Crc);
+        }
+        if response[1] != READ_INPUT_REGISTERS || response[2] as usize != 2 * REGISTER_COUNT {
+            return Err(Error::InvalidResponse);
+        }
+
+        // Parse registers. 32 bit values are sent as low word first.
+        let data = &response[3..3 + 2 * REGISTER_COUNT];
+        let register = |n: usize| u16::from_be_bytes([data[2 * n], data[2 * n + 1]]) as u32;
+        let register32 = |n: usize| register(n) | register(n + 1) << 16;
+        Ok(Measurement {
+            voltage: register(0) as f32 * 0.1,
+            current: register32(1) as f32 * 0.001,
+            power: register32(3) as f32 * 0.1,
+            energy: register32(5) as f32 * 0.001,
+            frequency: register(7) as f32 * 0.1,
+            power_factor: register(8) as f32 * 0.01,
+        })
+    }
+}
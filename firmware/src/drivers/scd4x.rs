@@ -0,0 +1,191 @@
+//! Driver for the Sensirion SCD40/SCD41 photoacoustic CO₂ sensor.
+//!
+//! The CO₂ concentration depends on the ambient pressure. The sensor can compensate for it,
+//! either with a fixed altitude (only settable while idle) or with the live ambient pressure
+//! (settable at any time, overrides the altitude).
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+
+use super::sensirion;
+
+/// I²C address of the SCD4x
+pub const ADDRESS: u8 = 0x62;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// CRC checksum validation failed
+    Crc,
+    /// The forced recalibration failed (the sensor was not operated long enough before)
+    RecalibrationFailed,
+}
+
+#[derive(Copy, Clone)]
+enum Command {
+    StartPeriodicMeasurement,
+    ReadMeasurement,
+    StopPeriodicMeasurement,
+    SetSensorAltitude,
+    SetAmbientPressure,
+    GetDataReadyStatus,
+    PerformForcedRecalibration,
+    GetSerialNumber,
+}
+
+impl Command {
+    fn as_bytes(self) -> [u8; 2] {
+        match self {
+            Self::StartPeriodicMeasurement => [0x21, 0xb1],
+            Self::ReadMeasurement => [0xec, 0x05],
+            Self::StopPeriodicMeasurement => [0x3f, 0x86],
+            Self::SetSensorAltitude => [0x24, 0x27],
+            Self::SetAmbientPressure => [0xe0, 0x00],
+            Self::GetDataReadyStatus => [0xe4, 0xb8],
+            Self::PerformForcedRecalibration => [0x36, 0x2f],
+            Self::GetSerialNumber => [0x36, 0x82],
+        }
+    }
+}
+
+/// A single CO₂ measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// CO₂ concentration in PPM
+    pub co2_ppm: u16,
+    /// Temperature in °C
+    pub temperature: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+}
+
+pub struct Scd4x<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C, E> Scd4x<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    fn send_command(&mut self, command: Command) -> Result<(), Error<E>> {
+        self.i2c
+            .write(ADDRESS, &command.as_bytes())
+            .map_err(Error::I2c)
+    }
+
+    fn send_command_with_arg(&mut self, command: Command, arg: u16) -> Result<(), Error<E>> {
+        let [cmd_h, cmd_l] = command.as_bytes();
+        let [arg_h, arg_l] = arg.to_be_bytes();
+        let crc = sensirion::crc8(&[arg_h, arg_l]);
+        self.i2c
+            .write(ADDRESS, &[cmd_h, cmd_l, arg_h, arg_l, crc])
+            .map_err(Error::I2c)
+    }
+
+    fn read_words(&mut self, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c.read(ADDRESS, buf).map_err(Error::I2c)?;
+        if !sensirion::check_crc(buf) {
+            return Err(Error::Crc);
+        }
+        Ok(())
+    }
+
+    /// Read the 48 bit serial number.
+    pub fn serial_number<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<u64, Error<E>> {
+        self.send_command(Command::GetSerialNumber)?;
+        delay.delay_ms(1);
+        let mut buf = [0; 9];
+        self.read_words(&mut buf)?;
+        Ok((0..3).fold(0, |serial, n| {
+            serial << 16 | sensirion::word(&buf, n) as u64
+        }))
+    }
+
+    /// Start periodic measurement. New values are available every 5 s.
+    pub fn start_periodic_measurement(&mut self) -> Result<(), Error<E>> {
+        self.send_command(Command::StartPeriodicMeasurement)
+    }
+
+    /// Stop periodic measurement. The sensor accepts other commands again after 500 ms.
+    pub fn stop_periodic_measurement<D: DelayMs<u16>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<(), Error<E>> {
+        self.send_command(Command::StopPeriodicMeasurement)?;
+        delay.delay_ms(500);
+        Ok(())
+    }
+
+    /// Set the altitude in m above sea level. Only possible while periodic measurement is stopped.
+    pub fn set_sensor_altitude<D: DelayMs<u16>>(
+        &mut self,
+        altitude: u16,
+        delay: &mut D,
+    ) -> Result<(), Error<E>> {
+        self.send_command_with_arg(Command::SetSensorAltitude, altitude)?;
+        delay.delay_ms(1);
+        Ok(())
+    }
+
+    /// Set the ambient pressure in hPa. This is possible during periodic measurement, and
+    /// overrides the altitude.
+    pub fn set_ambient_pressure(&mut self, pressure: f32) -> Result<(), Error<E>> {
+        self.send_command_with_arg(Command::SetAmbientPressure, pressure.round() as u16)
+    }
+
+    /// Return whether a new measurement is available.
+    pub fn data_ready<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<bool, Error<E>> {
+        self.send_command(Command::GetDataReadyStatus)?;
+        delay.delay_ms(1);
+        let mut buf = [0; 3];
+        self.read_words(&mut buf)?;
+        Ok(sensirion::word(&buf, 0) & 0x07ff != 0)
+    }
+
+    /// Read the latest measurement.
+    pub fn read_measurement<D: DelayMs<u16>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurement, Error<E>> {
+        self.send_command(Command::ReadMeasurement)?;
+        delay.delay_ms(1);
+        let mut buf = [0; 9];
+        self.read_words(&mut buf)?;
+        Ok(Measurement {
+            co2_ppm: sensirion::word(&buf, 0),
+            temperature: -45.0 + 175.0 * sensirion::word(&buf, 1) as f32 / 65535.0,
+            humidity: 100.0 * sensirion::word(&buf, 2) as f32 / 65535.0,
+        })
+    }
+
+    /// Recalibrate the sensor to a known CO₂ concentration (e.g. ~420 PPM outdoors). Return the
+    /// applied correction in PPM.
+    ///
+    /// The sensor must have been operated in the reference environment for at least 3 minutes.
+    /// Periodic measurement is stopped for the recalibration and restarted afterwards.
+    pub fn forced_recalibration<D: DelayMs<u16>>(
+        &mut self,
+        target_ppm: u16,
+        delay: &mut D,
+    ) -> Result<i32, Error<E>> {
+        self.stop_periodic_measurement(delay)?;
+        self.send_command_with_arg(Command::PerformForcedRecalibration, target_ppm)?;
+        delay.delay_ms(400);
+        let mut buf = [0; 3];
+        let result = self.read_words(&mut buf);
+        self.start_periodic_measurement()?;
+        result?;
+        match sensirion::word(&buf, 0) {
+            0xffff => Err(Error::RecalibrationFailed),
+            correction => Ok(correction as i32 - 0x8000),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Driver for the Sensirion SDP800 / SDP810 differential pressure sensors.
+//!
+//! The sensor is operated in continuous measurement mode with differential pressure temperature
+//! compensation and "average till read", so every read returns the average since the last read.
+
+use embedded_hal_0_2::blocking::i2c::{Read, Write};
+
+use super::sensirion;
+
+/// I²C address of the SDP8x0 variants (the SDP8x1 variants use 0x26)
+pub const ADDRESS_SDP8X0: u8 = 0x25;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// CRC checksum validation failed
+    Crc,
+}
+
+#[derive(Copy, Clone)]
+enum Command {
+    /// Continuous measurement, differential pressure temperature compensation, average till read
+    StartContinuousDiffPressureAveraged,
+    StopContinuousMeasurement,
+    ReadProductIdentifier1,
+    ReadProductIdentifier2,
+}
+
+impl Command {
+    fn as_bytes(self) -> [u8; 2] {
+        match self {
+            Self::StartContinuousDiffPressureAveraged => [0x36, 0x15],
+            Self::StopContinuousMeasurement => [0x3f, 0xf9],
+            Self::ReadProductIdentifier1 => [0x36, 0x7c],
+            Self::ReadProductIdentifier2 => [0xe1, 0x02],
+        }
+    }
+}
+
+/// A single differential pressure measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Differential pressure in Pa
+    pub differential_pressure: f32,
+    /// Sensor temperature in °C
+    pub temperature: f32,
+}
+
+pub struct Sdp8xx<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Sdp8xx<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    fn send_command(&mut self, command: Command) -> Result<(), Error<E>> {
+        self.i2c
+            .write(self.address, &command.as_bytes())
+            .map_err(Error::I2c)
+    }
+
+    /// Read the product number and the serial number.
+    ///
+    /// Note: This only works while the sensor is not in continuous measurement mode.
+    pub fn product_identifier(&mut self) -> Result<(u32, u64), Error<E>> {
+        self.send_command(Command::ReadProductIdentifier1)?;
+        self.send_command(Command::ReadProductIdentifier2)?;
+        let mut buf = [0; 18];
+        self.i2c.read(self.address, &mut buf).map_err(Error::I2c)?;
+        if !sensirion::check_crc(&buf) {
+            return Err(Error::Crc);
+        }
+        let product = (0..2).fold(0u32, |acc, n| acc << 16 | sensirion::word(&buf, n) as u32);
+        let serial = (2..6).fold(0u64, |acc, n| acc << 16 | sensirion::word(&buf, n) as u64);
+        Ok((product, serial))
+    }
+
+    /// Start continuous measurement. The first result is available after 8 ms.
+    pub fn start_continuous_measurement(&mut self) -> Result<(), Error<E>> {
+        self.send_command(Command::StartContinuousDiffPressureAveraged)
+    }
+
+    /// Stop continuous measurement. The sensor accepts new commands after 500 µs.
+    pub fn stop_continuous_measurement(&mut self) -> Result<(), Error<E>> {
+        self.send_command(Command::StopContinuousMeasurement)
+    }
+
+    /// Read the measurement averaged since the last read.
+    pub fn read_measurement(&mut self) -> Result<Measurement, Error<E>> {
+        let mut buf = [0; 9];
+        self.i2c.read(self.address, &mut buf).map_err(Error::I2c)?;
+        if !sensirion::check_crc(&buf) {
+            return Err(Error::Crc);
+        }
+        let pressure_raw = sensirion::word(&buf, 0) as i16;
+        let temperature_raw = sensirion::word(&buf, 1) as i16;
+        let scale_factor = sensirion::word(&buf, 2);
+        Ok(Measurement {
+            differential_pressure: pressure_raw as f32 / scale_factor as f32,
+            temperature: temperature_raw as f32 / 200.0,
+        })
+    }
+}
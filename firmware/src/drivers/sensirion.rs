@@ -0,0 +1,30 @@
+//! Helpers shared by the Sensirion sensor drivers.
+
+/// Calculate the CRC-8 checksum used by Sensirion sensors (polynomial 0x31, init 0xff).
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xff;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ 0x31;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Verify the CRC of every word in a response buffer.
+///
+/// Sensirion sensors return data as 16-bit big endian words, each followed by a CRC byte.
+pub fn check_crc(buf: &[u8]) -> bool {
+    buf.chunks(3)
+        .all(|chunk| chunk.len() == 3 && crc8(&chunk[..2]) == chunk[2])
+}
+
+/// Return the n-th 16-bit data word of a response buffer.
+pub fn word(buf: &[u8], n: usize) -> u16 {
+    u16::from_be_bytes([buf[n * 3], buf[n * 3 + 1]])
+}
@@ -0,0 +1,119 @@
+//! Driver for the Sensirion SFA30 formaldehyde sensor.
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayMs,
+    i2c::{Read, Write},
+};
+
+use super::sensirion;
+
+/// I²C address of the SFA30
+pub const ADDRESS: u8 = 0x5d;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// CRC checksum validation failed
+    Crc,
+}
+
+#[derive(Copy, Clone)]
+enum Command {
+    StartContinuousMeasurement,
+    ReadMeasuredValues,
+    GetDeviceMarking,
+    DeviceReset,
+}
+
+impl Command {
+    fn as_bytes(self) -> [u8; 2] {
+        match self {
+            Self::StartContinuousMeasurement => [0x00, 0x06],
+            Self::ReadMeasuredValues => [0x03, 0x27],
+            Self::GetDeviceMarking => [0xd0, 0x60],
+            Self::DeviceReset => [0xd3, 0x04],
+        }
+    }
+}
+
+/// A single formaldehyde measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Formaldehyde concentration in PPB
+    pub hcho_ppb: f32,
+    /// Relative humidity in %
+    pub humidity: f32,
+    /// Temperature in °C
+    pub temperature: f32,
+}
+
+pub struct Sfa30<I2C> {
+    i2c: I2C,
+}
+
+impl<I2C, E> Sfa30<I2C>
+where
+    I2C: Read<Error = E> + Write<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self { i2c }
+    }
+
+    fn send_command(&mut self, command: Command) -> Result<(), Error<E>> {
+        self.i2c
+            .write(ADDRESS, &command.as_bytes())
+            .map_err(Error::I2c)
+    }
+
+    fn read_words(&mut self, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c.read(ADDRESS, buf).map_err(Error::I2c)?;
+        if !sensirion::check_crc(buf) {
+            return Err(Error::Crc);
+        }
+        Ok(())
+    }
+
+    /// Reset the sensor. It is ready to accept commands again after 100 ms.
+    pub fn reset<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<(), Error<E>> {
+        self.send_command(Command::DeviceReset)?;
+        delay.delay_ms(100);
+        Ok(())
+    }
+
+    /// Read the device marking, which contains the serial number.
+    pub fn device_marking<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<String, Error<E>> {
+        self.send_command(Command::GetDeviceMarking)?;
+        delay.delay_ms(2);
+        let mut buf = [0; 48];
+        self.read_words(&mut buf)?;
+        let marking = (0..16)
+            .flat_map(|n| sensirion::word(&buf, n).to_be_bytes())
+            .take_while(|&byte| byte != 0)
+            .map(char::from)
+            .collect();
+        Ok(marking)
+    }
+
+    /// Start continuous measurement. New values are available every 500 ms.
+    pub fn start_continuous_measurement(&mut self) -> Result<(), Error<E>> {
+        self.send_command(Command::StartContinuousMeasurement)
+    }
+
+    /// Read the latest measurement.
+    pub fn read_measurement<D: DelayMs<u16>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<Measurement, Error<E>> {
+        self.send_command(Command::ReadMeasuredValues)?;
+        delay.delay_ms(5);
+        let mut buf = [0; 9];
+        self.read_words(&mut buf)?;
+        Ok(Measurement {
+            hcho_ppb: sensirion::word(&buf, 0) as i16 as f32 / 5.0,
+            humidity: sensirion::word(&buf, 1) as i16 as f32 / 100.0,
+            temperature: sensirion::word(&buf, 2) as i16 as f32 / 200.0,
+        })
+    }
+}
@@ -0,0 +1,133 @@
+//! Driver for the Solomon Systech SSD1680 e-paper controller (e.g. 2.13" 122x250 panels).
+//!
+//! Write-only SPI (mode 0, max. 20 MHz) with a separate data/command pin. The controller is busy
+//! while the BUSY pin is high. The reset pin is not used, the controller is reset in software.
+//!
+//! Every update initializes the controller from scratch and redraws the whole panel, so no state
+//! needs to be kept between updates (e.g. across deep sleep). After the update, the analog
+//! circuitry and the clock of the controller are disabled, the panel keeps showing the image
+//! without power.
+
+use embedded_hal_0_2::{
+    blocking::{delay::DelayMs, spi::Write},
+    digital::v2::{InputPin, OutputPin},
+};
+
+/// Width of the panel in pixels (source lines)
+pub const WIDTH: usize = 122;
+
+/// Height of the panel in pixels (gate lines)
+pub const HEIGHT: usize = 250;
+
+/// Bytes per row in the frame buffer (the width padded to 128 pixels). Every byte contains 8
+/// horizontal pixels, MSB first.
+pub const ROW_SIZE: usize = 16;
+
+/// Size of the frame buffer in bytes
+pub const BUFFER_SIZE: usize = ROW_SIZE * HEIGHT;
+
+/// Maximum time to wait for the controller, a full refresh takes about 2-4 s
+const BUSY_TIMEOUT_MS: u32 = 10_000;
+
+const CMD_DRIVER_OUTPUT_CONTROL: u8 = 0x01;
+const CMD_DATA_ENTRY_MODE: u8 = 0x11;
+const CMD_SW_RESET: u8 = 0x12;
+const CMD_TEMPERATURE_SENSOR: u8 = 0x18;
+const CMD_MASTER_ACTIVATION: u8 = 0x20;
+const CMD_DISPLAY_UPDATE_CONTROL_1: u8 = 0x21;
+const CMD_DISPLAY_UPDATE_CONTROL_2: u8 = 0x22;
+const CMD_WRITE_RAM_BW: u8 = 0x24;
+const CMD_BORDER_WAVEFORM: u8 = 0x3c;
+const CMD_RAM_X_RANGE: u8 = 0x44;
+const CMD_RAM_Y_RANGE: u8 = 0x45;
+const CMD_RAM_X_COUNTER: u8 = 0x4e;
+const CMD_RAM_Y_COUNTER: u8 = 0x4f;
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<SE, PE> {
+    /// SPI bus error
+    Spi(SE),
+    /// GPIO error
+    Pin(PE),
+    /// The controller did not become ready in time
+    Timeout,
+}
+
+pub struct Ssd1680<SPI, DC, BUSY> {
+    spi: SPI,
+    dc: DC,
+    busy: BUSY,
+}
+
+impl<SPI, SE, DC, BUSY, PE> Ssd1680<SPI, DC, BUSY>
+where
+    SPI: Write<u8, Error = SE>,
+    DC: OutputPin<Error = PE>,
+    BUSY: InputPin<Error = PE>,
+{
+    pub fn new(spi: SPI, dc: DC, busy: BUSY) -> Self {
+        Self { spi, dc, busy }
+    }
+
+    /// Show the frame buffer (see [`BUFFER_SIZE`]) on the panel. A set bit is a white pixel.
+    pub fn update(
+        &mut self,
+        frame: &[u8; BUFFER_SIZE],
+        delay: &mut impl DelayMs<u16>,
+    ) -> Result<(), Error<SE, PE>> {
+        self.command(CMD_SW_RESET, &[])?;
+        delay.delay_ms(10);
+        self.wait_until_idle(delay)?;
+
+        let last_gate = (HEIGHT - 1) as u16;
+        self.command(
+            CMD_DRIVER_OUTPUT_CONTROL,
+            &[last_gate as u8, (last_gate >> 8) as u8, 0x00],
+        )?;
+        // X and Y increment, the address counter is updated in X direction
+        self.command(CMD_DATA_ENTRY_MODE, &[0x03])?;
+        self.command(CMD_RAM_X_RANGE, &[0x00, (ROW_SIZE - 1) as u8])?;
+        self.command(
+            CMD_RAM_Y_RANGE,
+            &[0x00, 0x00, last_gate as u8, (last_gate >> 8) as u8],
+        )?;
+        self.command(CMD_BORDER_WAVEFORM, &[0x05])?;
+        self.command(CMD_DISPLAY_UPDATE_CONTROL_1, &[0x00, 0x80])?;
+        // Internal temperature sensor
+        self.command(CMD_TEMPERATURE_SENSOR, &[0x80])?;
+        self.command(CMD_RAM_X_COUNTER, &[0x00])?;
+        self.command(CMD_RAM_Y_COUNTER, &[0x00, 0x00])?;
+        self.wait_until_idle(delay)?;
+
+        self.command(CMD_WRITE_RAM_BW, frame)?;
+
+        // Full update: Enable clock and analog, load the LUT, display, then disable analog and
+        // clock again
+        self.command(CMD_DISPLAY_UPDATE_CONTROL_2, &[0xf7])?;
+        self.command(CMD_MASTER_ACTIVATION, &[])?;
+        self.wait_until_idle(delay)
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), Error<SE, PE>> {
+        self.dc.set_low().map_err(Error::Pin)?;
+        self.spi.write(&[command]).map_err(Error::Spi)?;
+        if !data.is_empty() {
+            self.dc.set_high().map_err(Error::Pin)?;
+            self.spi.write(data).map_err(Error::Spi)?;
+        }
+        Ok(())
+    }
+
+    fn wait_until_idle(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), Error<SE, PE>> {
+        let mut waited_ms = 0;
+        while self.busy.is_high().map_err(Error::Pin)? {
+            if waited_ms >= BUSY_TIMEOUT_MS {
+                return Err(Error::Timeout);
+            }
+            delay.delay_ms(10);
+            waited_ms += 10;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Driver for the ams TSL2591 high dynamic range light sensor.
+//!
+//! The gain is adjusted automatically: Saturated readings are repeated with a lower gain, very
+//! low readings (e.g. in moonlight) with a higher gain.
+
+use embedded_hal_0_2::blocking::{
+    delay::DelayMs,
+    i2c::{Write, WriteRead},
+};
+
+/// I²C address of the TSL2591
+pub const ADDRESS: u8 = 0x29;
+
+/// Expected value of the ID register
+const ID: u8 = 0x50;
+
+/// Command bit, must be set for every register access (normal operation)
+const COMMAND: u8 = 0xa0;
+
+mod reg {
+    pub const ENABLE: u8 = 0x00;
+    pub const CONFIG: u8 = 0x01;
+    pub const ID: u8 = 0x12;
+    pub const STATUS: u8 = 0x13;
+    pub const C0DATAL: u8 = 0x14;
+}
+
+mod enable {
+    pub const PON: u8 = 1 << 0;
+    pub const AEN: u8 = 1 << 1;
+}
+
+/// AVALID flag in the STATUS register
+const AVALID: u8 = 1 << 0;
+
+/// Integration time 200 ms (ATIME register value 1)
+const ATIME: u8 = 1;
+const INTEGRATION_TIME_MS: u16 = 200;
+
+/// Maximum ADC count for integration times above 100 ms
+const MAX_COUNT: u16 = 0xffff;
+
+/// Readings below this count (on the full spectrum channel) are repeated with a higher gain
+const LOW_COUNT: u16 = 100;
+
+/// Lux coefficient from the ams application note
+const LUX_DF: f32 = 408.0;
+
+/// Maximum number of gain adjustments per measurement
+const MAX_ADJUSTMENTS: usize = 3;
+
+/// Analog gain
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Gain {
+    /// 1x
+    Low,
+    /// 25x
+    Medium,
+    /// 428x
+    High,
+    /// 9876x
+    Max,
+}
+
+impl Gain {
+    fn bits(self) -> u8 {
+        match self {
+            Self::Low => 0b00 << 4,
+            Self::Medium => 0b01 << 4,
+            Self::High => 0b10 << 4,
+            Self::Max => 0b11 << 4,
+        }
+    }
+
+    /// Return the gain factor.
+    pub fn factor(self) -> f32 {
+        match self {
+            Self::Low => 1.0,
+            Self::Medium => 25.0,
+            Self::High => 428.0,
+            Self::Max => 9876.0,
+        }
+    }
+
+    fn lower(self) -> Option<Self> {
+        match self {
+            Self::Low => None,
+            Self::Medium => Some(Self::Low),
+            Self::High => Some(Self::Medium),
+            Self::Max => Some(Self::High),
+        }
+    }
+
+    fn higher(self) -> Option<Self> {
+        match self {
+            Self::Low => Some(Self::Medium),
+            Self::Medium => Some(Self::High),
+            Self::High => Some(Self::Max),
+            Self::Max => None,
+        }
+    }
+}
+
+/// Driver errors
+#[derive(Debug)]
+pub enum Error<E> {
+    /// I²C bus error
+    I2c(E),
+    /// The ID does not match a TSL2591
+    InvalidId(u8),
+    /// The measurement did not complete in time
+    Timeout,
+    /// The sensor is saturated even at the lowest gain
+    Saturated,
+}
+
+/// A single light measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Illuminance in Lux
+    pub lux: f32,
+    /// Gain used for this measurement
+    pub gain: Gain,
+}
+
+pub struct Tsl2591<I2C> {
+    i2c: I2C,
+    gain: Gain,
+}
+
+impl<I2C, E> Tsl2591<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            gain: Gain::Medium,
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), Error<E>> {
+        self.i2c
+            .write(ADDRESS, &[COMMAND | register, value])
+            .map_err(Error::I2c)
+    }
+
+    fn read_registers(&mut self, register: u8, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.i2c
+            .write_read(ADDRESS, &[COMMAND | register], buf)
+            .map_err(Error::I2c)
+    }
+
+    fn read_register(&mut self, register: u8) -> Result<u8, Error<E>> {
+        let mut buf = [0];
+        self.read_registers(register, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Verify the ID and configure gain and integration time.
+    ///
+    /// The sensor stays powered down between measurements.
+    pub fn init(&mut self) -> Result<(), Error<E>> {
+        let id = self.read_register(reg::ID)?;
+        if id != ID {
+            return Err(Error::InvalidId(id));
+        }
+        self.write_register(reg::CONFIG, self.gain.bits() | ATIME)?;
+        self.write_register(reg::ENABLE, 0)
+    }
+
+    /// Run a single ALS cycle with the current gain and return the raw (full, ir) counts.
+    fn measure_raw<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<(u16, u16), Error<E>> {
+        self.write_register(reg::CONFIG, self.gain.bits() | ATIME)?;
+        self.write_register(reg::ENABLE, enable::PON | enable::AEN)?;
+        delay.delay_ms(INTEGRATION_TIME_MS);
+
+        let mut valid = false;
+        for _ in 0..10 {
+            if self.read_register(reg::STATUS)? & AVALID != 0 {
+                valid = true;
+                break;
+            }
+            delay.delay_ms(10);
+        }
+
+        let result = if valid {
+            let mut buf = [0; 4];
+            self.read_registers(reg::C0DATAL, &mut buf)?;
+            Ok((
+                u16::from_le_bytes([buf[0], buf[1]]),
+                u16::from_le_bytes([buf[2], buf[3]]),
+            ))
+        } else {
+            Err(Error::Timeout)
+        };
+
+        self.write_register(reg::ENABLE, 0)?;
+        result
+    }
+
+    /// Measure the illuminance, adjusting the gain if the reading is saturated or very low.
+    ///
+    /// The chosen gain is kept for the next measurement. Depending on the number of gain
+    /// adjustments, this takes between 200 and 800 ms.
+    pub fn measure<D: DelayMs<u16>>(&mut self, delay: &mut D) -> Result<Measurement, Error<E>> {
+        let mut adjustments = 0;
+        loop {
+            let (full, ir) = self.measure_raw(delay)?;
+            let saturated = full == MAX_COUNT || ir == MAX_COUNT;
+            let next_gain = if saturated {
+                self.gain.lower()
+            } else if full < LOW_COUNT {
+                self.gain.higher()
+            } else {
+                None
+            };
+            match next_gain {
+                Some(gain) if adjustments < MAX_ADJUSTMENTS => {
+                    self.gain = gain;
+                    adjustments += 1;
+                }
+                _ if saturated => return Err(Error::Saturated),
+                _ => {
+                    return Ok(Measurement {
+                        lux: self.lux(full, ir),
+                        gain: self.gain,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Calculate the illuminance from the raw channel counts.
+    fn lux(&self, full: u16, ir: u16) -> f32 {
+        if full == 0 || ir >= full {
+            return 0.0;
+        }
+        let (full, ir) = (full as f32, ir as f32);
+        let counts_per_lux = INTEGRATION_TIME_MS as f32 * self.gain.factor() / LUX_DF;
+        (full - ir) * (1.0 - ir / full) / counts_per_lux
+    }
+}
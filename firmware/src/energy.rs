@@ -0,0 +1,113 @@
+//! Energy measurement with the S0 pulse output of an electricity meter.
+//!
+//! The meter emits a fixed number of impulses per kWh. The instantaneous power is derived from the
+//! spacing between pulses, the cumulative energy from the pulse count. The energy total is
+//! persisted in NVS periodically, so that it survives reboots.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+
+use crate::pulse::PulseCounter;
+
+/// NVS namespace and key of the energy total
+const NAMESPACE: &str = "energy";
+const KEY: &str = "s0_wh";
+
+/// S0 pulses are at least 30 ms long, shorter pulses are bounces
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+/// An energy measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Instantaneous power in W (`None` if there weren't two pulses yet)
+    pub power: Option<f32>,
+    /// Cumulative energy in kWh
+    pub energy: f64,
+}
+
+pub struct EnergyMeter<'d> {
+    counter: PulseCounter<'d>,
+    impulses_per_kwh: f64,
+    nvs: EspNvs<NvsDefault>,
+    /// Cumulative energy in Wh
+    total_wh: f64,
+    save_interval: Duration,
+    last_save: Instant,
+    /// Whether the energy total changed since it was last saved
+    dirty: bool,
+}
+
+impl<'d> EnergyMeter<'d> {
+    /// Create a new instance, restoring the energy total from NVS. The energy total is saved
+    /// every `save_interval` (if it changed).
+    pub fn new(
+        pin: AnyIOPin,
+        impulses_per_kwh: u32,
+        partition: EspDefaultNvsPartition,
+        save_interval: Duration,
+    ) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        let mut buf = [0; 8];
+        let total_wh = match nvs.get_raw(KEY, &mut buf)? {
+            Some(stored) if stored.len() == 8 => f64::from_le_bytes(buf),
+            _ => 0.0,
+        };
+        println!("  Restored energy total: {:.3} kWh", total_wh / 1000.0);
+        Ok(Self {
+            counter: PulseCounter::new(pin, DEBOUNCE)?,
+            impulses_per_kwh: impulses_per_kwh as f64,
+            nvs,
+            total_wh,
+            save_interval,
+            last_save: Instant::now(),
+            dirty: false,
+        })
+    }
+
+    /// Add the pulses since the last call to the energy total and return the current measurement.
+    pub fn measure(&mut self) -> Measurement {
+        self.count_pulses();
+        if self.dirty && self.last_save.elapsed() >= self.save_interval {
+            self.save();
+        }
+
+        // One pulse corresponds to 1/impulses_per_kwh kWh, i.e. 3600 / impulses_per_kwh kWs
+        let power = self
+            .counter
+            .pulse_spacing()
+            .map(|spacing| (3_600_000.0 / (self.impulses_per_kwh * spacing.as_secs_f64())) as f32);
+        Measurement {
+            power,
+            energy: self.total_wh / 1000.0,
+        }
+    }
+
+    /// Save the energy total to NVS if it changed since it was last saved, e.g. before a restart.
+    pub fn save_pending(&mut self) {
+        self.count_pulses();
+        if self.dirty {
+            self.save();
+        }
+    }
+
+    /// Add the pulses since the last call to the energy total.
+    fn count_pulses(&mut self) {
+        let count = self.counter.take_count();
+        if count > 0 {
+            self.total_wh += count as f64 * 1000.0 / self.impulses_per_kwh;
+            self.dirty = true;
+        }
+    }
+
+    /// Save the energy total to NVS.
+    fn save(&mut self) {
+        self.last_save = Instant::now();
+        match self.nvs.set_raw(KEY, &self.total_wh.to_le_bytes()) {
+            Ok(_) => self.dirty = false,
+            Err(e) => eprintln!("Energy: Could not save energy total: {}", e),
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Subset of the ESPHome native API, so that Home Assistant can adopt the device with its ESPHome
+//! integration, without an MQTT broker.
+//!
+//! Only the plaintext protocol is supported (no Noise encryption), with the legacy API password
+//! if `esphome.password` is set. Every measured metric is a sensor entity. After the client has
+//! subscribed to the states, the current states are sent, and then every state that changes.
+//! Services, logs and Home Assistant states are not supported, the corresponding requests are
+//! ignored.
+//!
+//! Every message is framed as `0x00`, the length of the payload (varint), the message type
+//! (varint) and the payload (protobuf). The protobuf encoding is implemented here, limited to the
+//! field types that are used by the supported messages.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{Config, Metric},
+    history::History,
+    SENSILO_NAME, VERSION,
+};
+
+/// Stack size of the server and connection threads
+const STACK_SIZE: usize = 8192;
+
+/// Maximum number of simultaneous connections
+const MAX_CONNECTIONS: usize = 2;
+
+/// Interval at which changed states are sent to subscribed clients
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeout for the rest of a message, once the first byte has been received
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections are closed after this time without a message (Home Assistant pings every minute)
+const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Maximum payload size of a request
+const MAX_PAYLOAD_SIZE: usize = 1024;
+
+/// Implemented API version
+const API_VERSION: (u32, u32) = (1, 9);
+
+// Message types
+const HELLO_REQUEST: u32 = 1;
+const HELLO_RESPONSE: u32 = 2;
+const CONNECT_REQUEST: u32 = 3;
+const CONNECT_RESPONSE: u32 = 4;
+const DISCONNECT_REQUEST: u32 = 5;
+const DISCONNECT_RESPONSE: u32 = 6;
+const PING_REQUEST: u32 = 7;
+const PING_RESPONSE: u32 = 8;
+const DEVICE_INFO_REQUEST: u32 = 9;
+const DEVICE_INFO_RESPONSE: u32 = 10;
+const LIST_ENTITIES_REQUEST: u32 = 11;
+const LIST_ENTITIES_SENSOR_RESPONSE: u32 = 16;
+const LIST_ENTITIES_DONE_RESPONSE: u32 = 19;
+const SUBSCRIBE_STATES_REQUEST: u32 = 20;
+const SENSOR_STATE_RESPONSE: u32 = 25;
+
+/// State class of sensors: measurement
+const STATE_CLASS_MEASUREMENT: u32 = 1;
+
+/// Start the server thread.
+pub fn spawn(config: Arc<Config>, history: Arc<Mutex<History>>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", config.esphome.port))?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mac = mac_address();
+    thread::Builder::new()
+        .name("esphome".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("ESPHome: ERROR: Could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+                if connections.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    eprintln!("ESPHome: Too many connections, closing");
+                    continue;
+                }
+                connections.fetch_add(1, Ordering::Relaxed);
+                let mut connection = Connection {
+                    stream,
+                    config: config.clone(),
+                    history: history.clone(),
+                    mac: mac.clone(),
+                    authenticated: config.esphome.password.is_none(),
+                    sent: None,
+                };
+                let counter = connections.clone();
+                let result = thread::Builder::new()
+                    .name("esphome-conn".into())
+                    .stack_size(STACK_SIZE)
+                    .spawn(move || {
+                        if let Err(e) = connection.serve() {
+                            eprintln!("ESPHome: ERROR: {}", e);
+                        }
+                        counter.fetch_sub(1, Ordering::Relaxed);
+                    });
+                if let Err(e) = result {
+                    eprintln!("ESPHome: ERROR: Could not start connection thread: {}", e);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Return the Wi-Fi MAC address, formatted as `AA:BB:CC:DD:EE:FF`.
+fn mac_address() -> String {
+    let mut mac = [0u8; 6];
+    // Safety: The buffer has room for the 6 bytes of the address
+    unsafe {
+        esp_idf_sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+        )
+    };
+    let bytes: Vec<String> = mac.iter().map(|byte| format!("{:02X}", byte)).collect();
+    bytes.join(":")
+}
+
+struct Connection {
+    stream: TcpStream,
+    config: Arc<Config>,
+    history: Arc<Mutex<History>>,
+    mac: String,
+    authenticated: bool,
+    /// States that were last sent, by position in `Metric::ALL`. `None` until the client has
+    /// subscribed to the states.
+    sent: Option<Vec<Option<f32>>>,
+}
+
+impl Connection {
+    /// Serve the requests of the connection until it's closed or idle.
+    fn serve(&mut self) -> io::Result<()> {
+        let mut last_message = Instant::now();
+        loop {
+            self.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+            let mut preamble = [0u8; 1];
+            match self.stream.read(&mut preamble) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if last_message.elapsed() > IDLE_TIMEOUT {
+                        return Ok(());
+                    }
+                    self.send_states()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            if preamble[0] != 0x00 {
+                // Noise encryption, or not the ESPHome API
+                eprintln!("ESPHome: Unsupported protocol, closing");
+                return Ok(());
+            }
+
+            self.stream.set_read_timeout(Some(MESSAGE_TIMEOUT))?;
+            let length = read_varint(&mut self.stream)? as usize;
+            let message_type = read_varint(&mut self.stream)?;
+            if length > MAX_PAYLOAD_SIZE {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Message too large"));
+            }
+            let mut payload = vec![0; length];
+            self.stream.read_exact(&mut payload)?;
+            last_message = Instant::now();
+
+            if !self.handle(message_type, &payload)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Handle a message. Return `false` if the connection must be closed.
+    fn handle(&mut self, message_type: u32, payload: &[u8]) -> io::Result<bool> {
+        let name = self.config.name.as_deref().unwrap_or(SENSILO_NAME);
+        match message_type {
+            HELLO_REQUEST => {
+                let response = Message::new()
+                    .uint32(1, API_VERSION.0)
+                    .uint32(2, API_VERSION.1)
+                    .string(3, &format!("Sensilo {}", VERSION))
+                    .string(4, name);
+                self.write(HELLO_RESPONSE, &response)?;
+            }
+            CONNECT_REQUEST => {
+                let password = string_field(payload, 1).unwrap_or_default();
+                let valid = match self.config.esphome.password {
+                    Some(ref expected) => password == *expected,
+                    None => true,
+                };
+                self.write(CONNECT_RESPONSE, &Message::new().boolean(1, !valid))?;
+                if !valid {
+                    eprintln!("ESPHome: Invalid password, closing");
+                    return Ok(false);
+                }
+                self.authenticated = true;
+            }
+            DISCONNECT_REQUEST => {
+                self.write(DISCONNECT_RESPONSE, &Message::new())?;
+                return Ok(false);
+            }
+            PING_REQUEST => self.write(PING_RESPONSE, &Message::new())?,
+            DEVICE_INFO_REQUEST => {
+                let response = Message::new()
+                    .boolean(1, self.config.esphome.password.is_some())
+                    .string(2, name)
+                    .string(3, &self.mac)
+                    .string(4, VERSION)
+                    .string(6, "Sensilo")
+                    .string(8, "dbrgn.sensilo")
+                    .string(9, VERSION)
+                    .string(12, "Sensilo")
+                    .string(13, name);
+                self.write(DEVICE_INFO_RESPONSE, &response)?;
+            }
+            // Everything else requires authentication
+            _ if !self.authenticated => {
+                eprintln!("ESPHome: Not authenticated, closing");
+                return Ok(false);
+            }
+            LIST_ENTITIES_REQUEST => {
+                let measured: Vec<Metric> = {
+                    let history = self.history.lock().expect("Failed to lock history mutex");
+                    Metric::ALL
+                        .into_iter()
+                        .filter(|metric| history.latest(*metric).is_some())
+                        .collect()
+                };
+                let mac = self.mac.replace(':', "").to_lowercase();
+                for metric in measured {
+                    let (label, unit, device_class, decimals) = entity(metric);
+                    let response = Message::new()
+                        .string(1, metric.name())
+                        .fixed32(2, key(metric))
+                        .string(3, label)
+                        .string(4, &format!("{}-sensor-{}", mac, metric.name()))
+                        .string(6, unit)
+                        .uint32(7, decimals)
+                        .string(9, device_class)
+                        .uint32(10, STATE_CLASS_MEASUREMENT);
+                    self.write(LIST_ENTITIES_SENSOR_RESPONSE, &response)?;
+                }
+                self.write(LIST_ENTITIES_DONE_RESPONSE, &Message::new())?;
+            }
+            SUBSCRIBE_STATES_REQUEST => {
+                self.sent = Some(vec![None; Metric::ALL.len()]);
+                self.send_states()?;
+            }
+            // Unsupported requests, e.g. subscriptions to logs or services
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Send the states that changed since they were last sent, if the client has subscribed.
+    fn send_states(&mut self) -> io::Result<()> {
+        let sent = match self.sent {
+            Some(ref mut sent) => sent,
+            None => return Ok(()),
+        };
+        let mut changed = Vec::new();
+        {
+            let history = self.history.lock().expect("Failed to lock history mutex");
+            for (i, metric) in Metric::ALL.into_iter().enumerate() {
+                let value = history.latest(metric);
+                if value.is_some() && value != sent[i] {
+                    sent[i] = value;
+                    changed.push((metric, value.unwrap_or_default()));
+                }
+            }
+        }
+        for (metric, value) in changed {
+            let response = Message::new().fixed32(1, key(metric)).float(2, value);
+            self.write(SENSOR_STATE_RESPONSE, &response)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, message_type: u32, message: &Message) -> io::Result<()> {
+        let mut frame = vec![0x00];
+        write_varint(&mut frame, message.0.len() as u64);
+        write_varint(&mut frame, u64::from(message_type));
+        frame.extend_from_slice(&message.0);
+        self.stream.write_all(&frame)
+    }
+}
+
+/// Return the key of the entity of a metric, its position in `Metric::ALL` plus 1.
+fn key(metric: Metric) -> u32 {
+    Metric::ALL
+        .iter()
+        .position(|m| *m == metric)
+        .unwrap_or_default() as u32
+        + 1
+}
+
+/// Return the name, unit, Home Assistant device class and number of decimals of the entity of a
+/// metric. Also used for the MQTT discovery (see [`crate::discovery`]).
+pub fn entity(metric: Metric) -> (&'static str, &'static str, &'static str, u32) {
+    match metric {
+        Metric::Temperature => ("Temperature", "°C", "temperature", 1),
+        Metric::Humidity => ("Humidity", "%", "humidity", 0),
+        Metric::Illuminance => ("Illuminance", "lx", "illuminance", 0),
+        Metric::Co2 => ("CO2", "ppm", "carbon_dioxide", 0),
+        Metric::Co2eq => ("CO2 equivalent", "ppm", "", 0),
+        Metric::Tvoc => ("TVOC", "ppb", "volatile_organic_compounds_parts", 0),
+        Metric::Pressure => ("Pressure", "hPa", "atmospheric_pressure", 1),
+        Metric::SeaLevelPressure => ("Sea level pressure", "hPa", "atmospheric_pressure", 1),
+        Metric::Aqi => ("Air quality index", "", "aqi", 0),
+        Metric::Hcho => ("Formaldehyde", "ppb", "volatile_organic_compounds_parts", 0),
+        Metric::Occupancy => ("Occupancy", "%", "", 0),
+        Metric::DoseRate => ("Dose rate", "µSv/h", "", 2),
+        Metric::Power => ("Power", "W", "power", 0),
+        Metric::Thermocouple => ("Thermocouple", "°C", "temperature", 0),
+        Metric::Rtd => ("RTD temperature", "°C", "temperature", 1),
+    }
+}
+
+/// Protobuf message. Fields with default values are omitted, like proto3 does.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        write_varint(&mut self.0, u64::from(field << 3 | u32::from(wire_type)));
+    }
+
+    fn uint32(mut self, field: u32, value: u32) -> Self {
+        if value != 0 {
+            self.key(field, 0);
+            write_varint(&mut self.0, u64::from(value));
+        }
+        self
+    }
+
+    fn boolean(self, field: u32, value: bool) -> Self {
+        self.uint32(field, value.into())
+    }
+
+    fn fixed32(mut self, field: u32, value: u32) -> Self {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn float(self, field: u32, value: f32) -> Self {
+        self.fixed32(field, value.to_bits())
+    }
+
+    fn string(mut self, field: u32, value: &str) -> Self {
+        if !value.is_empty() {
+            self.key(field, 2);
+            write_varint(&mut self.0, value.len() as u64);
+            self.0.extend_from_slice(value.as_bytes());
+        }
+        self
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(reader: &mut impl Read) -> io::Result<u32> {
+    let mut value = 0u32;
+    for shift in (0..32).step_by(7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        value |= u32::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(ErrorKind::InvalidData, "Invalid varint"))
+}
+
+/// Decode a varint from a buffer, return the value and the remaining buffer.
+fn decode_varint(data: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+/// Return the value of a string field of a protobuf message.
+fn string_field(mut data: &[u8], field: u32) -> Option<String> {
+    while !data.is_empty() {
+        let (key, rest) = decode_varint(data)?;
+        let length = match key & 0x07 {
+            0 => {
+                let (_, after) = decode_varint(rest)?;
+                rest.len() - after.len()
+            }
+            1 => 8,
+            2 => {
+                let (length, after) = decode_varint(rest)?;
+                // Lengths that don't fit into a `usize` are past the end of the buffer anyway
+                let length = usize::try_from(length).ok()?;
+                if key >> 3 == u64::from(field) {
+                    let value = after.get(..length)?;
+                    return Some(String::from_utf8_lossy(value).into_owned());
+                }
+                data = after.get(length..)?;
+                continue;
+            }
+            5 => 4,
+            _ => return None,
+        };
+        data = rest.get(length..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Message with a varint, a fixed32 and a string field, the string last
+    fn message() -> Vec<u8> {
+        Message::new()
+            .uint32(2, 300)
+            .fixed32(3, 1)
+            .string(1, "secret")
+            .0
+    }
+
+    #[test]
+    fn string_field_is_found() {
+        assert_eq!(string_field(&message(), 1).as_deref(), Some("secret"));
+        assert_eq!(string_field(&message(), 4), None);
+    }
+
+    #[test]
+    fn truncated_message_has_no_string_field() {
+        let message = message();
+        for length in 0..message.len() {
+            assert_eq!(string_field(&message[..length], 1), None);
+        }
+    }
+
+    #[test]
+    fn malformed_message_has_no_string_field() {
+        // Unsupported wire type (start group) before the field
+        assert_eq!(string_field(&[0x13, 0x0a, 0x01, b'x'], 1), None);
+        // Length past the end of the message, also beyond 32 bits
+        assert_eq!(string_field(&[0x0a, 0x02, b'x'], 1), None);
+        assert_eq!(
+            string_field(&[0x0a, 0x81, 0x80, 0x80, 0x80, 0x10, b'x'], 1),
+            None
+        );
+        // Unterminated varint
+        assert_eq!(string_field(&[0x0a, 0x81], 1), None);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, u64::from(u32::MAX), u64::MAX] {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, value);
+            assert_eq!(decode_varint(&encoded), Some((value, [].as_slice())));
+        }
+        let mut encoded = Vec::new();
+        write_varint(&mut encoded, u64::from(u32::MAX));
+        assert_eq!(read_varint(&mut encoded.as_slice()).unwrap(), u32::MAX);
+    }
+
+    #[test]
+    fn malformed_varints_are_rejected() {
+        assert_eq!(decode_varint(&[]), None);
+        assert_eq!(decode_varint(&[0x80; 11]), None);
+        let error = read_varint(&mut [0x80, 0x80].as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        let error = read_varint(&mut [0x80; 6].as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
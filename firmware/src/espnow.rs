@@ -0,0 +1,210 @@
+//! ESP-NOW sender mode, for battery nodes next to a gateway.
+//!
+//! With `sinks.espnow`, the device doesn't associate with the access point at all: WiFi is only
+//! started on the channel of the gateway, and the readings of every measurement cycle are sent
+//! to the MAC address of the gateway via ESP-NOW. This saves the association, DHCP, DNS and the
+//! TLS handshakes, i.e. most of the time the radio is on. Without IP connectivity, there's no
+//! clock synchronization (the gateway timestamps the readings when it receives them), no local
+//! network services and no other sinks.
+//!
+//! Since an ESP-NOW frame carries at most 250 bytes, the readings of a cycle are split into
+//! frames. Every frame can be decoded on its own. All numbers are little endian, strings are
+//! UTF-8 with a length byte in front:
+//!
+//! ```text
+//! frame:   version (u8, 1) | cycle (u16) | index (u8) | count (u8) | name (string) | reading*
+//! reading: flags (u8) | measurement (string) | field (string) | [sensor (string)]
+//!          | [channel (string)] | value
+//! flags:   bits 0-1 type (0: float, 1: unsigned, 2: bool) | bit 2 sensor | bit 3 channel
+//!          | bits 4-7 decimals of a float
+//! value:   f32 (float), u32 (unsigned) or u8 (bool)
+//! ```
+//!
+//! `cycle` counts the measurement cycles since the start, so that the gateway can put the frames
+//! of a cycle together. A frame is only sent again (at most [`MAX_ATTEMPTS`] times) if the
+//! gateway didn't acknowledge it.
+
+use std::{
+    sync::mpsc::{self, Receiver},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use esp_idf_svc::espnow::{EspNow, PeerInfo, SendStatus};
+
+use crate::{
+    backend::{Backend, Submission},
+    config,
+    reading::{Reading, Value},
+    roaming,
+};
+
+/// Version of the encoding
+const VERSION: u8 = 1;
+
+/// Maximum size of an ESP-NOW frame (`ESP_NOW_MAX_DATA_LEN`)
+const MAX_FRAME_SIZE: usize = 250;
+
+/// Size of the frame header without the name
+const HEADER_SIZE: usize = 5;
+
+/// Number of attempts to send a frame
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Time to wait for the acknowledgement of the gateway
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+
+pub struct Sender {
+    espnow: EspNow,
+    gateway: [u8; 6],
+    name: String,
+    cycle: u16,
+    /// Send status of the frames, from the send callback
+    status: Receiver<SendStatus>,
+}
+
+impl Sender {
+    /// Register the gateway as peer. WiFi must be started on the channel of the gateway.
+    pub fn new(config: &config::EspNow, name: &str) -> anyhow::Result<Self> {
+        // Validated when loading the config
+        let gateway = roaming::parse_bssid(&config.gateway).context("Invalid gateway")?;
+        let espnow = EspNow::take().context("Could not initialize ESP-NOW")?;
+        espnow
+            .add_peer(PeerInfo {
+                peer_addr: gateway,
+                channel: config.channel,
+                ifidx: esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+                encrypt: false,
+                ..Default::default()
+            })
+            .context("Could not add gateway")?;
+        let (sender, status) = mpsc::channel();
+        espnow
+            .register_send_cb(move |_, status| {
+                let _ = sender.send(status);
+            })
+            .context("Could not register send callback")?;
+        Ok(Self {
+            espnow,
+            gateway,
+            name: name.into(),
+            cycle: 0,
+            status,
+        })
+    }
+
+    /// Send the readings of a measurement cycle. Fail if the gateway didn't acknowledge all
+    /// frames.
+    pub fn send(&mut self, readings: &[Reading]) -> anyhow::Result<()> {
+        let frames = encode(&self.name, self.cycle, readings);
+        self.cycle = self.cycle.wrapping_add(1);
+        // Stale status of an earlier frame whose acknowledgement timed out
+        while self.status.try_recv().is_ok() {}
+        for (i, frame) in frames.iter().enumerate() {
+            self.send_frame(frame)
+                .with_context(|| format!("Frame {} of {}", i + 1, frames.len()))?;
+        }
+        Ok(())
+    }
+
+    /// Send a frame, with retries.
+    fn send_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        for _ in 0..MAX_ATTEMPTS {
+            self.espnow
+                .send(self.gateway, frame)
+                .context("Could not send")?;
+            if let Ok(SendStatus::SUCCESS) = self.status.recv_timeout(ACK_TIMEOUT) {
+                return Ok(());
+            }
+        }
+        bail!("Not acknowledged by the gateway");
+    }
+}
+
+impl Backend for Sender {
+    fn name(&self) -> &'static str {
+        "ESP-NOW"
+    }
+
+    fn submit(&mut self, submission: &Submission) -> anyhow::Result<()> {
+        self.send(submission.readings)
+    }
+}
+
+/// Encode the readings of a cycle into frames of at most [`MAX_FRAME_SIZE`] bytes. Readings that
+/// don't fit into a frame on their own are skipped.
+fn encode(name: &str, cycle: u16, readings: &[Reading]) -> Vec<Vec<u8>> {
+    let mut header = vec![VERSION];
+    header.extend_from_slice(&cycle.to_le_bytes());
+    // Index and count, set below
+    header.extend_from_slice(&[0, 0]);
+    string(&mut header, name);
+
+    let mut frames: Vec<Vec<u8>> = Vec::new();
+    let mut frame = header.clone();
+    for reading in readings {
+        let encoded = self::reading(reading);
+        if header.len() + encoded.len() > MAX_FRAME_SIZE {
+            eprintln!(
+                "Error: {} {} is too large for an ESP-NOW frame",
+                reading.measurement, reading.field
+            );
+            continue;
+        }
+        if frame.len() + encoded.len() > MAX_FRAME_SIZE {
+            frames.push(frame);
+            frame = header.clone();
+        }
+        frame.extend_from_slice(&encoded);
+    }
+    frames.push(frame);
+
+    // At most 250 bytes per frame, so the count fits
+    let count = frames.len() as u8;
+    for (index, frame) in frames.iter_mut().enumerate() {
+        frame[HEADER_SIZE - 2] = index as u8;
+        frame[HEADER_SIZE - 1] = count;
+    }
+    frames
+}
+
+/// Encode a reading.
+fn reading(reading: &Reading) -> Vec<u8> {
+    let (kind, decimals) = match reading.value {
+        Value::Float(_, decimals) => (0, decimals.min(15) as u8),
+        Value::Unsigned(_) => (1, 0),
+        Value::Bool(_) => (2, 0),
+    };
+    let mut flags = kind | (decimals << 4);
+    if reading.sensor.is_some() {
+        flags |= 1 << 2;
+    }
+    if reading.channel.is_some() {
+        flags |= 1 << 3;
+    }
+    let mut out = vec![flags];
+    string(&mut out, reading.measurement);
+    string(&mut out, reading.field);
+    if let Some(sensor) = reading.sensor {
+        string(&mut out, sensor);
+    }
+    if let Some(ref channel) = reading.channel {
+        string(&mut out, channel);
+    }
+    match reading.value {
+        Value::Float(value, _) => out.extend_from_slice(&(value as f32).to_le_bytes()),
+        Value::Unsigned(value) => out.extend_from_slice(&value.to_le_bytes()),
+        Value::Bool(value) => out.push(u8::from(value)),
+    }
+    out
+}
+
+/// Append a string with its length, truncated to 255 bytes (at a character boundary).
+fn string(out: &mut Vec<u8>, text: &str) {
+    let mut end = text.len().min(u8::MAX as usize);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    out.push(end as u8);
+    out.extend_from_slice(&text.as_bytes()[..end]);
+}
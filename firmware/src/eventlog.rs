@@ -0,0 +1,322 @@
+//! Persistent event log, a flight recorder for post-mortem debugging.
+//!
+//! Notable events (boots with the reset reason, config changes, rule changes, sensors that could
+//! not be initialized, watchdog restarts and the safe mode) are appended to a log in NVS, so that
+//! they survive reboots. Every entry has a sequence number that increases monotonically across
+//! reboots, the uptime and (if the clock was synchronized) the Unix time. The entry with the
+//! sequence number `seq` is stored in the NVS slot `seq % MAX_ENTRIES`, so once the log is full,
+//! the oldest entry is overwritten.
+//!
+//! The log is stored in the NVS partition of the delivery queue (`nvs_log`, see `partitions.csv`),
+//! so that it doesn't take up the space of the config. Without it, events are only printed.
+//!
+//! Every entry is an NVS write, so only rare events are recorded (e.g. not every failed sensor
+//! read). The log is global, so that it can be written from everywhere, including the watchdog.
+//!
+//! Entries can be forwarded to InfluxDB as annotations (see [`Entry::to_line`]). The sequence
+//! number of the next entry to forward is stored in NVS as well, so that entries are retried
+//! until they were submitted, also across reboots.
+
+use std::{fmt, sync::Mutex};
+
+use esp_idf_svc::nvs::{
+    EspCustomNvsPartition, EspDefaultNvsPartition, EspNvs, NvsCustom, NvsPartitionId,
+};
+use esp_idf_sys::EspError;
+
+use crate::{
+    clock,
+    lineproto::{Line, TagSet},
+};
+
+/// NVS namespace of the log entries
+const NAMESPACE: &str = "eventlog";
+
+/// Number of entries that are kept, sized from the NVS budget in `partitions.csv`
+pub const MAX_ENTRIES: usize = 64;
+
+/// Maximum length of a message in bytes, longer messages are truncated
+const MAX_MESSAGE_LENGTH: usize = 64;
+
+/// Length of the fixed part of a stored entry: Sequence number, Unix time, uptime and kind
+const HEADER_LENGTH: usize = 4 + 8 + 4 + 1;
+
+/// NVS key of the sequence number of the next entry to forward
+const FORWARD_KEY: &str = "forward";
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+struct Log {
+    nvs: EspNvs<NvsCustom>,
+    next_seq: u32,
+    /// Sequence number of the first entry of this boot
+    boot_seq: u32,
+    /// Sequence number of the next entry to forward
+    forward_seq: u32,
+}
+
+/// The kinds are part of the config, so that they can be selected for the annotations
+pub use crate::config::EventKind as Kind;
+
+pub struct Entry {
+    pub seq: u32,
+    /// Unix time in seconds, `None` if the clock was not synchronized
+    pub unix_time: Option<u64>,
+    pub uptime_secs: u32,
+    pub kind: Kind,
+    pub message: String,
+}
+
+impl Entry {
+    fn encode(&self) -> Vec<u8> {
+        let mut blob = Vec::with_capacity(HEADER_LENGTH + self.message.len());
+        blob.extend_from_slice(&self.seq.to_le_bytes());
+        blob.extend_from_slice(&self.unix_time.unwrap_or(0).to_le_bytes());
+        blob.extend_from_slice(&self.uptime_secs.to_le_bytes());
+        blob.push(self.kind as u8);
+        blob.extend_from_slice(self.message.as_bytes());
+        blob
+    }
+
+    /// Return the entry in InfluxDB line protocol format, with its timestamp, or `None` if its
+    /// time is not known:
+    ///
+    /// `events,kind=<kind>,<tags> seq=<seq>u,message="<message>" <unix time>`
+    pub fn to_line(&self, tags: &TagSet) -> Option<String> {
+        let unix_time = self.unix_time?;
+        Some(
+            Line::build("events")
+                .tag("kind", self.kind.name())
+                .tags(tags)
+                .field("seq", self.seq)
+                .field("message", self.message.as_str())
+                .timestamp(unix_time)
+                .into_string(),
+        )
+    }
+
+    fn decode(blob: &[u8]) -> Option<Self> {
+        if blob.len() < HEADER_LENGTH {
+            return None;
+        }
+        let unix_time = u64::from_le_bytes(blob[4..12].try_into().unwrap());
+        Some(Self {
+            seq: u32::from_le_bytes(blob[0..4].try_into().unwrap()),
+            unix_time: (unix_time != 0).then_some(unix_time),
+            uptime_secs: u32::from_le_bytes(blob[12..16].try_into().unwrap()),
+            kind: *Kind::ALL.get(blob[16] as usize)?,
+            message: std::str::from_utf8(&blob[HEADER_LENGTH..]).ok()?.into(),
+        })
+    }
+}
+
+/// Format as `<seq> <unix time or -> <uptime>s <kind> <message>`.
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.unix_time {
+            Some(unix_time) => write!(f, "{} {}", self.seq, unix_time)?,
+            None => write!(f, "{} -", self.seq)?,
+        }
+        write!(
+            f,
+            " {}s {} {}",
+            self.uptime_secs,
+            self.kind.name(),
+            self.message
+        )
+    }
+}
+
+/// Open the log in the NVS `partition` of the delivery queue. Until then, events are only printed.
+///
+/// Older firmware stored the log in the default NVS partition. Its entries are moved to
+/// `partition` if it has none yet, otherwise they are dropped.
+pub fn open(
+    partition: EspCustomNvsPartition,
+    default_partition: EspDefaultNvsPartition,
+) -> Result<(), EspError> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    move_legacy(&mut nvs, default_partition);
+    let next_seq = read(&nvs).last().map(|entry| entry.seq + 1).unwrap_or(0);
+    let mut buf = [0; 4];
+    let forward_seq = match nvs.get_raw(FORWARD_KEY, &mut buf)? {
+        Some(&[a, b, c, d]) => u32::from_le_bytes([a, b, c, d]),
+        _ => 0,
+    };
+    *LOG.lock().expect("Failed to lock event log mutex") = Some(Log {
+        nvs,
+        next_seq,
+        boot_seq: next_seq,
+        // The entries may have been erased
+        forward_seq: forward_seq.min(next_seq),
+    });
+    Ok(())
+}
+
+/// Append an event to the log.
+pub fn record(kind: Kind, message: &str) {
+    println!("Event log: {} {}", kind.name(), message);
+    let mut log = LOG.lock().expect("Failed to lock event log mutex");
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+    let mut length = message.len().min(MAX_MESSAGE_LENGTH);
+    while !message.is_char_boundary(length) {
+        length -= 1;
+    }
+    let entry = Entry {
+        seq: log.next_seq,
+        unix_time: clock::unix_time(),
+        uptime_secs: uptime_secs(),
+        kind,
+        message: message[..length].into(),
+    };
+    if let Err(e) = log.nvs.set_raw(&key(entry.seq), &entry.encode()) {
+        eprintln!("Event log: Could not store entry {}: {}", entry.seq, e);
+    }
+    log.next_seq += 1;
+}
+
+/// Return the entries of the log, oldest first.
+pub fn entries() -> Vec<Entry> {
+    match LOG.lock().expect("Failed to lock event log mutex").as_ref() {
+        Some(log) => read(&log.nvs),
+        None => Vec::new(),
+    }
+}
+
+/// Return the entries that were not forwarded yet (see [`mark_forwarded`]), oldest first. If the
+/// clock is synchronized now, the Unix time of the entries of this boot that were recorded before
+/// is derived from their uptime.
+pub fn unforwarded() -> Vec<Entry> {
+    let log = LOG.lock().expect("Failed to lock event log mutex");
+    let log = match log.as_ref() {
+        Some(log) => log,
+        None => return Vec::new(),
+    };
+    let (now, uptime_secs) = (clock::unix_time(), uptime_secs());
+    read(&log.nvs)
+        .into_iter()
+        .filter(|entry| entry.seq >= log.forward_seq)
+        .map(|mut entry| {
+            if entry.unix_time.is_none() && entry.seq >= log.boot_seq {
+                entry.unix_time =
+                    now.map(|now| now - u64::from(uptime_secs.saturating_sub(entry.uptime_secs)));
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Mark the entries up to the sequence number `seq` as forwarded.
+pub fn mark_forwarded(seq: u32) {
+    let mut log = LOG.lock().expect("Failed to lock event log mutex");
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+    log.forward_seq = seq + 1;
+    if let Err(e) = log.nvs.set_raw(FORWARD_KEY, &log.forward_seq.to_le_bytes()) {
+        eprintln!("Event log: Could not store forwarded entries: {}", e);
+    }
+}
+
+/// Return the uptime in seconds.
+fn uptime_secs() -> u32 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u32
+}
+
+/// Move the entries of an older firmware from the default NVS partition to `nvs`.
+fn move_legacy(nvs: &mut EspNvs<NvsCustom>, partition: EspDefaultNvsPartition) {
+    // Read-only, so that the namespace isn't created if it doesn't exist
+    let (entries, forward) = match EspNvs::new(partition.clone(), NAMESPACE, false) {
+        Ok(legacy) => {
+            let mut buf = [0; 4];
+            let forward = legacy
+                .get_raw(FORWARD_KEY, &mut buf)
+                .ok()
+                .flatten()
+                .map(<[u8]>::to_vec);
+            (read(&legacy), forward)
+        }
+        Err(_) => return,
+    };
+    if entries.is_empty() && forward.is_none() {
+        return;
+    }
+    if read(nvs).is_empty() {
+        println!(
+            "Event log: Moving {} entries from the default NVS partition",
+            entries.len()
+        );
+        for entry in &entries {
+            if let Err(e) = nvs.set_raw(&key(entry.seq), &entry.encode()) {
+                eprintln!("Event log: Could not store entry {}: {}", entry.seq, e);
+            }
+        }
+        if let Some(forward) = forward {
+            if let Err(e) = nvs.set_raw(FORWARD_KEY, &forward) {
+                eprintln!("Event log: Could not store forwarded entries: {}", e);
+            }
+        }
+    } else {
+        eprintln!(
+            "Event log: Dropping {} entries of an older firmware",
+            entries.len()
+        );
+    }
+
+    let mut legacy = match EspNvs::new(partition, NAMESPACE, true) {
+        Ok(legacy) => legacy,
+        Err(e) => {
+            eprintln!(
+                "Event log: Could not remove the entries of an older firmware: {}",
+                e
+            );
+            return;
+        }
+    };
+    for slot in 0..MAX_ENTRIES as u32 {
+        if let Err(e) = legacy.remove(&key(slot)) {
+            eprintln!("Event log: Could not remove entry slot {}: {}", slot, e);
+        }
+    }
+    if let Err(e) = legacy.remove(FORWARD_KEY) {
+        eprintln!("Event log: Could not remove forwarded entries: {}", e);
+    }
+}
+
+/// Read the stored entries, oldest first.
+fn read<T: NvsPartitionId>(nvs: &EspNvs<T>) -> Vec<Entry> {
+    let mut buf = [0; HEADER_LENGTH + MAX_MESSAGE_LENGTH];
+    let mut entries: Vec<Entry> = (0..MAX_ENTRIES as u32)
+        .filter_map(|slot| {
+            let blob = nvs.get_raw(&key(slot), &mut buf).ok().flatten()?;
+            Entry::decode(blob)
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.seq);
+    entries
+}
+
+/// Return the NVS key of the slot of an entry.
+fn key(seq: u32) -> String {
+    format!("entry{}", seq as usize % MAX_ENTRIES)
+}
+
+/// Return the reason of the last reset.
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power-on",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        _ => "unknown",
+    }
+}
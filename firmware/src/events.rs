@@ -0,0 +1,56 @@
+//! Events are submitted to InfluxDB as soon as they happen, instead of waiting for the next
+//! measurement interval.
+//!
+//! Producers (e.g. timer tasks polling a sensor) send events through an [`mpsc`] channel to the
+//! main loop, which submits them while waiting for the next interval.
+//!
+//! [`mpsc`]: std::sync::mpsc
+
+use crate::lineproto::{Line, TagSet};
+
+/// An event that should be submitted immediately
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Presence was detected or has ended (only sent with a presence sensor)
+    #[cfg_attr(not(any(feature = "presence", feature = "ld2410")), allow(dead_code))]
+    Presence {
+        /// Sensor type tag, e.g. "apds9960"
+        sensor: &'static str,
+        /// Whether somebody is present
+        present: bool,
+    },
+    /// The lights were switched on or off (only sent with `sensors.veml7700.lights_on_lux`)
+    #[cfg_attr(not(feature = "lux"), allow(dead_code))]
+    Lights {
+        /// Whether the lights are on
+        on: bool,
+    },
+    /// The condition of a rule became true or false
+    Rule {
+        /// Name of the rule
+        name: String,
+        /// Whether the condition is true
+        active: bool,
+    },
+}
+
+impl Event {
+    /// Return the event in InfluxDB line protocol format.
+    pub fn to_line(&self, tags: &TagSet) -> String {
+        match self {
+            Self::Presence { sensor, present } => Line::build("presence")
+                .tag("sensor_type", sensor)
+                .tags(tags)
+                .field("present", *present),
+            Self::Lights { on } => Line::build("lights")
+                .tag("sensor_type", "veml7700")
+                .tags(tags)
+                .field("on", *on),
+            Self::Rule { name, active } => Line::build("rule")
+                .tag("rule", name)
+                .tags(tags)
+                .field("active", *active),
+        }
+        .into_string()
+    }
+}
@@ -0,0 +1,227 @@
+//! Fault injection, for exercising the recovery logic (the failed sensor reads, the delivery
+//! queue, the outage detection with the WiFi reconnection, the watchdog) on a bench device.
+//!
+//! Only built with the `faults` feature, never enable it in production builds. Faults are injected
+//! at runtime, with the `fault` console command or with a message to
+//! `<topic_prefix>/<name>/fault/set` (see [`crate::mqtt`]), with the arguments of the command as
+//! payload:
+//!
+//! - `i2c [<count>]`: I²C transactions fail with a timeout, so the reads of all I²C sensors fail
+//! - `http [<count>]`: Requests to InfluxDB and of [`crate::webhook::send`] (the JSON webhook,
+//!   ntfy, the rule webhooks) fail before they're sent
+//! - `wifi`: WiFi is disconnected in the next measurement cycle, without reconnecting right away
+//! - `lock <ms> [<count>]`: The main loop holds the sensors mutex `ms` longer in every cycle,
+//!   delaying the timer tasks that read the sensors in between
+//! - `clear`: Stop injecting faults
+//!
+//! Without a count, a fault is injected until it's cleared. The count of `i2c` is in I²C
+//! transactions (a sensor read usually takes two), of `http` in requests and of `lock` in cycles.
+//!
+//! Like the event log, the faults are global, so that they can be injected from the console and
+//! the MQTT client, and checked everywhere.
+
+use std::{
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use embedded_hal_0_2::blocking::i2c::{Read, Write, WriteRead};
+use esp_idf_sys::{EspError, ESP_ERR_TIMEOUT};
+
+static FAULTS: Mutex<Faults> = Mutex::new(Faults {
+    i2c: Remaining::Off,
+    http: Remaining::Off,
+    wifi: false,
+    lock: None,
+});
+
+/// How often a fault is still injected
+#[derive(Clone, Copy, PartialEq)]
+enum Remaining {
+    Off,
+    Times(u32),
+    UntilCleared,
+}
+
+struct Faults {
+    i2c: Remaining,
+    http: Remaining,
+    /// Whether WiFi is disconnected in the next cycle
+    wifi: bool,
+    /// Delay while holding the sensors mutex, with the number of cycles
+    lock: Option<(Duration, Remaining)>,
+}
+
+impl Remaining {
+    fn new(count: Option<u32>) -> Self {
+        match count {
+            Some(0) => Self::Off,
+            Some(count) => Self::Times(count),
+            None => Self::UntilCleared,
+        }
+    }
+
+    /// Return whether the fault is injected now, and count it.
+    fn take(&mut self) -> bool {
+        match *self {
+            Self::Off => false,
+            Self::Times(count) => {
+                *self = if count > 1 {
+                    Self::Times(count - 1)
+                } else {
+                    Self::Off
+                };
+                true
+            }
+            Self::UntilCleared => true,
+        }
+    }
+
+    fn describe(&self, name: &str) -> Option<String> {
+        match *self {
+            Self::Off => None,
+            Self::Times(count) => Some(format!("{} ({} more)", name, count)),
+            Self::UntilCleared => Some(format!("{} (until cleared)", name)),
+        }
+    }
+}
+
+fn faults() -> MutexGuard<'static, Faults> {
+    FAULTS.lock().expect("Failed to lock faults mutex")
+}
+
+/// Run a fault injection command (the arguments of the `fault` console command, see the module
+/// documentation) and return the confirmation.
+pub fn command(args: &[&str]) -> anyhow::Result<String> {
+    let count = |arg: Option<&&str>| -> anyhow::Result<Option<u32>> {
+        match arg {
+            Some(arg) => match arg.parse() {
+                Ok(count) => Ok(Some(count)),
+                Err(_) => bail!("Invalid count {:?}", arg),
+            },
+            None => Ok(None),
+        }
+    };
+    let mut faults = faults();
+    match args {
+        [] => {}
+        ["i2c"] | ["i2c", _] => faults.i2c = Remaining::new(count(args.get(1))?),
+        ["http"] | ["http", _] => faults.http = Remaining::new(count(args.get(1))?),
+        ["wifi"] => faults.wifi = true,
+        ["lock", ms] | ["lock", ms, _] => {
+            let ms: u64 = match ms.parse() {
+                Ok(ms) => ms,
+                Err(_) => bail!("Invalid delay {:?}", ms),
+            };
+            faults.lock = match Remaining::new(count(args.get(2))?) {
+                _ if ms == 0 => None,
+                Remaining::Off => None,
+                remaining => Some((Duration::from_millis(ms), remaining)),
+            };
+        }
+        ["clear"] => {
+            faults.i2c = Remaining::Off;
+            faults.http = Remaining::Off;
+            faults.wifi = false;
+            faults.lock = None;
+        }
+        _ => bail!("Usage: fault [i2c|http [<count>] | wifi | lock <ms> [<count>] | clear]"),
+    }
+    let active: Vec<String> = [
+        faults.i2c.describe("i2c"),
+        faults.http.describe("http"),
+        faults.wifi.then(|| "wifi (next cycle)".into()),
+        faults.lock.and_then(|(delay, remaining)| {
+            remaining.describe(&format!("lock {}ms", delay.as_millis()))
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    if active.is_empty() {
+        Ok("Injected faults: None".into())
+    } else {
+        Ok(format!("Injected faults: {}", active.join(", ")))
+    }
+}
+
+/// Fail if an HTTP fault is injected. Called before a request is sent.
+pub fn http() -> anyhow::Result<()> {
+    if faults().http.take() {
+        bail!("Injected HTTP failure");
+    }
+    Ok(())
+}
+
+/// Return whether WiFi is to be disconnected now. The fault is injected once.
+pub fn wifi() -> bool {
+    std::mem::take(&mut faults().wifi)
+}
+
+/// Sleep while the caller holds the sensors mutex, if a lock delay is injected. Called once per
+/// cycle.
+pub fn delay_lock() {
+    if let Some(delay) = lock_delay() {
+        println!(
+            "Faults: Holding the sensors mutex for {}ms",
+            delay.as_millis()
+        );
+        thread::sleep(delay);
+    }
+}
+
+/// Return the lock delay if it's injected now, and count it.
+fn lock_delay() -> Option<Duration> {
+    let mut faults = faults();
+    let (delay, mut remaining) = faults.lock?;
+    let injected = remaining.take();
+    faults.lock = Some((delay, remaining)).filter(|_| remaining != Remaining::Off);
+    injected.then_some(delay)
+}
+
+/// An I²C bus whose transactions fail while an I²C fault is injected
+pub struct I2c<I>(pub I);
+
+impl<I> I2c<I> {
+    /// Fail if an I²C fault is injected.
+    fn check(&self) -> Result<(), EspError> {
+        if faults().i2c.take() {
+            return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>());
+        }
+        Ok(())
+    }
+}
+
+impl<I: Read<Error = EspError>> Read for I2c<I> {
+    type Error = EspError;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.read(address, buffer)
+    }
+}
+
+impl<I: Write<Error = EspError>> Write for I2c<I> {
+    type Error = EspError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.write(address, bytes)
+    }
+}
+
+impl<I: WriteRead<Error = EspError>> WriteRead for I2c<I> {
+    type Error = EspError;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.check()?;
+        self.0.write_read(address, bytes, buffer)
+    }
+}
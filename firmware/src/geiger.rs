@@ -0,0 +1,70 @@
+//! Radiation measurement with a Geiger counter board.
+//!
+//! The board's pulse output is counted by a [`PulseCounter`]. At high rates, the tube (and the
+//! counter) miss pulses that arrive while they're still recovering from the previous pulse. The
+//! counter ignores pulses within the configured dead time, so the count can be corrected with the
+//! non-paralyzable dead time model.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_sys::EspError;
+
+use crate::pulse::PulseCounter;
+
+/// A radiation measurement
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Counts per minute, corrected for dead time
+    pub cpm: f32,
+    /// Dose rate in µSv/h
+    pub dose_rate: f32,
+}
+
+pub struct Geiger<'d> {
+    counter: PulseCounter<'d>,
+    /// Conversion factor from CPM to µSv/h (tube specific)
+    usvh_per_cpm: f32,
+    /// Dead time of the tube
+    dead_time: Duration,
+    /// Start of the current counting interval
+    interval_start: Instant,
+}
+
+impl<'d> Geiger<'d> {
+    pub fn new(pin: AnyIOPin, usvh_per_cpm: f32, dead_time: Duration) -> Result<Self, EspError> {
+        Ok(Self {
+            counter: PulseCounter::new(pin, dead_time)?,
+            usvh_per_cpm,
+            dead_time,
+            interval_start: Instant::now(),
+        })
+    }
+
+    /// Return the measurement since the last call, and start a new counting interval.
+    ///
+    /// Returns `None` if the counter is saturated (i.e. the observed rate is so high that the
+    /// tube is dead all the time) or if no time has elapsed.
+    pub fn measure(&mut self) -> Option<Measurement> {
+        let count = self.counter.take_count();
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.interval_start).as_secs_f32();
+        self.interval_start = now;
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        // Non-paralyzable dead time correction: n = m / (1 - m·τ)
+        let observed_rate = count as f32 / elapsed;
+        let dead_fraction = observed_rate * self.dead_time.as_secs_f32();
+        if dead_fraction >= 1.0 {
+            return None;
+        }
+        let cpm = observed_rate / (1.0 - dead_fraction) * 60.0;
+
+        Some(Measurement {
+            cpm,
+            dose_rate: cpm * self.usvh_per_cpm,
+        })
+    }
+}
@@ -0,0 +1,318 @@
+//! In-RAM history of the recent measurements.
+//!
+//! For every metric (see [`Metric`]), a ring buffer keeps one value per time slot of
+//! `history.resolution_secs` (the mean of the values measured during the slot), for
+//! `history.retention_hours`. By default, that's 24 hours at a resolution of one minute. Only
+//! metrics that are actually measured take up memory. The history is lost on restart. It is used
+//! for the trend sparklines on the display and the daily summaries, and can be queried through
+//! the local HTTP API.
+//!
+//! The values are stored as 16 bit fixed-point numbers, with a range and resolution that depend
+//! on the metric (see [`encoding`]), so every slot takes two bytes. The slots are not timestamped,
+//! the time of a slot follows from its position. Slots are counted from the uptime, which is
+//! monotonic even if the clock is not synchronized yet. The uptime is converted to Unix time when
+//! the history is queried.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Instant,
+};
+
+use crate::{
+    clock,
+    config::{self, Metric},
+};
+
+/// Maximum number of slots per metric
+pub const MAX_CAPACITY: usize = 4320;
+
+/// Value of slots without a value, e.g. while the device was busy
+const MISSING: u16 = u16::MAX;
+
+/// Values of a metric
+struct Series {
+    /// Fixed-point values, oldest first
+    slots: VecDeque<u16>,
+    /// Number of the newest slot, counted from the creation of the history
+    newest: u32,
+    /// Sum and number of the values in the newest slot
+    sum: f32,
+    count: u32,
+    /// Latest value, not averaged
+    latest: f32,
+}
+
+/// Minimum, maximum and mean of a metric over a time range
+#[derive(Debug, Copy, Clone)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+pub struct History {
+    start: Instant,
+    resolution_secs: u32,
+    capacity: usize,
+    series: BTreeMap<Metric, Series>,
+}
+
+impl History {
+    pub fn new(config: &config::History) -> Self {
+        Self {
+            start: Instant::now(),
+            resolution_secs: config.resolution_secs,
+            capacity: config.capacity(),
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// Add a value that was measured at `at` to its slot. If the history of the metric is full,
+    /// the oldest slot is dropped. Values that are older than the newest slot of the metric are
+    /// dropped as well, since the older slots are already averaged.
+    pub fn record(&mut self, metric: Metric, value: f32, at: Instant) {
+        let elapsed = at.saturating_duration_since(self.start);
+        let slot = (elapsed.as_secs() / u64::from(self.resolution_secs)) as u32;
+        let capacity = self.capacity;
+        let series = self.series.entry(metric).or_insert_with(|| Series {
+            slots: VecDeque::with_capacity(capacity),
+            newest: slot,
+            sum: 0.0,
+            count: 0,
+            latest: value,
+        });
+        let ahead = match slot.checked_sub(series.newest) {
+            Some(ahead) => ahead,
+            None => return,
+        };
+        if series.slots.is_empty() || ahead > 0 {
+            // Slots without values in between are missing
+            let skipped = ahead.saturating_sub(1) as usize;
+            for _ in 0..skipped.min(capacity) {
+                push(&mut series.slots, capacity, MISSING);
+            }
+            push(&mut series.slots, capacity, MISSING);
+            series.newest = slot;
+            series.sum = 0.0;
+            series.count = 0;
+        }
+        series.sum += value;
+        series.count += 1;
+        series.latest = value;
+        if let Some(newest) = series.slots.back_mut() {
+            *newest = encode(metric, series.sum / series.count as f32);
+        }
+    }
+
+    /// Return the values of a metric, oldest first. Missing slots are skipped.
+    pub fn values(&self, metric: Metric) -> impl Iterator<Item = f32> + '_ {
+        self.series
+            .get(&metric)
+            .into_iter()
+            .flat_map(|series| series.slots.iter())
+            .filter_map(move |raw| decode(metric, *raw))
+    }
+
+    /// Return the latest value of a metric, if it was measured.
+    pub fn latest(&self, metric: Metric) -> Option<f32> {
+        self.series.get(&metric).map(|series| series.latest)
+    }
+
+    /// Return the values of a metric with the uptime (in seconds) at the start of their slot,
+    /// oldest first.
+    fn samples(&self, metric: Metric) -> impl Iterator<Item = (u64, f32)> + '_ {
+        let resolution = u64::from(self.resolution_secs);
+        self.series
+            .get(&metric)
+            .into_iter()
+            .flat_map(move |series| {
+                let oldest = u64::from(series.newest) + 1 - series.slots.len() as u64;
+                series.slots.iter().enumerate().filter_map(move |(i, raw)| {
+                    let value = decode(metric, *raw)?;
+                    Some(((oldest + i as u64) * resolution, value))
+                })
+            })
+    }
+
+    /// Return the minimum, maximum and mean of a metric over the last `secs` seconds, or `None`
+    /// if there are no values.
+    pub fn summary(&self, metric: Metric, secs: u64) -> Option<Summary> {
+        let since = self.start.elapsed().as_secs().saturating_sub(secs);
+        let (mut min, mut max, mut sum, mut count) = (f32::MAX, f32::MIN, 0.0, 0);
+        for (_, value) in self.samples(metric).filter(|(uptime, _)| *uptime >= since) {
+            min = min.min(value);
+            max = max.max(value);
+            sum += f64::from(value);
+            count += 1;
+        }
+        if count == 0 {
+            return None;
+        }
+        Some(Summary {
+            min,
+            max,
+            mean: (sum / f64::from(count)) as f32,
+        })
+    }
+
+    /// Return the values of a metric between the Unix times `from` and `to` (inclusive) as
+    /// `(timestamp, value)` pairs, oldest first. The timestamp of a value is the start of its
+    /// slot.
+    ///
+    /// If there are more than `max_points` values, they are downsampled: The time range is divided
+    /// into `max_points` buckets of equal length, and every bucket is reduced to the mean time and
+    /// value of its samples. If `max_points` is 0, all values are returned. Returns `None` if the
+    /// clock is not synchronized yet.
+    pub fn query(
+        &self,
+        metric: Metric,
+        from: u64,
+        to: u64,
+        max_points: usize,
+    ) -> Option<Vec<(u64, f32)>> {
+        let now = clock::unix_time()?;
+        let boot = now.saturating_sub(self.start.elapsed().as_secs());
+        let samples: Vec<(u64, f32)> = self
+            .samples(metric)
+            .map(|(uptime, value)| (boot + uptime, value))
+            .filter(|(timestamp, _)| (from..=to).contains(timestamp))
+            .collect();
+        if samples.len() <= max_points || max_points == 0 {
+            return Some(samples);
+        }
+
+        // Downsample
+        let (first, last) = (samples[0].0, samples[samples.len() - 1].0);
+        let bucket_secs = (last - first) / max_points as u64 + 1;
+        let mut points = Vec::with_capacity(max_points);
+        let mut bucket = Bucket::default();
+        for (timestamp, value) in samples {
+            let index = (timestamp - first) / bucket_secs;
+            if bucket.count > 0 && bucket.index != index {
+                points.push(bucket.mean());
+                bucket = Bucket::default();
+            }
+            bucket.index = index;
+            bucket.times += timestamp;
+            bucket.values += f64::from(value);
+            bucket.count += 1;
+        }
+        if bucket.count > 0 {
+            points.push(bucket.mean());
+        }
+        Some(points)
+    }
+}
+
+/// A downsampling bucket
+#[derive(Default)]
+struct Bucket {
+    index: u64,
+    /// Sum of the timestamps
+    times: u64,
+    /// Sum of the values
+    values: f64,
+    count: u32,
+}
+
+impl Bucket {
+    /// Return the mean time and value.
+    fn mean(&self) -> (u64, f32) {
+        let count = u64::from(self.count);
+        (self.times / count, (self.values / count as f64) as f32)
+    }
+}
+
+/// Add a slot to a ring buffer, drop the oldest slot if it's full.
+fn push(slots: &mut VecDeque<u16>, capacity: usize, raw: u16) {
+    if slots.len() == capacity {
+        slots.pop_front();
+    }
+    slots.push_back(raw);
+}
+
+/// Return the offset and the scale of the fixed-point encoding of a metric. The encoded value is
+/// `(value - offset) * scale`, values outside of the range are clamped.
+fn encoding(metric: Metric) -> (f32, f32) {
+    match metric {
+        // -100 to 555 °C, 0.01 °C
+        Metric::Temperature => (-100.0, 100.0),
+        // 0 to 655 %, 0.01 %
+        Metric::Humidity | Metric::Occupancy => (0.0, 100.0),
+        // 0 to 65534 lx, ppm, ppb or W
+        Metric::Illuminance | Metric::Co2 | Metric::Co2eq | Metric::Tvoc | Metric::Power => {
+            (0.0, 1.0)
+        }
+        // 300 to 1610 hPa, 0.02 hPa
+        Metric::Pressure | Metric::SeaLevelPressure => (300.0, 50.0),
+        // 0 to 655, 0.01
+        Metric::Aqi => (0.0, 100.0),
+        // 0 to 6553 ppb, 0.1 ppb
+        Metric::Hcho => (0.0, 10.0),
+        // 0 to 65 µSv/h, 0.001 µSv/h
+        Metric::DoseRate => (0.0, 1000.0),
+        // -300 to 2976 °C, 0.05 °C
+        Metric::Thermocouple => (-300.0, 20.0),
+        // -250 to 1060 °C, 0.02 °C
+        Metric::Rtd => (-250.0, 50.0),
+    }
+}
+
+fn encode(metric: Metric, value: f32) -> u16 {
+    let (offset, scale) = encoding(metric);
+    // Casts from float saturate, NaN becomes 0
+    (((value - offset) * scale).round() as u16).min(MISSING - 1)
+}
+
+fn decode(metric: Metric, raw: u16) -> Option<f32> {
+    if raw == MISSING {
+        return None;
+    }
+    let (offset, scale) = encoding(metric);
+    Some(f32::from(raw) / scale + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn history() -> History {
+        History::new(&config::History {
+            resolution_secs: 60,
+            retention_hours: 1,
+        })
+    }
+
+    #[test]
+    fn skipped_slots_are_missing() {
+        let mut history = history();
+        history.record(Metric::Temperature, 21.0, history.start);
+        history.record(
+            Metric::Temperature,
+            22.0,
+            history.start + Duration::from_secs(180),
+        );
+        assert_eq!(history.series[&Metric::Temperature].slots.len(), 4);
+        assert_eq!(
+            history.values(Metric::Temperature).collect::<Vec<_>>(),
+            [21.0, 22.0]
+        );
+    }
+
+    #[test]
+    fn older_values_are_dropped() {
+        let mut history = history();
+        history.record(
+            Metric::Temperature,
+            22.0,
+            history.start + Duration::from_secs(180),
+        );
+        history.record(Metric::Temperature, 21.0, history.start);
+        let series = &history.series[&Metric::Temperature];
+        assert_eq!(series.slots.len(), 1);
+        assert_eq!(series.newest, 3);
+        assert_eq!(history.latest(Metric::Temperature), Some(22.0));
+    }
+}
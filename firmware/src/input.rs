@@ -0,0 +1,123 @@
+//! Push buttons for local interaction (e.g. paging through the display).
+//!
+//! The buttons connect their pin to ground, the internal pull-up is used. They are polled in a
+//! periodic timer task at [`POLL_INTERVAL`], which debounces them and distinguishes short from
+//! long presses. Every press is mapped to an [`Action`], which is handled by the main loop.
+//!
+//! Note: The ESP32-C3 has no touch sensor peripheral, so only mechanical buttons are supported.
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::{AnyIOPin, Input, PinDriver, Pull};
+use esp_idf_sys::EspError;
+
+/// Interval at which the buttons are polled
+pub const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The pin level must be stable for this long until a change is accepted
+const DEBOUNCE_TIME: Duration = Duration::from_millis(30);
+
+/// An action that is triggered by a button press
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Show the next display page
+    NextPage,
+    /// Show the previous display page
+    PreviousPage,
+    /// Measure and submit immediately, instead of waiting for the end of the interval
+    MeasureNow,
+}
+
+/// A debounced push button with long-press detection.
+pub struct Button<'d> {
+    pin: PinDriver<'d, AnyIOPin, Input>,
+    /// Name of the button, for logging
+    name: &'static str,
+    /// Presses that are held at least this long are long presses
+    long_press: Duration,
+    /// Action on a short press
+    short_action: Action,
+    /// Action on a long press
+    long_action: Action,
+    /// Raw pin state (pressed) of the last poll
+    raw_pressed: bool,
+    /// Time at which the raw pin state last changed
+    raw_changed: Instant,
+    /// Start of the current (debounced) press, `None` if the button is released
+    pressed_since: Option<Instant>,
+    /// Whether the long press of the current press was already reported
+    long_reported: bool,
+}
+
+impl<'d> Button<'d> {
+    pub fn new(
+        pin: AnyIOPin,
+        name: &'static str,
+        long_press: Duration,
+        short_action: Action,
+        long_action: Action,
+    ) -> Result<Self, EspError> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        Ok(Self {
+            pin,
+            name,
+            long_press,
+            short_action,
+            long_action,
+            raw_pressed: false,
+            raw_changed: Instant::now(),
+            pressed_since: None,
+            long_reported: false,
+        })
+    }
+
+    /// Return the short name of the button, e.g. "A".
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Poll the button. Return the action of a press, if one was completed.
+    ///
+    /// A long press is reported as soon as the button was held long enough (so that there's
+    /// feedback without releasing it), a short press when the button is released.
+    pub fn poll(&mut self) -> Option<Action> {
+        let now = Instant::now();
+        let raw_pressed = self.pin.is_low();
+        if raw_pressed != self.raw_pressed {
+            self.raw_pressed = raw_pressed;
+            self.raw_changed = now;
+        }
+        if now.duration_since(self.raw_changed) < DEBOUNCE_TIME {
+            return None;
+        }
+
+        match (self.pressed_since, raw_pressed) {
+            // Pressed
+            (None, true) => {
+                self.pressed_since = Some(now);
+                self.long_reported = false;
+                None
+            }
+            // Held
+            (Some(since), true) => {
+                if !self.long_reported && now.duration_since(since) >= self.long_press {
+                    self.long_reported = true;
+                    Some(self.long_action)
+                } else {
+                    None
+                }
+            }
+            // Released
+            (Some(_), false) => {
+                self.pressed_since = None;
+                if self.long_reported {
+                    None
+                } else {
+                    Some(self.short_action)
+                }
+            }
+            (None, false) => None,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! JSON webhook sink, for users without InfluxDB who ingest the data into their own services.
+//!
+//! Every measurement cycle, a JSON document with the device, the Unix time (if the clock is
+//! synchronized), the custom tags of the config and all readings is POSTed to the configured URL,
+//! with the configured headers:
+//!
+//! ```json
+//! {"name":"livingroom","serial":"SN-0042","fw_version":"0.3.0","timestamp":1700000000,
+//!  "tags":{"room":"living"},"readings":[{"measurement":"temperature","field":"celsius",
+//!  "metric":"temperature","value":21.53,"unit":"°C"},{"measurement":"co2","field":"ppm",
+//!  "sensor":"scd4x","metric":"co2","value":612,"unit":"ppm"}]}
+//! ```
+//!
+//! `sensor`, `channel`, `sensor_serial`, `metric` and `unit` are omitted if the reading has none.
+//! Values that JSON can't represent (NaN and infinity) are `null`.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use embedded_svc::http::Method;
+
+use crate::{
+    config,
+    reading::{Reading, Value},
+    webhook, VERSION,
+};
+
+pub struct JsonWebhook {
+    url: String,
+    headers: Vec<(String, String)>,
+    /// Fields of the device, the same in every document (without the braces)
+    device: String,
+}
+
+impl JsonWebhook {
+    pub fn new(
+        config: &config::JsonWebhook,
+        name: &str,
+        serial: Option<&str>,
+        tags: &BTreeMap<String, String>,
+    ) -> Self {
+        // Writing to a string cannot fail
+        let mut device = format!("\"name\":{}", string(name));
+        if let Some(serial) = serial {
+            let _ = write!(device, ",\"serial\":{}", string(serial));
+        }
+        let _ = write!(device, ",\"fw_version\":{}", string(VERSION));
+        let tags: Vec<String> = tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", string(key), string(value)))
+            .collect();
+        let _ = write!(device, ",\"tags\":{{{}}}", tags.join(","));
+        Self {
+            url: config.url.clone(),
+            headers: config
+                .headers
+                .iter()
+                .map(|(key, value)| (key.to_lowercase(), value.clone()))
+                .collect(),
+            device,
+        }
+    }
+
+    /// POST the readings. Fail if the server doesn't respond with a success status.
+    pub fn send(&self, timestamp: Option<u64>, readings: &[Reading]) -> anyhow::Result<()> {
+        let mut headers = vec![("content-type", "application/json")];
+        headers.extend(self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        webhook::send(
+            Method::Post,
+            &self.url,
+            &headers,
+            &self.document(timestamp, readings),
+        )
+    }
+
+    /// Return the JSON document of the readings.
+    fn document(&self, timestamp: Option<u64>, readings: &[Reading]) -> String {
+        let mut json = format!("{{{}", self.device);
+        if let Some(timestamp) = timestamp {
+            let _ = write!(json, ",\"timestamp\":{}", timestamp);
+        }
+        let readings: Vec<String> = readings.iter().map(reading).collect();
+        let _ = write!(json, ",\"readings\":[{}]}}", readings.join(","));
+        json
+    }
+}
+
+/// Return a reading as JSON object.
+fn reading(reading: &Reading) -> String {
+    let mut json = format!(
+        "{{\"measurement\":{},\"field\":{}",
+        string(reading.measurement),
+        string(reading.field)
+    );
+    if let Some(sensor) = reading.sensor {
+        let _ = write!(json, ",\"sensor\":{}", string(sensor));
+    }
+    if let Some(ref channel) = reading.channel {
+        let _ = write!(json, ",\"channel\":{}", string(channel));
+    }
+    if let Some(ref serial) = reading.serial {
+        let _ = write!(json, ",\"sensor_serial\":{}", string(serial));
+    }
+    if let Some(metric) = reading.metric {
+        let _ = write!(json, ",\"metric\":{}", string(metric.name()));
+    }
+    let _ = match reading.value {
+        // JSON has no representation for NaN and infinity
+        Value::Float(value, _) if !value.is_finite() => write!(json, ",\"value\":null"),
+        Value::Float(value, decimals) => write!(json, ",\"value\":{:.*}", decimals, value),
+        Value::Unsigned(value) => write!(json, ",\"value\":{}", value),
+        Value::Bool(value) => write!(json, ",\"value\":{}", value),
+    };
+    let unit = reading.unit.symbol();
+    if !unit.is_empty() {
+        let _ = write!(json, ",\"unit\":{}", string(unit));
+    }
+    json.push('}');
+    json
+}
+
+/// Return a JSON string, with quotes and escapes.
+fn string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
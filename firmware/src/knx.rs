@@ -0,0 +1,146 @@
+//! KNXnet/IP routing, so that the device can act as a KNX sensor.
+//!
+//! Every measurement cycle, the values of the configured metrics are sent as `GroupValueWrite`
+//! telegrams (cEMI `L_Data.ind` frames in `ROUTING_INDICATION` messages) to the KNX multicast
+//! group. A KNX IP router forwards them to the bus. The device doesn't receive anything, i.e.
+//! `GroupValueRead` requests are not answered.
+
+use std::{
+    io,
+    net::{SocketAddrV4, UdpSocket},
+};
+
+use crate::config::{self, Dpt, Metric};
+
+/// KNXnet/IP header: header length, protocol version 1.0
+const HEADER: [u8; 2] = [0x06, 0x10];
+
+/// Service type identifier of `ROUTING_INDICATION`
+const ROUTING_INDICATION: u16 = 0x0530;
+
+/// cEMI message code of `L_Data.ind`
+const L_DATA_IND: u8 = 0x29;
+
+/// Control field 1: standard frame, not repeated, low priority
+const CONTROL_1: u8 = 0xbc;
+
+/// Control field 2: group address as destination, hop count 6
+const CONTROL_2: u8 = 0xe0;
+
+/// APCI of `GroupValueWrite`
+const GROUP_VALUE_WRITE: u8 = 0x80;
+
+pub struct Knx {
+    socket: UdpSocket,
+    destination: SocketAddrV4,
+    source: u16,
+    /// Metrics, group addresses and datapoint types
+    telegrams: Vec<(Metric, u16, Dpt)>,
+}
+
+impl Knx {
+    pub fn new(config: &config::Knx) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        // Don't let the telegrams leave the local network
+        socket.set_multicast_ttl_v4(1)?;
+        let telegrams = config
+            .telegrams
+            .iter()
+            .filter_map(|telegram| {
+                let group_address = parse_group_address(&telegram.group_address)?;
+                Some((telegram.metric, group_address, telegram.dpt))
+            })
+            .collect();
+        Ok(Self {
+            socket,
+            destination: SocketAddrV4::new(config.multicast_address, config.port),
+            source: parse_individual_address(&config.individual_address).unwrap_or(0xffff),
+            telegrams,
+        })
+    }
+
+    /// Send the values of the configured metrics. Metrics without a value are skipped.
+    pub fn send(&self, values: &[(Metric, f32)]) -> io::Result<()> {
+        for &(metric, group_address, dpt) in self.telegrams.iter() {
+            let value = match values.iter().find(|(m, _)| *m == metric) {
+                Some(&(_, value)) if value.is_finite() => value,
+                _ => continue,
+            };
+            let message = self.routing_indication(group_address, &encode(dpt, value));
+            self.socket.send_to(&message, self.destination)?;
+        }
+        Ok(())
+    }
+
+    /// Return a `ROUTING_INDICATION` message with a `GroupValueWrite` of the data.
+    fn routing_indication(&self, group_address: u16, data: &[u8]) -> Vec<u8> {
+        let mut cemi = vec![L_DATA_IND, 0, CONTROL_1, CONTROL_2];
+        cemi.extend_from_slice(&self.source.to_be_bytes());
+        cemi.extend_from_slice(&group_address.to_be_bytes());
+        // Length of the data after the TPCI, the APCI is part of the data
+        cemi.push(data.len() as u8);
+        cemi.extend_from_slice(&[0x00, GROUP_VALUE_WRITE]);
+        cemi.extend_from_slice(data);
+
+        let mut message = HEADER.to_vec();
+        message.extend_from_slice(&ROUTING_INDICATION.to_be_bytes());
+        message.extend_from_slice(&((cemi.len() + 6) as u16).to_be_bytes());
+        message.extend(cemi);
+        message
+    }
+}
+
+/// Encode a value. Casts from float saturate, so values out of range are clamped.
+fn encode(dpt: Dpt, value: f32) -> Vec<u8> {
+    match dpt {
+        Dpt::Scaling => vec![(value.clamp(0.0, 100.0) * 2.55).round() as u8],
+        Dpt::Unsigned16 => (value.round() as u16).to_be_bytes().to_vec(),
+        Dpt::Float16 => encode_float16(value).to_be_bytes().to_vec(),
+        Dpt::Float32 => value.to_be_bytes().to_vec(),
+    }
+}
+
+/// Encode a 2-byte float (DPT 9): `0.01 * M * 2^E` as `MEEEEMMM MMMMMMMM`, with the mantissa `M`
+/// in two's complement.
+fn encode_float16(value: f32) -> u16 {
+    let mut mantissa = value * 100.0;
+    let mut exponent = 0;
+    while !(-2048.0..=2047.0).contains(&mantissa) && exponent < 15 {
+        mantissa /= 2.0;
+        exponent += 1;
+    }
+    let mantissa = (mantissa.round() as i32).clamp(-2048, 2047);
+    let sign = if mantissa < 0 { 0x8000 } else { 0 };
+    sign | (exponent << 11) | (mantissa as u16 & 0x07ff)
+}
+
+/// Parse a group address with three levels (`main/middle/sub`) or two levels (`main/sub`).
+pub fn parse_group_address(address: &str) -> Option<u16> {
+    let parts: Vec<u16> = address
+        .split('/')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let address = match parts[..] {
+        [main, middle, sub] if main < 32 && middle < 8 && sub < 256 => {
+            main << 11 | middle << 8 | sub
+        }
+        [main, sub] if main < 32 && sub < 2048 => main << 11 | sub,
+        _ => return None,
+    };
+    // 0/0/0 is the broadcast address
+    (address != 0).then_some(address)
+}
+
+/// Parse an individual address (`area.line.device`).
+pub fn parse_individual_address(address: &str) -> Option<u16> {
+    let parts: Vec<u16> = address
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    match parts[..] {
+        [area, line, device] if area < 16 && line < 16 && device < 256 => {
+            Some(area << 12 | line << 8 | device)
+        }
+        _ => None,
+    }
+}
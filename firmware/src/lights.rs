@@ -0,0 +1,60 @@
+//! Light switch events from the interrupt thresholds of the VEML7700, so that switching the lights
+//! on or off is submitted right away, instead of with the next measurement.
+//!
+//! With `sensors.veml7700.lights_on_lux`, the high threshold of the sensor is set to
+//! `lights_on_lux` and the low threshold to `lights_off_lux`, and the sensor flags when the
+//! illuminance is beyond a threshold for two samples in a row (so that a flicker is ignored). The
+//! VEML7700 has no interrupt pin (unlike the VEML6030), so the flags are polled every second in a
+//! timer task. When the lights are switched on or off, a `lights` event is submitted (see
+//! [`crate::events`]).
+
+use std::time::Duration;
+
+use veml6030::InterruptStatus;
+
+/// Interval at which the interrupt flags are polled
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Turns the interrupt flags of the thresholds into the state of the lights.
+pub struct LightSwitch {
+    on_lux: f32,
+    off_lux: f32,
+    /// Whether the lights are on, `None` until the first poll
+    on: Option<bool>,
+}
+
+impl LightSwitch {
+    pub fn new(on_lux: f32, off_lux: f32) -> Self {
+        Self {
+            on_lux,
+            off_lux,
+            on: None,
+        }
+    }
+
+    /// Process the interrupt flags since the last poll and the current illuminance. Return the new
+    /// state if it changed. The state after the first poll is not reported.
+    pub fn update(&mut self, status: InterruptStatus, lux: f32) -> Option<bool> {
+        let on = match (self.on, status.was_too_high, status.was_too_low) {
+            (None, _, _) => lux >= self.on_lux,
+            // Both thresholds were crossed since the last poll, the current illuminance decides
+            (Some(on), true, true) => {
+                if lux >= self.on_lux {
+                    true
+                } else if lux < self.off_lux {
+                    false
+                } else {
+                    on
+                }
+            }
+            (Some(_), true, false) => true,
+            (Some(_), false, true) => false,
+            (Some(on), false, false) => on,
+        };
+        let previous = self.on.replace(on);
+        match previous {
+            Some(previous) if previous != on => Some(on),
+            _ => None,
+        }
+    }
+}
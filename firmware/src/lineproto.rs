@@ -0,0 +1,274 @@
+//! Serializer for the InfluxDB line protocol.
+//!
+//! A [`Line`] writes its measurement, tags, fields and timestamp directly into a [`fmt::Write`]
+//! sink, escaping the names and values on the fly instead of building intermediate strings:
+//!
+//! `<measurement>[,<tag>=<value>...] <field>=<value>[,<field>=<value>...] [<timestamp>]`
+//!
+//! The module only depends on `core`, so that it can be used without `std` as well.
+
+use core::fmt::{self, Write};
+
+/// Characters that are escaped in measurements
+const MEASUREMENT_SPECIAL: &[char] = &[',', ' '];
+
+/// Characters that are escaped in tag keys, tag values and field keys
+const KEY_SPECIAL: &[char] = &[',', '=', ' '];
+
+/// Characters that are escaped in string field values
+const STRING_SPECIAL: &[char] = &['"', '\\'];
+
+/// Value of a field, with its line protocol type
+pub trait FieldValue {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result;
+
+    /// Whether the value can be written. InfluxDB rejects NaN and infinite floats, the fields with
+    /// them are skipped.
+    fn is_valid(&self) -> bool {
+        true
+    }
+}
+
+/// A float that is written with a fixed number of decimals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fixed(pub f64, pub usize);
+
+impl FieldValue for Fixed {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{:.*}", self.1, self.0)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.0.is_finite()
+    }
+}
+
+impl FieldValue for f32 {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", self)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.is_finite()
+    }
+}
+
+impl FieldValue for f64 {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", self)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.is_finite()
+    }
+}
+
+macro_rules! impl_integer {
+    ($suffix:literal: $($type:ty),*) => {
+        $(
+            impl FieldValue for $type {
+                fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+                    write!(out, concat!("{}", $suffix), self)
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!("u": u8, u16, u32, u64, u128);
+impl_integer!("i": i8, i16, i32, i64, i128);
+
+impl FieldValue for bool {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+impl FieldValue for &str {
+    fn write_value(&self, out: &mut dyn Write) -> fmt::Result {
+        out.write_char('"')?;
+        escape(out, self, STRING_SPECIAL)?;
+        out.write_char('"')
+    }
+}
+
+/// Part of the line that was written last
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Tags,
+    Fields,
+    Timestamp,
+}
+
+/// A line that is being written. Tags must be added before the fields, the timestamp last.
+///
+/// Errors of the sink are kept until [`Line::finish`], nothing is written after an error.
+///
+/// Fields with invalid values (see [`FieldValue::is_valid`]) are skipped. A line without fields
+/// is invalid, so it must be dropped if [`Line::has_fields`] returns `false`.
+pub struct Line<W: Write> {
+    out: W,
+    section: Section,
+    result: fmt::Result,
+}
+
+impl<W: Write> Line<W> {
+    /// Start a line of `measurement` in `out`.
+    pub fn new(out: W, measurement: &str) -> Self {
+        let mut line = Self {
+            out,
+            section: Section::Tags,
+            result: Ok(()),
+        };
+        line.write(|out| escape(out, measurement, MEASUREMENT_SPECIAL));
+        line
+    }
+
+    /// Add a tag.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        debug_assert!(self.section == Section::Tags, "Tag after the fields");
+        self.write(|out| {
+            out.write_char(',')?;
+            write_tag(out, key, value)
+        });
+        self
+    }
+
+    /// Add the tags of a [`TagSet`].
+    pub fn tags(mut self, tags: &TagSet) -> Self {
+        debug_assert!(self.section == Section::Tags, "Tag after the fields");
+        if !tags.0.is_empty() {
+            self.write(|out| write!(out, ",{}", tags.0));
+        }
+        self
+    }
+
+    /// Add a field, unless its value is invalid.
+    pub fn field(mut self, key: &str, value: impl FieldValue) -> Self {
+        debug_assert!(
+            self.section != Section::Timestamp,
+            "Field after the timestamp"
+        );
+        if !value.is_valid() {
+            return self;
+        }
+        let separator = match self.section {
+            Section::Tags => ' ',
+            _ => ',',
+        };
+        self.section = Section::Fields;
+        self.write(|out| {
+            out.write_char(separator)?;
+            escape(out, key, KEY_SPECIAL)?;
+            out.write_char('=')?;
+            value.write_value(out)
+        });
+        self
+    }
+
+    /// Add the timestamp (in the precision of the write request). It's not written without
+    /// fields, since the line is dropped then.
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        debug_assert!(self.section != Section::Timestamp, "Second timestamp");
+        if self.section == Section::Fields {
+            self.section = Section::Timestamp;
+            self.write(|out| write!(out, " {}", timestamp));
+        }
+        self
+    }
+
+    /// Whether a field was added, a line without fields must be dropped.
+    pub fn has_fields(&self) -> bool {
+        self.section != Section::Tags
+    }
+
+    /// Return the sink, or the first error while writing to it.
+    pub fn finish(self) -> Result<W, fmt::Error> {
+        self.result.map(|()| self.out)
+    }
+
+    fn write(&mut self, f: impl FnOnce(&mut dyn Write) -> fmt::Result) {
+        if self.result.is_ok() {
+            self.result = f(&mut self.out);
+        }
+    }
+}
+
+impl Line<String> {
+    /// Start a line of `measurement` in a new string.
+    pub fn build(measurement: &str) -> Self {
+        Self::new(String::new(), measurement)
+    }
+
+    /// Return the line.
+    pub fn into_string(self) -> String {
+        // Writing to a string cannot fail
+        self.finish().unwrap_or_default()
+    }
+}
+
+/// Tags that are serialized once and added to many lines, e.g. the device tags
+#[derive(Debug, Clone, Default)]
+pub struct TagSet(String);
+
+impl TagSet {
+    /// Add a tag.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        if !self.0.is_empty() {
+            self.0.push(',');
+        }
+        // Writing to a string cannot fail
+        let _ = write_tag(&mut self.0, key, value);
+        self
+    }
+}
+
+/// Write `<key>=<value>`.
+fn write_tag(out: &mut dyn Write, key: &str, value: &str) -> fmt::Result {
+    escape(out, key, KEY_SPECIAL)?;
+    out.write_char('=')?;
+    escape(out, value, KEY_SPECIAL)
+}
+
+/// Write `text`, with a backslash before the `special` characters.
+fn escape(out: &mut dyn Write, text: &str, special: &[char]) -> fmt::Result {
+    let mut rest = text;
+    while let Some(index) = rest.find(special) {
+        out.write_str(&rest[..index])?;
+        out.write_char('\\')?;
+        let c = rest[index..].chars().next().unwrap_or_default();
+        out.write_char(c)?;
+        rest = &rest[index + c.len_utf8()..];
+    }
+    out.write_str(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_fields_are_skipped() {
+        let line = Line::build("air")
+            .tag("room", "office")
+            .field("temperature", f32::NAN)
+            .field("humidity", 40.5f32)
+            .field("pressure", Fixed(f64::INFINITY, 1))
+            .field("co2", Fixed(812.0, 0))
+            .timestamp(1700000000);
+        assert!(line.has_fields());
+        assert_eq!(
+            line.into_string(),
+            "air,room=office humidity=40.5,co2=812 1700000000"
+        );
+    }
+
+    #[test]
+    fn line_without_finite_fields_has_no_fields() {
+        let line = Line::build("air")
+            .field("temperature", f64::NEG_INFINITY)
+            .field("humidity", Fixed(f64::NAN, 1))
+            .timestamp(1700000000);
+        assert!(!line.has_fields());
+    }
+}
@@ -1,86 +1,600 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    ffi::CString,
+    marker::PhantomData,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::Context;
-use embedded_hal_0_2::blocking::delay::{DelayMs, DelayUs};
+use anyhow::{bail, Context};
+use embedded_hal_0_2::blocking::delay::DelayMs;
+#[cfg(any(feature = "lux", feature = "diff_pressure"))]
+use embedded_hal_0_2::blocking::delay::DelayUs;
 use embedded_svc::{
     http::{client::Client as HttpClient, Status},
     io::Write,
     utils::io,
     wifi::{ClientConfiguration, Configuration as WifiConfiguration, Wifi},
 };
+#[cfg(any(
+    feature = "ccs811",
+    feature = "fan",
+    feature = "thermocouple",
+    feature = "rtd",
+    feature = "epaper"
+))]
+use esp_idf_hal::gpio::OutputPin;
 use esp_idf_hal::{
     delay::FreeRtos,
+    gpio::{AnyIOPin, AnyInputPin, AnyOutputPin, Input, Output, PinDriver},
     i2c::{config::Config as I2cConfig, I2cDriver},
-    modem::Modem,
+    ledc::{LedcChannel, LedcTimer},
+    peripheral::Peripheral,
     peripherals::Peripherals,
+    spi::config::{Config as SpiConfig, MODE_0},
     units::FromValueType,
 };
 use esp_idf_svc::{
-    eventloop::{EspEventLoop, EspSystemEventLoop, System},
+    eventloop::EspSystemEventLoop,
     http::client::{Configuration as HttpConfiguration, EspHttpConnection},
-    nvs::{EspDefaultNvsPartition, EspNvsPartition, NvsDefault},
+    nvs::{EspCustomNvsPartition, EspDefaultNvsPartition},
     timer::EspTaskTimerService,
     wifi::EspWifi,
 };
-use sgp30::Sgp30;
-use shared_bus::I2cProxy;
-use shtcx::ShtC3;
-use veml6030::Veml6030;
+use shared_bus::{BusManagerStd, I2cProxy};
 
+mod api;
+mod asset;
+mod backend;
+mod bacnet;
+#[cfg(any(feature = "gas", feature = "ccs811"))]
+mod baseline;
+mod broadcast;
+#[cfg(feature = "bthome")]
+mod bthome;
+#[cfg(feature = "ina219")]
+mod budget;
+mod calibration;
+#[cfg(any(feature = "ina219", feature = "thermocouple", feature = "rtd"))]
+mod channel;
+mod clock;
+mod commands;
+mod config;
+mod connectivity;
+mod cpu;
+mod deadline;
 mod delay;
+mod delivery;
+mod discovery;
+mod display;
+mod downsampling;
+mod drivers;
+#[cfg(feature = "s0")]
+mod energy;
+mod esphome;
+mod espnow;
+mod eventlog;
+mod events;
+#[cfg(feature = "faults")]
+mod faults;
+#[cfg(feature = "geiger")]
+mod geiger;
+mod history;
+mod input;
+mod json;
+mod knx;
+#[cfg(feature = "lux")]
+mod lights;
+mod lineproto;
+mod mdns;
+mod modbus;
+mod mqtt;
+mod networks;
+mod ntfy;
+mod outputs;
+mod portal;
+mod power;
+mod presence;
+mod profile;
+mod prometheus;
+#[cfg(any(feature = "geiger", feature = "s0"))]
+mod pulse;
+mod reading;
+mod remote;
+mod roaming;
+mod rules;
+mod shutdown;
+mod simulation;
+mod snmp;
+mod spi;
+mod stats;
+mod ventilation;
+#[cfg(any(feature = "temp_humi", feature = "lux"))]
+mod warmup;
+mod watchdog;
+mod webhook;
+
+use crate::{
+    backend::{Dispatcher, InfluxDb, Submission},
+    broadcast::Broadcaster,
+    config::{Config, InfluxDbApi, Metric},
+    connectivity::{Connectivity, Transition},
+    delay::GeneralPurposeDelay,
+    delivery::Queue,
+    display::Display,
+    downsampling::Downsampler,
+    drivers::ssd1680::Ssd1680,
+    events::Event,
+    history::History,
+    input::{Action, Button},
+    json::JsonWebhook,
+    knx::Knx,
+    lineproto::{Line, TagSet},
+    mqtt::Publisher,
+    outputs::{Outputs, PwmOutput},
+    power::SafeMode,
+    presence::Occupancy,
+    profile::ActiveProfile,
+    prometheus::Exporter,
+    reading::{Reading, Unit},
+    roaming::Roaming,
+    rules::Rules,
+    simulation::Simulator,
+    spi::{SpiBus, SpiDevice},
+    ventilation::Ventilation,
+    watchdog::Heartbeat,
+};
 
-use crate::delay::GeneralPurposeDelay;
+// Sensors and peripherals, only with their features
+#[cfg(any(feature = "gas", feature = "ccs811"))]
+use crate::baseline::BaselinePersistence;
+#[cfg(any(feature = "ina219", feature = "thermocouple", feature = "rtd"))]
+use crate::channel::Channel;
+#[cfg(feature = "spectral")]
+use crate::drivers::as7341::As7341;
+#[cfg(feature = "pressure")]
+use crate::drivers::bmp390::Bmp390;
+#[cfg(feature = "ccs811")]
+use crate::drivers::ccs811::Ccs811;
+#[cfg(feature = "ens160")]
+use crate::drivers::ens160::Ens160;
+#[cfg(feature = "ld2410")]
+use crate::drivers::ld2410::Ld2410;
+#[cfg(feature = "thermocouple")]
+use crate::drivers::max31855::Max31855;
+#[cfg(feature = "rtd")]
+use crate::drivers::max31865::Max31865;
+#[cfg(feature = "pzem")]
+use crate::drivers::pzem004t::Pzem004t;
+#[cfg(feature = "scd4x")]
+use crate::drivers::scd4x::Scd4x;
+#[cfg(feature = "diff_pressure")]
+use crate::drivers::sdp8xx::Sdp8xx;
+#[cfg(feature = "hcho")]
+use crate::drivers::sfa30::Sfa30;
+#[cfg(feature = "tsl2591")]
+use crate::drivers::tsl2591::Tsl2591;
+#[cfg(feature = "s0")]
+use crate::energy::EnergyMeter;
+#[cfg(feature = "geiger")]
+use crate::geiger::Geiger;
+#[cfg(feature = "supply")]
+use crate::power::SupplyMonitor;
+#[cfg(any(feature = "presence", feature = "ld2410"))]
+use crate::presence::PresenceDetector;
+#[cfg(any(feature = "temp_humi", feature = "lux"))]
+use crate::warmup::Warmup;
+#[cfg(feature = "ina219")]
+use crate::{budget::EnergyBudget, drivers::ina219::Ina219};
+#[cfg(feature = "presence")]
+use apds9960::Apds9960;
+#[cfg(any(feature = "geiger", feature = "s0", feature = "buttons"))]
+use esp_idf_hal::gpio::IOPin;
+#[cfg(feature = "epaper")]
+use esp_idf_hal::gpio::InputPin;
+#[cfg(feature = "rtd")]
+use esp_idf_hal::spi::config::MODE_1;
+#[cfg(any(feature = "ld2410", feature = "pzem"))]
+use esp_idf_hal::uart::{config::Config as UartConfig, UartDriver};
+#[cfg(feature = "gas")]
+use sgp30::Sgp30;
+#[cfg(feature = "temp_humi")]
+use shtcx::ShtC3;
+#[cfg(feature = "lux")]
+use veml6030::Veml6030;
 
 // VEML sensor integration time
+#[cfg(feature = "lux")]
 const VEML_INTEGRATION_TIME: veml6030::IntegrationTime = veml6030::IntegrationTime::Ms25;
 
-// Sensor information
-const SENSILO_NAME: &str = env!("SENSILO_NAME");
+// SDP8xx sensor I²C address
+#[cfg(feature = "diff_pressure")]
+const SDP8XX_ADDRESS: u8 = drivers::sdp8xx::ADDRESS_SDP8X0;
+
+// BMP390 sensor I²C address
+#[cfg(feature = "pressure")]
+const BMP390_ADDRESS: u8 = drivers::bmp390::ADDRESS_SDO_HIGH;
+
+// ENS160 sensor I²C address
+#[cfg(feature = "ens160")]
+const ENS160_ADDRESS: u8 = drivers::ens160::ADDRESS_ADDR_LOW;
+
+// SGP30 baseline handling: The stored baseline is restored right after the initialization, and
+// the current one is saved every hour (as recommended by the datasheet).
+#[cfg(feature = "gas")]
+const SGP30_BASELINE_SAVE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// CCS811 sensor I²C address
+#[cfg(feature = "ccs811")]
+const CCS811_ADDRESS: u8 = drivers::ccs811::ADDRESS_ADDR_LOW;
+
+// CCS811 baseline handling: The baseline should be restored after the 20 minute conditioning
+// period, and saved once per day.
+#[cfg(feature = "ccs811")]
+const CCS811_BASELINE_WARMUP: Duration = Duration::from_secs(20 * 60);
+#[cfg(feature = "ccs811")]
+const CCS811_BASELINE_SAVE_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+// S0 energy meter: Interval at which the energy total is saved to NVS (if it changed)
+#[cfg(feature = "s0")]
+const ENERGY_SAVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// Watchdog: Time allowed for a measurement cycle on top of the measurement interval (reading the
+// sensors, submitting with retries, backfilling), and maximum time between two gas sensor ticks
+const MAIN_LOOP_GRACE: Duration = Duration::from_secs(5 * 60);
+#[cfg(feature = "gas")]
+const GAS_TIMER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// WiFi: Time to wait for the connection to the access point, and number of attempts before the
+// provisioning portal is opened (see `portal.rs`)
+const WIFI_CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+const WIFI_CONNECT_ATTEMPTS: u32 = 3;
 
-// WiFi credentials
-const SENSILO_WIFI_SSID: &str = env!("SENSILO_WIFI_SSID");
-const SENSILO_WIFI_PASSWORD: &str = env!("SENSILO_WIFI_PASSWORD");
+// Backfill: Maximum number of lines that are submitted at once
+const BACKFILL_BATCH_SIZE: usize = 50;
 
-// InfluxDB
-const SENSILO_INFLUXDB_HOST: &str = env!("SENSILO_INFLUXDB_HOST");
-const SENSILO_INFLUXDB_ORG: &str = env!("SENSILO_INFLUXDB_ORG");
-const SENSILO_INFLUXDB_BUCKET: &str = env!("SENSILO_INFLUXDB_BUCKET");
-const SENSILO_INFLUXDB_API_TOKEN: &str = env!("SENSILO_INFLUXDB_API_TOKEN");
+// InfluxDB fields of the AS7341 spectral channels
+#[cfg(feature = "spectral")]
+const SPECTRUM_FIELDS: [&str; 8] = ["f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8"];
+
+// Name of the sensor if the config has none
+const SENSILO_NAME: &str = "sensilo";
 
 // Firmware version
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-type SharedBuxProxyI2c<'a> = I2cProxy<'a, Mutex<I2cDriver<'a>>>;
+// NVS partition of the delivery queue and the event log (see `partitions.csv`)
+const LOG_PARTITION: &str = "nvs_log";
+
+/// Durations of a request to InfluxDB, submitted as diagnostics
+#[derive(Debug, Copy, Clone)]
+struct RequestTiming {
+    /// Time to open the connection, including the TLS handshake (close to 0 if the connection
+    /// was reused)
+    connect: Duration,
+    /// Time to send the payload
+    write: Duration,
+    /// Time from the end of the payload to the status, including the processing by InfluxDB, and
+    /// to read the response body
+    response: Duration,
+    /// Time of the whole request, including the connection
+    total: Duration,
+    reused: bool,
+}
+
+/// Durations of the stages of a measurement cycle on the submission path, submitted as
+/// diagnostics with the next cycle
+#[derive(Debug, Copy, Clone)]
+struct CycleTiming {
+    /// Time to read the sensors
+    read: Duration,
+    /// Time to serialize the readings and events to lines
+    serialize: Duration,
+    /// Time from the start of the sensor reads until the submission was confirmed, `None` if it
+    /// was not delivered
+    latency: Option<Duration>,
+}
+
+thread_local! {
+    /// HTTP client of the last request to InfluxDB with its host. The connection is kept open, so
+    /// that the next submission to the same host saves the TCP and TLS handshakes. TLS sessions
+    /// are not resumed, a new connection always does a full handshake (`esp_http_client` of
+    /// ESP-IDF 4.4 can't resume sessions).
+    static INFLUXDB_CLIENT: RefCell<Option<(String, HttpClient<EspHttpConnection>)>> =
+        const { RefCell::new(None) };
+
+    /// Durations of the last request to InfluxDB
+    static LAST_REQUEST: Cell<Option<RequestTiming>> = const { Cell::new(None) };
+}
+
+// Unused in builds without I²C sensors
+#[allow(dead_code)]
+#[cfg(not(feature = "faults"))]
+type SharedBuxProxyI2c<'a> = deadline::I2c<I2cProxy<'a, Mutex<I2cDriver<'a>>>>;
+#[allow(dead_code)]
+#[cfg(feature = "faults")]
+type SharedBuxProxyI2c<'a> = faults::I2c<deadline::I2c<I2cProxy<'a, Mutex<I2cDriver<'a>>>>>;
+#[cfg(feature = "ccs811")]
+type Ccs811Sensor<'a> = Ccs811<SharedBuxProxyI2c<'a>, PinDriver<'a, AnyOutputPin, Output>>;
+type EpaperDisplay<'a> =
+    Display<SpiDevice<'a>, PinDriver<'a, AnyOutputPin, Output>, PinDriver<'a, AnyInputPin, Input>>;
 
+/// The usable sensors. Only the sensors of the enabled features have a field, so that the drivers
+/// of the other sensors are not compiled in. Built with a [`SensorsBuilder`].
 #[derive(Default)]
 struct Sensors<'a> {
-    temp_humi: Option<ShtC3<SharedBuxProxyI2c<'a>>>,
-    lux: Option<Veml6030<SharedBuxProxyI2c<'a>>>,
-    gas: Option<Sgp30<SharedBuxProxyI2c<'a>, GeneralPurposeDelay>>,
+    #[cfg(feature = "temp_humi")]
+    temp_humi: Option<(ShtC3<SharedBuxProxyI2c<'a>>, Warmup)>,
+    /// With the light switch detector, if the light events are enabled
+    #[cfg(feature = "lux")]
+    lux: Option<(
+        Veml6030<SharedBuxProxyI2c<'a>>,
+        Warmup,
+        Option<lights::LightSwitch>,
+    )>,
+    #[cfg(feature = "tsl2591")]
+    tsl2591: Option<Tsl2591<SharedBuxProxyI2c<'a>>>,
+    #[cfg(feature = "gas")]
+    gas: Option<(
+        Sgp30<SharedBuxProxyI2c<'a>, GeneralPurposeDelay>,
+        BaselinePersistence,
+    )>,
+    #[cfg(feature = "diff_pressure")]
+    diff_pressure: Option<Sdp8xx<SharedBuxProxyI2c<'a>>>,
+    /// With the altitude, for the sea-level pressure
+    #[cfg(feature = "pressure")]
+    pressure: Option<(Bmp390<SharedBuxProxyI2c<'a>>, Option<f32>)>,
+    #[cfg(feature = "ens160")]
+    air_quality: Option<Ens160<SharedBuxProxyI2c<'a>>>,
+    #[cfg(feature = "ccs811")]
+    ccs811: Option<(Ccs811Sensor<'a>, BaselinePersistence)>,
+    #[cfg(feature = "hcho")]
+    hcho: Option<Sfa30<SharedBuxProxyI2c<'a>>>,
+    /// With the lux factor
+    #[cfg(feature = "spectral")]
+    spectral: Option<(As7341<SharedBuxProxyI2c<'a>>, f32)>,
+    #[cfg(feature = "presence")]
+    presence: Option<(Apds9960<SharedBuxProxyI2c<'a>>, PresenceDetector)>,
+    #[cfg(feature = "ld2410")]
+    radar: Option<(Ld2410<UartDriver<'a>>, PresenceDetector)>,
+    #[cfg(feature = "geiger")]
+    geiger: Option<Geiger<'a>>,
+    #[cfg(feature = "s0")]
+    energy: Option<EnergyMeter<'a>>,
+    #[cfg(feature = "pzem")]
+    power_meter: Option<Pzem004t<UartDriver<'a>>>,
+    #[cfg(feature = "thermocouple")]
+    thermocouple: Option<Channel<Max31855<SpiDevice<'a>>>>,
+    #[cfg(feature = "rtd")]
+    rtd: Option<Channel<Max31865<SpiDevice<'a>>>>,
+    #[cfg(feature = "ina219")]
+    current: Vec<Channel<Ina219<SharedBuxProxyI2c<'a>>>>,
+    #[cfg(feature = "scd4x")]
+    co2: Option<Scd4x<SharedBuxProxyI2c<'a>>>,
+    /// Serial numbers of the sensors by their name in the config, for the `sensor_serial` tag.
+    /// Empty without `sensors.serial_tags`.
+    serials: BTreeMap<&'static str, String>,
+    /// Keeps the lifetime of the drivers in builds without any sensors
+    _drivers: PhantomData<&'a ()>,
+}
+
+impl<'a> Sensors<'a> {
+    /// Print whether the enabled sensors are usable.
+    fn print_usable(&self) {
+        println!("Usable sensors:");
+        #[cfg(feature = "temp_humi")]
+        println!(
+            "  Temperature/Humidity (SHTC3): {}",
+            self.temp_humi.is_some()
+        );
+        #[cfg(feature = "lux")]
+        println!("  Lux (VEML7700): {}", self.lux.is_some());
+        #[cfg(feature = "tsl2591")]
+        println!("  Lux (TSL2591): {}", self.tsl2591.is_some());
+        #[cfg(feature = "gas")]
+        println!("  Gas (SGP30): {}", self.gas.is_some());
+        #[cfg(feature = "diff_pressure")]
+        println!(
+            "  Differential pressure (SDP8xx): {}",
+            self.diff_pressure.is_some()
+        );
+        #[cfg(feature = "pressure")]
+        println!("  Pressure (BMP390): {}", self.pressure.is_some());
+        #[cfg(feature = "ens160")]
+        println!("  Air quality (ENS160): {}", self.air_quality.is_some());
+        #[cfg(feature = "ccs811")]
+        println!("  Gas (CCS811): {}", self.ccs811.is_some());
+        #[cfg(feature = "hcho")]
+        println!("  Formaldehyde (SFA30): {}", self.hcho.is_some());
+        #[cfg(feature = "spectral")]
+        println!("  Spectral (AS7341): {}", self.spectral.is_some());
+        #[cfg(feature = "presence")]
+        println!("  Presence (APDS9960): {}", self.presence.is_some());
+        #[cfg(feature = "ld2410")]
+        println!("  Presence (LD2410): {}", self.radar.is_some());
+        #[cfg(feature = "geiger")]
+        println!("  Radiation (Geiger counter): {}", self.geiger.is_some());
+        #[cfg(feature = "s0")]
+        println!("  Energy (S0): {}", self.energy.is_some());
+        #[cfg(feature = "pzem")]
+        println!("  Energy (PZEM-004T): {}", self.power_meter.is_some());
+        #[cfg(feature = "thermocouple")]
+        println!("  Thermocouple (MAX31855): {}", self.thermocouple.is_some());
+        #[cfg(feature = "rtd")]
+        println!("  RTD (MAX31865): {}", self.rtd.is_some());
+        #[cfg(feature = "ina219")]
+        println!("  Current (INA219): {} channel(s)", self.current.len());
+        #[cfg(feature = "scd4x")]
+        println!("  CO₂ (SCD4x): {}", self.co2.is_some());
+    }
+
+    /// Return the enabled sensors with their name, their name in the config (see
+    /// [`config::SENSOR_NAMES`]) and whether they could be initialized.
+    fn enabled(&self) -> Vec<(&'static str, &'static str, bool)> {
+        let sensors: &[(&'static str, &'static str, bool)] = &[
+            #[cfg(feature = "temp_humi")]
+            ("SHTC3", "shtc3", self.temp_humi.is_some()),
+            #[cfg(feature = "lux")]
+            ("VEML7700", "veml7700", self.lux.is_some()),
+            #[cfg(feature = "tsl2591")]
+            ("TSL2591", "tsl2591", self.tsl2591.is_some()),
+            #[cfg(feature = "gas")]
+            ("SGP30", "sgp30", self.gas.is_some()),
+            #[cfg(feature = "diff_pressure")]
+            ("SDP8xx", "sdp8xx", self.diff_pressure.is_some()),
+            #[cfg(feature = "pressure")]
+            ("BMP390", "bmp390", self.pressure.is_some()),
+            #[cfg(feature = "ens160")]
+            ("ENS160", "ens160", self.air_quality.is_some()),
+            #[cfg(feature = "ccs811")]
+            ("CCS811", "ccs811", self.ccs811.is_some()),
+            #[cfg(feature = "hcho")]
+            ("SFA30", "sfa30", self.hcho.is_some()),
+            #[cfg(feature = "spectral")]
+            ("AS7341", "as7341", self.spectral.is_some()),
+            #[cfg(feature = "scd4x")]
+            ("SCD4x", "scd4x", self.co2.is_some()),
+            #[cfg(feature = "presence")]
+            ("APDS9960", "apds9960", self.presence.is_some()),
+            #[cfg(feature = "ld2410")]
+            ("LD2410", "ld2410", self.radar.is_some()),
+            #[cfg(feature = "pzem")]
+            ("PZEM-004T", "pzem004t", self.power_meter.is_some()),
+        ];
+        sensors.to_vec()
+    }
+
+    /// Return the names of the enabled sensors that could not be initialized.
+    fn failed(&self) -> Vec<&'static str> {
+        self.enabled()
+            .into_iter()
+            .filter(|(_, _, usable)| !usable)
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    /// Return whether a presence sensor is usable, which is polled in the presence task.
+    fn has_presence_sensor(&self) -> bool {
+        [
+            #[cfg(feature = "presence")]
+            self.presence.is_some(),
+            #[cfg(feature = "ld2410")]
+            self.radar.is_some(),
+        ]
+        .contains(&true)
+    }
+
+    /// Save the state of the sensors that is persisted in NVS (the SGP30 and CCS811 baselines and
+    /// the S0 energy total), before a restart.
+    #[cfg(any(feature = "gas", feature = "ccs811", feature = "s0"))]
+    fn save_state(&mut self) {
+        // The baselines are only saved once they are based on the stored ones, like when they're
+        // saved periodically
+        #[cfg(feature = "gas")]
+        if let Some((sgp30, baseline)) = self.gas.as_mut().filter(|(_, b)| b.is_restored()) {
+            match sgp30.get_baseline() {
+                Ok(current) => baseline.save(&sgp30_baseline_bytes(&current)),
+                Err(e) => eprintln!("SGP30: ERROR: Could not read baseline: {:?}", e),
+            }
+        }
+        #[cfg(feature = "ccs811")]
+        if let Some((ccs, baseline)) = self.ccs811.as_mut().filter(|(_, b)| b.is_restored()) {
+            match ccs.baseline() {
+                Ok(current) => baseline.save(&current),
+                Err(e) => eprintln!("CCS811: ERROR: Could not read baseline: {:?}", e),
+            }
+        }
+        #[cfg(feature = "s0")]
+        if let Some(ref mut energy) = self.energy {
+            energy.save_pending();
+        }
+    }
 }
 
+/// The readings of a measurement cycle (see `reading.rs`)
 #[derive(Default)]
 struct Measurements {
-    /// Temperature
-    temperature: Option<shtcx::Temperature>,
-    /// Humidity
-    humidity: Option<shtcx::Humidity>,
-    /// Illuminance in Lux
-    illuminance: Option<f32>,
-    /// CO2 equivalent in PPM
-    co2eq_ppm: Option<u16>,
-    /// TVOC equivalent in PPB
-    tvoc_ppb: Option<u16>,
+    readings: Vec<Reading>,
+    /// Sensors whose reads failed, by their name in the config (see [`config::SENSOR_NAMES`])
+    failed: Vec<&'static str>,
+    /// Sensors whose reads took longer than their budget, with the duration of the read (see
+    /// `deadline.rs`)
+    timeouts: Vec<(&'static str, Duration)>,
 }
 
 impl Measurements {
     /// Reset measurement values to their defaults
     fn reset(&mut self) {
-        *self = Self::default();
+        self.readings.clear();
+        self.failed.clear();
+        self.timeouts.clear();
+    }
+
+    /// Record a failed read of a sensor. A sensor is recorded once per measurement cycle, even
+    /// if it's read more often (like the SGP30).
+    #[allow(dead_code)] // Unused in builds without I²C, SPI or UART sensors
+    fn fail(&mut self, sensor: &'static str) {
+        if !self.failed.contains(&sensor) {
+            self.failed.push(sensor);
+        }
+    }
+
+    /// Add a reading. An earlier reading of the same quantity from the same sensor and channel is
+    /// replaced.
+    fn push(&mut self, reading: Reading) {
+        match self
+            .readings
+            .iter_mut()
+            .find(|existing| existing.same_series(&reading))
+        {
+            Some(existing) => *existing = reading,
+            None => self.readings.push(reading),
+        }
     }
+
+    /// Return the primary reading of a metric, if it was measured.
+    fn reading(&self, metric: Metric) -> Option<&Reading> {
+        self.readings
+            .iter()
+            .find(|reading| reading.metric == Some(metric))
+    }
+
+    /// Return the latest value of a metric, if it was measured.
+    fn value(&self, metric: Metric) -> Option<f32> {
+        self.reading(metric).map(|reading| reading.value.as_f32())
+    }
+}
+
+/// Reason why waiting for the next measurement ended early
+enum Wakeup {
+    /// A button was pressed
+    Action(Action),
+    /// Somebody entered or left the room
+    Occupancy,
+    /// A restart was requested
+    Restart,
+}
+
+/// A message to the main loop
+enum Message {
+    /// An event that should be submitted immediately (only sent by the presence sensors and the
+    /// light switch task)
+    #[cfg_attr(
+        not(any(feature = "presence", feature = "ld2410", feature = "lux")),
+        allow(dead_code)
+    )]
+    Event(Event),
+    /// A button was pressed
+    Action(Action),
+    /// The history values between two Unix timestamps should be resubmitted
+    Backfill { from: u64, to: u64 },
+    /// The device should be restarted, after a graceful shutdown (see `shutdown.rs`)
+    Restart,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -92,6 +606,58 @@ fn main() -> anyhow::Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
+    let log_nvs = match EspCustomNvsPartition::take(LOG_PARTITION) {
+        Ok(partition) => Some(partition),
+        Err(e) => {
+            eprintln!(
+                "Error: Could not open NVS partition {} (flashed without partitions.csv?): {}",
+                LOG_PARTITION, e
+            );
+            None
+        }
+    };
+
+    // Flight recorder, see `eventlog.rs`
+    if let Some(ref partition) = log_nvs {
+        if let Err(e) = eventlog::open(partition.clone(), nvs.clone()) {
+            eprintln!("Error: Could not open event log: {}", e);
+        }
+    }
+    eventlog::record(
+        eventlog::Kind::Boot,
+        &format!(
+            "Firmware v{}, reset reason: {}",
+            VERSION,
+            eventlog::reset_reason()
+        ),
+    );
+
+    // Runtime configuration
+    let config = Arc::new(config::load(nvs.clone()));
+    println!("Config: Hash {}", config::hash(&config));
+
+    // Start in the safe mode after repeated brown-outs or low supply voltage, see `power.rs`
+    #[cfg_attr(not(feature = "supply"), allow(unused_mut))]
+    let mut safe_mode = SafeMode::start(nvs.clone(), &config.safe_mode);
+    if let Some(reason) = safe_mode.reason() {
+        println!("Safe mode: {}", reason);
+    }
+
+    // CPU frequency and automatic light sleep, see `cpu.rs`
+    if let Err(e) = cpu::configure(&config.cpu) {
+        eprintln!("Error: Could not configure CPU frequency: {}", e);
+    }
+
+    // Asset tag, independent of the name
+    let serial = asset::serial(nvs.clone());
+    if let Some(ref serial) = serial {
+        println!("Serial number: {}", serial);
+    }
+
+    // Calibration dates of the sensors, see `calibration.rs`
+    if let Err(e) = calibration::open(nvs.clone(), &config.calibration) {
+        eprintln!("Error: Could not open calibration log: {}", e);
+    }
 
     // Delay provider
     let mut delay = GeneralPurposeDelay;
@@ -110,345 +676,3207 @@ fn main() -> anyhow::Result<()> {
     let i2c: &'static _ = shared_bus::new_std!(I2cDriver = i2c0).unwrap();
 
     // Sensors wrapper
-    let mut sensors = Sensors::default();
+    #[allow(unused_mut)]
+    let mut sensors = SensorsBuilder::new(i2c, config.sensors.serial_tags);
 
     // Initialize SHTC3 temperature/humidity sensor
-    if cfg!(feature = "temp_humi") {
+    #[cfg(feature = "temp_humi")]
+    {
         println!("SHTC3: Enabled");
-        init_shtc3(&mut sensors, i2c.acquire_i2c());
+        sensors.shtc3(&config.sensors.shtc3);
     }
 
     // Initialize VEML7700 lux sensor
-    if cfg!(feature = "lux") {
+    #[cfg(feature = "lux")]
+    {
         println!("VEML7700: Enabled");
-        init_veml7700(&mut sensors, i2c.acquire_i2c());
+        sensors.veml7700(&config.sensors.veml7700);
+    }
+
+    // Initialize TSL2591 lux sensor
+    #[cfg(feature = "tsl2591")]
+    {
+        println!("TSL2591: Enabled");
+        sensors.tsl2591();
     }
 
     // Initialize SGP30 gas sensor
-    if cfg!(feature = "gas") {
+    #[cfg(feature = "gas")]
+    {
         println!("SGP30: Enabled");
-        init_sgp30(&mut sensors, i2c.acquire_i2c());
+        sensors.sgp30(nvs.clone());
     }
 
-    println!();
+    // Initialize SDP8xx differential pressure sensor
+    #[cfg(feature = "diff_pressure")]
+    {
+        println!("SDP8xx: Enabled");
+        sensors.sdp8xx();
+    }
 
-    // Connect WiFi
-    let wifi = connect_wifi(peripherals.modem, sys_loop, nvs)?;
+    // Initialize BMP390 barometric pressure sensor
+    #[cfg(feature = "pressure")]
+    {
+        println!("BMP390: Enabled");
+        sensors.bmp390(&config.sensors.bmp390, config.altitude);
+    }
 
-    // Wait for IP assignment from DHCP
-    println!("WiFi connected! Waiting for IP...");
-    loop {
-        let ip_info = wifi.sta_netif().get_ip_info().unwrap();
-        if ip_info.ip.is_unspecified() {
-            delay.delay_ms(100);
-        } else {
-            println!("  Assigned IP: {}", ip_info.ip);
-            if let Some(dns) = ip_info.dns {
-                println!("  DNS:         {}", dns);
-            } else {
-                println!("  Warning: No DNS server assigned!");
-            }
-            break;
-        }
+    // Initialize ENS160 air quality sensor
+    #[cfg(feature = "ens160")]
+    {
+        println!("ENS160: Enabled");
+        sensors.ens160();
     }
-    println!();
 
-    println!("Usable sensors:");
-    println!(
-        "  Temperature/Humidity (SHTC3): {}",
-        sensors.temp_humi.is_some()
-    );
-    println!("  Lux (VEML7700): {}", sensors.lux.is_some());
-    println!("  Gas (SGP30): {}", sensors.gas.is_some());
-    println!();
+    // Initialize CCS811 gas sensor. The fan PWM output uses the nWAKE pin as well, so the
+    // ventilation controller can only be used without the CCS811 (enforced by the build script).
+    #[cfg(feature = "ccs811")]
+    {
+        println!("CCS811: Enabled");
+        sensors.ccs811(
+            peripherals.pins.gpio10.downgrade_output(), // nWAKE
+            nvs.clone(),
+        );
+    }
+    #[cfg(feature = "fan")]
+    let fan_pin = Some(peripherals.pins.gpio10.downgrade_output());
+    #[cfg(not(feature = "fan"))]
+    let fan_pin = None;
 
-    println!("Starting main loop");
+    // Initialize SFA30 formaldehyde sensor
+    #[cfg(feature = "hcho")]
+    {
+        println!("SFA30: Enabled");
+        sensors.sfa30();
+    }
 
-    let schedule_gas_sensor_timer = sensors.gas.is_some();
+    // Initialize AS7341 spectral sensor
+    #[cfg(feature = "spectral")]
+    {
+        println!("AS7341: Enabled");
+        sensors.as7341(&config.sensors.as7341);
+    }
 
-    let sensors = Arc::new(Mutex::new(sensors));
-    let measurements = Arc::new(Mutex::new(Measurements::default()));
+    // Initialize SCD4x CO₂ sensor
+    #[cfg(feature = "scd4x")]
+    {
+        println!("SCD4x: Enabled");
+        sensors.scd4x(config.altitude);
+    }
 
-    // The SGP30 requires to be called at 1s intervals for the internal algorithm to work. Thus,
-    // schedule a periodic timer task.
-    let mut gas_sensor_timer = None;
-    if schedule_gas_sensor_timer {
-        // Create timer task
-        let timer_sensors = sensors.clone();
-        let timer_measurements = measurements.clone();
-        let mut seconds_since_start = 0usize;
-        let timer = EspTaskTimerService::new()?.timer(move || {
-            seconds_since_start = seconds_since_start.saturating_add(1);
-            let mut s = timer_sensors.lock().expect("Failed to lock sensors mutex");
-            if let Some(ref mut sgp30) = s.gas {
-                match sgp30.measure() {
-                    Ok(measurement) => {
-                        println!(":: CO₂eq: {} PPM", measurement.co2eq_ppm);
-                        println!(":: TVOC:  {} PPB", measurement.tvoc_ppb);
-                        if seconds_since_start > 32 {
-                            // Note: Give sensor some time for initial calibration (>15s)
-                            let mut m = timer_measurements
-                                .lock()
-                                .expect("Failed to lock measurements mutex");
-                            m.co2eq_ppm = Some(measurement.co2eq_ppm);
-                            m.tvoc_ppb = Some(measurement.tvoc_ppb);
-                        }
-                    }
-                    Err(e) => eprintln!("SGP30: ERROR: {:?}", e),
-                }
-            }
-        })?;
+    // Initialize INA219 current monitors
+    #[cfg(feature = "ina219")]
+    {
+        println!("INA219: Enabled");
+        let ina219 = &config.sensors.ina219;
+        for channel in ina219.channels.iter() {
+            println!("  Channel {} at 0x{:02x}", channel.tag(), channel.address);
+            sensors.ina219(
+                channel.address,
+                ina219.shunt_milliohm / 1000.0,
+                channel.tag(),
+            );
+        }
+    }
 
-        // Schedule timer
-        timer.every(Duration::from_secs(1))?;
+    // Initialize APDS9960 proximity sensor
+    #[cfg(feature = "presence")]
+    {
+        println!("APDS9960: Enabled");
+        sensors.apds9960(&config.sensors.apds9960);
+    }
 
-        // Prevent timer from being dropped, and thus, being cancelled
-        gas_sensor_timer = Some(timer);
+    // Initialize LD2410 mmWave radar or PZEM-004T energy monitor. Both are connected to UART1, so
+    // only one of them can be used. The e-paper display uses the UART1 pins as well, so it can
+    // only be used without UART1 devices. The build script rejects conflicting features.
+    #[cfg(feature = "ld2410")]
+    {
+        println!("LD2410: Enabled");
+        match UartDriver::new(
+            peripherals.uart1,
+            peripherals.pins.gpio4, // TX
+            peripherals.pins.gpio5, // RX
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::new().baudrate(drivers::ld2410::BAUD_RATE.Hz()),
+        ) {
+            Ok(uart) => sensors.ld2410(uart, &config.sensors.ld2410),
+            Err(e) => eprintln!("  Error: Could not initialize UART: {}", e),
+        }
     }
-    if gas_sensor_timer.is_some() {
-        println!("Scheduled periodic gas sensor task at 1s intervals");
+    #[cfg(feature = "pzem")]
+    {
+        println!("PZEM-004T: Enabled");
+        match UartDriver::new(
+            peripherals.uart1,
+            peripherals.pins.gpio4, // TX
+            peripherals.pins.gpio5, // RX
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            &UartConfig::new().baudrate(drivers::pzem004t::BAUD_RATE.Hz()),
+        ) {
+            Ok(uart) => sensors.pzem004t(uart),
+            Err(e) => eprintln!("  Error: Could not initialize UART: {}", e),
+        }
     }
+    #[cfg(feature = "epaper")]
+    let epaper_pins = Some((
+        peripherals.pins.gpio0.downgrade_output(), // CS
+        peripherals.pins.gpio4.downgrade_output(), // DC
+        peripherals.pins.gpio5.downgrade_input(),  // BUSY
+    ));
+    #[cfg(not(feature = "epaper"))]
+    let epaper_pins = None;
 
-    loop {
-        {
-            // Get access to shared data
-            let mut s = sensors.lock().expect("Failed to lock sensors mutex");
-            let mut m = measurements
-                .lock()
-                .expect("Failed to lock measurements mutex");
+    // SPI bus, only initialized if there's an SPI device
+    let mut display = None;
+    if cfg!(any(feature = "thermocouple", feature = "rtd")) || epaper_pins.is_some() {
+        match SpiBus::new(
+            peripherals.spi2,
+            peripherals.pins.gpio2, // SCLK
+            peripherals.pins.gpio8, // SDO (MOSI)
+            peripherals.pins.gpio9, // SDI (MISO)
+        ) {
+            Ok(spi) => {
+                // Initialize MAX31855 thermocouple converter
+                #[cfg(feature = "thermocouple")]
+                {
+                    println!("MAX31855: Enabled");
+                    sensors.max31855(
+                        &spi,
+                        peripherals.pins.gpio0.downgrade_output(),
+                        &config.sensors.max31855,
+                    );
+                }
 
-            // Read sensors
-            read_sensors(&mut s, &mut m, &mut delay);
+                // Initialize MAX31865 RTD converter
+                #[cfg(feature = "rtd")]
+                {
+                    println!("MAX31865: Enabled");
+                    sensors.max31865(
+                        &spi,
+                        peripherals.pins.gpio10.downgrade_output(),
+                        &config.sensors.max31865,
+                        config.mains_frequency,
+                    );
+                }
 
-            // Submit measurements
-            if let Err(e) = submit_measurements(&m) {
-                eprintln!("Error: Could not submit measurement: {}", e);
+                // Initialize SSD1680 e-paper display
+                if let Some((cs, dc, busy)) = epaper_pins {
+                    println!("E-paper display: Enabled");
+                    display = init_epaper(&spi, cs, dc, busy);
+                }
             }
+            Err(e) => eprintln!("Error: Could not initialize SPI bus: {}", e),
+        }
+    }
 
-            // Reset measurements
-            m.reset();
+    // Initialize Geiger counter pulse input. The buttons use the pulse input pins as well, so a
+    // button is only available if the pulse input on its pin is not used (the build script rejects
+    // buttons if both pulse inputs are used).
+    #[cfg(feature = "geiger")]
+    {
+        println!("Geiger counter: Enabled");
+        if cfg!(feature = "buttons") {
+            eprintln!("  Warning: Button A disabled, it shares GPIO3 with the Geiger counter");
         }
+        sensors.geiger(peripherals.pins.gpio3.downgrade(), &config.sensors.geiger);
+    }
+    #[cfg(all(feature = "buttons", not(feature = "geiger")))]
+    let button_a = Some(peripherals.pins.gpio3.downgrade());
+    #[cfg(not(all(feature = "buttons", not(feature = "geiger"))))]
+    let button_a = None;
 
-        // Wait for a few seconds until the next submission interval.
-        //
-        // Note: It's important that the mutexes are not locked while sleeping!
-        delay.delay_ms(30 * 1000);
+    // Initialize S0 energy meter pulse input
+    #[cfg(feature = "s0")]
+    {
+        println!("S0 energy meter: Enabled");
+        if cfg!(feature = "buttons") {
+            eprintln!("  Warning: Button B disabled, it shares GPIO1 with the S0 energy meter");
+        }
+        sensors.energy_meter(
+            peripherals.pins.gpio1.downgrade(),
+            nvs.clone(),
+            &config.sensors.s0,
+        );
     }
-}
+    #[cfg(all(feature = "buttons", not(feature = "s0")))]
+    let button_b = Some(peripherals.pins.gpio1.downgrade());
+    #[cfg(not(all(feature = "buttons", not(feature = "s0"))))]
+    let button_b = None;
 
-/// Initialize the SHTC3 sensor. If successful, add it to the [`Sensors`] instance.
-fn init_shtc3<'a>(sensors: &mut Sensors<'a>, i2c: SharedBuxProxyI2c<'a>) {
-    let mut shtc3 = shtcx::shtc3(i2c);
-    let mut success = true;
-    match shtc3.device_identifier() {
-        Ok(id) => println!("  Device ID: {}", id),
-        Err(e) => {
-            eprintln!("  Error: Could not get device ID: {:?}", e);
-            success = false;
+    let sensors = sensors.build();
+
+    // Initialize buttons. Button A turns to the next display page, button B to the previous one.
+    // A long press on either button triggers an immediate measurement.
+    let mut buttons = Vec::new();
+    if cfg!(feature = "buttons") {
+        println!("Buttons: Enabled");
+        let long_press = Duration::from_millis(config.buttons.long_press_ms.into());
+        if let Some(pin) = button_a {
+            init_button(&mut buttons, pin, "A", long_press, Action::NextPage);
+        }
+        if let Some(pin) = button_b {
+            init_button(&mut buttons, pin, "B", long_press, Action::PreviousPage);
         }
     }
-    if success {
-        sensors.temp_humi = Some(shtc3);
+
+    // Initialize ventilation controller
+    let mut ventilation = None;
+    if let Some(pin) = fan_pin {
+        println!("Ventilation controller: Enabled");
+        match Ventilation::new(
+            peripherals.ledc.timer0,
+            peripherals.ledc.channel0,
+            pin,
+            &config.ventilation,
+        ) {
+            Ok(v) => ventilation = Some(v),
+            Err(e) => eprintln!("  Error: Could not initialize PWM output: {}", e),
+        }
     }
-}
 
-/// Initialize the VEML7700 sensor. If successful, add it to the [`Sensors`] instance.
-fn init_veml7700<'a>(sensors: &mut Sensors<'a>, i2c: SharedBuxProxyI2c<'a>) {
-    let mut delay = GeneralPurposeDelay;
-    let mut veml = Veml6030::new(i2c, veml6030::SlaveAddr::default());
-    let mut success = true;
-    if let Err(e) = veml.set_gain(veml6030::Gain::OneQuarter) {
-        eprintln!("  Error: Could not set gain: {:?}", e);
-        success = false;
+    // Initialize PWM outputs. Every output uses its own LEDC timer and channel.
+    let mut outputs = Outputs::default();
+    let mut output_configs = config.outputs.iter();
+    if let Some(output) = output_configs.next() {
+        init_output(
+            &mut outputs,
+            peripherals.ledc.timer1,
+            peripherals.ledc.channel1,
+            output,
+        );
     }
-    if let Err(e) = veml.set_integration_time(VEML_INTEGRATION_TIME) {
-        eprintln!("  Error: Could not set integration time: {:?}", e);
-        success = false;
+    if let Some(output) = output_configs.next() {
+        init_output(
+            &mut outputs,
+            peripherals.ledc.timer2,
+            peripherals.ledc.channel2,
+            output,
+        );
     }
-    if let Err(e) = veml.enable() {
-        eprintln!("  Error: Could not enable sensor: {:?}", e);
-        success = false;
+    if let Some(output) = output_configs.next() {
+        init_output(
+            &mut outputs,
+            peripherals.ledc.timer3,
+            peripherals.ledc.channel3,
+            output,
+        );
     }
 
-    // After enabling the sensor, a startup time of 4 ms plus the integration time must be awaited.
-    delay.delay_us(VEML_INTEGRATION_TIME.as_us() + 4_000);
+    // Supply voltage, for the safe mode
+    #[cfg(feature = "supply")]
+    let mut supply = match SupplyMonitor::new(
+        peripherals.adc1,
+        peripherals.pins.gpio0,
+        &config.sensors.supply,
+    ) {
+        Ok(supply) => {
+            println!("Supply voltage (ADC): Enabled");
+            Some(supply)
+        }
+        Err(e) => {
+            eprintln!("Error: Could not initialize supply voltage ADC: {}", e);
+            None
+        }
+    };
+
+    println!();
 
-    if success {
-        sensors.lux = Some(veml);
+    // Connect WiFi (only start it in the ESP-NOW sender mode, see `espnow.rs`). Without an SSID,
+    // or if the access point can't be reached, the provisioning portal is opened instead.
+    let espnow = config.sinks.espnow.as_ref();
+    let mut wifi = EspWifi::new(peripherals.modem, sys_loop, Some(nvs.clone()))
+        .context("Could not create EspWifi instance")?;
+    if let Err(e) = connect_wifi(&mut wifi, &config.wifi, espnow, nvs.clone()) {
+        if espnow.is_some() || !config.wifi.portal {
+            return Err(e);
+        }
+        eprintln!("Error: {:#}", e);
+        portal::run(&mut wifi, nvs.clone(), &config);
+    }
+    if safe_mode.is_active() {
+        if let Err(e) = power::set_modem_sleep() {
+            eprintln!("Error: Could not enable WiFi modem sleep: {}", e);
+        }
     }
-}
 
-/// Initialize the SGP30 sensor. If successful, add it to the [`Sensors`] instance.
-fn init_sgp30<'a>(sensors: &mut Sensors<'a>, i2c: SharedBuxProxyI2c<'a>) {
-    let mut sgp30 = Sgp30::new(i2c, 0x58, GeneralPurposeDelay);
-    let mut success = true;
-    match sgp30.serial() {
-        Ok(serial) => println!("  Serial: {:?}", serial),
-        Err(e) => {
-            eprintln!("  Error: Could not get serial: {:?}", e);
-            success = false;
+    // Wait for IP assignment from DHCP
+    if espnow.is_none() {
+        println!("WiFi connected! Waiting for IP...");
+        loop {
+            let ip_info = wifi.sta_netif().get_ip_info().unwrap();
+            if ip_info.ip.is_unspecified() {
+                delay.delay_ms(100);
+            } else {
+                println!("  Assigned IP: {}", ip_info.ip);
+                if let Some(dns) = ip_info.dns {
+                    println!("  DNS:         {}", dns);
+                } else {
+                    println!("  Warning: No DNS server assigned!");
+                }
+                break;
+            }
         }
     }
-    if let Err(e) = sgp30.init() {
-        eprintln!("  Error: Could not initialize: {:?}", e);
-        success = false;
+
+    // Resolve the InfluxDB host right away, so that DNS problems show up at startup. lwIP caches
+    // the address for the TTL of the record, and the connection is kept open between submissions
+    // (see `write_influxdb`), so there's no lookup on every cycle.
+    if let (true, Some(influxdb)) = (influxdb_enabled(&config), &config.sinks.influxdb) {
+        match resolve_host(&influxdb.host) {
+            Ok(address) => println!("  InfluxDB:    {}", address),
+            Err(e) => eprintln!("  Warning: Could not resolve InfluxDB host: {:#}", e),
+        }
     }
-    if success {
-        sensors.gas = Some(sgp30);
+    println!();
+
+    // Submitted lines are timestamped, the display shows the time of the last update and rules
+    // can depend on the time of day, so synchronize the clock
+    clock::set_timezone(
+        config
+            .timezone
+            .as_deref()
+            .unwrap_or(clock::DEFAULT_TIMEZONE),
+    );
+    let mut sntp = None;
+    if espnow.is_none() {
+        match clock::start_sntp(&config.time.servers) {
+            Ok(s) => sntp = Some(s),
+            Err(e) => eprintln!("Error: Could not start SNTP: {}", e),
+        }
+    }
+    if sntp.is_some() {
+        println!("Started SNTP time synchronization");
+        println!();
     }
-}
 
-fn connect_wifi(
-    modem: Modem,
-    event_loop: EspEventLoop<System>,
-    nvs: EspNvsPartition<NvsDefault>,
-) -> anyhow::Result<EspWifi<'static>> {
-    let mut wifi =
-        EspWifi::new(modem, event_loop, Some(nvs)).context("Could not create EspWifi instance")?;
-
-    wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration {
-        ssid: SENSILO_WIFI_SSID.into(),
-        password: SENSILO_WIFI_PASSWORD.into(),
-        ..Default::default()
-    }))
-    .unwrap();
-    wifi.start().context("Could not start WiFi")?;
-    wifi.connect().context("Could not connect WiFi")?;
-    println!(
-        "Waiting for station with SSID {}...",
-        wifi.get_configuration()
-            .ok()
-            .as_ref()
-            .and_then(|conf| conf.as_client_conf_ref().map(|client| &client.ssid))
-            .unwrap()
+    let mut rules = Rules::new(
+        &config.rules,
+        config.name.as_deref().unwrap_or(SENSILO_NAME),
+        config.sinks.ntfy.clone(),
     );
-    while !wifi.is_connected().unwrap() {
-        FreeRtos::delay_ms(100);
+    if safe_mode.is_active() {
+        rules.disable_tls();
     }
-    println!();
 
-    Ok(wifi)
-}
+    // Batches that could not be submitted are retried, also after a reboot
+    let delivery = Queue::new(log_nvs, nvs.clone()).context("Could not open delivery queue")?;
+    let mut connectivity = Connectivity::new(&config.outage);
+    let mut roaming = Roaming::new(&config.wifi);
 
-/// Read sensors, print data and update measurements.
-///
-/// Note: The gas sensor is not being read here, since it needs to be processed at a 1s intervals
-/// inside the periodic timer task!
-fn read_sensors(
-    sensors: &mut Sensors,
-    measurements: &mut Measurements,
-    delay: &mut GeneralPurposeDelay,
-) {
-    // Read temp/humi sensor, if present
-    if let Some(ref mut shtc3) = sensors.temp_humi {
-        match shtc3.measure(shtcx::PowerMode::NormalMode, delay) {
-            Ok(measurement) => {
-                println!(
-                    ":: Temp:  {} °C",
-                    measurement.temperature.as_degrees_celsius()
-                );
-                println!(":: Humi:  {} %RH", measurement.humidity.as_percent());
-                measurements.temperature = Some(measurement.temperature);
-                measurements.humidity = Some(measurement.humidity);
-            }
-            Err(e) => eprintln!("Temp/Humi: ERROR: {:?}", e),
-        }
+    sensors.print_usable();
+
+    // Record the enabled sensors that could not be initialized
+    for name in sensors.failed() {
+        eventlog::record(
+            eventlog::Kind::Sensor,
+            &format!("{}: Could not initialize", name),
+        );
     }
+    println!("Display (SSD1680): {}", display.is_some());
+    println!("Buttons: {}", buttons.len());
+    println!("Ventilation controller: {}", ventilation.is_some());
+    println!("PWM outputs: {}", outputs.iter().count());
+    println!();
 
-    // Read lux sensor, if present
-    if let Some(ref mut veml) = sensors.lux {
-        match veml.read_lux() {
-            Ok(lux) => {
-                println!(":: Lux:   {}", lux);
-                measurements.illuminance = Some(lux);
+    // Announce the start (not in the safe mode, the notification is sent over TLS)
+    if let Some(ref ntfy_config) = config.sinks.ntfy {
+        if ntfy_config.notify_startup && !safe_mode.is_active() {
+            let name = config.name.as_deref().unwrap_or(SENSILO_NAME);
+            let message = format!("{} started (firmware v{})", name, VERSION);
+            match ntfy::publish(ntfy_config, Some(name), &message, None) {
+                Ok(()) => println!("Published startup notification"),
+                Err(e) => eprintln!("Error: Could not publish startup notification: {:#}", e),
             }
-            Err(e) => eprintln!("Lux: ERROR: {:?}", e),
+            println!();
         }
     }
-}
 
-fn submit_measurements(measurements: &Measurements) -> anyhow::Result<()> {
-    println!("-> Submitting measurements");
+    println!("Starting main loop");
+
+    let profile = ActiveProfile::new(config.clone());
+    if let Some(name) = safe_mode.profile().filter(|_| safe_mode.is_active()) {
+        // Validated when loading the config
+        let _ = profile.set(Some(name));
+    }
+    if let Some(name) = profile.name() {
+        println!("Active profile: {}", name);
+    }
+
+    #[cfg(feature = "gas")]
+    let schedule_gas_sensor_timer = sensors.gas.is_some();
+    let schedule_presence_timer = sensors.has_presence_sensor();
+    #[cfg(feature = "lux")]
+    let schedule_lights_timer = matches!(sensors.lux, Some((_, _, Some(_))));
+
+    // Latest readings and sensor errors, for the Prometheus metrics of the HTTP API
+    let exporter = Arc::new(Mutex::new(Exporter::new(
+        config.name.as_deref().unwrap_or(SENSILO_NAME),
+        sensors
+            .enabled()
+            .into_iter()
+            .map(|(_, name, usable)| (name, usable))
+            .collect(),
+    )));
 
-    // Create HTTP(S) client
-    let mut client = HttpClient::wrap(EspHttpConnection::new(&HttpConfiguration {
-        timeout: Some(Duration::from_secs(10)),
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach), // Needed for HTTPS support
-        ..Default::default()
-    })?);
+    let sensors = Arc::new(Mutex::new(sensors));
+    let outputs = Arc::new(Mutex::new(outputs));
 
-    // Prepare payload
-    let mut lines = Vec::new();
-    let tags = format!("name={},fw_version={}", SENSILO_NAME, VERSION);
-    if let Some(temp) = measurements.temperature {
-        let val = temp.as_degrees_celsius();
-        lines.push(format!("temperature,{} celsius={:.2}", tags, val));
+    // Save the sensor state and turn the outputs off before a restart. The hooks may run while
+    // another task holds the lock (see `shutdown.rs`), in which case they are skipped.
+    #[cfg(any(feature = "gas", feature = "ccs811", feature = "s0"))]
+    {
+        let hook_sensors = sensors.clone();
+        shutdown::add_hook(move || match hook_sensors.try_lock() {
+            Ok(mut s) => s.save_state(),
+            Err(_) => eprintln!("Shutdown: Sensors are locked, not saving their state"),
+        });
     }
-    if let Some(humi) = measurements.humidity {
-        let val = humi.as_percent();
-        lines.push(format!("humidity,{} percent={:.2}", tags, val));
+    let hook_outputs = outputs.clone();
+    shutdown::add_hook(move || match hook_outputs.try_lock() {
+        Ok(mut outputs) => outputs.turn_off(),
+        Err(_) => eprintln!("Shutdown: Outputs are locked, not turning them off"),
+    });
+    let measurements = Arc::new(Mutex::new(Measurements::default()));
+
+    // Recent measurements, for the sparklines on the display and the HTTP API
+    let history = Arc::new(Mutex::new(History::new(&config.history)));
+
+    // Events are sent to the main loop through a channel and submitted immediately.
+    //
+    // Note: The sender is kept alive for the entire main loop, so the channel is never
+    // disconnected, even if there's no event producer.
+    let (event_sender, event_receiver) = mpsc::channel::<Message>();
+
+    // Serial console for calibration commands
+    commands::spawn(
+        #[cfg(any(feature = "gas", feature = "scd4x"))]
+        sensors.clone(),
+        config.clone(),
+        serial.clone(),
+        profile.clone(),
+        nvs.clone(),
+        event_sender.clone(),
+    )
+    .context("Could not start serial console")?;
+
+    // Local HTTP API. Like the other network services, it's not part of the minimal build (see the
+    // config validation), and it's not started in the safe mode.
+    let network_services = !cfg!(feature = "minimal") && !safe_mode.is_active() && espnow.is_none();
+    let mut api_server = None;
+    if config.api.enabled && network_services {
+        match api::start(
+            config.clone(),
+            nvs.clone(),
+            outputs.clone(),
+            history.clone(),
+            exporter.clone(),
+            event_sender.clone(),
+        ) {
+            Ok(server) => api_server = Some(server),
+            Err(e) => eprintln!("Error: Could not start HTTP API: {}", e),
+        }
     }
-    if let Some(lux) = measurements.illuminance {
-        lines.push(format!("illumination,{} lux={:.2}", tags, lux));
+    if api_server.is_some() {
+        println!("Started HTTP API on port 80");
     }
-    if let Some(co2eq) = measurements.co2eq_ppm {
-        lines.push(format!("co2,sensor_type=mox,{} ppm={}u", tags, co2eq));
+
+    // SNMP agent
+    if config.snmp.enabled && network_services {
+        match snmp::spawn(config.clone(), serial.clone(), history.clone()) {
+            Ok(()) => println!("Started SNMP agent on port {}", config.snmp.port),
+            Err(e) => eprintln!("Error: Could not start SNMP agent: {}", e),
+        }
     }
-    if let Some(tvoc) = measurements.tvoc_ppb {
-        lines.push(format!("tvoc,{} ppb={}u", tags, tvoc));
+
+    // BACnet/IP device
+    if config.bacnet.enabled && network_services {
+        match bacnet::spawn(config.clone(), history.clone()) {
+            Ok(()) => println!("Started BACnet/IP device on port {}", config.bacnet.port),
+            Err(e) => eprintln!("Error: Could not start BACnet/IP device: {}", e),
+        }
     }
-    let payload: String = lines.join("\n").chars().collect();
-    println!("Sending payload:\n{}", &payload);
 
-    // Prepare headers and URL
-    let authorization_header = format!("Token {}", SENSILO_INFLUXDB_API_TOKEN);
-    let content_length_header = format!("{}", payload.len());
-    let headers = [
-        ("authorization", &*authorization_header),
-        ("content-type", "text/plain; charset=utf-8"),
-        ("content-length", &*content_length_header),
-        ("accept", "application/json"),
-        ("connection", "close"),
-    ];
-    let url = format!(
-        "{}/api/v2/write?org={}&bucket={}",
-        SENSILO_INFLUXDB_HOST.trim_end_matches('/'),
-        SENSILO_INFLUXDB_ORG,
-        SENSILO_INFLUXDB_BUCKET,
-    );
+    // Modbus TCP server
+    if config.modbus.enabled && network_services {
+        match modbus::spawn(config.clone(), history.clone()) {
+            Ok(()) => println!("Started Modbus TCP server on port {}", config.modbus.port),
+            Err(e) => eprintln!("Error: Could not start Modbus TCP server: {}", e),
+        }
+    }
 
-    // Send request
-    let mut request = client.post(&url, &headers)?;
-    request.write_all(payload.as_bytes())?;
-    request.flush()?;
+    // ESPHome native API
+    if config.esphome.enabled && network_services {
+        match esphome::spawn(config.clone(), history.clone()) {
+            Ok(()) => println!("Started ESPHome API on port {}", config.esphome.port),
+            Err(e) => eprintln!("Error: Could not start ESPHome API: {}", e),
+        }
+    }
 
-    // Read response
-    let mut response = request.submit()?;
-    let status = response.status();
-    let (_headers, mut body) = response.split();
-    let success = status == 204;
-    if success {
-        println!("-> Data sent successfully to InfluxDB!");
+    // mDNS responder, kept for the entire main loop, since dropping it stops the advertisements
+    let _mdns = if config.mdns.enabled && network_services {
+        match mdns::start(&config) {
+            Ok(mdns) => Some(mdns),
+            Err(e) => {
+                eprintln!("Error: Could not start mDNS: {}", e);
+                None
+            }
+        }
     } else {
-        eprintln!("-> Error: Server returned HTTP {}", status);
-    }
+        None
+    };
 
-    // Drain body, print it if not successful
-    let mut buf = [0u8; 1024];
-    if !success {
-        let bytes_read = io::try_read_full(&mut body, &mut buf).map_err(|e| e.0)?;
-        println!("  Read {} bytes", bytes_read);
-        match std::str::from_utf8(&buf[0..bytes_read]) {
-            Ok(body_string) => println!(
-                "   Response body (truncated to {} bytes): {}",
-                buf.len(),
-                body_string
-            ),
-            Err(e) => eprintln!("  Error decoding response body: {}", e),
+    // Remote config, like the network services not in the safe mode. The sender is kept for the
+    // entire main loop, since the remote config thread stops when the channel is disconnected.
+    let remote_config =
+        if config.remote_config.is_enabled() && !safe_mode.is_active() && espnow.is_none() {
+            match remote::spawn(config.clone(), nvs.clone(), event_sender.clone()) {
+                Ok(sender) => {
+                    println!("Started remote config");
+                    Some(sender)
+                }
+                Err(e) => {
+                    eprintln!("Error: Could not start remote config: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
         };
-    }
-    while body.read(&mut buf)? > 0 {} // Drain the remaining response bytes
-    println!();
 
+    // The SGP30 requires to be called at 1s intervals for the internal algorithm to work. Thus,
+    // schedule a periodic timer task. The timer is kept, since dropping it cancels the task.
+    #[cfg(feature = "gas")]
+    let (_gas_sensor_timer, gas_heartbeat) = if schedule_gas_sensor_timer {
+        // Create timer task
+        let timer_sensors = sensors.clone();
+        let timer_measurements = measurements.clone();
+        let timer_profile = profile.clone();
+        let timer_simulated = config.simulation.enabled;
+        let timer_heartbeat = Heartbeat::new("Gas sensor task", GAS_TIMER_TIMEOUT);
+        let heartbeat = timer_heartbeat.clone();
+        let mut seconds_since_start = 0usize;
+        // Whether the last read failed, so that an error is only logged once until it recovers
+        let mut failing = false;
+        let timer = EspTaskTimerService::new()?.timer(move || {
+            seconds_since_start = seconds_since_start.saturating_add(1);
+            timer_heartbeat.beat(GAS_TIMER_TIMEOUT);
+            let mut s = timer_sensors.lock().expect("Failed to lock sensors mutex");
+            let serial = s.serials.get("sgp30").cloned();
+            if let Some((ref mut sgp30, ref mut baseline)) = s.gas {
+                match sgp30.measure() {
+                    Ok(measurement) => {
+                        if failing {
+                            println!("SGP30: Recovered");
+                            failing = false;
+                        }
+                        println!(":: CO₂eq: {} PPM", measurement.co2eq_ppm);
+                        println!(":: TVOC:  {} PPB", measurement.tvoc_ppb);
+                        // Note: The sensor is measured even if it's not part of the active profile,
+                        // to keep the internal algorithm running. Only the values are discarded.
+                        if seconds_since_start > 32
+                            && timer_profile.settings().reads("sgp30")
+                            && !timer_simulated
+                        {
+                            // Note: Give sensor some time for initial calibration (>15s)
+                            let mut m = timer_measurements
+                                .lock()
+                                .expect("Failed to lock measurements mutex");
+                            m.push(
+                                Reading::unsigned("co2", "ppm", measurement.co2eq_ppm, Unit::Ppm)
+                                    .sensor("mox")
+                                    .metric(Metric::Co2eq)
+                                    .serial(serial.clone()),
+                            );
+                            m.push(
+                                Reading::unsigned("tvoc", "ppb", measurement.tvoc_ppb, Unit::Ppb)
+                                    .metric(Metric::Tvoc)
+                                    .serial(serial),
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        if !failing {
+                            eprintln!("SGP30: ERROR: {:?}", e);
+                            failing = true;
+                        }
+                        timer_measurements
+                            .lock()
+                            .expect("Failed to lock measurements mutex")
+                            .fail("sgp30");
+                    }
+                }
+
+                // Persist the current baseline periodically
+                if baseline.save_due() {
+                    match sgp30.get_baseline() {
+                        Ok(current) => baseline.save(&sgp30_baseline_bytes(&current)),
+                        Err(e) => eprintln!("SGP30: ERROR: Could not read baseline: {:?}", e),
+                    }
+                }
+            }
+        })?;
+
+        // Schedule timer
+        timer.every(Duration::from_secs(1))?;
+        println!("Scheduled periodic gas sensor task at 1s intervals");
+        (Some(timer), Some(heartbeat))
+    } else {
+        (None, None)
+    };
+    #[cfg(not(feature = "gas"))]
+    let gas_heartbeat = None;
+
+    // Presence is detected by polling the presence sensors in a periodic timer task.
+    let mut presence_poll_interval = profile.settings().presence_poll_interval;
+    #[cfg(any(feature = "presence", feature = "ld2410"))]
+    let presence_timer = if schedule_presence_timer {
+        let timer_sensors = sensors.clone();
+        let timer_profile = profile.clone();
+        let timer_event_sender = event_sender.clone();
+        #[cfg(feature = "presence")]
+        let presence_threshold = config.sensors.apds9960.presence_threshold;
+        let send_presence_event = move |sensor, present| {
+            println!(":: Presence: {} ({})", present, sensor);
+            let event = Event::Presence { sensor, present };
+            if let Err(e) = timer_event_sender.send(Message::Event(event)) {
+                eprintln!("Presence: ERROR: Could not send event: {}", e);
+            }
+        };
+        let timer = EspTaskTimerService::new()?.timer(move || {
+            let settings = timer_profile.settings();
+            let mut s = timer_sensors.lock().expect("Failed to lock sensors mutex");
+            #[cfg(feature = "presence")]
+            if let Some((apds9960, detector)) =
+                s.presence.as_mut().filter(|_| settings.reads("apds9960"))
+            {
+                match apds9960.read_proximity() {
+                    Ok(proximity) => {
+                        if let Some(present) = detector.update(proximity >= presence_threshold) {
+                            send_presence_event("apds9960", present);
+                        }
+                    }
+                    // No new proximity value available yet
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other(e)) => eprintln!("Presence: ERROR: {:?}", e),
+                }
+            }
+            #[cfg(feature = "ld2410")]
+            if let Some((ld2410, detector)) = s.radar.as_mut().filter(|_| settings.reads("ld2410"))
+            {
+                match ld2410.poll() {
+                    Ok(Some(report)) => {
+                        if let Some(present) = detector.update(report.target_state.is_present()) {
+                            send_presence_event("ld2410", present);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Radar: ERROR: {:?}", e),
+                }
+            }
+        })?;
+        timer.every(presence_poll_interval)?;
+        println!(
+            "Scheduled periodic presence task at {}ms intervals",
+            presence_poll_interval.as_millis()
+        );
+        Some(timer)
+    } else {
+        None
+    };
+    #[cfg(not(any(feature = "presence", feature = "ld2410")))]
+    let presence_timer: Option<esp_idf_svc::timer::EspTimer> = None;
+
+    // Switching the lights on or off is detected by polling the interrupt flags of the VEML7700 in
+    // a periodic timer task (see `lights.rs`). The timer is kept, since dropping it cancels the
+    // task.
+    #[cfg(feature = "lux")]
+    let _lights_timer = if schedule_lights_timer {
+        let timer_sensors = sensors.clone();
+        let timer_profile = profile.clone();
+        let timer_event_sender = event_sender.clone();
+        let timer = EspTaskTimerService::new()?.timer(move || {
+            let settings = timer_profile.settings();
+            let mut s = timer_sensors.lock().expect("Failed to lock sensors mutex");
+            if let Some((veml, _, Some(light_switch))) =
+                s.lux.as_mut().filter(|_| settings.reads("veml7700"))
+            {
+                let result = veml
+                    .read_interrupt_status()
+                    .and_then(|status| veml.read_lux().map(|lux| (status, lux)));
+                match result {
+                    Ok((status, lux)) => {
+                        if let Some(on) = light_switch.update(status, lux) {
+                            println!(":: Lights: {} ({} lux)", if on { "On" } else { "Off" }, lux);
+                            let event = Event::Lights { on };
+                            if let Err(e) = timer_event_sender.send(Message::Event(event)) {
+                                eprintln!("Lights: ERROR: Could not send event: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Lights: ERROR: {:?}", e),
+                }
+            }
+        })?;
+        timer.every(lights::POLL_INTERVAL)?;
+        println!(
+            "Scheduled periodic light switch task at {}ms intervals",
+            lights::POLL_INTERVAL.as_millis()
+        );
+        Some(timer)
+    } else {
+        None
+    };
+
+    // The power of the own supply is sampled in a periodic timer task as well, for the energy
+    // budget (see `budget.rs`)
+    #[cfg(feature = "ina219")]
+    let (_budget_timer, budget) = match config.sensors.ina219.self_channel.clone() {
+        Some(tag) => {
+            let budget = Arc::new(Mutex::new(EnergyBudget::default()));
+            let timer_sensors = sensors.clone();
+            let timer_budget = budget.clone();
+            let timer = EspTaskTimerService::new()?.timer(move || {
+                let mut s = timer_sensors.lock().expect("Failed to lock sensors mutex");
+                // Not found if the INA219 could not be initialized
+                if let Some(channel) = s.current.iter_mut().find(|channel| channel.tag == tag) {
+                    match channel.sensor.measure() {
+                        Ok(measurement) => timer_budget
+                            .lock()
+                            .expect("Failed to lock energy budget mutex")
+                            .sample(measurement.power),
+                        Err(e) => eprintln!("Energy budget: ERROR: {:?}", e),
+                    }
+                }
+            })?;
+            timer.every(budget::SAMPLE_INTERVAL)?;
+            println!(
+                "Scheduled periodic energy budget task at {}ms intervals",
+                budget::SAMPLE_INTERVAL.as_millis()
+            );
+            (Some(timer), Some(budget))
+        }
+        None => (None, None),
+    };
+
+    // The buttons are polled in a periodic timer task as well, the actions are sent to the main
+    // loop.
+    let mut button_timer = None;
+    if !buttons.is_empty() {
+        let timer_event_sender = event_sender.clone();
+        let timer = EspTaskTimerService::new()?.timer(move || {
+            for button in buttons.iter_mut() {
+                if let Some(action) = button.poll() {
+                    println!(":: Button {}: {:?}", button.name(), action);
+                    if let Err(e) = timer_event_sender.send(Message::Action(action)) {
+                        eprintln!("Buttons: ERROR: Could not send action: {}", e);
+                    }
+                }
+            }
+        })?;
+        timer.every(input::POLL_INTERVAL)?;
+        button_timer = Some(timer);
+    }
+    if button_timer.is_some() {
+        println!(
+            "Scheduled periodic button task at {}ms intervals",
+            input::POLL_INTERVAL.as_millis()
+        );
+    }
+
+    // Content of the display, kept for turning the pages between two refreshes
+    let mut summary = None;
+
+    // Whether somebody is present, for the presence-dependent measurement intervals
+    let mut occupancy = Occupancy::default();
+
+    // Tags of all submitted lines
+    let tags = tags(&config, serial.as_deref());
+
+    // Durations of the stages of the previous cycle, submitted as diagnostics
+    let mut last_cycle = None;
+
+    // Aggregates for long-term storage, submitted in addition to the raw measurements
+    let mut downsampler = config.sinks.downsampling.as_ref().map(Downsampler::new);
+
+    // Live readings for consumers on the LAN (not in the safe mode)
+    let mut broadcaster = None;
+    if let Some(udp) = config.sinks.udp.as_ref().filter(|_| !safe_mode.is_active()) {
+        match Broadcaster::new(udp) {
+            Ok(b) => {
+                println!("Sending readings to UDP {}:{}", udp.address, udp.port);
+                broadcaster = Some(b);
+            }
+            Err(e) => eprintln!("Error: Could not open UDP socket: {}", e),
+        }
+    }
+    let mut knx = None;
+    if let Some(config) = config.sinks.knx.as_ref().filter(|_| !safe_mode.is_active()) {
+        match Knx::new(config) {
+            Ok(k) => {
+                println!(
+                    "Sending KNX telegrams to {}:{}",
+                    config.multicast_address, config.port
+                );
+                knx = Some(k);
+            }
+            Err(e) => eprintln!("Error: Could not open KNX socket: {}", e),
+        }
+    }
+    // Submission backends, every measurement cycle is submitted to all of them (see `backend.rs`)
+    let mut dispatcher = Dispatcher::default();
+    if influxdb_enabled(&config) {
+        dispatcher.add(
+            Box::new(InfluxDb::new(delivery, config.clone())),
+            config
+                .sinks
+                .influxdb
+                .as_ref()
+                .and_then(|influxdb| influxdb.interval_secs),
+        );
+    }
+    if let Some(ref mqtt) = config.sinks.mqtt {
+        let name = config.name.as_deref().unwrap_or(SENSILO_NAME);
+        let documents = remote_config.clone().filter(|_| config.remote_config.mqtt);
+        match Publisher::new(mqtt, name, documents) {
+            Ok(publisher) => {
+                println!("Publishing readings to MQTT broker {}", mqtt.url);
+                dispatcher.add(Box::new(publisher), mqtt.interval_secs);
+            }
+            Err(e) => eprintln!("Error: Could not create MQTT client: {:#}", e),
+        }
+    }
+    if let Some(ref webhook) = config.sinks.webhook {
+        println!("Posting readings to {}", webhook.url);
+        dispatcher.add(
+            Box::new(JsonWebhook::new(
+                webhook,
+                config.name.as_deref().unwrap_or(SENSILO_NAME),
+                serial.as_deref(),
+                &config.tags,
+            )),
+            webhook.interval_secs,
+        );
+    }
+    if let Some(espnow) = espnow {
+        match espnow::Sender::new(espnow, config.name.as_deref().unwrap_or(SENSILO_NAME)) {
+            Ok(sender) => {
+                println!("Sending readings via ESP-NOW to {}", espnow.gateway);
+                dispatcher.add(Box::new(sender), espnow.interval_secs);
+            }
+            Err(e) => eprintln!("Error: Could not start ESP-NOW: {:#}", e),
+        }
+    }
+    #[cfg(feature = "bthome")]
+    if let Some(ref bthome) = config.sinks.bthome {
+        match bthome::Advertiser::new(bthome, config.name.as_deref().unwrap_or(SENSILO_NAME)) {
+            Ok(advertiser) => {
+                println!("Advertising readings via Bluetooth (BTHome)");
+                dispatcher.add(Box::new(advertiser), bthome.interval_secs);
+            }
+            Err(e) => eprintln!("Error: Could not start Bluetooth: {}", e),
+        }
+    }
+
+    // Restart the device if the main loop or the gas sensor task hang
+    let main_heartbeat = Heartbeat::new(
+        "Main loop",
+        profile.settings().max_measurement_interval() + MAIN_LOOP_GRACE,
+    );
+    let heartbeats = std::iter::once(main_heartbeat.clone())
+        .chain(gas_heartbeat.clone())
+        .collect();
+    let _watchdog_timer = watchdog::start(heartbeats).context("Could not start watchdog")?;
+
+    // Simulated readings instead of the sensors, see `simulation.rs`
+    let mut simulator = config.simulation.enabled.then(|| {
+        println!("Simulation: Enabled, the sensors are not read");
+        Simulator::new(&config.simulation)
+    });
+
+    loop {
+        let settings = profile.settings();
+
+        // Reschedule the presence task if the poll interval of the active profile differs
+        if let Some(ref timer) = presence_timer {
+            if settings.presence_poll_interval != presence_poll_interval {
+                presence_poll_interval = settings.presence_poll_interval;
+                match timer
+                    .cancel()
+                    .and_then(|_| timer.every(presence_poll_interval))
+                {
+                    Ok(()) => println!(
+                        "Rescheduled periodic presence task at {}ms intervals",
+                        presence_poll_interval.as_millis()
+                    ),
+                    Err(e) => eprintln!("Error: Could not reschedule presence task: {}", e),
+                }
+            }
+        }
+
+        // Set when the portal is opened for re-provisioning after failed WiFi reconnections
+        let mut open_portal = false;
+
+        {
+            // Get access to shared data
+            let mut s = sensors.lock().expect("Failed to lock sensors mutex");
+            let mut m = measurements
+                .lock()
+                .expect("Failed to lock measurements mutex");
+            #[cfg(feature = "faults")]
+            faults::delay_lock();
+
+            // Read sensors, or generate the simulated readings instead (see `simulation.rs`)
+            let read_start = Instant::now();
+            match simulator {
+                Some(ref mut simulator) => {
+                    for reading in simulator.readings() {
+                        m.push(reading);
+                    }
+                }
+                None => {
+                    let read_timeout = Duration::from_millis(config.sensors.read_timeout_ms.into());
+                    read_sensors(&mut s, &mut m, &settings, read_timeout);
+                    m.timeouts = deadline::take_timeouts();
+                }
+            }
+            let read = read_start.elapsed();
+
+            // Energy consumed since the previous cycle
+            #[cfg(feature = "ina219")]
+            if let (Some(budget), Some(tag)) = (
+                budget.as_ref(),
+                config.sensors.ina219.self_channel.as_deref(),
+            ) {
+                let cycle = budget
+                    .lock()
+                    .expect("Failed to lock energy budget mutex")
+                    .take();
+                if let Some(cycle) = cycle {
+                    println!(
+                        ":: Energy budget: {:.3} mWh in {}s ({:.1} mW)",
+                        cycle.energy,
+                        cycle.duration.as_secs(),
+                        cycle.average_power
+                    );
+                    for reading in [
+                        Reading::float("budget", "cycle_mwh", cycle.energy, 3, Unit::MilliwattHour),
+                        Reading::float(
+                            "budget",
+                            "average_mw",
+                            cycle.average_power,
+                            1,
+                            Unit::Milliwatt,
+                        ),
+                        Reading::unsigned(
+                            "budget",
+                            "cycle_secs",
+                            cycle.duration.as_secs() as u32,
+                            Unit::Second,
+                        ),
+                    ] {
+                        m.push(reading.sensor("ina219").channel(tag));
+                    }
+                }
+            }
+
+            // Measure the supply voltage, and restart when entering or leaving the safe mode
+            #[cfg(feature = "supply")]
+            if let Some(ref mut supply) = supply {
+                match supply.measure() {
+                    Ok(voltage) => {
+                        println!(":: Supply: {:.2} V", voltage);
+                        m.push(
+                            Reading::float("supply", "voltage", voltage, 2, Unit::Volt)
+                                .sensor("adc"),
+                        );
+                        if safe_mode.update(voltage) {
+                            if let Err(e) = event_sender.send(Message::Restart) {
+                                eprintln!("Error: Could not request restart: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Supply: ERROR: {}", e);
+                        m.fail("supply");
+                    }
+                }
+            }
+            if safe_mode.retry_due() {
+                println!("Safe mode: Restarting to retry the normal mode");
+                eventlog::record(eventlog::Kind::Power, "Leaving safe mode: Retrying");
+                if let Err(e) = event_sender.send(Message::Restart) {
+                    eprintln!("Error: Could not request restart: {}", e);
+                }
+            }
+
+            // Days since the last calibration of the tracked sensors
+            for reading in calibration::readings() {
+                m.push(reading);
+            }
+            let readings: Vec<(Metric, f32, Instant)> = Metric::ALL
+                .into_iter()
+                .filter_map(|metric| {
+                    m.reading(metric)
+                        .map(|r| (metric, r.value.as_f32(), r.timestamp))
+                })
+                .collect();
+            let values: Vec<(Metric, f32)> = readings
+                .iter()
+                .map(|&(metric, value, _)| (metric, value))
+                .collect();
+            let mut h = history.lock().expect("Failed to lock history mutex");
+            for &(metric, value, at) in readings.iter() {
+                h.record(metric, value, at);
+                if let Some(ref mut downsampler) = downsampler {
+                    downsampler.record(metric, value);
+                }
+            }
+            drop(h);
+
+            // Send the readings to the LAN
+            if let Some(ref broadcaster) = broadcaster {
+                let name = config.name.as_deref().unwrap_or(SENSILO_NAME);
+                let timestamp = clock::unix_time();
+                if let Err(e) = broadcaster.send(name, serial.as_deref(), timestamp, &values) {
+                    eprintln!("Error: Could not send UDP datagram: {}", e);
+                }
+            }
+            if let Some(ref knx) = knx {
+                if let Err(e) = knx.send(&values) {
+                    eprintln!("Error: Could not send KNX telegram: {}", e);
+                }
+            }
+
+            // Adjust the fan speed
+            if let Some(ref mut ventilation) = ventilation {
+                if let Some(value) = m.value(ventilation.metric()) {
+                    match ventilation.update(value) {
+                        Ok(measurement) => {
+                            println!(":: Fan: {:.0} %", measurement.output);
+                            m.push(Reading::float(
+                                "ventilation",
+                                "value",
+                                measurement.value,
+                                2,
+                                Unit::None,
+                            ));
+                            m.push(Reading::float(
+                                "ventilation",
+                                "setpoint",
+                                measurement.setpoint,
+                                2,
+                                Unit::None,
+                            ));
+                            m.push(Reading::float(
+                                "ventilation",
+                                "fan_percent",
+                                measurement.output,
+                                1,
+                                Unit::Percent,
+                            ));
+                        }
+                        Err(e) => eprintln!("Ventilation: ERROR: {}", e),
+                    }
+                }
+            }
+
+            // Evaluate the rules
+            let rule_events = rules.evaluate(|metric| m.value(metric), &outputs);
+
+            // Submit measurements and rule events as one batch
+            println!("-> Submitting measurements");
+            let serialize_start = Instant::now();
+            let mut lines = reading::lines(&m.readings, &tags);
+            lines.extend(rule_events.iter().map(|event| event.to_line(&tags)));
+            if let Some(aggregates) = downsampler
+                .as_mut()
+                .and_then(|downsampler| downsampler.take_lines(&tags))
+            {
+                lines.extend(aggregates);
+            }
+            lines.push(diagnostics_line(
+                &tags,
+                main_heartbeat.count(),
+                gas_heartbeat.as_ref().map(|heartbeat| heartbeat.count()),
+                safe_mode.is_active(),
+                &m.timeouts,
+                last_cycle,
+            ));
+            let (annotations, forwarded) = match config.sinks.annotations {
+                Some(ref annotations) => annotation_lines(annotations, &tags),
+                None => (Vec::new(), None),
+            };
+            if !annotations.is_empty() {
+                println!("-> Submitting {} annotations", annotations.len());
+            }
+            let serialize = serialize_start.elapsed();
+            let delivered = dispatcher.submit(&Submission {
+                readings: &m.readings,
+                lines: &lines,
+                annotations: &annotations,
+                timestamp: clock::unix_time(),
+            });
+            // The annotations are in the delivery queue now, which retries them like the
+            // measurements
+            if let Some(seq) = forwarded {
+                eventlog::mark_forwarded(seq);
+            }
+            last_cycle = Some(CycleTiming {
+                read,
+                serialize,
+                latency: delivered.then(|| read_start.elapsed()),
+            });
+            exporter
+                .lock()
+                .expect("Failed to lock exporter mutex")
+                .update(&m.readings, &m.failed, delivered);
+            match connectivity.record(delivered, clock::unix_time()) {
+                Some(Transition::Offline) => {
+                    eprintln!(
+                        "Network: Offline after {} failed submissions",
+                        config.outage.failures
+                    );
+                    set_outage_led(&outputs, &config, true);
+                }
+                Some(Transition::Online { since }) => {
+                    println!("Network: Back online");
+                    set_outage_led(&outputs, &config, false);
+                    // The backfill is submitted while waiting for events, when the mutexes are
+                    // not locked
+                    if let (true, Some(from), Some(to)) = (
+                        config.outage.backfill && influxdb_enabled(&config),
+                        since,
+                        clock::unix_time(),
+                    ) {
+                        if let Err(e) = event_sender.send(Message::Backfill { from, to }) {
+                            eprintln!("Error: Could not request backfill: {}", e);
+                        }
+                    }
+                }
+                None => {}
+            }
+            #[cfg(feature = "faults")]
+            if faults::wifi() && espnow.is_none() {
+                println!("Faults: Disconnecting WiFi");
+                let _ = wifi.disconnect();
+            }
+            if connectivity.reconnect_due() && espnow.is_none() {
+                let has_ip = wifi.is_connected().unwrap_or(false)
+                    && wifi
+                        .sta_netif()
+                        .get_ip_info()
+                        .map(|info| !info.ip.is_unspecified())
+                        .unwrap_or(false);
+                if !has_ip {
+                    // The earlier reconnections failed, since the IP address is still missing
+                    let failed = connectivity.record_reconnect() - 1;
+                    if matches!(config.outage.portal_after, Some(after) if failed >= after) {
+                        println!(
+                            "Network: Opening the portal after {} failed reconnections",
+                            failed
+                        );
+                        open_portal = true;
+                    } else {
+                        println!("Network: Reconnecting WiFi");
+                        let _ = wifi.disconnect();
+                        // The access point may be gone, look for the strongest one again
+                        if config.wifi.roaming {
+                            if let Err(e) = roaming::select(&mut wifi) {
+                                eprintln!("Error: Could not select access point: {}", e);
+                            }
+                        }
+                        if let Err(e) = wifi.connect() {
+                            eprintln!("Error: Could not reconnect WiFi: {}", e);
+                        }
+                    }
+                }
+            }
+            if let Some(ref mut roaming) = roaming {
+                if let Err(e) = roaming.check(&mut wifi) {
+                    eprintln!("Error: Could not roam: {}", e);
+                }
+            }
+
+            // Show the latest readings
+            if let Some(ref mut display) = display {
+                let s = display_summary(
+                    &m,
+                    &config,
+                    serial.as_deref(),
+                    &history.lock().expect("Failed to lock history mutex"),
+                    connectivity.is_offline(),
+                );
+                if let Err(e) = display.show(&s, &mut delay) {
+                    eprintln!("Display: ERROR: {:?}", e);
+                }
+                summary = Some(s);
+            }
+
+            // Reset measurements
+            m.reset();
+        }
+
+        // Remind of the sensors that are due for calibration, once per day
+        if let Some(reminder) = calibration::reminder() {
+            eprintln!("Warning: {}", reminder);
+            // Not in the safe mode, the notification is sent over TLS
+            if let Some(ntfy_config) = config
+                .sinks
+                .ntfy
+                .as_ref()
+                .filter(|_| config.calibration.notify && !safe_mode.is_active())
+            {
+                let name = config.name.as_deref().unwrap_or(SENSILO_NAME);
+                let message = format!("{}: {}", name, reminder);
+                if let Err(e) = ntfy::publish(ntfy_config, Some(name), &message, None) {
+                    eprintln!("Error: Could not publish calibration reminder: {:#}", e);
+                }
+            }
+        }
+
+        // The portal is opened while the mutexes are not locked, so that the timer tasks keep
+        // running. It restarts the device once it times out or a config was stored.
+        if open_portal {
+            main_heartbeat.beat(portal::TIMEOUT + MAIN_LOOP_GRACE);
+            portal::run(&mut wifi, nvs.clone(), &config);
+        }
+
+        main_heartbeat.beat(settings.max_measurement_interval() + MAIN_LOOP_GRACE);
+
+        // Wait until the next submission interval, submitting events and handling button
+        // presses in the meantime. The interval depends on whether somebody is present, so it's
+        // recalculated whenever that changes.
+        //
+        // Note: It's important that the mutexes are not locked while waiting!
+        let waiting_since = Instant::now();
+        loop {
+            let occupied = schedule_presence_timer.then(|| occupancy.is_occupied());
+            let deadline = waiting_since + settings.measurement_interval(occupied);
+            match wait_for_events(
+                &event_receiver,
+                deadline,
+                &tags,
+                &config,
+                &history,
+                &mut occupancy,
+            ) {
+                Some(Wakeup::Action(action @ (Action::NextPage | Action::PreviousPage))) => {
+                    if let (Some(display), Some(summary)) = (display.as_mut(), summary.as_ref()) {
+                        let result = if action == Action::NextPage {
+                            display.show(summary, &mut delay)
+                        } else {
+                            display.show_previous(summary, &mut delay)
+                        };
+                        if let Err(e) = result {
+                            eprintln!("Display: ERROR: {:?}", e);
+                        }
+                    }
+                }
+                Some(Wakeup::Occupancy) => println!(
+                    "Occupied: {}, measurement interval {}s",
+                    occupancy.is_occupied(),
+                    settings
+                        .measurement_interval(Some(occupancy.is_occupied()))
+                        .as_secs()
+                ),
+                Some(Wakeup::Restart) => {
+                    println!("Shutting down");
+                    dispatcher.shutdown();
+                    if let Some(ref mut ventilation) = ventilation {
+                        if let Err(e) = ventilation.stop() {
+                            eprintln!("Ventilation: ERROR: {}", e);
+                        }
+                    }
+                    shutdown::restart();
+                }
+                Some(Wakeup::Action(Action::MeasureNow)) | None => break,
+            }
+        }
+    }
+}
+
+/// Wait until the deadline. Submit all events that are received in the meantime, handle backfill
+/// requests, and track the occupancy of the room.
+///
+/// Returns early if a button action or a restart request is received or the occupancy changes.
+/// Returns `None` when the deadline is reached.
+fn wait_for_events(
+    receiver: &Receiver<Message>,
+    deadline: Instant,
+    tags: &TagSet,
+    config: &Config,
+    history: &Mutex<History>,
+    occupancy: &mut Occupancy,
+) -> Option<Wakeup> {
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(timeout) {
+            Ok(Message::Event(event)) => {
+                let occupancy_changed = match event {
+                    Event::Presence { sensor, present } => occupancy.update(sensor, present),
+                    Event::Lights { .. } | Event::Rule { .. } => false,
+                };
+                if let Err(e) = submit_events(&[event], tags, config) {
+                    eprintln!("Error: Could not submit event: {}", e);
+                }
+                if occupancy_changed {
+                    return Some(Wakeup::Occupancy);
+                }
+            }
+            Ok(Message::Action(action)) => return Some(Wakeup::Action(action)),
+            Ok(Message::Restart) => return Some(Wakeup::Restart),
+            Ok(Message::Backfill { from, to }) => {
+                if let Err(e) = submit_backfill(history, from, to, tags, config) {
+                    eprintln!("Error: Could not submit backfill: {}", e);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    None
+}
+
+/// Initializes the enabled sensors one after the other, and assembles the [`Sensors`] from the
+/// ones that could be initialized. There's a method per sensor, which only exists if the feature of
+/// the sensor is enabled.
+struct SensorsBuilder<'a> {
+    /// Bus of the I²C sensors
+    #[allow(dead_code)]
+    i2c: &'a BusManagerStd<I2cDriver<'a>>,
+    /// Whether the serial numbers are kept for the `sensor_serial` tag
+    serial_tags: bool,
+    sensors: Sensors<'a>,
+}
+
+impl<'a> SensorsBuilder<'a> {
+    fn new(i2c: &'a BusManagerStd<I2cDriver<'a>>, serial_tags: bool) -> Self {
+        Self {
+            i2c,
+            serial_tags,
+            sensors: Sensors::default(),
+        }
+    }
+
+    /// Return a proxy of the I²C bus for a sensor.
+    // Unused in builds without I²C sensors
+    #[allow(dead_code)]
+    fn bus(&self) -> SharedBuxProxyI2c<'a> {
+        let bus = deadline::I2c(self.i2c.acquire_i2c());
+        #[cfg(not(feature = "faults"))]
+        return bus;
+        #[cfg(feature = "faults")]
+        return faults::I2c(bus);
+    }
+
+    /// Print the serial number of a sensor, and keep it for the `sensor_serial` tag.
+    // Unused in builds without sensors that have a serial number
+    #[allow(dead_code)]
+    fn serial(&mut self, sensor: &'static str, label: &str, serial: String) {
+        println!("  {}: {}", label, serial);
+        if self.serial_tags {
+            self.sensors.serials.insert(sensor, serial);
+        }
+    }
+
+    /// Initialize the SHTC3 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "temp_humi")]
+    fn shtc3(&mut self, config: &config::Warmup) {
+        let mut shtc3 = shtcx::shtc3(self.bus());
+        let mut success = true;
+        match shtc3.device_identifier() {
+            Ok(id) => self.serial("shtc3", "Device ID", id.to_string()),
+            Err(e) => {
+                eprintln!("  Error: Could not get device ID: {:?}", e);
+                success = false;
+            }
+        }
+        if success {
+            self.sensors.temp_humi = Some((shtc3, Warmup::new(config.discard_samples)));
+        }
+    }
+
+    /// Initialize the VEML7700 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "lux")]
+    fn veml7700(&mut self, config: &config::Veml7700) {
+        let mut delay = GeneralPurposeDelay;
+        let mut veml = Veml6030::new(self.bus(), veml6030::SlaveAddr::default());
+        let mut success = true;
+        if let Err(e) = veml.set_gain(veml6030::Gain::OneQuarter) {
+            eprintln!("  Error: Could not set gain: {:?}", e);
+            success = false;
+        }
+        if let Err(e) = veml.set_integration_time(VEML_INTEGRATION_TIME) {
+            eprintln!("  Error: Could not set integration time: {:?}", e);
+            success = false;
+        }
+        if let Err(e) = veml.enable() {
+            eprintln!("  Error: Could not enable sensor: {:?}", e);
+            success = false;
+        }
+
+        // After enabling the sensor, a startup time of 4 ms plus the integration time must be awaited.
+        delay.delay_us(VEML_INTEGRATION_TIME.as_us() + 4_000);
+
+        if !success {
+            return;
+        }
+
+        // The thresholds in lux depend on the gain and the integration time, which are set above
+        let light_switch = config.lights_on_lux.and_then(|on_lux| {
+            let off_lux = config.lights_off_lux.unwrap_or(on_lux / 2.0);
+            let result = veml
+                .set_high_threshold_lux(on_lux)
+                .and_then(|_| veml.set_low_threshold_lux(off_lux))
+                // Flag a threshold only after two samples beyond it, so that a flicker is ignored
+                .and_then(|_| veml.set_fault_count(veml6030::FaultCount::Two))
+                .and_then(|_| veml.enable_interrupts());
+            match result {
+                Ok(()) => {
+                    println!(
+                        "  Light events: On at {} lux, off at {} lux",
+                        on_lux, off_lux
+                    );
+                    Some(lights::LightSwitch::new(on_lux, off_lux))
+                }
+                Err(e) => {
+                    eprintln!("  Error: Could not set interrupt thresholds: {:?}", e);
+                    None
+                }
+            }
+        });
+        self.sensors.lux = Some((veml, Warmup::new(config.discard_samples), light_switch));
+    }
+
+    /// Initialize the TSL2591 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "tsl2591")]
+    fn tsl2591(&mut self) {
+        let mut tsl2591 = Tsl2591::new(self.bus());
+        match tsl2591.init() {
+            Ok(()) => self.sensors.tsl2591 = Some(tsl2591),
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Initialize the SGP30 sensor and restore its stored baseline. If successful, add it to the
+    /// [`Sensors`].
+    #[cfg(feature = "gas")]
+    fn sgp30(&mut self, nvs: EspDefaultNvsPartition) {
+        let mut baseline = match BaselinePersistence::new(
+            nvs,
+            "sgp30",
+            Duration::ZERO,
+            SGP30_BASELINE_SAVE_INTERVAL,
+        ) {
+            Ok(baseline) => baseline,
+            Err(e) => {
+                eprintln!("  Error: Could not open baseline storage: {}", e);
+                return;
+            }
+        };
+        let mut sgp30 = Sgp30::new(self.bus(), 0x58, GeneralPurposeDelay);
+        let mut success = true;
+        match sgp30.serial() {
+            Ok(serial) => {
+                let serial: String = serial.iter().map(|b| format!("{:02x}", b)).collect();
+                self.serial("sgp30", "Serial", format!("0x{}", serial));
+            }
+            Err(e) => {
+                eprintln!("  Error: Could not get serial: {:?}", e);
+                success = false;
+            }
+        }
+        if let Err(e) = sgp30.init() {
+            eprintln!("  Error: Could not initialize: {:?}", e);
+            success = false;
+        }
+        if !success {
+            return;
+        }
+        if let Some(stored) = baseline.take_restore() {
+            let stored = sgp30_baseline(stored);
+            match sgp30.set_baseline(&stored) {
+                Ok(()) => println!(
+                    "  Restored baseline co2eq={} tvoc={}",
+                    stored.co2eq, stored.tvoc
+                ),
+                Err(e) => eprintln!("  Error: Could not restore baseline: {:?}", e),
+            }
+        }
+        self.sensors.gas = Some((sgp30, baseline));
+    }
+
+    /// Initialize the SDP8xx sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "diff_pressure")]
+    fn sdp8xx(&mut self) {
+        let mut delay = GeneralPurposeDelay;
+        let mut sdp = Sdp8xx::new(self.bus(), SDP8XX_ADDRESS);
+        let mut success = true;
+
+        // The sensor does not respond to any other command while in continuous measurement mode,
+        // which might still be the case after a reset of the MCU. Errors can be ignored here.
+        let _ = sdp.stop_continuous_measurement();
+        delay.delay_us(500u16);
+
+        match sdp.product_identifier() {
+            Ok((product, serial)) => {
+                println!("  Product: 0x{:08x}", product);
+                self.serial("sdp8xx", "Serial", serial.to_string());
+            }
+            Err(e) => {
+                eprintln!("  Error: Could not get product identifier: {:?}", e);
+                success = false;
+            }
+        }
+        if success {
+            if let Err(e) = sdp.start_continuous_measurement() {
+                eprintln!("  Error: Could not start continuous measurement: {:?}", e);
+                success = false;
+            }
+        }
+
+        // The first measurement result is available 8 ms after starting the measurement.
+        delay.delay_ms(8u16);
+
+        if success {
+            self.sensors.diff_pressure = Some(sdp);
+        }
+    }
+
+    /// Initialize the BMP390 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "pressure")]
+    fn bmp390(&mut self, config: &config::Bmp390, altitude: Option<f32>) {
+        let mut delay = GeneralPurposeDelay;
+        // Note: The config is validated, so the factor is always valid
+        let oversampling = drivers::bmp390::Oversampling::from_factor(config.oversampling)
+            .unwrap_or(drivers::bmp390::Oversampling::X8);
+        println!("  Oversampling: {:?}", oversampling);
+        let mut bmp = Bmp390::new(self.bus(), BMP390_ADDRESS, oversampling);
+        match bmp.init(&mut delay) {
+            Ok(()) => self.sensors.pressure = Some((bmp, altitude)),
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Initialize the ENS160 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "ens160")]
+    fn ens160(&mut self) {
+        use drivers::ens160::OperatingMode;
+
+        let mut delay = GeneralPurposeDelay;
+        let mut ens = Ens160::new(self.bus(), ENS160_ADDRESS);
+        let mut success = true;
+
+        // Reset the sensor, then transition through idle mode to standard (gas sensing) mode
+        if let Err(e) = ens.set_operating_mode(OperatingMode::Reset) {
+            eprintln!("  Error: Could not reset sensor: {:?}", e);
+            success = false;
+        }
+        delay.delay_ms(10u16);
+        match ens.part_id() {
+            Ok(id) => println!("  Part ID: 0x{:04x}", id),
+            Err(e) => {
+                eprintln!("  Error: Could not get part ID: {:?}", e);
+                success = false;
+            }
+        }
+        for mode in [OperatingMode::Idle, OperatingMode::Standard] {
+            if let Err(e) = ens.set_operating_mode(mode) {
+                eprintln!("  Error: Could not switch to {:?} mode: {:?}", mode, e);
+                success = false;
+            }
+        }
+
+        if success {
+            self.sensors.air_quality = Some(ens);
+        }
+    }
+
+    /// Initialize the CCS811 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "ccs811")]
+    fn ccs811(&mut self, n_wake: AnyOutputPin, nvs: EspDefaultNvsPartition) {
+        let mut delay = GeneralPurposeDelay;
+        let n_wake = match PinDriver::output(n_wake) {
+            Ok(pin) => pin,
+            Err(e) => {
+                eprintln!("  Error: Could not initialize nWAKE pin: {}", e);
+                return;
+            }
+        };
+        let baseline = match BaselinePersistence::new(
+            nvs,
+            "ccs811",
+            CCS811_BASELINE_WARMUP,
+            CCS811_BASELINE_SAVE_INTERVAL,
+        ) {
+            Ok(baseline) => baseline,
+            Err(e) => {
+                eprintln!("  Error: Could not open baseline storage: {}", e);
+                return;
+            }
+        };
+        let mut ccs = Ccs811::new(self.bus(), CCS811_ADDRESS, n_wake);
+        match ccs.init(&mut delay) {
+            Ok(()) => self.sensors.ccs811 = Some((ccs, baseline)),
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Initialize the SFA30 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "hcho")]
+    fn sfa30(&mut self) {
+        let mut delay = GeneralPurposeDelay;
+        let mut sfa = Sfa30::new(self.bus());
+        let mut success = true;
+        if let Err(e) = sfa.reset(&mut delay) {
+            eprintln!("  Error: Could not reset sensor: {:?}", e);
+            success = false;
+        }
+        match sfa.device_marking(&mut delay) {
+            Ok(marking) => self.serial("sfa30", "Device marking", marking),
+            Err(e) => {
+                eprintln!("  Error: Could not get device marking: {:?}", e);
+                success = false;
+            }
+        }
+        if let Err(e) = sfa.start_continuous_measurement() {
+            eprintln!("  Error: Could not start continuous measurement: {:?}", e);
+            success = false;
+        }
+        if success {
+            self.sensors.hcho = Some(sfa);
+        }
+    }
+
+    /// Initialize the AS7341 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "spectral")]
+    fn as7341(&mut self, config: &config::As7341) {
+        let mut as7341 = As7341::new(self.bus());
+        match as7341.init() {
+            Ok(()) => self.sensors.spectral = Some((as7341, config.lux_factor)),
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Initialize the SCD4x sensor. If successful, add it to the [`Sensors`].
+    ///
+    /// If there's no pressure sensor, the configured altitude is used for pressure compensation.
+    /// Otherwise, the live pressure is fed to the sensor in [`read_sensors`].
+    #[cfg(feature = "scd4x")]
+    fn scd4x(&mut self, altitude: Option<f32>) {
+        let mut delay = GeneralPurposeDelay;
+        let mut scd = Scd4x::new(self.bus());
+
+        // Periodic measurement might still be running after a reset of the MCU, and the sensor
+        // doesn't accept other commands while measuring.
+        if let Err(e) = scd.stop_periodic_measurement(&mut delay) {
+            eprintln!("  Error: Could not stop periodic measurement: {:?}", e);
+            return;
+        }
+        match scd.serial_number(&mut delay) {
+            Ok(serial) => self.serial("scd4x", "Serial", format!("0x{:012x}", serial)),
+            Err(e) => {
+                eprintln!("  Error: Could not get serial number: {:?}", e);
+                return;
+            }
+        }
+        // The BMP390 is initialized before
+        #[cfg(feature = "pressure")]
+        let live_pressure = self.sensors.pressure.is_some();
+        #[cfg(not(feature = "pressure"))]
+        let live_pressure = false;
+        if !live_pressure {
+            if let Some(altitude) = altitude {
+                let altitude = altitude.max(0.0).round() as u16;
+                match scd.set_sensor_altitude(altitude, &mut delay) {
+                    Ok(()) => println!("  Pressure compensation: Altitude {} m", altitude),
+                    Err(e) => eprintln!("  Error: Could not set altitude: {:?}", e),
+                }
+            }
+        }
+        match scd.start_periodic_measurement() {
+            Ok(()) => self.sensors.co2 = Some(scd),
+            Err(e) => eprintln!("  Error: Could not start periodic measurement: {:?}", e),
+        }
+    }
+
+    /// Initialize an INA219 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "ina219")]
+    fn ina219(&mut self, address: u8, shunt: f32, tag: String) {
+        let mut ina219 = Ina219::new(self.bus(), address, shunt);
+        match ina219.init() {
+            Ok(()) => self.sensors.current.push(Channel {
+                sensor: ina219,
+                tag,
+            }),
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Initialize the APDS9960 sensor. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "presence")]
+    fn apds9960(&mut self, config: &config::Apds9960) {
+        let mut apds9960 = Apds9960::new(self.bus());
+        if let Err(e) = apds9960.enable() {
+            eprintln!("  Error: Could not enable sensor: {:?}", e);
+            return;
+        }
+        if let Err(e) = apds9960.enable_proximity() {
+            eprintln!("  Error: Could not enable proximity engine: {:?}", e);
+            return;
+        }
+        let hold_time = Duration::from_secs(config.hold_time_secs.into());
+        self.sensors.presence = Some((apds9960, PresenceDetector::new(hold_time)));
+    }
+
+    /// Initialize the Geiger counter pulse input. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "geiger")]
+    fn geiger(&mut self, pin: AnyIOPin, config: &config::Geiger) {
+        let dead_time = Duration::from_micros(config.dead_time_us.into());
+        match Geiger::new(pin, config.usvh_per_cpm, dead_time) {
+            Ok(geiger) => self.sensors.geiger = Some(geiger),
+            Err(e) => eprintln!("  Error: Could not initialize pulse counter: {}", e),
+        }
+    }
+
+    /// Initialize the S0 energy meter pulse input. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "s0")]
+    fn energy_meter(&mut self, pin: AnyIOPin, nvs: EspDefaultNvsPartition, config: &config::S0) {
+        match EnergyMeter::new(pin, config.impulses_per_kwh, nvs, ENERGY_SAVE_INTERVAL) {
+            Ok(meter) => self.sensors.energy = Some(meter),
+            Err(e) => eprintln!("  Error: Could not initialize energy meter: {}", e),
+        }
+    }
+
+    /// Initialize the MAX31855 converter. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "thermocouple")]
+    fn max31855(&mut self, spi: &SpiBus<'a>, cs: AnyOutputPin, config: &config::Max31855) {
+        let spi_config = SpiConfig::new().baudrate(4.MHz().into()).data_mode(MODE_0);
+        match spi.device(cs, &spi_config) {
+            Ok(device) => {
+                self.sensors.thermocouple = Some(Channel {
+                    sensor: Max31855::new(device),
+                    tag: config.channel.clone(),
+                })
+            }
+            Err(e) => eprintln!("  Error: Could not initialize SPI device: {}", e),
+        }
+    }
+
+    /// Initialize the MAX31865 converter. If successful, add it to the [`Sensors`].
+    #[cfg(feature = "rtd")]
+    fn max31865(
+        &mut self,
+        spi: &SpiBus<'a>,
+        cs: AnyOutputPin,
+        config: &config::Max31865,
+        mains_frequency: u8,
+    ) {
+        let rtd = match config.rtd {
+            config::Rtd::Pt100 => drivers::max31865::Rtd::Pt100,
+            config::Rtd::Pt1000 => drivers::max31865::Rtd::Pt1000,
+        };
+        let reference = config.rref.unwrap_or_else(|| rtd.default_reference());
+        let wiring = match config.wires {
+            3 => drivers::max31865::Wiring::ThreeWire,
+            _ => drivers::max31865::Wiring::TwoOrFourWire,
+        };
+        let filter = match mains_frequency {
+            60 => drivers::max31865::Filter::Hz60,
+            _ => drivers::max31865::Filter::Hz50,
+        };
+
+        let spi_config = SpiConfig::new().baudrate(4.MHz().into()).data_mode(MODE_1);
+        let device = match spi.device(cs, &spi_config) {
+            Ok(device) => device,
+            Err(e) => {
+                eprintln!("  Error: Could not initialize SPI device: {}", e);
+                return;
+            }
+        };
+        let mut max31865 = Max31865::new(device, rtd, reference, wiring, filter);
+        match max31865.init() {
+            Ok(()) => {
+                self.sensors.rtd = Some(Channel {
+                    sensor: max31865,
+                    tag: config.channel.clone(),
+                })
+            }
+            Err(e) => eprintln!("  Error: Could not initialize: {:?}", e),
+        }
+    }
+
+    /// Add the LD2410 radar, connected to `uart`.
+    #[cfg(feature = "ld2410")]
+    fn ld2410(&mut self, uart: UartDriver<'a>, config: &config::Ld2410) {
+        let hold_time = Duration::from_secs(config.hold_time_secs.into());
+        self.sensors.radar = Some((Ld2410::new(uart), PresenceDetector::new(hold_time)));
+    }
+
+    /// Add the PZEM-004T energy monitor, connected to `uart`.
+    #[cfg(feature = "pzem")]
+    fn pzem004t(&mut self, uart: UartDriver<'a>) {
+        self.sensors.power_meter = Some(Pzem004t::new(uart, drivers::pzem004t::ADDRESS_GENERAL));
+    }
+
+    fn build(self) -> Sensors<'a> {
+        self.sensors
+    }
+}
+
+/// Initialize a button. If successful, add it to the list of buttons. A long press triggers an
+/// immediate measurement.
+fn init_button(
+    buttons: &mut Vec<Button<'static>>,
+    pin: AnyIOPin,
+    name: &'static str,
+    long_press: Duration,
+    short_action: Action,
+) {
+    match Button::new(pin, name, long_press, short_action, Action::MeasureNow) {
+        Ok(button) => buttons.push(button),
+        Err(e) => eprintln!("  Error: Could not initialize button {}: {}", name, e),
+    }
+}
+
+/// Encode an SGP30 baseline for NVS, the CO₂eq baseline first.
+#[cfg(feature = "gas")]
+fn sgp30_baseline_bytes(baseline: &sgp30::Baseline) -> [u8; 4] {
+    let [co2eq_high, co2eq_low] = baseline.co2eq.to_be_bytes();
+    let [tvoc_high, tvoc_low] = baseline.tvoc.to_be_bytes();
+    [co2eq_high, co2eq_low, tvoc_high, tvoc_low]
+}
+
+/// Decode an SGP30 baseline from NVS, see [`sgp30_baseline_bytes`].
+#[cfg(feature = "gas")]
+fn sgp30_baseline(bytes: [u8; 4]) -> sgp30::Baseline {
+    sgp30::Baseline {
+        co2eq: u16::from_be_bytes([bytes[0], bytes[1]]),
+        tvoc: u16::from_be_bytes([bytes[2], bytes[3]]),
+    }
+}
+
+/// Turn the `outage.led` output on or off, if configured.
+fn set_outage_led(outputs: &Mutex<Outputs>, config: &Config, on: bool) {
+    if let Some(ref led) = config.outage.led {
+        let level = if on { 100.0 } else { 0.0 };
+        if let Err(e) = outputs
+            .lock()
+            .expect("Failed to lock outputs mutex")
+            .set_level(led, level)
+        {
+            eprintln!("Error: Could not set outage LED: {:#}", e);
+        }
+    }
+}
+
+/// Initialize a PWM output. If successful, add it to the [`Outputs`] instance.
+fn init_output<T: LedcTimer, C: LedcChannel>(
+    outputs: &mut Outputs<'static>,
+    timer: impl Peripheral<P = T> + 'static,
+    channel: impl Peripheral<P = C> + 'static,
+    config: &config::Output,
+) {
+    println!("PWM output {}: GPIO{}", config.name, config.pin);
+    // Safety: The config validation ensures that the pin is not used by an enabled feature or by
+    // another output.
+    let pin = unsafe { AnyOutputPin::new(config.pin.into()) };
+    match PwmOutput::new(timer, channel, pin, config) {
+        Ok(output) => outputs.push(output),
+        Err(e) => eprintln!("  Error: Could not initialize PWM output: {}", e),
+    }
+}
+
+/// Initialize the SSD1680 e-paper display.
+fn init_epaper<'a>(
+    spi: &SpiBus<'a>,
+    cs: AnyOutputPin,
+    dc: AnyOutputPin,
+    busy: AnyInputPin,
+) -> Option<EpaperDisplay<'a>> {
+    let spi_config = SpiConfig::new().baudrate(10.MHz().into()).data_mode(MODE_0);
+    let device = match spi.device(cs, &spi_config) {
+        Ok(device) => device,
+        Err(e) => {
+            eprintln!("  Error: Could not initialize SPI device: {}", e);
+            return None;
+        }
+    };
+    let dc = match PinDriver::output(dc) {
+        Ok(pin) => pin,
+        Err(e) => {
+            eprintln!("  Error: Could not initialize DC pin: {}", e);
+            return None;
+        }
+    };
+    let busy = match PinDriver::input(busy) {
+        Ok(pin) => pin,
+        Err(e) => {
+            eprintln!("  Error: Could not initialize BUSY pin: {}", e);
+            return None;
+        }
+    };
+    Some(Display::new(Ssd1680::new(device, dc, busy)))
+}
+
+/// Start WiFi and connect to one of the networks (see `networks.rs`), with
+/// [`WIFI_CONNECT_ATTEMPTS`] attempts per network. In the ESP-NOW sender mode, WiFi is only
+/// started, on the channel of the gateway.
+fn connect_wifi(
+    wifi: &mut EspWifi<'static>,
+    config: &config::Wifi,
+    espnow: Option<&config::EspNow>,
+    partition: EspDefaultNvsPartition,
+) -> anyhow::Result<()> {
+    wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration::default()))
+        .unwrap();
+    if let Some(ref country) = config.country {
+        // Validated when loading the config. 802.11d is disabled, so that the country of the
+        // access point doesn't override it.
+        let country = CString::new(country.as_str()).unwrap();
+        esp_idf_sys::EspError::convert(unsafe {
+            esp_idf_sys::esp_wifi_set_country_code(country.as_ptr(), false)
+        })
+        .context("Could not set WiFi country")?;
+    }
+    wifi.start().context("Could not start WiFi")?;
+    if let Some(power) = config.max_tx_power_dbm {
+        // The unit is 0.25 dBm, the TX power can only be set after starting WiFi
+        esp_idf_sys::EspError::convert(unsafe {
+            esp_idf_sys::esp_wifi_set_max_tx_power((power * 4.0).round() as i8)
+        })
+        .context("Could not set WiFi TX power")?;
+    }
+    if let Some(espnow) = espnow {
+        esp_idf_sys::EspError::convert(unsafe {
+            esp_idf_sys::esp_wifi_set_channel(
+                espnow.channel,
+                esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE,
+            )
+        })
+        .context("Could not set WiFi channel")?;
+        println!("WiFi started on channel {} for ESP-NOW", espnow.channel);
+        return Ok(());
+    }
+    let networks = networks::ordered(wifi, config, partition.clone());
+    if networks.is_empty() {
+        bail!("No WiFi configured (wifi.ssid)");
+    }
+    for network in &networks {
+        wifi.set_configuration(&WifiConfiguration::Client(ClientConfiguration {
+            ssid: network.ssid.into(),
+            password: network.password.unwrap_or("").into(),
+            bssid: network.bssid,
+            ..Default::default()
+        }))
+        .context("Could not configure WiFi")?;
+        if config.roaming {
+            match roaming::select(wifi) {
+                Ok(Some(ap)) => println!(
+                    "Strongest access point: {} ({} dBm)",
+                    roaming::format_bssid(&ap.bssid),
+                    ap.signal_strength
+                ),
+                Ok(None) => println!("Warning: No access point found"),
+                Err(e) => eprintln!("Error: Could not select access point: {}", e),
+            }
+        }
+        for attempt in 1..=WIFI_CONNECT_ATTEMPTS {
+            wifi.connect().context("Could not connect WiFi")?;
+            println!("Waiting for station with SSID {}...", network.ssid);
+            let start = Instant::now();
+            while !wifi.is_connected().unwrap() && start.elapsed() < WIFI_CONNECT_TIMEOUT {
+                FreeRtos::delay_ms(100);
+            }
+            if wifi.is_connected().unwrap() {
+                println!();
+                if networks.len() > 1 {
+                    networks::remember(partition, network.ssid);
+                }
+                return Ok(());
+            }
+            eprintln!(
+                "Warning: Could not connect to {} (attempt {} of {})",
+                network.ssid, attempt, WIFI_CONNECT_ATTEMPTS
+            );
+            let _ = wifi.disconnect();
+        }
+    }
+    let ssids: Vec<&str> = networks.iter().map(|network| network.ssid).collect();
+    bail!("Could not connect to the access point {}", ssids.join(", "));
+}
+
+/// Read sensors, print data and update measurements. Sensors that are not part of the active
+/// profile are skipped. Every read has a time budget of `read_timeout` (see `deadline.rs`).
+///
+/// Note: The gas sensor is not being read here, since it needs to be processed at a 1s intervals
+/// inside the periodic timer task!
+// Without any sensor features, there is nothing to read
+#[allow(unused_variables)]
+fn read_sensors(
+    sensors: &mut Sensors,
+    measurements: &mut Measurements,
+    settings: &profile::Settings,
+    read_timeout: Duration,
+) {
+    let serial = |sensor: &str| sensors.serials.get(sensor).cloned();
+
+    // Read temp/humi sensor, if present
+    #[cfg(feature = "temp_humi")]
+    if let Some((shtc3, warmup)) = sensors
+        .temp_humi
+        .as_mut()
+        .filter(|_| settings.reads("shtc3"))
+    {
+        let _read = deadline::start("shtc3", read_timeout);
+        let power_mode = if settings.low_power {
+            shtcx::PowerMode::LowPower
+        } else {
+            shtcx::PowerMode::NormalMode
+        };
+        match shtc3.measure(power_mode, &mut GeneralPurposeDelay) {
+            Ok(_) if !warmup.accept() => println!(":: Temp/Humi: Discarded (warming up)"),
+            Ok(measurement) => {
+                measurements.push(
+                    Reading::float(
+                        "temperature",
+                        "celsius",
+                        measurement.temperature.as_degrees_celsius(),
+                        2,
+                        Unit::Celsius,
+                    )
+                    .metric(Metric::Temperature)
+                    .serial(serial("shtc3")),
+                );
+                measurements.push(
+                    Reading::float(
+                        "humidity",
+                        "percent",
+                        measurement.humidity.as_percent(),
+                        2,
+                        Unit::Percent,
+                    )
+                    .metric(Metric::Humidity)
+                    .serial(serial("shtc3")),
+                );
+            }
+            Err(e) => {
+                eprintln!("Temp/Humi: ERROR: {:?}", e);
+                measurements.fail("shtc3");
+            }
+        }
+    }
+
+    // Read TSL2591 lux sensor, if present. Thanks to its higher dynamic range, it takes
+    // precedence over the VEML7700.
+    #[cfg(feature = "tsl2591")]
+    if let Some(tsl2591) = sensors
+        .tsl2591
+        .as_mut()
+        .filter(|_| settings.reads("tsl2591"))
+    {
+        let _read = deadline::start("tsl2591", read_timeout);
+        match tsl2591.measure(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                println!(":: TSL2591 gain: {:?}", measurement.gain);
+                measurements.push(
+                    Reading::float("illumination", "lux", measurement.lux, 2, Unit::Lux)
+                        .metric(Metric::Illuminance),
+                );
+            }
+            Err(e) => {
+                eprintln!("Lux (TSL2591): ERROR: {:?}", e);
+                measurements.fail("tsl2591");
+            }
+        }
+    }
+
+    // Read lux sensor, if present and the illuminance wasn't read by the TSL2591
+    #[cfg(feature = "lux")]
+    if let Some((veml, warmup, _)) = sensors.lux.as_mut().filter(|_| {
+        measurements.reading(Metric::Illuminance).is_none() && settings.reads("veml7700")
+    }) {
+        let _read = deadline::start("veml7700", read_timeout);
+        match veml.read_lux() {
+            Ok(_) if !warmup.accept() => println!(":: Lux:   Discarded (warming up)"),
+            Ok(lux) => measurements.push(
+                Reading::float("illumination", "lux", lux, 2, Unit::Lux)
+                    .metric(Metric::Illuminance),
+            ),
+            Err(e) => {
+                eprintln!("Lux: ERROR: {:?}", e);
+                measurements.fail("veml7700");
+            }
+        }
+    }
+
+    // Read differential pressure sensor, if present
+    #[cfg(feature = "diff_pressure")]
+    if let Some(sdp) = sensors
+        .diff_pressure
+        .as_mut()
+        .filter(|_| settings.reads("sdp8xx"))
+    {
+        let _read = deadline::start("sdp8xx", read_timeout);
+        match sdp.read_measurement() {
+            Ok(measurement) => {
+                println!(":: DP T:  {} °C", measurement.temperature);
+                measurements.push(
+                    Reading::float(
+                        "differential_pressure",
+                        "pa",
+                        measurement.differential_pressure,
+                        2,
+                        Unit::Pascal,
+                    )
+                    .serial(serial("sdp8xx")),
+                );
+            }
+            Err(e) => {
+                eprintln!("Differential pressure: ERROR: {:?}", e);
+                measurements.fail("sdp8xx");
+            }
+        }
+    }
+
+    // Read barometric pressure sensor, if present
+    #[cfg(feature = "pressure")]
+    if let Some((bmp, altitude)) = sensors
+        .pressure
+        .as_mut()
+        .filter(|_| settings.reads("bmp390"))
+    {
+        let _read = deadline::start("bmp390", read_timeout);
+        match bmp.measure(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                let pressure = measurement.pressure / 100.0;
+                println!(":: BMP T: {} °C", measurement.temperature);
+                measurements.push(
+                    Reading::float("pressure", "station_hpa", pressure, 2, Unit::Hectopascal)
+                        .metric(Metric::Pressure),
+                );
+                if let Some(altitude) = *altitude {
+                    let sea_level = drivers::bmp390::sea_level_pressure(pressure, altitude);
+                    measurements.push(
+                        Reading::float(
+                            "pressure",
+                            "sea_level_hpa",
+                            sea_level,
+                            2,
+                            Unit::Hectopascal,
+                        )
+                        .metric(Metric::SeaLevelPressure),
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Pressure: ERROR: {:?}", e);
+                measurements.fail("bmp390");
+            }
+        }
+    }
+
+    // Feed the live pressure to CO₂ sensors that support pressure compensation
+    #[cfg(feature = "scd4x")]
+    if let Some(pressure) = measurements.value(Metric::Pressure) {
+        if let Some(scd) = sensors.co2.as_mut().filter(|_| settings.reads("scd4x")) {
+            if let Err(e) = scd.set_ambient_pressure(pressure) {
+                eprintln!("CO₂: ERROR: Could not set ambient pressure: {:?}", e);
+            }
+        }
+    }
+
+    // Read CO₂ sensor, if present
+    #[cfg(feature = "scd4x")]
+    if let Some(scd) = sensors.co2.as_mut().filter(|_| settings.reads("scd4x")) {
+        let _read = deadline::start("scd4x", read_timeout);
+        match scd.data_ready(&mut GeneralPurposeDelay) {
+            Ok(true) => match scd.read_measurement(&mut GeneralPurposeDelay) {
+                Ok(measurement) => {
+                    measurements.push(
+                        Reading::unsigned("co2", "ppm", measurement.co2_ppm, Unit::Ppm)
+                            .sensor("scd4x")
+                            .metric(Metric::Co2)
+                            .serial(serial("scd4x")),
+                    );
+                    measurements.push(
+                        Reading::float(
+                            "temperature",
+                            "celsius",
+                            measurement.temperature,
+                            2,
+                            Unit::Celsius,
+                        )
+                        .sensor("scd4x")
+                        .serial(serial("scd4x")),
+                    );
+                    measurements.push(
+                        Reading::float(
+                            "humidity",
+                            "percent",
+                            measurement.humidity,
+                            2,
+                            Unit::Percent,
+                        )
+                        .sensor("scd4x")
+                        .serial(serial("scd4x")),
+                    );
+                }
+                Err(e) => {
+                    eprintln!("CO₂: ERROR: {:?}", e);
+                    measurements.fail("scd4x");
+                }
+            },
+            Ok(false) => println!(":: CO₂:   No new measurement available"),
+            Err(e) => {
+                eprintln!("CO₂: ERROR: {:?}", e);
+                measurements.fail("scd4x");
+            }
+        }
+    }
+
+    // Temperature/humidity compensation data for the gas sensors, if available
+    #[cfg(any(feature = "ens160", feature = "ccs811"))]
+    let environment = measurements
+        .value(Metric::Temperature)
+        .zip(measurements.value(Metric::Humidity));
+
+    // Read air quality sensor, if present
+    #[cfg(feature = "ens160")]
+    if let Some(ens) = sensors
+        .air_quality
+        .as_mut()
+        .filter(|_| settings.reads("ens160"))
+    {
+        let _read = deadline::start("ens160", read_timeout);
+        if let Some((temp, humi)) = environment {
+            if let Err(e) = ens.set_compensation(temp, humi) {
+                eprintln!("Air quality: ERROR: Could not set compensation: {:?}", e);
+            }
+        }
+        match ens.measure() {
+            Ok(measurement) if measurement.validity == drivers::ens160::Validity::Normal => {
+                measurements.push(
+                    Reading::unsigned("co2", "ppm", measurement.co2eq_ppm, Unit::Ppm)
+                        .sensor("ens160")
+                        .metric(Metric::Co2eq),
+                );
+                measurements.push(
+                    Reading::unsigned("tvoc", "ppb", measurement.tvoc_ppb, Unit::Ppb)
+                        .sensor("ens160")
+                        .metric(Metric::Tvoc),
+                );
+                measurements.push(
+                    Reading::unsigned("aqi", "uba", measurement.aqi, Unit::None)
+                        .sensor("ens160")
+                        .metric(Metric::Aqi),
+                );
+            }
+            Ok(measurement) => println!(
+                ":: AQI:   Not submitting, sensor status: {:?}",
+                measurement.validity
+            ),
+            Err(e) => {
+                eprintln!("Air quality: ERROR: {:?}", e);
+                measurements.fail("ens160");
+            }
+        }
+    }
+
+    // Read CCS811 gas sensor, if present
+    #[cfg(feature = "ccs811")]
+    if let Some((ccs, baseline)) = sensors.ccs811.as_mut().filter(|_| settings.reads("ccs811")) {
+        let _read = deadline::start("ccs811", read_timeout);
+        if let Some((temp, humi)) = environment {
+            if let Err(e) = ccs.set_environment(temp, humi) {
+                eprintln!("CCS811: ERROR: Could not set environment data: {:?}", e);
+            }
+        }
+
+        // Restore the stored baseline once the sensor is conditioned
+        if let Some(stored) = baseline.take_restore() {
+            match ccs.set_baseline(stored) {
+                Ok(()) => println!("CCS811: Restored baseline {:02x?}", stored),
+                Err(e) => eprintln!("CCS811: ERROR: Could not restore baseline: {:?}", e),
+            }
+        }
+
+        match ccs.measure() {
+            Ok(measurement) => {
+                measurements.push(
+                    Reading::unsigned("co2", "ppm", measurement.co2eq_ppm, Unit::Ppm)
+                        .sensor("ccs811")
+                        .metric(Metric::Co2eq),
+                );
+                measurements.push(
+                    Reading::unsigned("tvoc", "ppb", measurement.tvoc_ppb, Unit::Ppb)
+                        .sensor("ccs811")
+                        .metric(Metric::Tvoc),
+                );
+            }
+            Err(e) => {
+                eprintln!("CCS811: ERROR: {:?}", e);
+                measurements.fail("ccs811");
+            }
+        }
+
+        // Persist the current baseline periodically
+        if baseline.save_due() {
+            match ccs.baseline() {
+                Ok(current) => baseline.save(&current),
+                Err(e) => eprintln!("CCS811: ERROR: Could not read baseline: {:?}", e),
+            }
+        }
+    }
+
+    // Read formaldehyde sensor, if present
+    #[cfg(feature = "hcho")]
+    if let Some(sfa) = sensors.hcho.as_mut().filter(|_| settings.reads("sfa30")) {
+        let _read = deadline::start("sfa30", read_timeout);
+        match sfa.read_measurement(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                measurements.push(
+                    Reading::float("formaldehyde", "ppb", measurement.hcho_ppb, 1, Unit::Ppb)
+                        .metric(Metric::Hcho)
+                        .serial(serial("sfa30")),
+                );
+                measurements.push(
+                    Reading::float(
+                        "temperature",
+                        "celsius",
+                        measurement.temperature,
+                        2,
+                        Unit::Celsius,
+                    )
+                    .sensor("sfa30")
+                    .serial(serial("sfa30")),
+                );
+                measurements.push(
+                    Reading::float(
+                        "humidity",
+                        "percent",
+                        measurement.humidity,
+                        2,
+                        Unit::Percent,
+                    )
+                    .sensor("sfa30")
+                    .serial(serial("sfa30")),
+                );
+            }
+            Err(e) => {
+                eprintln!("Formaldehyde: ERROR: {:?}", e);
+                measurements.fail("sfa30");
+            }
+        }
+    }
+
+    // Read spectral sensor, if present
+    #[cfg(feature = "spectral")]
+    if let Some((as7341, lux_factor)) = sensors
+        .spectral
+        .as_mut()
+        .filter(|_| settings.reads("as7341"))
+    {
+        let _read = deadline::start("as7341", read_timeout);
+        match as7341.measure(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                for ((count, field), wavelength) in measurement
+                    .channels
+                    .iter()
+                    .zip(SPECTRUM_FIELDS)
+                    .zip(drivers::as7341::WAVELENGTHS)
+                {
+                    println!(":: {}: {} nm", field, wavelength);
+                    measurements.push(Reading::unsigned("spectrum", field, *count, Unit::None));
+                }
+                measurements.push(Reading::unsigned(
+                    "spectrum",
+                    "clear",
+                    measurement.clear,
+                    Unit::None,
+                ));
+                measurements.push(Reading::unsigned(
+                    "spectrum",
+                    "nir",
+                    measurement.nir,
+                    Unit::None,
+                ));
+                let lux = measurement.lux(*lux_factor);
+                measurements.push(
+                    Reading::float("illumination", "lux", lux, 2, Unit::Lux).sensor("as7341"),
+                );
+                if let Some(cct) = measurement.cct() {
+                    measurements.push(Reading::float(
+                        "color_temperature",
+                        "kelvin",
+                        cct,
+                        0,
+                        Unit::Kelvin,
+                    ));
+                }
+            }
+            Err(e) => {
+                eprintln!("Spectral: ERROR: {:?}", e);
+                measurements.fail("as7341");
+            }
+        }
+    }
+
+    // Collect occupancy since the last interval, if a presence sensor is present. It takes
+    // precedence over the LD2410 occupancy.
+    #[cfg(feature = "presence")]
+    if let Some((_, detector)) = sensors
+        .presence
+        .as_mut()
+        .filter(|_| settings.reads("apds9960"))
+    {
+        if let Some(occupancy) = detector.take_occupancy() {
+            measurements.push(
+                Reading::float("occupancy", "percent", occupancy, 1, Unit::Percent)
+                    .sensor("apds9960")
+                    .metric(Metric::Occupancy),
+            );
+        }
+    }
+
+    // Collect radar state and occupancy, if a radar is present
+    #[cfg(feature = "ld2410")]
+    if let Some((ld2410, detector)) = sensors.radar.as_mut().filter(|_| settings.reads("ld2410")) {
+        let _read = deadline::start("ld2410", read_timeout);
+        if let Some(report) = ld2410.latest() {
+            let state = report.target_state;
+            for reading in [
+                Reading::bool("radar", "present", state.is_present()),
+                Reading::bool("radar", "moving", state.is_moving()),
+                Reading::bool("radar", "still", state.is_still()),
+                Reading::unsigned(
+                    "radar",
+                    "moving_distance_cm",
+                    report.moving_distance,
+                    Unit::Centimeter,
+                ),
+                Reading::unsigned("radar", "moving_energy", report.moving_energy, Unit::None),
+                Reading::unsigned(
+                    "radar",
+                    "still_distance_cm",
+                    report.still_distance,
+                    Unit::Centimeter,
+                ),
+                Reading::unsigned("radar", "still_energy", report.still_energy, Unit::None),
+                Reading::unsigned(
+                    "radar",
+                    "detection_distance_cm",
+                    report.detection_distance,
+                    Unit::Centimeter,
+                ),
+            ] {
+                measurements.push(reading);
+            }
+        }
+        if let Some(occupancy) = detector.take_occupancy() {
+            measurements.push(
+                Reading::float("occupancy", "percent", occupancy, 1, Unit::Percent)
+                    .sensor("ld2410")
+                    .metric(Metric::Occupancy),
+            );
+        }
+    }
+
+    // Read Geiger counter, if present
+    #[cfg(feature = "geiger")]
+    if let Some(geiger) = sensors.geiger.as_mut().filter(|_| settings.reads("geiger")) {
+        match geiger.measure() {
+            Some(measurement) => {
+                measurements.push(Reading::float(
+                    "radiation",
+                    "cpm",
+                    measurement.cpm,
+                    1,
+                    Unit::CountsPerMinute,
+                ));
+                measurements.push(
+                    Reading::float(
+                        "radiation",
+                        "usvh",
+                        measurement.dose_rate,
+                        4,
+                        Unit::MicrosievertsPerHour,
+                    )
+                    .metric(Metric::DoseRate),
+                );
+            }
+            None => eprintln!("Geiger counter: ERROR: Counter saturated"),
+        }
+    }
+
+    // Read energy monitor, if present. It takes precedence over the S0 energy meter.
+    #[cfg(feature = "pzem")]
+    if let Some(pzem) = sensors
+        .power_meter
+        .as_mut()
+        .filter(|_| settings.reads("pzem004t"))
+    {
+        let _read = deadline::start("pzem004t", read_timeout);
+        match pzem.measure(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                for reading in [
+                    Reading::float("power", "watts", measurement.power, 1, Unit::Watt)
+                        .metric(Metric::Power),
+                    Reading::float("energy", "kwh", measurement.energy, 3, Unit::KilowattHour),
+                    Reading::float("electricity", "voltage", measurement.voltage, 1, Unit::Volt),
+                    Reading::float(
+                        "electricity",
+                        "current",
+                        measurement.current,
+                        3,
+                        Unit::Ampere,
+                    ),
+                    Reading::float(
+                        "electricity",
+                        "frequency",
+                        measurement.frequency,
+                        1,
+                        Unit::Hertz,
+                    ),
+                    Reading::float(
+                        "electricity",
+                        "power_factor",
+                        measurement.power_factor,
+                        2,
+                        Unit::None,
+                    ),
+                ] {
+                    measurements.push(reading.sensor("pzem004t"));
+                }
+            }
+            Err(e) => {
+                eprintln!("Energy monitor: ERROR: {:?}", e);
+                measurements.fail("pzem004t");
+            }
+        }
+    }
+
+    // Read energy meter, if present
+    #[cfg(feature = "s0")]
+    if let Some(meter) = sensors.energy.as_mut().filter(|_| settings.reads("s0")) {
+        let measurement = meter.measure();
+        if let Some(power) = measurement.power {
+            measurements.push(
+                Reading::float("power", "watts", power, 1, Unit::Watt)
+                    .sensor("s0")
+                    .metric(Metric::Power),
+            );
+        }
+        measurements.push(
+            Reading::float("energy", "kwh", measurement.energy, 3, Unit::KilowattHour).sensor("s0"),
+        );
+    }
+
+    // Read current monitors
+    #[cfg(feature = "ina219")]
+    for channel in sensors
+        .current
+        .iter_mut()
+        .filter(|_| settings.reads("ina219"))
+    {
+        let _read = deadline::start("ina219", read_timeout);
+        match channel.sensor.measure() {
+            Ok(measurement) => {
+                for reading in [
+                    Reading::float("power", "watts", measurement.power, 3, Unit::Watt),
+                    Reading::float("electricity", "voltage", measurement.voltage, 3, Unit::Volt),
+                    Reading::float(
+                        "electricity",
+                        "current",
+                        measurement.current,
+                        4,
+                        Unit::Ampere,
+                    ),
+                ] {
+                    measurements.push(reading.sensor("ina219").channel(&channel.tag));
+                }
+            }
+            Err(e) => {
+                eprintln!("Current ({}): ERROR: {:?}", channel.tag, e);
+                measurements.fail("ina219");
+            }
+        }
+    }
+
+    // Read thermocouple, if present
+    #[cfg(feature = "thermocouple")]
+    if let Some(channel) = sensors
+        .thermocouple
+        .as_mut()
+        .filter(|_| settings.reads("max31855"))
+    {
+        let _read = deadline::start("max31855", read_timeout);
+        match channel.sensor.measure() {
+            Ok(measurement) => {
+                println!(":: TC CJ: {} °C", measurement.internal_temperature);
+                measurements.push(
+                    Reading::float(
+                        "temperature",
+                        "celsius",
+                        measurement.temperature,
+                        2,
+                        Unit::Celsius,
+                    )
+                    .sensor("max31855")
+                    .channel(&channel.tag)
+                    .metric(Metric::Thermocouple),
+                );
+            }
+            Err(e) => {
+                eprintln!("Thermocouple: ERROR: {:?}", e);
+                measurements.fail("max31855");
+            }
+        }
+    }
+
+    // Read RTD, if present
+    #[cfg(feature = "rtd")]
+    if let Some(channel) = sensors.rtd.as_mut().filter(|_| settings.reads("max31865")) {
+        let _read = deadline::start("max31865", read_timeout);
+        match channel.sensor.measure(&mut GeneralPurposeDelay) {
+            Ok(measurement) => {
+                println!(":: RTD R: {} Ω", measurement.resistance);
+                measurements.push(
+                    Reading::float(
+                        "temperature",
+                        "celsius",
+                        measurement.temperature,
+                        2,
+                        Unit::Celsius,
+                    )
+                    .sensor("max31865")
+                    .channel(&channel.tag)
+                    .metric(Metric::Rtd),
+                );
+            }
+            Err(e) => {
+                eprintln!("RTD: ERROR: {:?}", e);
+                measurements.fail("max31865");
+            }
+        }
+    }
+
+    for reading in measurements.readings.iter() {
+        println!(":: {}", reading);
+    }
+}
+
+/// Collect the readings that are shown on the display.
+fn display_summary(
+    measurements: &Measurements,
+    config: &Config,
+    serial: Option<&str>,
+    history: &History,
+    offline: bool,
+) -> display::Summary {
+    let pages = display::layout::pages(
+        &config.display,
+        |metric| measurements.value(metric),
+        history,
+    );
+    let battery_voltage = config
+        .display
+        .battery_channel
+        .as_ref()
+        .and_then(|channel| {
+            measurements.readings.iter().find(|reading| {
+                reading.measurement == "electricity"
+                    && reading.field == "voltage"
+                    && reading.channel.as_deref() == Some(channel.as_str())
+            })
+        })
+        .map(|reading| reading.value.as_f32());
+    display::Summary {
+        name: config.name.as_deref().unwrap_or(SENSILO_NAME).into(),
+        serial: serial.map(Into::into),
+        pages,
+        battery_voltage,
+        offline,
+        calibration_due: calibration::status().iter().any(|status| status.due),
+    }
+}
+
+fn submit_events(events: &[Event], tags: &TagSet, config: &Config) -> anyhow::Result<()> {
+    println!("-> Submitting events");
+
+    let lines: Vec<String> = events.iter().map(|event| event.to_line(tags)).collect();
+
+    submit_lines(&lines, config)
+}
+
+/// Return the event log entries of the selected kinds that were not forwarded yet as `events`
+/// lines, with the time of the entry, and the sequence number of the last entry, which is marked
+/// as forwarded once the lines were submitted (see [`eventlog::mark_forwarded`]). Entries without
+/// a known time (recorded before the clock was synchronized during an earlier boot) are skipped.
+fn annotation_lines(
+    annotations: &config::Annotations,
+    tags: &TagSet,
+) -> (Vec<String>, Option<u32>) {
+    // Until then, the entries of this boot can't be dated
+    if clock::unix_time().is_none() {
+        return (Vec::new(), None);
+    }
+    let entries = eventlog::unforwarded();
+    let lines = entries
+        .iter()
+        .filter(|entry| annotations.kinds.contains(&entry.kind))
+        .filter_map(|entry| entry.to_line(tags))
+        .collect();
+    (lines, entries.last().map(|entry| entry.seq))
+}
+
+/// Return the `diagnostics` line with the uptime, the free heap, the number of main loop
+/// iterations, the number of gas sensor task ticks (if the task is running), whether the device is
+/// in the safe mode, the durations of the sensor reads that took longer than their budget, the
+/// durations of the stages of the previous cycle (if any) and of the previous request to InfluxDB
+/// (if any).
+fn diagnostics_line(
+    tags: &TagSet,
+    loop_iterations: u32,
+    gas_timer_ticks: Option<u32>,
+    safe_mode: bool,
+    timeouts: &[(&'static str, Duration)],
+    last_cycle: Option<CycleTiming>,
+) -> String {
+    let uptime_secs = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000;
+    let free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+    let mut line = Line::build("diagnostics")
+        .tags(tags)
+        .field("uptime_secs", uptime_secs as u64)
+        .field("free_heap", free_heap)
+        .field("loop_iterations", loop_iterations)
+        .field("safe_mode", safe_mode);
+    if let Some(ticks) = gas_timer_ticks {
+        line = line.field("gas_timer_ticks", ticks);
+    }
+    match clock::sync_status() {
+        Some(status) => {
+            line = line
+                .field("time_synced", true)
+                .field("last_sync_secs", status.age_secs);
+            if let Some(offset) = status.offset_ms {
+                line = line.field("sync_offset_ms", offset);
+            }
+        }
+        None => line = line.field("time_synced", false),
+    }
+    for (sensor, duration) in timeouts {
+        line = line.field(&format!("{}_timeout_ms", sensor), duration.as_millis());
+    }
+    if let Some(timing) = last_cycle {
+        line = line
+            .field("read_ms", timing.read.as_millis())
+            .field("serialize_ms", timing.serialize.as_millis());
+        if let Some(latency) = timing.latency {
+            line = line.field("latency_ms", latency.as_millis());
+        }
+    }
+    if let Some(timing) = LAST_REQUEST.with(Cell::get) {
+        line = line
+            .field("connect_ms", timing.connect.as_millis())
+            .field("write_ms", timing.write.as_millis())
+            .field("response_ms", timing.response.as_millis())
+            .field("request_ms", timing.total.as_millis())
+            .field("connection_reused", timing.reused);
+    }
+    line.into_string()
+}
+
+/// Resubmit the history values between two Unix timestamps, as
+/// `history,metric=<metric>,<tags> value=<value> <timestamp>` lines.
+///
+/// The lines are submitted in batches of [`BACKFILL_BATCH_SIZE`], so that the payload stays
+/// small.
+fn submit_backfill(
+    history: &Mutex<History>,
+    from: u64,
+    to: u64,
+    tags: &TagSet,
+    config: &Config,
+) -> anyhow::Result<()> {
+    println!("-> Submitting backfill from {} to {}", from, to);
+
+    for metric in Metric::ALL {
+        // Don't keep the mutex locked while submitting
+        let points = history
+            .lock()
+            .expect("Failed to lock history mutex")
+            .query(metric, from, to, 0)
+            .unwrap_or_default();
+        for batch in points.chunks(BACKFILL_BATCH_SIZE) {
+            let lines: Vec<String> = batch
+                .iter()
+                .map(|&(timestamp, value)| {
+                    Line::build("history")
+                        .tag("metric", metric.name())
+                        .tags(tags)
+                        .field("value", value)
+                        .timestamp(timestamp)
+                })
+                .filter(Line::has_fields)
+                .map(Line::into_string)
+                .collect();
+            if !lines.is_empty() {
+                submit_lines(&lines, config)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Return the tags that are added to every line.
+fn tags(config: &Config, serial: Option<&str>) -> TagSet {
+    let name = config.name.as_deref().unwrap_or(SENSILO_NAME);
+    let mut tags = TagSet::default()
+        .tag("name", name)
+        .tag("fw_version", VERSION)
+        .tag("config_hash", &config::hash(config));
+    if config.simulation.enabled {
+        tags = tags.tag("simulated", "true");
+    }
+    if let Some(serial) = serial {
+        tags = tags.tag("serial", serial);
+    }
+    for (key, value) in config.tags.iter() {
+        tags = tags.tag(key, value);
+    }
+    tags
+}
+
+/// Submit lines in InfluxDB line protocol format.
+fn submit_lines(lines: &[String], config: &Config) -> anyhow::Result<()> {
+    submit_payload(&lines.join("\n"), config).map_err(|failure| failure.error)
+}
+
+/// Submit a payload in InfluxDB line protocol format. The lines are written to the buckets of
+/// their measurements (see `sinks.routes`). Fail if InfluxDB doesn't confirm that all data was
+/// written, with the lines of the buckets that were not written yet if the others were.
+fn submit_payload(payload: &str, config: &Config) -> Result<(), delivery::Failure> {
+    let influxdb = match config.sinks.influxdb {
+        Some(ref influxdb) if influxdb_enabled(config) => influxdb,
+        _ => return Ok(()),
+    };
+    let (host, org, bucket) = (
+        influxdb.host.as_str(),
+        influxdb.org.as_str(),
+        influxdb.bucket.as_str(),
+    );
+    let api = match influxdb.api {
+        InfluxDbApi::V2 => WriteApi::V2 {
+            api_token: influxdb.api_token.as_deref(),
+        },
+        InfluxDbApi::V1 => WriteApi::V1 {
+            username: influxdb.username.as_deref(),
+            password: influxdb.password.as_deref(),
+        },
+    };
+
+    // Group the lines by org and bucket, in the order of their first occurrence
+    let mut groups: Vec<((&str, &str), Vec<&str>)> = Vec::new();
+    for line in payload.lines() {
+        let measurement = line.split([',', ' ']).next().unwrap_or("");
+        let destination = config
+            .sinks
+            .routes
+            .iter()
+            .find(|route| route.measurements.iter().any(|name| name == measurement))
+            .map(|route| (route.org.as_deref().unwrap_or(org), route.bucket.as_str()))
+            .unwrap_or((org, bucket));
+        match groups.iter_mut().find(|(group, _)| *group == destination) {
+            Some((_, lines)) => lines.push(line),
+            None => groups.push((destination, vec![line])),
+        }
+    }
+
+    // Stop at the first failure. The groups that were written are not retried, so that lines
+    // without timestamps are not written twice.
+    for (i, ((org, bucket), lines)) in groups.iter().enumerate() {
+        if let Err(error) = write_influxdb(host, &api, org, bucket, &lines.join("\n")) {
+            let pending = groups[i..]
+                .iter()
+                .flat_map(|(_, lines)| lines.iter().copied())
+                .collect::<Vec<_>>()
+                .join("\n");
+            return Err(delivery::Failure {
+                error,
+                pending: (i > 0).then_some(pending),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Return whether data is written to InfluxDB, i.e. whether it's not replaced by MQTT, the JSON
+/// webhook, BTHome or ESP-NOW.
+fn influxdb_enabled(config: &Config) -> bool {
+    config.sinks.espnow.is_none()
+        && !matches!(config.sinks.mqtt, Some(ref mqtt) if !mqtt.influxdb)
+        && !matches!(config.sinks.webhook, Some(ref webhook) if !webhook.influxdb)
+        && !matches!(config.sinks.bthome, Some(ref bthome) if !bthome.influxdb)
+}
+
+/// Write API of the InfluxDB server, with its credentials
+enum WriteApi<'a> {
+    /// Without an API token, the requests are not authenticated
+    V2 { api_token: Option<&'a str> },
+    /// Without a username, the requests are not authenticated
+    V1 {
+        username: Option<&'a str>,
+        password: Option<&'a str>,
+    },
+}
+
+/// Write a payload in InfluxDB line protocol format to a bucket (the database and optionally the
+/// retention policy with the v1 API, `<db>/<rp>`). Fail if InfluxDB doesn't confirm that the data
+/// was written.
+///
+/// The connection of the previous request is reused if it was to the same host. If the reused
+/// connection fails (e.g. because the server closed it in the meantime), the request is retried
+/// once with a new connection.
+fn write_influxdb(
+    host: &str,
+    api: &WriteApi,
+    org: &str,
+    bucket: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    println!("Sending payload to bucket {}:\n{}", bucket, payload);
+    #[cfg(feature = "faults")]
+    faults::http()?;
+
+    // Prepare headers and URL
+    let host = host.trim_end_matches('/');
+    let (url, authorization_header) = match *api {
+        WriteApi::V2 { api_token } => (
+            format!(
+                "{}/api/v2/write?org={}&bucket={}&precision=s",
+                host, org, bucket
+            ),
+            api_token.map(|api_token| format!("Token {}", api_token)),
+        ),
+        WriteApi::V1 { username, password } => {
+            let url = match bucket.split_once('/') {
+                Some((db, rp)) => format!("{}/write?db={}&rp={}&precision=s", host, db, rp),
+                None => format!("{}/write?db={}&precision=s", host, bucket),
+            };
+            let authorization = username.map(|username| {
+                let credentials = format!("{}:{}", username, password.unwrap_or(""));
+                format!("Basic {}", base64(credentials.as_bytes()))
+            });
+            (url, authorization)
+        }
+    };
+    let content_length_header = format!("{}", payload.len());
+    let mut headers = vec![
+        ("content-type", "text/plain; charset=utf-8"),
+        ("content-length", &*content_length_header),
+        ("accept", "application/json"),
+    ];
+    if let Some(ref authorization) = authorization_header {
+        headers.push(("authorization", authorization));
+    }
+
+    let start = Instant::now();
+    let cached = INFLUXDB_CLIENT
+        .with(|client| client.borrow_mut().take())
+        .filter(|(cached_host, _)| cached_host == host);
+    let mut reused = cached.is_some();
+    let mut client = match cached {
+        Some((_, client)) => client,
+        None => connect_influxdb()?,
+    };
+    let (status, timing) = match request_influxdb(&mut client, &url, &headers, payload) {
+        Err(e) if reused => {
+            eprintln!("-> Reused connection failed ({:#}), reconnecting", e);
+            reused = false;
+            client = connect_influxdb()?;
+            request_influxdb(&mut client, &url, &headers, payload)?
+        }
+        result => result?,
+    };
+    LAST_REQUEST.with(|last| {
+        last.set(Some(RequestTiming {
+            total: start.elapsed(),
+            reused,
+            ..timing
+        }))
+    });
+    // Only keep connections that completed a request
+    INFLUXDB_CLIENT.with(|cached| *cached.borrow_mut() = Some((host.to_string(), client)));
+
+    if status != 204 {
+        bail!("Server returned HTTP {}", status);
+    }
     Ok(())
 }
+
+/// Encode bytes as base64 (with padding), e.g. for basic authentication.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Resolve the host of a URL (e.g. `https://influxdb.example.com:8086`) to an address.
+fn resolve_host(url: &str) -> anyhow::Result<SocketAddr> {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let default_port = if scheme == "https" { 443 } else { 80 };
+    let (host, port) = split_authority(authority, default_port)?;
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("No address for {}", host))
+}
+
+/// Split the authority of a URL into the host and the port. IPv6 addresses are in brackets
+/// (e.g. `[fe80::1]:8086`), the brackets are removed.
+fn split_authority(authority: &str, default_port: u16) -> anyhow::Result<(&str, u16)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => bail!("Invalid host {}", authority),
+            },
+            None => bail!("Missing ']' in {}", authority),
+        },
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse::<u16>().context("Invalid port")?,
+        None => default_port,
+    };
+    Ok((host, port))
+}
+
+/// Create an HTTP(S) client for InfluxDB.
+fn connect_influxdb() -> anyhow::Result<HttpClient<EspHttpConnection>> {
+    Ok(HttpClient::wrap(EspHttpConnection::new(
+        &HttpConfiguration {
+            timeout: Some(Duration::from_secs(10)),
+            crt_bundle_attach: webhook::crt_bundle_attach(),
+            ..Default::default()
+        },
+    )?))
+}
+
+/// Send a write request to InfluxDB and read the response. Return the HTTP status and the
+/// durations of the request, the connection is not marked as reused.
+fn request_influxdb(
+    client: &mut HttpClient<EspHttpConnection>,
+    url: &str,
+    headers: &[(&str, &str)],
+    payload: &str,
+) -> anyhow::Result<(u16, RequestTiming)> {
+    // Send request, the connection is opened with the request
+    let connect_start = Instant::now();
+    let mut request = client.post(url, headers)?;
+    let connect = connect_start.elapsed();
+    let write_start = Instant::now();
+    request.write_all(payload.as_bytes())?;
+    request.flush()?;
+    let write = write_start.elapsed();
+
+    // Read response
+    let response_start = Instant::now();
+    let mut response = request.submit()?;
+    let status = response.status();
+    let (_headers, mut body) = response.split();
+    let success = status == 204;
+    if success {
+        println!("-> Data sent successfully to InfluxDB!");
+    } else {
+        eprintln!("-> Error: Server returned HTTP {}", status);
+    }
+
+    // Drain body, print it if not successful. The body must be read completely so that the
+    // connection can be reused.
+    let mut buf = [0u8; 1024];
+    if !success {
+        let bytes_read = io::try_read_full(&mut body, &mut buf).map_err(|e| e.0)?;
+        println!("  Read {} bytes", bytes_read);
+        match std::str::from_utf8(&buf[0..bytes_read]) {
+            Ok(body_string) => println!(
+                "   Response body (truncated to {} bytes): {}",
+                buf.len(),
+                body_string
+            ),
+            Err(e) => eprintln!("  Error decoding response body: {}", e),
+        };
+    }
+    while body.read(&mut buf)? > 0 {} // Drain the remaining response bytes
+    println!();
+
+    let timing = RequestTiming {
+        connect,
+        write,
+        response: response_start.elapsed(),
+        total: connect_start.elapsed(),
+        reused: false,
+    };
+    Ok((status, timing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_authority_with_port() {
+        assert_eq!(
+            split_authority("influxdb.example.com:8086", 80).unwrap(),
+            ("influxdb.example.com", 8086)
+        );
+        assert_eq!(
+            split_authority("influxdb.example.com", 443).unwrap(),
+            ("influxdb.example.com", 443)
+        );
+    }
+
+    #[test]
+    fn split_authority_ipv6() {
+        assert_eq!(
+            split_authority("[fe80::1]:8086", 80).unwrap(),
+            ("fe80::1", 8086)
+        );
+        assert_eq!(split_authority("[::1]", 80).unwrap(), ("::1", 80));
+        assert!(split_authority("[fe80::1", 80).is_err());
+        assert!(split_authority("[fe80::1]8086", 80).is_err());
+    }
+
+    #[test]
+    fn failed_read_is_recorded_once_per_cycle() {
+        let mut measurements = Measurements::default();
+        measurements.fail("sgp30");
+        measurements.fail("sgp30");
+        measurements.fail("shtc3");
+        assert_eq!(measurements.failed, ["sgp30", "shtc3"]);
+        measurements.reset();
+        measurements.fail("sgp30");
+        assert_eq!(measurements.failed, ["sgp30"]);
+    }
+}
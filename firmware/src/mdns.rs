@@ -0,0 +1,90 @@
+//! mDNS, so that the device can be reached as `sensilo-<name>.local` instead of by its IP address,
+//! and so that its services are discovered on the local network (DNS-SD).
+//!
+//! The hostname is `sensilo-` followed by the name of the device in lowercase, with every character
+//! other than a letter or digit replaced by `-`, or followed by the end of the MAC address if no
+//! name is configured. The services that are running are advertised with the name of the device as
+//! instance name:
+//!
+//! - `_http._tcp` on port 80, if the HTTP API is enabled, with the paths of the endpoints as TXT
+//!   records (`api=/api/v1`, and `metrics=/metrics` if `api.metrics` is set), and `auth=bearer` if
+//!   `api.token` is set
+//! - `_esphomelib._tcp` on `esphome.port`, if the ESPHome API is enabled, so that Home Assistant
+//!   discovers the device, with its MAC address as `mac` TXT record
+
+use esp_idf_svc::mdns::EspMdns;
+
+use crate::{config::Config, SENSILO_NAME};
+
+/// Start the mDNS responder and advertise the services. The services are advertised as long as
+/// the returned responder is kept.
+pub fn start(config: &Config) -> anyhow::Result<EspMdns> {
+    let mac = mac_address();
+    let hostname = hostname(config.name.as_deref(), &mac);
+    let instance = config.name.as_deref().unwrap_or(SENSILO_NAME);
+
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&hostname)?;
+    mdns.set_instance_name(instance)?;
+    println!("mDNS: Hostname {}.local", hostname);
+
+    if config.api.enabled {
+        let mut txt = vec![("api", "/api/v1")];
+        if config.api.metrics {
+            txt.push(("metrics", "/metrics"));
+        }
+        if config.api.token.is_some() {
+            txt.push(("auth", "bearer"));
+        }
+        mdns.add_service(Some(instance), "_http", "_tcp", 80, &txt)?;
+        println!("mDNS: Advertising the HTTP API");
+    }
+    if config.esphome.enabled {
+        let mac: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+        mdns.add_service(
+            Some(instance),
+            "_esphomelib",
+            "_tcp",
+            config.esphome.port,
+            &[("mac", &mac)],
+        )?;
+        println!("mDNS: Advertising the ESPHome API");
+    }
+    Ok(mdns)
+}
+
+/// Return the hostname (without `.local`) of a device with the given name.
+fn hostname(name: Option<&str>, mac: &[u8; 6]) -> String {
+    let name: String = name
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = name.trim_matches('-');
+    if name.is_empty() {
+        return format!("sensilo-{:02x}{:02x}", mac[4], mac[5]);
+    }
+    // A DNS label has at most 63 characters
+    let mut hostname = format!("sensilo-{}", name);
+    hostname.truncate(63);
+    hostname.trim_end_matches('-').into()
+}
+
+/// Return the WiFi MAC address.
+fn mac_address() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    // Safety: The buffer has room for the 6 bytes of the address
+    unsafe {
+        esp_idf_sys::esp_read_mac(
+            mac.as_mut_ptr(),
+            esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+        )
+    };
+    mac
+}
@@ -0,0 +1,221 @@
+//! Modbus TCP server, for PLCs and SCADA systems.
+//!
+//! Every configured register maps a metric to one or two input registers, as a fixed-point value
+//! (the value times the scale, rounded). Only the function "Read Input Registers" (0x04) is
+//! supported, the unit identifier is ignored. Reading an address that isn't mapped is answered
+//! with the exception "Illegal Data Address".
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, Register, RegisterFormat},
+    history::History,
+};
+
+/// Stack size of the server and connection threads
+const STACK_SIZE: usize = 6144;
+
+/// Maximum number of simultaneous connections
+const MAX_CONNECTIONS: usize = 2;
+
+/// Connections are closed after this time without a request
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of registers in a request, so that the response fits into an ADU
+const MAX_QUANTITY: u16 = 125;
+
+// Function codes
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+// Exception codes
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+/// Start the server thread.
+pub fn spawn(config: Arc<Config>, history: Arc<Mutex<History>>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", config.modbus.port))?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("modbus".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Modbus: ERROR: Could not accept connection: {}", e);
+                        continue;
+                    }
+                };
+                if connections.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    eprintln!("Modbus: Too many connections, closing");
+                    continue;
+                }
+                connections.fetch_add(1, Ordering::Relaxed);
+                let (config, history, counter) =
+                    (config.clone(), history.clone(), connections.clone());
+                let result = thread::Builder::new()
+                    .name("modbus-conn".into())
+                    .stack_size(STACK_SIZE)
+                    .spawn(move || {
+                        if let Err(e) = serve(stream, &config, &history) {
+                            eprintln!("Modbus: ERROR: {}", e);
+                        }
+                        counter.fetch_sub(1, Ordering::Relaxed);
+                    });
+                if let Err(e) = result {
+                    eprintln!("Modbus: ERROR: Could not start connection thread: {}", e);
+                    connections.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Serve the requests of a connection until it's closed or idle.
+fn serve(mut stream: TcpStream, config: &Config, history: &Mutex<History>) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut header = [0u8; 7];
+    loop {
+        // MBAP header: transaction identifier, protocol identifier (0), length, unit identifier
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::UnexpectedEof | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        // The length includes the unit identifier
+        let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+            // Not Modbus, or out of sync
+            return Ok(());
+        }
+        let mut pdu = vec![0; length - 1];
+        stream.read_exact(&mut pdu)?;
+
+        let response = handle(&pdu, &config.modbus.registers, history);
+        let mut adu = header[..4].to_vec();
+        adu.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+        adu.push(header[6]);
+        adu.extend(response);
+        stream.write_all(&adu)?;
+    }
+}
+
+/// Handle a request PDU, return the response PDU.
+fn handle(pdu: &[u8], registers: &[Register], history: &Mutex<History>) -> Vec<u8> {
+    let function = pdu[0];
+    if function != READ_INPUT_REGISTERS {
+        return exception(function, ILLEGAL_FUNCTION);
+    }
+    if pdu.len() != 5 {
+        return exception(function, ILLEGAL_DATA_VALUE);
+    }
+    let start = u16::from_be_bytes([pdu[1], pdu[2]]);
+    let quantity = u16::from_be_bytes([pdu[3], pdu[4]]);
+    if !(1..=MAX_QUANTITY).contains(&quantity) {
+        return exception(function, ILLEGAL_DATA_VALUE);
+    }
+
+    // Values of all mapped registers, by address. The addresses are widened, a `u16` range starting
+    // at the last address would overflow, and requests past it must not wrap around to address 0.
+    let mapped: Vec<(u32, u16)> = {
+        let history = history.lock().expect("Failed to lock history mutex");
+        registers
+            .iter()
+            .flat_map(|register| {
+                let words = encode(register, history.latest(register.metric));
+                (u32::from(register.address)..).zip(words)
+            })
+            .collect()
+    };
+
+    let mut response = vec![function, (quantity * 2) as u8];
+    for address in u32::from(start)..u32::from(start) + u32::from(quantity) {
+        match mapped.iter().find(|(a, _)| *a == address) {
+            Some((_, word)) => response.extend_from_slice(&word.to_be_bytes()),
+            None => return exception(function, ILLEGAL_DATA_ADDRESS),
+        }
+    }
+    response
+}
+
+/// Encode the value of a register, the high word first. Missing values are encoded as the minimum
+/// of signed formats and the maximum of unsigned formats.
+fn encode(register: &Register, value: Option<f32>) -> Vec<u16> {
+    // Casts from float saturate
+    let scaled = value.map(|value| (value * register.scale()).round());
+    match register.format() {
+        RegisterFormat::Int16 => vec![scaled.map_or(i16::MIN, |value| value as i16) as u16],
+        RegisterFormat::Uint16 => vec![scaled.map_or(u16::MAX, |value| value as u16)],
+        RegisterFormat::Int32 => {
+            let value = scaled.map_or(i32::MIN, |value| value as i32) as u32;
+            vec![(value >> 16) as u16, value as u16]
+        }
+    }
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, Metric};
+
+    fn register(address: u16, format: RegisterFormat) -> Register {
+        Register {
+            address,
+            metric: Metric::Temperature,
+            scale: None,
+            format: Some(format),
+        }
+    }
+
+    #[test]
+    fn last_address_can_be_read() {
+        let history = Mutex::new(History::new(&config::History::default()));
+        let registers = [register(0xFFFF, RegisterFormat::Uint16)];
+        let response = handle(
+            &[READ_INPUT_REGISTERS, 0xFF, 0xFF, 0, 1],
+            &registers,
+            &history,
+        );
+        assert_eq!(response, [READ_INPUT_REGISTERS, 2, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn read_past_last_address_does_not_wrap() {
+        let history = Mutex::new(History::new(&config::History::default()));
+        let registers = [
+            register(0, RegisterFormat::Uint16),
+            register(0xFFFF, RegisterFormat::Uint16),
+        ];
+        let response = handle(
+            &[READ_INPUT_REGISTERS, 0xFF, 0xFF, 0, 2],
+            &registers,
+            &history,
+        );
+        assert_eq!(
+            response,
+            [READ_INPUT_REGISTERS | 0x80, ILLEGAL_DATA_ADDRESS]
+        );
+    }
+}
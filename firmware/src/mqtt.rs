@@ -0,0 +1,300 @@
+//! Publishing of the readings to an MQTT broker, as alternative or in addition to InfluxDB.
+//!
+//! Every measurement cycle, each reading is published as plain number (or `true`/`false`) to its
+//! own topic, so that home automation systems can subscribe to single values:
+//!
+//! `<topic_prefix>/<name>/<measurement>[/<sensor>][/<channel>]/<field>`, e.g.
+//! `sensilo/livingroom/temperature/celsius` or `sensilo/livingroom/co2/scd4x/ppm`
+//!
+//! The availability of the device is published (retained) to `<topic_prefix>/<name>/status`:
+//! `online` after connecting, `offline` before a restart, and `offline` as last will when the
+//! broker loses the connection.
+//!
+//! With `discovery`, the metrics are announced to Home Assistant (see [`crate::discovery`]).
+//!
+//! If sensors are tracked in the calibration log (see [`crate::calibration`]), a calibration is
+//! recorded with a message to `<topic_prefix>/<name>/calibration/<sensor>/set`, with the date
+//! (`YYYY-MM-DD`) as payload, or an empty payload for today. The messages should not be retained,
+//! since they're received again after every reconnection.
+//!
+//! With `remote_config.mqtt`, partial configs are received from `<topic_prefix>/<name>/config/set`
+//! (see [`crate::remote`]). Unlike the other command topics, the message can be retained, a
+//! document that doesn't change the config is ignored.
+//!
+//! With the `faults` feature, faults are injected with a message to
+//! `<topic_prefix>/<name>/fault/set`, with the arguments of the `fault` console command as payload
+//! (see [`crate::faults`]).
+//!
+//! The ESP-IDF client runs in its own task and reconnects on its own. Readings are only
+//! published while connected, i.e. the readings of a cycle without connection are dropped.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use embedded_svc::mqtt::client::{Client, Details, Event, Message, Publish, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+
+#[cfg(feature = "faults")]
+use crate::faults;
+use crate::{
+    calibration, config,
+    discovery::Discovery,
+    reading::{Reading, Value},
+    remote, webhook,
+};
+
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+pub struct Publisher {
+    client: EspMqttClient,
+    /// `<topic_prefix>/<name>`
+    base_topic: String,
+    qos: QoS,
+    retain: bool,
+    connected: Arc<AtomicBool>,
+    /// Set when the client (re)connected, until `online` was published
+    announce: Arc<AtomicBool>,
+    discovery: Option<Discovery>,
+    /// Whether partial configs are received (see [`crate::remote`])
+    remote_config: bool,
+}
+
+impl Publisher {
+    /// Create the client, which connects in the background. Partial configs are sent to
+    /// `remote_config`, if set.
+    pub fn new(
+        config: &config::Mqtt,
+        name: &str,
+        remote_config: Option<Sender<String>>,
+    ) -> anyhow::Result<Self> {
+        let base_topic = format!("{}/{}", config.topic_prefix, name);
+        let status_topic = format!("{}/status", base_topic);
+        let connected = Arc::new(AtomicBool::new(false));
+        let announce = Arc::new(AtomicBool::new(false));
+
+        let conf = MqttClientConfiguration {
+            client_id: Some(config.client_id.as_deref().unwrap_or(name)),
+            username: config.username.as_deref(),
+            password: config.password.as_deref(),
+            keep_alive_interval: Some(Duration::from_secs(60)),
+            lwt: Some(LwtConfiguration {
+                topic: &status_topic,
+                payload: OFFLINE,
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            crt_bundle_attach: if config.url.starts_with("mqtts://") {
+                webhook::crt_bundle_attach()
+            } else {
+                None
+            },
+            // Room for a whole partial config, larger messages are received in chunks
+            buffer_size: if remote_config.is_some() {
+                remote::MAX_SIZE
+            } else {
+                0
+            },
+            ..Default::default()
+        };
+        let subscribe_remote_config = remote_config.is_some();
+        let client = {
+            let connected = connected.clone();
+            let announce = announce.clone();
+            let base_topic = base_topic.clone();
+            EspMqttClient::new(&config.url, &conf, move |event| match event {
+                Ok(Event::Connected(_)) => {
+                    println!("MQTT: Connected");
+                    connected.store(true, Ordering::Relaxed);
+                    announce.store(true, Ordering::Relaxed);
+                }
+                Ok(Event::Disconnected) => {
+                    println!("MQTT: Disconnected");
+                    connected.store(false, Ordering::Relaxed);
+                }
+                Ok(Event::Received(message)) => {
+                    if let Some(topic) = message.topic() {
+                        if topic.strip_prefix(base_topic.as_str()) == Some("/config/set") {
+                            if let Some(ref remote_config) = remote_config {
+                                receive_config(remote_config, message);
+                            }
+                        } else {
+                            handle_command(&base_topic, topic, message.data());
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("MQTT: ERROR: {}", e),
+            })?
+        };
+
+        Ok(Self {
+            client,
+            base_topic,
+            qos: match config.qos {
+                0 => QoS::AtMostOnce,
+                _ => QoS::AtLeastOnce,
+            },
+            retain: config.retain,
+            connected,
+            announce,
+            discovery: config
+                .discovery
+                .then(|| Discovery::new(&config.discovery_prefix, name, &status_topic)),
+            remote_config: subscribe_remote_config,
+        })
+    }
+
+    /// Publish the readings. Fail if the client is not connected or a message couldn't be queued.
+    pub fn publish(&mut self, readings: &[Reading]) -> anyhow::Result<()> {
+        if !self.connected.load(Ordering::Relaxed) {
+            bail!("Not connected to the broker");
+        }
+        if self.announce.swap(false, Ordering::Relaxed) {
+            let topic = format!("{}/status", self.base_topic);
+            if let Err(e) = self.client.publish(&topic, QoS::AtLeastOnce, true, ONLINE) {
+                // Try again with the next readings
+                self.announce.store(true, Ordering::Relaxed);
+                bail!("Could not publish status: {}", e);
+            }
+            if calibration::is_enabled() {
+                let topic = format!("{}/calibration/+/set", self.base_topic);
+                if let Err(e) = self.client.subscribe(&topic, QoS::AtLeastOnce) {
+                    self.announce.store(true, Ordering::Relaxed);
+                    bail!("Could not subscribe to {}: {}", topic, e);
+                }
+            }
+            if self.remote_config {
+                let topic = format!("{}/config/set", self.base_topic);
+                if let Err(e) = self.client.subscribe(&topic, QoS::AtLeastOnce) {
+                    self.announce.store(true, Ordering::Relaxed);
+                    bail!("Could not subscribe to {}: {}", topic, e);
+                }
+            }
+            #[cfg(feature = "faults")]
+            {
+                let topic = format!("{}/fault/set", self.base_topic);
+                if let Err(e) = self.client.subscribe(&topic, QoS::AtLeastOnce) {
+                    self.announce.store(true, Ordering::Relaxed);
+                    bail!("Could not subscribe to {}: {}", topic, e);
+                }
+            }
+            if let Some(ref mut discovery) = self.discovery {
+                discovery.reset();
+            }
+        }
+        for reading in readings {
+            let topic = self.topic(reading);
+            if let (Some(metric), Some(discovery)) = (reading.metric, self.discovery.as_mut()) {
+                if let Some((config_topic, config)) = discovery.announce(metric, &topic) {
+                    let result = self.client.publish(
+                        &config_topic,
+                        QoS::AtLeastOnce,
+                        true,
+                        config.as_bytes(),
+                    );
+                    if let Err(e) = result {
+                        // Announce all metrics again with the next readings
+                        discovery.reset();
+                        bail!("Could not publish discovery config: {}", e);
+                    }
+                }
+            }
+            let payload = match reading.value {
+                Value::Float(value, decimals) => format!("{:.*}", decimals, value),
+                Value::Unsigned(value) => format!("{}", value),
+                Value::Bool(value) => format!("{}", value),
+            };
+            self.client
+                .publish(&topic, self.qos, self.retain, payload.as_bytes())
+                .map_err(|e| anyhow!("Could not publish {}: {}", reading.measurement, e))?;
+        }
+        Ok(())
+    }
+
+    /// Publish `offline` to the status topic, before a restart. Unlike the last will, this is
+    /// published right away instead of when the broker notices the lost connection.
+    pub fn publish_offline(&mut self) -> anyhow::Result<()> {
+        if !self.connected.load(Ordering::Relaxed) {
+            bail!("Not connected to the broker");
+        }
+        let topic = format!("{}/status", self.base_topic);
+        self.client
+            .publish(&topic, QoS::AtLeastOnce, true, OFFLINE)
+            .map_err(|e| anyhow!("Could not publish status: {}", e))?;
+        Ok(())
+    }
+
+    /// Return the topic of a reading.
+    fn topic(&self, reading: &Reading) -> String {
+        let mut topic = format!("{}/{}", self.base_topic, reading.measurement);
+        if let Some(sensor) = reading.sensor {
+            let _ = write!(topic, "/{}", sensor);
+        }
+        if let Some(ref channel) = reading.channel {
+            let _ = write!(topic, "/{}", channel);
+        }
+        let _ = write!(topic, "/{}", reading.field);
+        topic
+    }
+}
+
+/// Send a partial config to the remote config thread. Chunked messages are too large.
+fn receive_config(remote_config: &Sender<String>, message: &impl Message) {
+    if !matches!(message.details(), Details::Complete) {
+        eprintln!(
+            "MQTT: ERROR: Config is too large (max {} bytes)",
+            remote::MAX_SIZE
+        );
+        return;
+    }
+    let document = String::from_utf8_lossy(message.data()).into_owned();
+    if let Err(e) = remote_config.send(document) {
+        eprintln!("MQTT: ERROR: Could not forward config: {}", e);
+    }
+}
+
+/// Handle a message to a command topic: `<base_topic>/calibration/<sensor>/set` records a
+/// calibration of the sensor, `<base_topic>/fault/set` injects a fault.
+fn handle_command(base_topic: &str, topic: &str, payload: &[u8]) {
+    #[cfg(feature = "faults")]
+    if topic.strip_prefix(base_topic) == Some("/fault/set") {
+        let payload = String::from_utf8_lossy(payload);
+        let args: Vec<&str> = payload.split_whitespace().collect();
+        match faults::command(&args) {
+            Ok(status) => println!("MQTT: {}", status),
+            Err(e) => eprintln!("MQTT: ERROR: Could not inject fault: {:#}", e),
+        }
+        return;
+    }
+    let sensor = match topic
+        .strip_prefix(base_topic)
+        .and_then(|topic| topic.strip_prefix("/calibration/"))
+        .and_then(|topic| topic.strip_suffix("/set"))
+    {
+        Some(sensor) => sensor,
+        None => return,
+    };
+    let payload = String::from_utf8_lossy(payload);
+    let day = match payload.trim() {
+        "" => None,
+        date => match calibration::parse_date(date) {
+            Some(day) => Some(day),
+            None => {
+                eprintln!("MQTT: ERROR: Invalid calibration date {:?}", date);
+                return;
+            }
+        },
+    };
+    if let Err(e) = calibration::record(sensor, day) {
+        eprintln!("MQTT: ERROR: Could not record calibration: {:#}", e);
+    }
+}
@@ -0,0 +1,120 @@
+//! Selection of the WiFi network, for devices that are moved between places (e.g. between home
+//! and the office).
+//!
+//! The networks are `wifi.ssid`, followed by `wifi.networks` in the order of priority. With more
+//! than one network, the networks in range are scanned before connecting, and tried in order,
+//! starting with the network that worked last. Its SSID is stored in NVS, so that a device that
+//! stays at the office doesn't try the network at home first after every restart. Networks that
+//! are not in range (e.g. with a hidden SSID) are only tried if none of the networks is in range,
+//! or if the scan failed.
+
+use embedded_svc::wifi::Wifi;
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs},
+    wifi::EspWifi,
+};
+use esp_idf_sys::EspError;
+
+use crate::{config, roaming};
+
+/// NVS namespace and key of the SSID of the network that worked last
+const NAMESPACE: &str = "wifi";
+const KEY: &str = "last_ssid";
+
+/// A WiFi network to connect to
+pub struct Network<'a> {
+    pub ssid: &'a str,
+    pub password: Option<&'a str>,
+    /// Pinned BSSID of the access point, only for `wifi.ssid`
+    pub bssid: Option<[u8; 6]>,
+}
+
+/// Return the configured networks in the order of priority.
+fn configured(config: &config::Wifi) -> Vec<Network<'_>> {
+    let mut networks = Vec::new();
+    if let Some(ref ssid) = config.ssid {
+        networks.push(Network {
+            ssid,
+            password: config.password.as_deref(),
+            // Validated when loading the config
+            bssid: config.bssid.as_deref().and_then(roaming::parse_bssid),
+        });
+    }
+    networks.extend(config.networks.iter().map(|network| Network {
+        ssid: &network.ssid,
+        password: network.password.as_deref(),
+        bssid: None,
+    }));
+    networks
+}
+
+/// Return the networks in the order in which they're tried. With more than one network, the
+/// networks in range are scanned, WiFi must be started.
+pub fn ordered<'a>(
+    wifi: &mut EspWifi<'static>,
+    config: &'a config::Wifi,
+    partition: EspDefaultNvsPartition,
+) -> Vec<Network<'a>> {
+    let networks = configured(config);
+    if networks.len() < 2 {
+        return networks;
+    }
+    let mut networks = match wifi.scan() {
+        Ok(aps) => {
+            let in_range: Vec<Network> = networks
+                .into_iter()
+                .filter(|network| aps.iter().any(|ap| ap.ssid.as_str() == network.ssid))
+                .collect();
+            if in_range.is_empty() {
+                // Not a single network was seen, try them all
+                configured(config)
+            } else {
+                println!(
+                    "WiFi networks in range: {}",
+                    in_range
+                        .iter()
+                        .map(|network| network.ssid)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                in_range
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: Could not scan for WiFi networks: {}", e);
+            networks
+        }
+    };
+    match last(partition) {
+        Ok(Some(last)) => {
+            if let Some(index) = networks.iter().position(|network| network.ssid == last) {
+                let network = networks.remove(index);
+                networks.insert(0, network);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => eprintln!("Error: Could not read the last WiFi network: {}", e),
+    }
+    networks
+}
+
+/// Store the network that worked, if it's not stored already.
+pub fn remember(partition: EspDefaultNvsPartition, ssid: &str) {
+    let result = match last(partition.clone()) {
+        Ok(Some(ref last)) if last == ssid => Ok(()),
+        _ => EspNvs::new(partition, NAMESPACE, true)
+            .and_then(|mut nvs| nvs.set_raw(KEY, ssid.as_bytes()).map(|_| ())),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: Could not store the last WiFi network: {}", e);
+    }
+}
+
+/// Return the SSID of the network that worked last.
+fn last(partition: EspDefaultNvsPartition) -> Result<Option<String>, EspError> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut buf = [0u8; 32];
+    Ok(nvs
+        .get_raw(KEY, &mut buf)?
+        .map(|ssid| String::from_utf8_lossy(ssid).into_owned()))
+}
@@ -0,0 +1,40 @@
+//! Push notifications through [ntfy](https://ntfy.sh), e.g. for rule alerts and status messages.
+//!
+//! A message is published with an HTTP PUT of the message text to `<server>/<topic>`, the title,
+//! the priority and the authentication are passed as headers.
+
+use embedded_svc::http::Method;
+
+use crate::{config, webhook};
+
+/// Default server
+const DEFAULT_SERVER: &str = "https://ntfy.sh";
+
+/// Publish a message. Without `priority`, the priority from the config is used.
+pub fn publish(
+    config: &config::Ntfy,
+    title: Option<&str>,
+    message: &str,
+    priority: Option<u8>,
+) -> anyhow::Result<()> {
+    let server = config.server.as_deref().unwrap_or(DEFAULT_SERVER);
+    let url = format!("{}/{}", server.trim_end_matches('/'), config.topic);
+
+    let priority_header = priority.or(config.priority).map(|p| p.to_string());
+    let authorization_header = config
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token));
+    let mut headers = vec![("content-type", "text/plain; charset=utf-8")];
+    if let Some(title) = title {
+        headers.push(("title", title));
+    }
+    if let Some(ref priority) = priority_header {
+        headers.push(("priority", priority));
+    }
+    if let Some(ref authorization) = authorization_header {
+        headers.push(("authorization", authorization));
+    }
+
+    webhook::send(Method::Put, &url, &headers, message)
+}
@@ -0,0 +1,122 @@
+//! PWM outputs (e.g. for dimming grow lights or driving small fans), controlled through the local
+//! HTTP API.
+//!
+//! Every output has a level in percent. Level 0 turns the output off, other levels are mapped
+//! linearly to the duty cycle range of the output (e.g. for fans that need a minimal duty cycle to
+//! spin). All outputs are off after a restart.
+//!
+//! Every output uses its own LEDC timer, so the frequencies are independent. LEDC timer 0 and
+//! channel 0 are used by the ventilation controller, which leaves [`MAX_OUTPUTS`] outputs.
+
+use anyhow::{anyhow, bail};
+use esp_idf_hal::{
+    gpio::AnyOutputPin,
+    ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver},
+    peripheral::Peripheral,
+    units::FromValueType,
+};
+use esp_idf_sys::EspError;
+
+use crate::config;
+
+/// Maximum number of outputs
+pub const MAX_OUTPUTS: usize = 3;
+
+/// PWM frequency in Hz, if not configured
+const DEFAULT_FREQUENCY: u32 = 1000;
+
+pub struct PwmOutput<'d> {
+    name: String,
+    pwm: LedcDriver<'d>,
+    /// Duty cycle range in percent
+    min_duty: f32,
+    max_duty: f32,
+    /// Level in percent
+    level: f32,
+}
+
+impl<'d> PwmOutput<'d> {
+    /// Create a new output. The output is off.
+    pub fn new<T: LedcTimer, C: LedcChannel>(
+        timer: impl Peripheral<P = T> + 'd,
+        channel: impl Peripheral<P = C> + 'd,
+        pin: AnyOutputPin,
+        config: &config::Output,
+    ) -> Result<Self, EspError> {
+        let timer = LedcTimerDriver::new(
+            timer,
+            &TimerConfig::new().frequency(config.frequency.unwrap_or(DEFAULT_FREQUENCY).Hz()),
+        )?;
+        let mut pwm = LedcDriver::new(channel, timer, pin)?;
+        pwm.set_duty(0)?;
+        Ok(Self {
+            name: config.name.clone(),
+            pwm,
+            min_duty: config.min_duty.unwrap_or(0.0),
+            max_duty: config.max_duty.unwrap_or(100.0),
+            level: 0.0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the level in percent.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    /// Set the level in percent.
+    pub fn set_level(&mut self, level: f32) -> Result<(), EspError> {
+        let duty = if level > 0.0 {
+            self.min_duty + (self.max_duty - self.min_duty) * level / 100.0
+        } else {
+            0.0
+        };
+        let max_duty = self.pwm.get_max_duty() as f32;
+        self.pwm
+            .set_duty((max_duty * duty / 100.0).round() as u32)?;
+        self.level = level;
+        Ok(())
+    }
+}
+
+/// All configured outputs
+#[derive(Default)]
+pub struct Outputs<'d> {
+    outputs: Vec<PwmOutput<'d>>,
+}
+
+impl<'d> Outputs<'d> {
+    pub fn push(&mut self, output: PwmOutput<'d>) {
+        self.outputs.push(output);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PwmOutput<'d>> {
+        self.outputs.iter()
+    }
+
+    /// Set the level (0-100 %) of the output with the specified name.
+    pub fn set_level(&mut self, name: &str, level: f32) -> anyhow::Result<()> {
+        if !(0.0..=100.0).contains(&level) {
+            bail!("Level must be between 0 and 100, not {}", level);
+        }
+        let output = self
+            .outputs
+            .iter_mut()
+            .find(|output| output.name == name)
+            .ok_or_else(|| anyhow!("Unknown output: {:?}", name))?;
+        output.set_level(level)?;
+        Ok(())
+    }
+
+    /// Turn all outputs off, e.g. before a restart.
+    pub fn turn_off(&mut self) {
+        for output in self.outputs.iter_mut() {
+            if let Err(e) = output.set_level(0.0) {
+                eprintln!("Outputs: Could not turn {} off: {}", output.name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,553 @@
+//! Captive portal for provisioning a device without the serial console.
+//!
+//! If no SSID is configured, or if the access point can't be reached at boot, the device opens an
+//! access point of its own, `Sensilo-<xxxx>` (the end of its MAC address), protected with
+//! `wifi.portal_password` if set. If an SSID is configured, the portal is only opened with
+//! `wifi.portal_password`, since anyone in range could reconfigure the device otherwise. Every DNS
+//! query is answered with the address of the device, so that phones and laptops joining the access
+//! point show the web form right away (captive portal detection), and every path serves the form.
+//!
+//! The form sets the WiFi credentials, the name and the InfluxDB server. Fields that are left
+//! empty keep their current value, except for the WiFi password, which is only kept for the same
+//! SSID (an empty password for another SSID is an open network), and the InfluxDB API token and
+//! password, which are only kept for the same host. The changed config is validated and stored in
+//! NVS (see [`config::save`]), then the device restarts to apply it.
+//!
+//! If an SSID is configured and nothing is stored within [`TIMEOUT`], the device restarts to try
+//! the access point again, so that it recovers on its own if the access point was only down (e.g.
+//! after a power outage, if the access point takes longer to boot than the device).
+
+use std::{
+    cmp::Reverse,
+    io,
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerResult, Request},
+        Method,
+    },
+    io::Write,
+    wifi::{AccessPointConfiguration, AuthMethod, Configuration as WifiConfiguration, Wifi},
+};
+use esp_idf_svc::{
+    http::server::{Configuration as HttpServerConfiguration, EspHttpServer},
+    nvs::EspDefaultNvsPartition,
+    wifi::EspWifi,
+};
+
+use crate::{
+    api,
+    config::{self, Config, InfluxDb, InfluxDbApi},
+    shutdown, SENSILO_NAME,
+};
+
+/// Time after which the device restarts to try the configured access point again
+pub const TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Time for the response to be sent before the device restarts
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Stack size of the DNS thread
+const DNS_STACK_SIZE: usize = 4096;
+
+/// Maximum number of networks that are suggested in the form
+const MAX_NETWORKS: usize = 10;
+
+/// State of the provisioning
+enum State {
+    /// Waiting for the form to be submitted, since the portal was opened
+    Waiting(Instant),
+    /// The config was stored, the device restarts once the response was sent
+    Stored(Instant),
+}
+
+/// Open the portal and wait until a config was stored, then restart the device. Without an SSID,
+/// the portal stays open until then, otherwise it times out after [`TIMEOUT`].
+pub fn run(wifi: &mut EspWifi<'static>, nvs: EspDefaultNvsPartition, config: &Config) -> ! {
+    let (sender, receiver) = mpsc::channel();
+    // The server is stopped when it's dropped
+    let _server = match start(wifi, nvs, config, sender) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Portal: ERROR: Could not open the portal: {:#}", e);
+            thread::sleep(TIMEOUT);
+            shutdown::restart();
+        }
+    };
+    let mut state = State::Waiting(Instant::now());
+    loop {
+        state = match state {
+            State::Waiting(since) => match receiver.recv_timeout(RESTART_DELAY) {
+                Ok(()) => State::Stored(Instant::now()),
+                Err(RecvTimeoutError::Timeout)
+                    if config.wifi.ssid.is_none() || since.elapsed() < TIMEOUT =>
+                {
+                    State::Waiting(since)
+                }
+                Err(_) => {
+                    println!("Portal: Timed out, trying the access point again");
+                    shutdown::restart();
+                }
+            },
+            State::Stored(at) if at.elapsed() >= RESTART_DELAY => {
+                println!("Portal: Config stored");
+                shutdown::restart();
+            }
+            State::Stored(at) => {
+                thread::sleep(RESTART_DELAY - at.elapsed());
+                State::Stored(at)
+            }
+        };
+    }
+}
+
+/// Start the access point, the DNS server and the HTTP server.
+fn start(
+    wifi: &mut EspWifi<'static>,
+    nvs: EspDefaultNvsPartition,
+    config: &Config,
+    sender: Sender<()>,
+) -> anyhow::Result<EspHttpServer> {
+    // Anyone in range could change the config of a provisioned device through an open access point
+    if config.wifi.ssid.is_some() && config.wifi.portal_password.is_none() {
+        bail!("Not opened without wifi.portal_password, since an SSID is configured");
+    }
+
+    // Scanning needs the station mode, so the networks are scanned before the access point is
+    // started
+    let networks = Arc::new(scan(wifi));
+
+    let mac = wifi.ap_netif().get_mac()?;
+    let ssid = format!("Sensilo-{:02x}{:02x}", mac[4], mac[5]);
+    wifi.stop().context("Could not stop WiFi")?;
+    wifi.set_configuration(&WifiConfiguration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().into(),
+        auth_method: match config.wifi.portal_password {
+            Some(_) => AuthMethod::WPA2Personal,
+            None => AuthMethod::None,
+        },
+        // Validated when loading the config
+        password: config.wifi.portal_password.as_deref().unwrap_or("").into(),
+        ..Default::default()
+    }))
+    .context("Could not configure the access point")?;
+    wifi.start().context("Could not start the access point")?;
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    println!("Portal: Join {} and open http://{}/", ssid, ip);
+
+    spawn_dns(ip.octets()).context("Could not start the DNS server")?;
+
+    let mut server = EspHttpServer::new(&HttpServerConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let handler_config = config.clone();
+    let handler_networks = networks.clone();
+    server.fn_handler("/*", Method::Get, move |request| {
+        respond(
+            request,
+            200,
+            &form(&handler_config, &handler_networks, None),
+        )
+    })?;
+
+    let handler_config = config.clone();
+    server.fn_handler("/*", Method::Post, move |mut request| {
+        let body = match api::read_body(&mut request)? {
+            Some(body) => body,
+            None => return respond(request, 413, &page("<p>Request body too large</p>")),
+        };
+        let fields = form_fields(&String::from_utf8_lossy(&body));
+        match config::save(nvs.clone(), &apply(&handler_config, &fields)) {
+            Ok(()) => {
+                respond(
+                    request,
+                    200,
+                    &page("<p>Stored, the device restarts and connects to the network.</p>"),
+                )?;
+                let _ = sender.send(());
+                Ok(())
+            }
+            Err(e) => respond(
+                request,
+                400,
+                &form(&handler_config, &networks, Some(&format!("{:#}", e))),
+            ),
+        }
+    })?;
+
+    Ok(server)
+}
+
+/// Return the SSIDs of the networks in range, the strongest first.
+fn scan(wifi: &mut EspWifi<'static>) -> Vec<String> {
+    let mut access_points = match wifi.scan() {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            eprintln!("Portal: ERROR: Could not scan: {}", e);
+            return Vec::new();
+        }
+    };
+    access_points.sort_by_key(|ap| Reverse(ap.signal_strength));
+    let mut networks: Vec<String> = Vec::new();
+    for ap in access_points {
+        let ssid = ap.ssid.as_str();
+        if !ssid.is_empty() && !networks.iter().any(|network| network == ssid) {
+            networks.push(ssid.into());
+        }
+    }
+    networks.truncate(MAX_NETWORKS);
+    networks
+}
+
+/// Return the config with the settings of the submitted form.
+fn apply(config: &Config, fields: &[(String, String)]) -> Config {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let mut config = config.clone();
+    if let Some(ssid) = field("ssid") {
+        config.wifi.password = match field("password") {
+            Some(password) => Some(password.into()),
+            None if config.wifi.ssid.as_deref() == Some(ssid) => config.wifi.password.take(),
+            None => None,
+        };
+        config.wifi.ssid = Some(ssid.into());
+    }
+    if let Some(name) = field("name") {
+        config.name = Some(name.into());
+    }
+    if let Some(host) = field("influxdb_host") {
+        let influxdb = config.sinks.influxdb.get_or_insert_with(|| InfluxDb {
+            host: host.into(),
+            api: InfluxDbApi::V2,
+            org: String::new(),
+            bucket: String::new(),
+            api_token: None,
+            username: None,
+            password: None,
+            interval_secs: None,
+        });
+        // The credentials are only kept for the same server, so that they aren't sent to another one
+        if influxdb.host != host {
+            influxdb.api_token = None;
+            influxdb.password = None;
+        }
+        influxdb.host = host.into();
+        if let Some(org) = field("influxdb_org") {
+            influxdb.org = org.into();
+        }
+        if let Some(bucket) = field("influxdb_bucket") {
+            influxdb.bucket = bucket.into();
+        }
+        if let Some(api_token) = field("influxdb_api_token") {
+            influxdb.api_token = Some(api_token.into());
+        }
+    }
+    config
+}
+
+/// Return the form, prefilled with the current settings (without secrets).
+fn form(config: &Config, networks: &[String], error: Option<&str>) -> String {
+    let influxdb = config.sinks.influxdb.as_ref();
+    let input =
+        |label: &str, name: &str, kind: &str, value: Option<&str>, hint: &str| {
+            format!(
+            "<label>{}<input name=\"{}\" type=\"{}\" value=\"{}\" placeholder=\"{}\"{}></label>\n",
+            label,
+            name,
+            kind,
+            escape(value.unwrap_or("")),
+            hint,
+            if name == "ssid" { " list=\"networks\"" } else { "" },
+        )
+        };
+    let mut body = String::new();
+    if let Some(error) = error {
+        body.push_str(&format!("<p class=\"error\">{}</p>\n", escape(error)));
+    }
+    body.push_str("<form method=\"post\" action=\"/\">\n<h2>WiFi</h2>\n");
+    body.push_str(&input(
+        "SSID",
+        "ssid",
+        "text",
+        config.wifi.ssid.as_deref(),
+        "",
+    ));
+    body.push_str(&input(
+        "Password",
+        "password",
+        "password",
+        None,
+        "unchanged, or empty for an open network",
+    ));
+    body.push_str("<datalist id=\"networks\">");
+    for network in networks {
+        body.push_str(&format!("<option value=\"{}\">", escape(network)));
+    }
+    body.push_str("</datalist>\n<h2>Device</h2>\n");
+    body.push_str(&input(
+        "Name",
+        "name",
+        "text",
+        config.name.as_deref(),
+        SENSILO_NAME,
+    ));
+    body.push_str("<h2>InfluxDB</h2>\n");
+    body.push_str(&input(
+        "Host",
+        "influxdb_host",
+        "url",
+        influxdb.map(|influxdb| influxdb.host.as_str()),
+        "https://influxdb.example.com",
+    ));
+    body.push_str(&input(
+        "Organization",
+        "influxdb_org",
+        "text",
+        influxdb.map(|influxdb| influxdb.org.as_str()),
+        "",
+    ));
+    body.push_str(&input(
+        "Bucket",
+        "influxdb_bucket",
+        "text",
+        influxdb.map(|influxdb| influxdb.bucket.as_str()),
+        "",
+    ));
+    body.push_str(&input(
+        "API token",
+        "influxdb_api_token",
+        "password",
+        None,
+        "unchanged",
+    ));
+    body.push_str("<button type=\"submit\">Save and restart</button>\n</form>");
+    page(&body)
+}
+
+/// Return an HTML page with the body.
+fn page(body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>Sensilo</title>\n<style>body {{ font-family: sans-serif; max-width: 30em; \
+         margin: auto; padding: 1em; }} label, input, button {{ display: block; width: 100%; \
+         margin-bottom: 0.5em; }} .error {{ color: #c00; }}</style>\n</head>\n<body>\n\
+         <h1>Sensilo</h1>\n{}\n</body>\n</html>\n",
+        body
+    )
+}
+
+/// Send an HTML response.
+fn respond<C: Connection>(request: Request<C>, status: u16, html: &str) -> HandlerResult {
+    let mut response = request.into_response(
+        status,
+        None,
+        &[("content-type", "text/html; charset=utf-8")],
+    )?;
+    response.write_all(html.as_bytes())?;
+    Ok(())
+}
+
+/// Escape text for HTML, including attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parse a form body (`application/x-www-form-urlencoded`).
+fn form_fields(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter_map(|field| field.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+/// Decode a form value: `+` is a space, `%XX` a percent-encoded byte.
+fn decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [chars.next(), chars.next()];
+                let decoded = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                // Invalid escapes are kept as they are
+                match decoded {
+                    Some(decoded) => bytes.push(decoded),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Start a thread that answers all DNS queries with the IPv4 address of the device.
+fn spawn_dns(address: [u8; 4]) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 53))?;
+    thread::Builder::new()
+        .name("dns".into())
+        .stack_size(DNS_STACK_SIZE)
+        .spawn(move || {
+            let mut buf = [0; 512];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("Portal: ERROR: Could not receive DNS query: {}", e);
+                        thread::sleep(RESTART_DELAY);
+                        continue;
+                    }
+                };
+                if let Some(response) = dns_response(&buf[..len], address) {
+                    let _ = socket.send_to(&response, peer);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Return the response to a DNS query: An A record with `address` for A queries, no records for
+/// the other types. Returns `None` for invalid queries.
+fn dns_response(query: &[u8], address: [u8; 4]) -> Option<Vec<u8>> {
+    // Header, then the question: The name as length-prefixed labels, the type and the class
+    if query.len() < 12 || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let mut end = 12;
+    loop {
+        let len = usize::from(*query.get(end)?);
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        // Compressed names don't occur in the question of a query
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let question = query.get(12..end + 4)?;
+    let is_a = question[question.len() - 4..question.len() - 2] == [0, 1];
+
+    // ID, flags (response, authoritative, recursion desired as in the query, recursion
+    // available), one question and the answer
+    let mut response = query[..2].to_vec();
+    response.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x80]);
+    response.extend_from_slice(&[0, 1, 0, u8::from(is_a), 0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    if is_a {
+        // Pointer to the name of the question, type A, class IN, TTL 60 s, 4 bytes of data
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&address);
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: [u8; 4] = [192, 168, 71, 1];
+
+    /// Query for `example.com` with the ID 0x1234, recursion desired, and the record type
+    fn query(record_type: u8) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&[0, record_type, 0, 1]);
+        query
+    }
+
+    #[test]
+    fn a_query_is_answered_with_the_address() {
+        let query = query(1);
+        let response = dns_response(&query, ADDRESS).unwrap();
+        assert_eq!(
+            response[..12],
+            [0x12, 0x34, 0x85, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(response[12..query.len()], query[12..]);
+        assert!(response.ends_with(&[0, 4, 192, 168, 71, 1]));
+    }
+
+    #[test]
+    fn other_query_has_no_answer() {
+        // AAAA
+        let query = query(28);
+        let response = dns_response(&query, ADDRESS).unwrap();
+        assert_eq!(response[6..8], [0, 0]);
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn truncated_query_is_dropped() {
+        let query = query(1);
+        for length in 0..query.len() {
+            assert_eq!(dns_response(&query[..length], ADDRESS), None);
+        }
+    }
+
+    #[test]
+    fn malformed_query_is_dropped() {
+        // Response instead of a query
+        let mut response = query(1);
+        response[2] |= 0x80;
+        assert_eq!(dns_response(&response, ADDRESS), None);
+        // Compressed name
+        let mut compressed = query(1);
+        compressed[12] = 0xc0;
+        assert_eq!(dns_response(&compressed, ADDRESS), None);
+        // Label past the end of the query
+        let mut long_label = query(1);
+        long_label[12] = 0x3f;
+        assert_eq!(dns_response(&long_label, ADDRESS), None);
+    }
+
+    #[test]
+    fn form_fields_are_decoded() {
+        assert_eq!(
+            form_fields("ssid=My+WiFi&password=a%26b%3D&invalid"),
+            [
+                ("ssid".to_string(), "My WiFi".to_string()),
+                ("password".to_string(), "a&b=".to_string()),
+            ]
+        );
+        // Invalid escapes are kept as they are
+        assert_eq!(decode("100%zz%4"), "100%zz%4");
+    }
+}
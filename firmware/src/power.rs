@@ -0,0 +1,220 @@
+//! Low-voltage safe mode, so that devices on weak supplies (e.g. a small solar panel) don't
+//! boot-loop.
+//!
+//! A supply that can't deliver the current peaks of the radio (e.g. while transmitting during a
+//! TLS handshake) resets the device with a brown-out, and the next boot does the same again.
+//! After `safe_mode.brownouts` brown-out resets in a row, the device starts in the safe mode
+//! instead. With the `supply` feature, the supply voltage is measured through a voltage divider on
+//! GPIO0 as well: After `safe_mode.low_readings` readings in a row below `safe_mode.min_voltage`,
+//! the device restarts into the safe mode, before it browns out.
+//!
+//! In the safe mode, the `safe_mode.profile` is active (e.g. with a long measurement interval and
+//! fewer sensors), the WiFi modem sleeps between the beacons of the access point, the local
+//! network services and the UDP and KNX sinks are not started, and nothing is sent over TLS
+//! except for the submissions (no ntfy notifications, no HTTPS webhooks). The measurements are
+//! still submitted, with `safe_mode=true` in the diagnostics line, and entering and leaving the
+//! safe mode is recorded as a `power` event.
+//!
+//! The safe mode is left with a restart: After `safe_mode.low_readings` readings in a row at or
+//! above `safe_mode.resume_voltage`, or after `safe_mode.retry_hours` if it was entered because of
+//! brown-outs. The number of brown-outs in a row and whether the voltage was low are stored in
+//! NVS, so that they survive the restarts.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "supply")]
+use esp_idf_hal::{
+    adc::{self, AdcChannelDriver, AdcDriver, Atten11dB, ADC1},
+    gpio::Gpio0,
+};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+
+use crate::{config, eventlog};
+
+/// NVS namespace and key of the state: Number of brown-outs in a row and whether the voltage was
+/// low
+const NAMESPACE: &str = "power";
+const KEY: &str = "state";
+
+/// Why the device is in the safe mode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// Number of brown-out resets in a row
+    Brownouts(u8),
+    /// The supply voltage was below `safe_mode.min_voltage`
+    LowVoltage,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Brownouts(count) => write!(f, "{} brown-outs in a row", count),
+            Reason::LowVoltage => write!(f, "low supply voltage"),
+        }
+    }
+}
+
+pub struct SafeMode {
+    nvs: Option<EspNvs<NvsDefault>>,
+    config: config::SafeMode,
+    brownouts: u8,
+    reason: Option<Reason>,
+    since: Instant,
+    /// Number of supply voltage readings in a row beyond the threshold: Below `min_voltage`
+    /// outside of the safe mode, at or above `resume_voltage` in the safe mode
+    readings: u8,
+}
+
+impl SafeMode {
+    /// Count the brown-out resets and determine whether the device starts in the safe mode.
+    pub fn start(partition: EspDefaultNvsPartition, config: &config::SafeMode) -> Self {
+        let nvs = match EspNvs::new(partition, NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                eprintln!("Error: Could not open power state: {}", e);
+                None
+            }
+        };
+        let mut buf = [0; 2];
+        let (brownouts, low_voltage) = match nvs.as_ref().map(|nvs| nvs.get_raw(KEY, &mut buf)) {
+            Some(Ok(Some(stored))) if stored.len() == 2 => (stored[0], stored[1] != 0),
+            _ => (0, false),
+        };
+        let brownout = unsafe { esp_idf_sys::esp_reset_reason() }
+            == esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT;
+        let brownouts = if brownout {
+            brownouts.saturating_add(1)
+        } else {
+            0
+        };
+
+        let reason = if !config.enabled {
+            None
+        } else if low_voltage {
+            Some(Reason::LowVoltage)
+        } else if brownouts >= config.brownouts {
+            Some(Reason::Brownouts(brownouts))
+        } else {
+            None
+        };
+        let mut safe_mode = Self {
+            nvs,
+            config: config.clone(),
+            brownouts,
+            reason,
+            since: Instant::now(),
+            readings: 0,
+        };
+        safe_mode.save(low_voltage && config.enabled);
+        if let Some(reason) = reason {
+            eventlog::record(eventlog::Kind::Power, &format!("Safe mode: {}", reason));
+        }
+        safe_mode
+    }
+
+    /// Return why the device is in the safe mode, `None` if it isn't.
+    pub fn reason(&self) -> Option<Reason> {
+        self.reason
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.reason.is_some()
+    }
+
+    /// Name of the profile that is active in the safe mode, if any
+    pub fn profile(&self) -> Option<&str> {
+        self.config.profile.as_deref()
+    }
+
+    /// Update the safe mode with a supply voltage reading in V. Return `true` if the device must
+    /// be restarted to enter or leave the safe mode.
+    #[cfg_attr(not(feature = "supply"), allow(dead_code))]
+    pub fn update(&mut self, voltage: f32) -> bool {
+        let min_voltage = match self.config.min_voltage {
+            Some(min_voltage) if self.config.enabled => min_voltage,
+            _ => return false,
+        };
+        let resume_voltage = self.config.resume_voltage.unwrap_or(min_voltage);
+        let beyond = if self.is_active() {
+            voltage >= resume_voltage
+        } else {
+            voltage < min_voltage
+        };
+        self.readings = if beyond { self.readings + 1 } else { 0 };
+        if self.readings < self.config.low_readings {
+            return false;
+        }
+
+        self.readings = 0;
+        if self.is_active() {
+            eventlog::record(
+                eventlog::Kind::Power,
+                &format!("Leaving safe mode: Supply voltage {:.2} V", voltage),
+            );
+            // Start over with the brown-outs as well
+            self.brownouts = 0;
+            self.save(false);
+        } else {
+            eventlog::record(
+                eventlog::Kind::Power,
+                &format!("Entering safe mode: Supply voltage {:.2} V", voltage),
+            );
+            self.save(true);
+        }
+        true
+    }
+
+    /// Return `true` if the device should be restarted normally, to find out whether the supply
+    /// recovered from the brown-outs.
+    pub fn retry_due(&self) -> bool {
+        matches!(self.reason, Some(Reason::Brownouts(_)))
+            && self.config.retry_hours > 0
+            && self.since.elapsed() >= Duration::from_secs(self.config.retry_hours as u64 * 3600)
+    }
+
+    /// Store the state in NVS.
+    fn save(&mut self, low_voltage: bool) {
+        if let Some(ref mut nvs) = self.nvs {
+            if let Err(e) = nvs.set_raw(KEY, &[self.brownouts, u8::from(low_voltage)]) {
+                eprintln!("Error: Could not save power state: {}", e);
+            }
+        }
+    }
+}
+
+/// Let the WiFi modem sleep between the beacons of the access point (after WiFi was started).
+pub fn set_modem_sleep() -> Result<(), EspError> {
+    EspError::convert(unsafe {
+        esp_idf_sys::esp_wifi_set_ps(esp_idf_sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM)
+    })
+}
+
+/// Measurement of the supply voltage through a voltage divider on GPIO0
+#[cfg(feature = "supply")]
+pub struct SupplyMonitor<'d> {
+    adc: AdcDriver<'d, ADC1>,
+    pin: AdcChannelDriver<'d, Gpio0, Atten11dB<ADC1>>,
+    divider: f32,
+}
+
+#[cfg(feature = "supply")]
+impl<'d> SupplyMonitor<'d> {
+    pub fn new(adc: ADC1, pin: Gpio0, config: &config::Supply) -> Result<Self, EspError> {
+        Ok(Self {
+            // With calibration, the readings are in mV
+            adc: AdcDriver::new(adc, &adc::config::Config::new().calibration(true))?,
+            pin: AdcChannelDriver::new(pin)?,
+            divider: config.divider,
+        })
+    }
+
+    /// Return the supply voltage in V.
+    pub fn measure(&mut self) -> Result<f32, EspError> {
+        let millivolts = self.adc.read(&mut self.pin)?;
+        Ok(millivolts as f32 / 1000.0 * self.divider)
+    }
+}
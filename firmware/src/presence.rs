@@ -0,0 +1,92 @@
+//! Presence detection based on a sensor's (possibly noisy) detection signal.
+
+use std::collections::BTreeMap;
+#[cfg(any(feature = "presence", feature = "ld2410"))]
+use std::time::{Duration, Instant};
+
+/// Turns a noisy detection signal into a debounced presence state and tracks the share of time
+/// that somebody was present.
+#[cfg(any(feature = "presence", feature = "ld2410"))]
+pub struct PresenceDetector {
+    /// How long nobody must be detected until presence ends
+    hold_time: Duration,
+    /// Current (debounced) presence state
+    present: bool,
+    /// Last time somebody was detected
+    last_seen: Option<Instant>,
+    /// Number of samples in the current interval
+    samples: u32,
+    /// Number of samples in the current interval during which somebody was present
+    present_samples: u32,
+}
+
+#[cfg(any(feature = "presence", feature = "ld2410"))]
+impl PresenceDetector {
+    pub fn new(hold_time: Duration) -> Self {
+        Self {
+            hold_time,
+            present: false,
+            last_seen: None,
+            samples: 0,
+            present_samples: 0,
+        }
+    }
+
+    /// Process a new sample. Return the new presence state if it changed.
+    pub fn update(&mut self, detected: bool) -> Option<bool> {
+        let now = Instant::now();
+        if detected {
+            self.last_seen = Some(now);
+        }
+        let present = matches!(
+            self.last_seen,
+            Some(last_seen) if now.duration_since(last_seen) <= self.hold_time
+        );
+
+        self.samples = self.samples.saturating_add(1);
+        if present {
+            self.present_samples = self.present_samples.saturating_add(1);
+        }
+
+        if present != self.present {
+            self.present = present;
+            Some(present)
+        } else {
+            None
+        }
+    }
+
+    /// Return the percentage of samples since the last call during which somebody was present,
+    /// and start a new interval. Return `None` if there were no samples.
+    pub fn take_occupancy(&mut self) -> Option<f32> {
+        let occupancy = if self.samples > 0 {
+            Some(self.present_samples as f32 / self.samples as f32 * 100.0)
+        } else {
+            None
+        };
+        self.samples = 0;
+        self.present_samples = 0;
+        occupancy
+    }
+}
+
+/// Combines the presence states of all presence sensors into the occupancy of the room.
+#[derive(Default)]
+pub struct Occupancy {
+    /// Latest presence state per sensor type
+    present: BTreeMap<&'static str, bool>,
+}
+
+impl Occupancy {
+    /// Update the presence state of a sensor. Return whether the occupancy changed.
+    pub fn update(&mut self, sensor: &'static str, present: bool) -> bool {
+        let occupied = self.is_occupied();
+        self.present.insert(sensor, present);
+        self.is_occupied() != occupied
+    }
+
+    /// Return whether somebody is present, according to any of the sensors.
+    pub fn is_occupied(&self) -> bool {
+        self.present.values().any(|present| *present)
+    }
+}
@@ -0,0 +1,142 @@
+//! Named measurement profiles, switchable at runtime.
+//!
+//! A profile (e.g. "battery-saver") bundles the measurement intervals, the set of sensors that
+//! are read and the power options, see [`Profile`]. Settings that are not set in the
+//! profile are taken from the base config. The profile that is active at boot is set with
+//! `profile` in the config, it can be switched at runtime through the serial console. The
+//! switch is not persisted, after a restart the profile from the config is active again.
+//!
+//! [`Profile`]: crate::config::Profile
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::bail;
+
+use crate::config::Config;
+
+/// Settings of the active profile, resolved against the base config
+pub struct Settings {
+    /// Interval between two measurement submissions, if no presence-dependent interval applies
+    base_measurement_interval: Duration,
+    /// Interval at which the presence sensors are polled
+    pub presence_poll_interval: Duration,
+    /// Interval between two measurement submissions while somebody is present
+    occupied_measurement_interval: Option<Duration>,
+    /// Interval between two measurement submissions while nobody is present
+    vacant_measurement_interval: Option<Duration>,
+    /// Whether the low power measurement modes of the sensors are used
+    #[cfg_attr(not(feature = "temp_humi"), allow(dead_code))]
+    pub low_power: bool,
+    /// Sensors that are read, `None` if all sensors are read
+    sensors: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// Return the interval between two measurement submissions, depending on whether somebody is
+    /// present (`None` if there's no presence sensor).
+    pub fn measurement_interval(&self, occupied: Option<bool>) -> Duration {
+        let interval = match occupied {
+            Some(true) => self.occupied_measurement_interval,
+            Some(false) => self.vacant_measurement_interval,
+            None => None,
+        };
+        interval.unwrap_or(self.base_measurement_interval)
+    }
+
+    /// Return the longest possible interval between two measurement submissions.
+    pub fn max_measurement_interval(&self) -> Duration {
+        [None, Some(true), Some(false)]
+            .into_iter()
+            .map(|occupied| self.measurement_interval(occupied))
+            .max()
+            .unwrap_or(self.base_measurement_interval)
+    }
+
+    /// Return whether the specified sensor is read. See [`SENSOR_NAMES`] for the names.
+    ///
+    /// [`SENSOR_NAMES`]: crate::config::SENSOR_NAMES
+    #[allow(dead_code)] // Unused in builds without sensors
+    pub fn reads(&self, sensor: &str) -> bool {
+        match self.sensors {
+            Some(ref sensors) => sensors.iter().any(|name| name == sensor),
+            None => true,
+        }
+    }
+}
+
+/// The active profile, shared between the main loop, the timer tasks and the console
+#[derive(Clone)]
+pub struct ActiveProfile {
+    config: Arc<Config>,
+    name: Arc<Mutex<Option<String>>>,
+}
+
+impl ActiveProfile {
+    /// Create a new instance. The profile set in the config is active.
+    pub fn new(config: Arc<Config>) -> Self {
+        let name = Arc::new(Mutex::new(config.profile.clone()));
+        Self { config, name }
+    }
+
+    /// Return the name of the active profile, `None` if the base config is active.
+    pub fn name(&self) -> Option<String> {
+        self.name
+            .lock()
+            .expect("Failed to lock profile mutex")
+            .clone()
+    }
+
+    /// Return the names of all profiles in the config.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.config.profiles.keys().map(String::as_str)
+    }
+
+    /// Switch to the specified profile, or to the base config if `name` is `None`.
+    pub fn set(&self, name: Option<&str>) -> anyhow::Result<()> {
+        if let Some(name) = name {
+            if !self.config.profiles.contains_key(name) {
+                bail!("Unknown profile: {:?}", name);
+            }
+        }
+        *self.name.lock().expect("Failed to lock profile mutex") = name.map(String::from);
+        Ok(())
+    }
+
+    /// Return the settings of the active profile.
+    pub fn settings(&self) -> Settings {
+        let intervals = &self.config.intervals;
+        let name = self.name.lock().expect("Failed to lock profile mutex");
+        let profile = name
+            .as_ref()
+            .and_then(|name| self.config.profiles.get(name))
+            .cloned()
+            .unwrap_or_default();
+        Settings {
+            base_measurement_interval: Duration::from_secs(
+                profile
+                    .measurement_secs
+                    .unwrap_or(intervals.measurement_secs)
+                    .into(),
+            ),
+            presence_poll_interval: Duration::from_millis(
+                profile
+                    .presence_poll_ms
+                    .unwrap_or(intervals.presence_poll_ms)
+                    .into(),
+            ),
+            occupied_measurement_interval: profile
+                .occupied_measurement_secs
+                .or(intervals.occupied_measurement_secs)
+                .map(|secs| Duration::from_secs(secs.into())),
+            vacant_measurement_interval: profile
+                .vacant_measurement_secs
+                .or(intervals.vacant_measurement_secs)
+                .map(|secs| Duration::from_secs(secs.into())),
+            low_power: profile.low_power,
+            sensors: profile.sensors,
+        }
+    }
+}
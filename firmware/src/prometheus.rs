@@ -0,0 +1,178 @@
+//! Prometheus metrics, served at `/metrics` of the HTTP API (with `api.metrics`).
+//!
+//! The readings of the last measurement cycle are exposed in the Prometheus text format, as
+//! gauges named `sensilo_<measurement>_<field>` with the sensor and channel tags as labels, e.g.
+//! `sensilo_co2_ppm{sensor="scd4x"} 612`. In addition:
+//!
+//! - `sensilo_info{name="<name>",version="<version>"}`: Always 1
+//! - `sensilo_uptime_seconds` and `sensilo_free_heap_bytes`
+//! - `sensilo_sensor_up{sensor="<sensor>"}`: 1 if the enabled sensor could be initialized, 0 if
+//!   not
+//! - `sensilo_sensor_errors{sensor="<sensor>"}`: Number of measurement cycles with failed reads
+//!   since the start
+//! - `sensilo_submission_errors`: Number of measurement cycles that could not be submitted
+//!
+//! The sensor names are the ones of the profiles (see [`crate::config::SENSOR_NAMES`]). All
+//! metrics are gauges, the error counts start at 0 after every restart.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    reading::{Reading, Value},
+    VERSION,
+};
+
+pub struct Exporter {
+    name: String,
+    /// Readings of the last measurement cycle
+    readings: Vec<Reading>,
+    /// Enabled sensors and whether they could be initialized
+    sensors: Vec<(&'static str, bool)>,
+    /// Number of measurement cycles with failed reads per sensor
+    sensor_errors: BTreeMap<&'static str, u32>,
+    submission_errors: u32,
+}
+
+impl Exporter {
+    pub fn new(name: &str, sensors: Vec<(&'static str, bool)>) -> Self {
+        Self {
+            name: name.into(),
+            readings: Vec::new(),
+            sensors,
+            sensor_errors: BTreeMap::new(),
+            submission_errors: 0,
+        }
+    }
+
+    /// Update the metrics with a measurement cycle: Its readings, the sensors whose reads failed
+    /// and whether it was submitted.
+    pub fn update(&mut self, readings: &[Reading], failed: &[&'static str], submitted: bool) {
+        self.readings = readings.to_vec();
+        for sensor in failed {
+            *self.sensor_errors.entry(sensor).or_insert(0) += 1;
+        }
+        if !submitted {
+            self.submission_errors += 1;
+        }
+    }
+
+    /// Return the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // Writing to a string cannot fail
+        gauge(&mut out, "sensilo_info", "Name and firmware version");
+        let _ = writeln!(
+            out,
+            "sensilo_info{{name=\"{}\",version=\"{}\"}} 1",
+            escape(&self.name),
+            VERSION
+        );
+        gauge(&mut out, "sensilo_uptime_seconds", "Time since the start");
+        let _ = writeln!(
+            out,
+            "sensilo_uptime_seconds {}",
+            unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000
+        );
+        gauge(&mut out, "sensilo_free_heap_bytes", "Free heap");
+        let _ = writeln!(out, "sensilo_free_heap_bytes {}", unsafe {
+            esp_idf_sys::esp_get_free_heap_size()
+        });
+
+        gauge(
+            &mut out,
+            "sensilo_sensor_up",
+            "Whether the enabled sensor could be initialized",
+        );
+        for (sensor, up) in self.sensors.iter() {
+            let _ = writeln!(
+                out,
+                "sensilo_sensor_up{{sensor=\"{}\"}} {}",
+                sensor,
+                u8::from(*up)
+            );
+        }
+        gauge(
+            &mut out,
+            "sensilo_sensor_errors",
+            "Number of measurement cycles with failed sensor reads since the start",
+        );
+        for (sensor, _) in self.sensors.iter() {
+            let errors = self.sensor_errors.get(sensor).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "sensilo_sensor_errors{{sensor=\"{}\"}} {}",
+                sensor, errors
+            );
+        }
+        // Sensors that are not in the list of enabled sensors (e.g. the INA219 channels)
+        for (sensor, errors) in self.sensor_errors.iter() {
+            if !self.sensors.iter().any(|(name, _)| name == sensor) {
+                let _ = writeln!(
+                    out,
+                    "sensilo_sensor_errors{{sensor=\"{}\"}} {}",
+                    sensor, errors
+                );
+            }
+        }
+        gauge(
+            &mut out,
+            "sensilo_submission_errors",
+            "Number of measurement cycles that could not be submitted",
+        );
+        let _ = writeln!(out, "sensilo_submission_errors {}", self.submission_errors);
+
+        // All samples of a metric must be grouped, in the order of their first reading
+        let mut names: Vec<(&str, &str)> = Vec::new();
+        for reading in self.readings.iter() {
+            if !names.contains(&(reading.measurement, reading.field)) {
+                names.push((reading.measurement, reading.field));
+            }
+        }
+        for (measurement, field) in names {
+            let name = format!("sensilo_{}_{}", measurement, field);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for reading in self
+                .readings
+                .iter()
+                .filter(|r| r.measurement == measurement && r.field == field)
+            {
+                sample(&mut out, &name, reading);
+            }
+        }
+        out
+    }
+}
+
+/// Write the help and type lines of a gauge.
+fn gauge(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+}
+
+/// Write the sample of a reading, with the sensor and channel labels.
+fn sample(out: &mut String, name: &str, reading: &Reading) {
+    let _ = write!(out, "{}", name);
+    let mut labels = Vec::new();
+    if let Some(sensor) = reading.sensor {
+        labels.push(format!("sensor=\"{}\"", escape(sensor)));
+    }
+    if let Some(ref channel) = reading.channel {
+        labels.push(format!("channel=\"{}\"", escape(channel)));
+    }
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = match reading.value {
+        Value::Float(value, decimals) => writeln!(out, " {:.*}", decimals, value),
+        Value::Unsigned(value) => writeln!(out, " {}", value),
+        Value::Bool(value) => writeln!(out, " {}", u8::from(value)),
+    };
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,109 @@
+//! Pulse counting on a GPIO, for sensors with a pulse output (e.g. Geiger counters or S0 energy
+//! meters).
+//!
+//! The ESP32-C3 has no pulse counter (PCNT) peripheral, so pulses are counted in a GPIO
+//! interrupt handler. The handler only updates atomics, everything else happens when the count is
+//! taken.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use esp_idf_hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_sys::EspError;
+
+/// State shared with the interrupt handler
+struct PulseState {
+    /// Number of pulses since the count was last taken
+    count: AtomicU32,
+    /// Whether a pulse was seen since startup
+    seen: AtomicBool,
+    /// Timestamp of the last counted pulse in µs (wrapping)
+    last_pulse_us: AtomicU32,
+    /// Timestamp of the last counted pulse in ms (wrapping)
+    last_pulse_ms: AtomicU32,
+    /// Time between the last two counted pulses in ms (0 if there weren't two pulses yet)
+    last_interval_ms: AtomicU32,
+    /// Pulses closer to the previous pulse than this are ignored
+    min_interval_us: u32,
+}
+
+impl PulseState {
+    /// Called from the interrupt handler for every falling edge.
+    fn on_pulse(&self) {
+        // Note: The µs timestamp wraps every ~71 minutes, which doesn't matter for the short
+        // intervals that are compared here. The ms timestamp wraps every ~49 days.
+        let now_us = unsafe { esp_idf_sys::esp_timer_get_time() };
+        let (now, now_ms) = (now_us as u32, (now_us / 1000) as u32);
+        if self.seen.load(Ordering::Relaxed) {
+            let interval = now.wrapping_sub(self.last_pulse_us.load(Ordering::Relaxed));
+            if interval < self.min_interval_us {
+                return;
+            }
+            let interval_ms = now_ms.wrapping_sub(self.last_pulse_ms.load(Ordering::Relaxed));
+            self.last_interval_ms
+                .store(interval_ms.max(1), Ordering::Relaxed);
+        }
+        self.seen.store(true, Ordering::Relaxed);
+        self.last_pulse_us.store(now, Ordering::Relaxed);
+        self.last_pulse_ms.store(now_ms, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts falling edges on an input pin (with pull-up, for open collector outputs).
+pub struct PulseCounter<'d> {
+    _pin: PinDriver<'d, AnyIOPin, Input>,
+    state: Arc<PulseState>,
+}
+
+impl<'d> PulseCounter<'d> {
+    /// Start counting pulses on the specified pin.
+    ///
+    /// Pulses that follow the previous pulse within `min_interval` are ignored. This debounces
+    /// the input, and provides a well-defined dead time for sensors that need one.
+    pub fn new(pin: AnyIOPin, min_interval: Duration) -> Result<Self, EspError> {
+        let state = Arc::new(PulseState {
+            count: AtomicU32::new(0),
+            seen: AtomicBool::new(false),
+            last_pulse_us: AtomicU32::new(0),
+            last_pulse_ms: AtomicU32::new(0),
+            last_interval_ms: AtomicU32::new(0),
+            min_interval_us: min_interval.as_micros().try_into().unwrap_or(u32::MAX),
+        });
+
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_interrupt_type(InterruptType::NegEdge)?;
+        let isr_state = state.clone();
+        // Safety: The callback runs in interrupt context. It must not block or allocate, which it
+        // doesn't, since it only accesses atomics.
+        unsafe { pin.subscribe(move || isr_state.on_pulse())? };
+
+        Ok(Self { _pin: pin, state })
+    }
+
+    /// Return the number of pulses since the last call, and reset the count.
+    pub fn take_count(&self) -> u32 {
+        self.state.count.swap(0, Ordering::Relaxed)
+    }
+
+    /// Return the spacing between pulses: The time between the last two pulses, or the time since
+    /// the last pulse if that's longer.
+    ///
+    /// Returns `None` if there weren't two pulses yet.
+    #[cfg(feature = "s0")]
+    pub fn pulse_spacing(&self) -> Option<Duration> {
+        let interval_ms = self.state.last_interval_ms.load(Ordering::Relaxed);
+        if interval_ms == 0 {
+            return None;
+        }
+        let now_ms = (unsafe { esp_idf_sys::esp_timer_get_time() } / 1000) as u32;
+        let since_last_ms = now_ms.wrapping_sub(self.state.last_pulse_ms.load(Ordering::Relaxed));
+        Some(Duration::from_millis(interval_ms.max(since_last_ms) as u64))
+    }
+}
@@ -0,0 +1,268 @@
+//! Typed readings of the sensors.
+//!
+//! A measurement cycle produces a list of [`Reading`]s, one per measured value. Every reading
+//! carries its quantity (InfluxDB measurement and field), unit, sensor and the time it was taken,
+//! so that the sinks can serialize the readings generically instead of knowing every sensor.
+//!
+//! Readings that are the primary source of a [`Metric`] (e.g. the SHTC3 temperature, but not the
+//! temperature of the SCD4x) are marked with it. The display, the rules and the history use the
+//! first reading of a metric.
+
+use std::{fmt, time::Instant};
+
+use crate::{
+    config::Metric,
+    lineproto::{FieldValue, Fixed, Line, TagSet},
+};
+
+/// Unit of a reading
+// Which units are measured depends on the enabled sensors
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Celsius,
+    Percent,
+    Lux,
+    Ppm,
+    Ppb,
+    Pascal,
+    Hectopascal,
+    Kelvin,
+    Centimeter,
+    CountsPerMinute,
+    MicrosievertsPerHour,
+    Watt,
+    Milliwatt,
+    KilowattHour,
+    MilliwattHour,
+    Volt,
+    Ampere,
+    Hertz,
+    Second,
+    Day,
+    /// Counts, indices, states and ratios
+    None,
+}
+
+impl Unit {
+    /// Return the symbol of the unit, empty for [`Unit::None`].
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Celsius => "°C",
+            Unit::Percent => "%",
+            Unit::Lux => "lx",
+            Unit::Ppm => "ppm",
+            Unit::Ppb => "ppb",
+            Unit::Pascal => "Pa",
+            Unit::Hectopascal => "hPa",
+            Unit::Kelvin => "K",
+            Unit::Centimeter => "cm",
+            Unit::CountsPerMinute => "CPM",
+            Unit::MicrosievertsPerHour => "µSv/h",
+            Unit::Watt => "W",
+            Unit::Milliwatt => "mW",
+            Unit::KilowattHour => "kWh",
+            Unit::MilliwattHour => "mWh",
+            Unit::Volt => "V",
+            Unit::Ampere => "A",
+            Unit::Hertz => "Hz",
+            Unit::Second => "s",
+            Unit::Day => "d",
+            Unit::None => "",
+        }
+    }
+}
+
+/// Value of a reading, with its InfluxDB type
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    /// A float with the number of decimals that are submitted
+    Float(f64, usize),
+    Unsigned(u32),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn as_f32(self) -> f32 {
+        match self {
+            Value::Float(value, _) => value as f32,
+            Value::Unsigned(value) => value as f32,
+            Value::Bool(value) => f32::from(u8::from(value)),
+        }
+    }
+}
+
+impl FieldValue for Value {
+    fn write_value(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        match *self {
+            Value::Float(value, decimals) => Fixed(value, decimals).write_value(out),
+            Value::Unsigned(value) => value.write_value(out),
+            Value::Bool(value) => value.write_value(out),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match *self {
+            Value::Float(value, decimals) => Fixed(value, decimals).is_valid(),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reading {
+    /// Metric of which this is the primary reading, if any
+    pub metric: Option<Metric>,
+    /// InfluxDB measurement, e.g. `temperature`
+    pub measurement: &'static str,
+    /// InfluxDB field, e.g. `celsius`
+    pub field: &'static str,
+    pub value: Value,
+    pub unit: Unit,
+    /// Sensor type tag, `None` for the default sensor of the measurement
+    pub sensor: Option<&'static str>,
+    /// Channel tag, for sensors with several instances
+    pub channel: Option<String>,
+    /// Serial number of the sensor, with `sensors.serial_tags`
+    pub serial: Option<String>,
+    /// Time at which the reading was taken
+    pub timestamp: Instant,
+}
+
+// Which constructors and tags are used depends on the enabled sensors
+#[allow(dead_code)]
+impl Reading {
+    /// Create a float reading that is submitted with `decimals` decimals.
+    pub fn float(
+        measurement: &'static str,
+        field: &'static str,
+        value: impl Into<f64>,
+        decimals: usize,
+        unit: Unit,
+    ) -> Self {
+        Self::new(
+            measurement,
+            field,
+            Value::Float(value.into(), decimals),
+            unit,
+        )
+    }
+
+    pub fn unsigned(
+        measurement: &'static str,
+        field: &'static str,
+        value: impl Into<u32>,
+        unit: Unit,
+    ) -> Self {
+        Self::new(measurement, field, Value::Unsigned(value.into()), unit)
+    }
+
+    pub fn bool(measurement: &'static str, field: &'static str, value: bool) -> Self {
+        Self::new(measurement, field, Value::Bool(value), Unit::None)
+    }
+
+    fn new(measurement: &'static str, field: &'static str, value: Value, unit: Unit) -> Self {
+        Self {
+            metric: None,
+            measurement,
+            field,
+            value,
+            unit,
+            sensor: None,
+            channel: None,
+            serial: None,
+            timestamp: Instant::now(),
+        }
+    }
+
+    /// Mark the reading as the primary reading of `metric`.
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = Some(metric);
+        self
+    }
+
+    /// Set the sensor type tag.
+    pub fn sensor(mut self, sensor: &'static str) -> Self {
+        self.sensor = Some(sensor);
+        self
+    }
+
+    /// Set the channel tag.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    /// Set the sensor serial tag, if the serial number of the sensor is known.
+    pub fn serial(mut self, serial: Option<String>) -> Self {
+        self.serial = serial;
+        self
+    }
+
+    /// Return whether both readings are of the same quantity from the same sensor and channel.
+    pub fn same_series(&self, other: &Reading) -> bool {
+        self.same_line(other) && self.field == other.field
+    }
+
+    /// Return whether both readings belong to the same InfluxDB line.
+    fn same_line(&self, other: &Reading) -> bool {
+        self.measurement == other.measurement
+            && self.sensor == other.sensor
+            && self.channel == other.channel
+            && self.serial == other.serial
+    }
+}
+
+/// Format as `<measurement> <field> (<sensor>, <channel>): <value> <unit>`, for the console.
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.measurement, self.field)?;
+        match (self.sensor, &self.channel) {
+            (Some(sensor), Some(channel)) => write!(f, " ({}, {})", sensor, channel)?,
+            (Some(sensor), None) => write!(f, " ({})", sensor)?,
+            (None, Some(channel)) => write!(f, " ({})", channel)?,
+            (None, None) => {}
+        }
+        match self.value {
+            Value::Float(value, decimals) => write!(f, ": {:.*}", decimals, value)?,
+            Value::Unsigned(value) => write!(f, ": {}", value)?,
+            Value::Bool(value) => write!(f, ": {}", value)?,
+        }
+        write!(f, " {}", self.unit.symbol())
+    }
+}
+
+/// Return the readings in InfluxDB line protocol format, without timestamps. Consecutive readings
+/// of the same measurement, sensor and channel are one line with several fields:
+///
+/// `<measurement>[,sensor_type=...][,channel=...][,sensor_serial=...],<tags> <field>=<value>,...`
+pub fn lines(readings: &[Reading], tags: &TagSet) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rest = readings;
+    while let Some(first) = rest.first() {
+        let count = rest
+            .iter()
+            .position(|reading| !first.same_line(reading))
+            .unwrap_or(rest.len());
+        let mut line = Line::build(first.measurement);
+        if let Some(sensor) = first.sensor {
+            line = line.tag("sensor_type", sensor);
+        }
+        if let Some(ref channel) = first.channel {
+            line = line.tag("channel", channel);
+        }
+        if let Some(ref serial) = first.serial {
+            line = line.tag("sensor_serial", serial);
+        }
+        line = line.tags(tags);
+        for reading in &rest[..count] {
+            line = line.field(reading.field, reading.value);
+        }
+        if line.has_fields() {
+            lines.push(line.into_string());
+        }
+        rest = &rest[count..];
+    }
+    lines
+}
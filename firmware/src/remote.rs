@@ -0,0 +1,563 @@
+//! Remote config, so that a fleet of devices can be reconfigured from a central place (e.g. the
+//! measurement interval, the tags or the thresholds of the rules), without the serial console or
+//! reflashing.
+//!
+//! With `remote_config.url`, a config document is fetched right after the start and then every
+//! `remote_config.interval_secs`, with `Authorization: Bearer <token>` if `remote_config.token` is
+//! set. With `remote_config.mqtt`, documents are received from `<topic_prefix>/<name>/config/set`
+//! as well (see [`crate::mqtt`]), a retained message is received after every reconnection.
+//!
+//! A document is a partial config in TOML, or in JSON if it starts with `{`, with only the settings
+//! that are managed remotely, e.g.:
+//!
+//! ```toml
+//! [intervals]
+//! measurement_secs = 60
+//!
+//! [tags]
+//! building = "hq"
+//! ```
+//!
+//! It's merged into the current config (see [`config::merge`]): Tables are merged, all other
+//! settings (including arrays, e.g. the rules) are replaced. If that changes the config, the changed
+//! settings are logged, and the merged config is validated and stored in NVS, then the device
+//! restarts with a graceful shutdown to apply it. An invalid document is rejected as a whole, the
+//! config stays as it is. A document that doesn't change anything (e.g. the same document fetched
+//! again after the restart) is ignored.
+
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use embedded_svc::{
+    http::{client::Client as HttpClient, Method, Status},
+    io::Read,
+};
+use esp_idf_svc::{
+    http::client::{Configuration as HttpConfiguration, EspHttpConnection},
+    nvs::EspDefaultNvsPartition,
+};
+
+use crate::{
+    config::{self, Config},
+    webhook, Message,
+};
+
+/// Stack size of the thread, for the TLS handshake and the parsing
+const STACK_SIZE: usize = 16384;
+
+/// Maximum size of a document in bytes
+pub const MAX_SIZE: usize = 4096;
+
+/// Maximum nesting depth of the arrays and tables of a document, since the parsers recurse into
+/// them and a deeply nested document would overflow the stack
+const MAX_DEPTH: usize = 16;
+
+/// Start the thread that applies the documents, fetched from `remote_config.url` or sent through the
+/// returned sender (by the MQTT client). Restarts are requested through `sender`.
+///
+/// The returned sender must be kept, since the thread stops when the channel is disconnected.
+pub fn spawn(
+    config: Arc<Config>,
+    partition: EspDefaultNvsPartition,
+    sender: Sender<Message>,
+) -> io::Result<Sender<String>> {
+    let (documents, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("remote-config".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(&config, partition, &receiver, &sender))?;
+    Ok(documents)
+}
+
+fn run(
+    config: &Config,
+    partition: EspDefaultNvsPartition,
+    documents: &Receiver<String>,
+    sender: &Sender<Message>,
+) {
+    let remote = &config.remote_config;
+    let interval = Duration::from_secs(remote.interval_secs.into());
+    let mut next_fetch = Instant::now();
+    loop {
+        let (document, source) = match remote.url {
+            Some(ref url) => {
+                match documents.recv_timeout(next_fetch.saturating_duration_since(Instant::now())) {
+                    Ok(document) => (document, "MQTT"),
+                    Err(RecvTimeoutError::Timeout) => {
+                        next_fetch = Instant::now() + interval;
+                        match fetch(url, remote.token.as_deref()) {
+                            Ok(document) => (document, url.as_str()),
+                            Err(e) => {
+                                eprintln!("Remote config: ERROR: Could not fetch config: {:#}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+            None => match documents.recv() {
+                Ok(document) => (document, "MQTT"),
+                Err(_) => return,
+            },
+        };
+        match apply(config, partition.clone(), &document) {
+            Ok(false) => println!("Remote config: Unchanged ({})", source),
+            Ok(true) => {
+                // The merged config is applied after the restart
+                if let Err(e) = sender.send(Message::Restart) {
+                    eprintln!("Remote config: ERROR: Could not request restart: {}", e);
+                }
+                return;
+            }
+            Err(e) => eprintln!("Remote config: ERROR: {} rejected: {:#}", source, e),
+        }
+    }
+}
+
+/// Merge a document into the config. If it changes the config, store the merged config and return
+/// `true`.
+fn apply(
+    config: &Config,
+    partition: EspDefaultNvsPartition,
+    document: &str,
+) -> anyhow::Result<bool> {
+    let partial = if document.trim_start().starts_with('{') {
+        Json::new(document).parse().context("Invalid JSON")?
+    } else {
+        check_depth(document).context("Invalid config")?;
+        document.parse::<toml::Value>().context("Invalid config")?
+    };
+    let (merged, changed) = config::merge(config, partial)?;
+    if changed.is_empty() {
+        return Ok(false);
+    }
+    println!("Remote config: Changed {}", changed.join(", "));
+    config::save(partition, &merged)?;
+    Ok(true)
+}
+
+/// Ensure that a TOML document is not nested deeper than [`MAX_DEPTH`], before it's parsed.
+///
+/// The check is conservative: The depth is the number of open brackets and braces, plus the number
+/// of dots in the key of the current line and in the last table header, outside of strings and
+/// comments.
+fn check_depth(document: &str) -> anyhow::Result<()> {
+    let bytes = document.as_bytes();
+    // Open brackets and braces
+    let mut depth = 0;
+    // Keys of the current line, or of the table header that is being parsed
+    let mut key_depth = 0;
+    // Keys of the last table header
+    let mut table_depth = 0;
+    let mut in_key = true;
+    let mut in_header = false;
+    let mut line_start = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match c {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'"' | b'\'' => {
+                let quote = if bytes[i..].starts_with(&[c; 3]) {
+                    &bytes[i..i + 3]
+                } else {
+                    &bytes[i..i + 1]
+                };
+                let mut end = i + quote.len();
+                while end < bytes.len() && !bytes[end..].starts_with(quote) {
+                    // Only basic strings have escapes
+                    end += if c == b'"' && bytes[end] == b'\\' {
+                        2
+                    } else {
+                        1
+                    };
+                }
+                i = end + quote.len();
+                line_start = false;
+                continue;
+            }
+            b'[' if line_start && depth == 0 => {
+                in_header = true;
+                key_depth = 0;
+                depth += 1;
+            }
+            b'[' => depth += 1,
+            b'{' => {
+                in_key = true;
+                depth += 1;
+            }
+            b']' | b'}' => {
+                depth -= usize::from(depth > 0);
+                if !in_header {
+                    key_depth = 0;
+                } else if depth == 0 {
+                    in_header = false;
+                    table_depth = key_depth;
+                    key_depth = 0;
+                }
+                in_key = false;
+            }
+            b'=' => {
+                in_key = false;
+                key_depth = 0;
+            }
+            b',' => {
+                in_key = true;
+                key_depth = 0;
+            }
+            b'\n' => {
+                in_key = true;
+                key_depth = 0;
+                line_start = true;
+            }
+            b'.' if in_key => key_depth += 1,
+            _ => {}
+        }
+        if !c.is_ascii_whitespace() {
+            line_start = false;
+        }
+        if depth + key_depth + table_depth > MAX_DEPTH {
+            bail!("Nested too deeply at offset {} (max {})", i, MAX_DEPTH);
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Fetch a document.
+fn fetch(url: &str, token: Option<&str>) -> anyhow::Result<String> {
+    #[cfg(feature = "faults")]
+    crate::faults::http()?;
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        crt_bundle_attach: webhook::crt_bundle_attach(),
+        ..Default::default()
+    })?);
+
+    let authorization = token.map(|token| format!("Bearer {}", token));
+    let mut headers = vec![("connection", "close")];
+    if let Some(ref authorization) = authorization {
+        headers.push(("authorization", authorization));
+    }
+    let mut response = client.request(Method::Get, url, &headers)?.submit()?;
+    let status = response.status();
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = response.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        if body.len() + len > MAX_SIZE {
+            bail!("Config is too large (max {} bytes)", MAX_SIZE);
+        }
+        body.extend_from_slice(&buf[..len]);
+    }
+    if status != 200 {
+        bail!("Server returned HTTP {}", status);
+    }
+    String::from_utf8(body).context("Config is not valid UTF-8")
+}
+
+/// Minimal JSON parser, for documents in JSON. The values are converted to the equivalent TOML
+/// values. `null` is not supported, since TOML has no equivalent.
+struct Json<'a> {
+    text: &'a str,
+    /// Byte offset of the next character
+    pos: usize,
+    /// Number of the arrays and objects that are being parsed
+    depth: usize,
+}
+
+impl<'a> Json<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    /// Parse the whole text as a single value.
+    fn parse(mut self) -> anyhow::Result<toml::Value> {
+        let value = self.value()?;
+        self.skip_whitespace();
+        if self.pos < self.text.len() {
+            bail!("Unexpected characters at offset {}", self.pos);
+        }
+        Ok(value)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => bail!("Expected {:?} at offset {}", expected, self.pos),
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<toml::Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.nested(Self::object),
+            Some('[') => self.nested(Self::array),
+            Some('"') => Ok(toml::Value::String(self.string()?)),
+            Some('t') => self.literal("true", toml::Value::Boolean(true)),
+            Some('f') => self.literal("false", toml::Value::Boolean(false)),
+            Some('n') => bail!("null is not supported, at offset {}", self.pos),
+            Some('-' | '0'..='9') => self.number(),
+            _ => bail!("Expected a value at offset {}", self.pos),
+        }
+    }
+
+    /// Parse an array or object, at most [`MAX_DEPTH`] levels deep.
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> anyhow::Result<toml::Value>,
+    ) -> anyhow::Result<toml::Value> {
+        if self.depth == MAX_DEPTH {
+            bail!(
+                "Nested too deeply at offset {} (max {})",
+                self.pos,
+                MAX_DEPTH
+            );
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> anyhow::Result<toml::Value> {
+        self.expect('{')?;
+        let mut table = toml::value::Table::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(toml::Value::Table(table));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            let value = self.value()?;
+            if table.insert(key, value).is_some() {
+                bail!("Duplicate key at offset {}", self.pos);
+            }
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(toml::Value::Table(table)),
+                _ => bail!("Expected ',' or '}}' at offset {}", self.pos),
+            }
+        }
+    }
+
+    fn array(&mut self) -> anyhow::Result<toml::Value> {
+        self.expect('[')?;
+        let mut array = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(toml::Value::Array(array));
+        }
+        loop {
+            array.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(toml::Value::Array(array)),
+                _ => bail!("Expected ',' or ']' at offset {}", self.pos),
+            }
+        }
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        if self.next() != Some('"') {
+            bail!("Expected a string at offset {}", self.pos);
+        }
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode_escape()?,
+                        _ => bail!("Invalid escape at offset {}", self.pos),
+                    };
+                    string.push(c);
+                }
+                Some(c) if c >= ' ' => string.push(c),
+                _ => bail!("Unterminated string at offset {}", self.pos),
+            }
+        }
+    }
+
+    /// Parse the digits of a `\u` escape, for characters outside of the BMP with the escaped low
+    /// surrogate that follows.
+    fn unicode_escape(&mut self) -> anyhow::Result<char> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.text[self.pos..].starts_with("\\u") {
+                bail!("Unpaired surrogate at offset {}", self.pos);
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                bail!("Unpaired surrogate at offset {}", self.pos);
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).with_context(|| format!("Invalid character at offset {}", self.pos))
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()))
+            .with_context(|| format!("Invalid escape at offset {}", self.pos))?;
+        self.pos += 4;
+        // Only hex digits
+        Ok(u32::from_str_radix(digits, 16).unwrap_or_default())
+    }
+
+    fn number(&mut self) -> anyhow::Result<toml::Value> {
+        let start = self.pos;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.pos += 1;
+        }
+        let number = &self.text[start..self.pos];
+        let value = if number.contains(['.', 'e', 'E']) {
+            number.parse().ok().map(toml::Value::Float)
+        } else {
+            number.parse().ok().map(toml::Value::Integer)
+        };
+        value.with_context(|| format!("Invalid number {:?} at offset {}", number, start))
+    }
+
+    fn literal(&mut self, literal: &str, value: toml::Value) -> anyhow::Result<toml::Value> {
+        if !self.text[self.pos..].starts_with(literal) {
+            bail!("Expected a value at offset {}", self.pos);
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"{
+        "name": "living-room",
+        "sinks": { "influxdb": { "interval_secs": 60 } },
+        "list": [-1, 2.5e1, true, false, "é😀\n", []]
+    }"#;
+
+    #[test]
+    fn json_is_converted_to_toml() {
+        let expected: toml::Value = r#"
+            name = "living-room"
+            sinks = { influxdb = { interval_secs = 60 } }
+            list = [-1, 25.0, true, false, "é😀\n", []]
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(Json::new(DOCUMENT).parse().unwrap(), expected);
+    }
+
+    #[test]
+    fn truncated_json_is_rejected() {
+        for (end, _) in DOCUMENT.char_indices() {
+            assert!(Json::new(&DOCUMENT[..end]).parse().is_err(), "{}", end);
+        }
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        for document in [
+            r#"{"name": null}"#,
+            r#"{"name": "a", "name": "b"}"#,
+            r#"{"name": "a"} {}"#,
+            r#"{"name": "a",}"#,
+            r#"{"name" "a"}"#,
+            r#"{name: "a"}"#,
+            r#"["\u12"]"#,
+            r#"["\ud83d"]"#,
+            r#"["\ud83dA"]"#,
+            r#"["\x"]"#,
+            "[\"\t\"]",
+            "[1.2.3]",
+            "[01x]",
+            "[tru]",
+        ] {
+            assert!(Json::new(document).parse().is_err(), "{}", document);
+        }
+    }
+
+    #[test]
+    fn json_nesting_is_limited() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Json::new(&nested(MAX_DEPTH)).parse().is_ok());
+        assert!(Json::new(&nested(MAX_DEPTH + 1)).parse().is_err());
+        // Unbalanced, the limit applies before the end is reached
+        assert!(Json::new(&"[".repeat(10_000)).parse().is_err());
+    }
+
+    #[test]
+    fn toml_nesting_is_limited() {
+        assert!(check_depth(&format!("a = {}", "[".repeat(MAX_DEPTH))).is_ok());
+        assert!(check_depth(&format!("a = {}", "[".repeat(MAX_DEPTH + 1))).is_err());
+        // Dotted keys and table headers count as well
+        let dotted = vec!["a"; MAX_DEPTH + 2].join(".");
+        assert!(check_depth(&format!("{} = 1", dotted)).is_err());
+        assert!(check_depth(&format!("[{}]\nb = 1", dotted)).is_err());
+        // Brackets in strings and comments don't
+        let brackets = "[".repeat(MAX_DEPTH + 1);
+        assert!(check_depth(&format!("a = \"{}\" # {}", brackets, brackets)).is_ok());
+        assert!(check_depth(&format!("a = '''{}'''", brackets)).is_ok());
+        // Unterminated string
+        assert!(check_depth("a = \"").is_ok());
+    }
+}
@@ -0,0 +1,149 @@
+//! Selection of the WiFi access point.
+//!
+//! By default, the ESP-IDF connects to the first access point with the configured SSID and stays
+//! with it until the connection is lost. With mesh systems, that's often a distant access point.
+//! There are two alternatives: Pin the BSSID of an access point (`wifi.bssid`), or roam
+//! (`wifi.roaming`): Scan for the strongest access point before connecting, and scan again when
+//! the signal drops below `wifi.roaming_threshold_dbm`. Another access point is only chosen if
+//! it's at least [`HYSTERESIS_DB`] stronger, so that the device doesn't flap between two access
+//! points with a similar signal.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use embedded_svc::wifi::{AccessPointInfo, Configuration, Wifi};
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_sys::EspError;
+
+use crate::config;
+
+/// Minimum interval between two scans while roaming, a scan interrupts the connection briefly
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How much stronger (in dB) another access point must be to roam to it
+pub const HYSTERESIS_DB: i8 = 5;
+
+/// Parse a BSSID like `aa:bb:cc:dd:ee:ff`.
+pub fn parse_bssid(bssid: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = bssid.split(':');
+    for byte in bytes.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(bytes)
+}
+
+/// Format a BSSID like `aa:bb:cc:dd:ee:ff`.
+pub fn format_bssid(bssid: &[u8; 6]) -> String {
+    bssid
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Return the BSSID and signal strength (in dBm) of the access point the station is connected
+/// to, if any.
+pub fn connected_ap() -> Option<([u8; 6], i8)> {
+    let mut info = esp_idf_sys::wifi_ap_record_t::default();
+    EspError::convert(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) })
+        .ok()
+        .map(|()| (info.bssid, info.rssi))
+}
+
+/// Scan and return the strongest access point with the configured SSID, if any.
+fn strongest(wifi: &mut EspWifi<'static>) -> anyhow::Result<Option<AccessPointInfo>> {
+    let ssid = match wifi.get_configuration()? {
+        Configuration::Client(client) => client.ssid,
+        _ => bail!("WiFi is not in client mode"),
+    };
+    Ok(wifi
+        .scan()
+        .context("Could not scan")?
+        .into_iter()
+        .filter(|ap| ap.ssid == ssid)
+        .max_by_key(|ap| ap.signal_strength))
+}
+
+/// Configure the station to connect to the access point `ap`. Takes effect on the next
+/// connection.
+fn configure(wifi: &mut EspWifi<'static>, ap: &AccessPointInfo) -> anyhow::Result<()> {
+    let mut client = match wifi.get_configuration()? {
+        Configuration::Client(client) => client,
+        _ => bail!("WiFi is not in client mode"),
+    };
+    client.bssid = Some(ap.bssid);
+    client.channel = Some(ap.channel);
+    wifi.set_configuration(&Configuration::Client(client))?;
+    Ok(())
+}
+
+/// Scan and configure the station to connect to the strongest access point with the configured
+/// SSID. Return it, if one was found.
+pub fn select(wifi: &mut EspWifi<'static>) -> anyhow::Result<Option<AccessPointInfo>> {
+    let ap = match strongest(wifi)? {
+        Some(ap) => ap,
+        None => return Ok(None),
+    };
+    configure(wifi, &ap)?;
+    Ok(Some(ap))
+}
+
+pub struct Roaming {
+    threshold_dbm: i8,
+    last_scan: Option<Instant>,
+}
+
+impl Roaming {
+    /// Return `None` if roaming is disabled.
+    pub fn new(config: &config::Wifi) -> Option<Self> {
+        if !config.roaming {
+            return None;
+        }
+        Some(Self {
+            threshold_dbm: config.roaming_threshold_dbm,
+            last_scan: None,
+        })
+    }
+
+    /// If the signal is weak, scan (at most every [`RESCAN_INTERVAL`]) and reconnect to a
+    /// stronger access point. Return whether the station reconnected.
+    pub fn check(&mut self, wifi: &mut EspWifi<'static>) -> anyhow::Result<bool> {
+        let (bssid, rssi) = match connected_ap() {
+            Some(ap) => ap,
+            None => return Ok(false),
+        };
+        if rssi >= self.threshold_dbm
+            || matches!(self.last_scan, Some(last_scan) if last_scan.elapsed() < RESCAN_INTERVAL)
+        {
+            return Ok(false);
+        }
+        self.last_scan = Some(Instant::now());
+
+        let ap = match strongest(wifi)? {
+            Some(ap) => ap,
+            None => return Ok(false),
+        };
+        if ap.bssid == bssid || ap.signal_strength < rssi.saturating_add(HYSTERESIS_DB) {
+            return Ok(false);
+        }
+        println!(
+            "WiFi: Roaming from {} ({} dBm) to {} ({} dBm)",
+            format_bssid(&bssid),
+            rssi,
+            format_bssid(&ap.bssid),
+            ap.signal_strength,
+        );
+        wifi.disconnect().context("Could not disconnect")?;
+        configure(wifi, &ap)?;
+        wifi.connect().context("Could not connect")?;
+        Ok(true)
+    }
+}
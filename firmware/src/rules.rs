@@ -0,0 +1,294 @@
+//! Rule engine: Actions that are run when conditions on the measurements become true or false.
+//!
+//! The rules are defined in the config (see [`config::Rule`]). Conditions can compare metrics
+//! against thresholds (optionally for a minimal duration, e.g. "humidity above 70 % for 10
+//! minutes"), check the time of day, and combine other conditions with AND/OR.
+//!
+//! The rules are evaluated once per measurement cycle, with the latest measurements. A rule
+//! triggers on changes only: The `then` actions are run when its condition becomes true, the
+//! `else` actions when it becomes false again. Every change is submitted as a `rule` event.
+//!
+//! Webhook bodies are templates (see [`webhook`]). `metric`, `value` and `threshold` refer to the
+//! first metric condition of the rule.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use embedded_svc::http::Method;
+
+use crate::{
+    clock,
+    config::{self, Metric},
+    eventlog,
+    events::Event,
+    ntfy,
+    outputs::Outputs,
+    webhook,
+};
+
+/// A condition with its evaluation state
+enum Node {
+    All(Vec<Node>),
+    Any(Vec<Node>),
+    Metric {
+        metric: Metric,
+        above: Option<f32>,
+        below: Option<f32>,
+        duration: Duration,
+        /// Time since which the threshold is met
+        since: Option<Instant>,
+    },
+    Time {
+        /// Start and end in minutes since midnight (local time)
+        from: u32,
+        to: u32,
+    },
+}
+
+impl Node {
+    fn new(condition: &config::Condition) -> Self {
+        match condition {
+            config::Condition::All { all } => Self::All(all.iter().map(Self::new).collect()),
+            config::Condition::Any { any } => Self::Any(any.iter().map(Self::new).collect()),
+            config::Condition::Metric {
+                metric,
+                above,
+                below,
+                for_secs,
+            } => Self::Metric {
+                metric: *metric,
+                above: *above,
+                below: *below,
+                duration: Duration::from_secs((*for_secs).into()),
+                since: None,
+            },
+            // The times are checked by the config validation
+            config::Condition::Time { from, to } => Self::Time {
+                from: config::parse_time_of_day(from).unwrap_or(0),
+                to: config::parse_time_of_day(to).unwrap_or(0),
+            },
+        }
+    }
+
+    /// Return the metric and the threshold of the first metric condition.
+    fn threshold(&self) -> Option<(Metric, f32)> {
+        match self {
+            Self::All(nodes) | Self::Any(nodes) => nodes.iter().find_map(Self::threshold),
+            Self::Metric {
+                metric,
+                above,
+                below,
+                ..
+            } => above.or(*below).map(|threshold| (*metric, threshold)),
+            Self::Time { .. } => None,
+        }
+    }
+
+    /// Evaluate the condition. All sub-conditions are evaluated (without short-circuiting), so
+    /// that the durations are tracked.
+    fn evaluate(&mut self, value: &dyn Fn(Metric) -> Option<f32>, now: Instant) -> bool {
+        match self {
+            Self::All(nodes) => evaluate_all(nodes, value, now).iter().all(|result| *result),
+            Self::Any(nodes) => evaluate_all(nodes, value, now).iter().any(|result| *result),
+            Self::Metric {
+                metric,
+                above,
+                below,
+                duration,
+                since,
+            } => {
+                // A missing value does not meet the threshold
+                let met = match value(*metric) {
+                    Some(value) => {
+                        above.iter().all(|above| value > *above)
+                            && below.iter().all(|below| value < *below)
+                    }
+                    None => false,
+                };
+                if !met {
+                    *since = None;
+                    return false;
+                }
+                let since = *since.get_or_insert(now);
+                now.duration_since(since) >= *duration
+            }
+            // If the clock is not synchronized, the time is unknown
+            Self::Time { from, to } => match clock::minute_of_day() {
+                Some(minute) if from <= to => (*from..*to).contains(&minute),
+                Some(minute) => minute >= *from || minute < *to,
+                None => false,
+            },
+        }
+    }
+}
+
+/// Evaluate all conditions.
+fn evaluate_all(
+    nodes: &mut [Node],
+    value: &dyn Fn(Metric) -> Option<f32>,
+    now: Instant,
+) -> Vec<bool> {
+    nodes
+        .iter_mut()
+        .map(|node| node.evaluate(value, now))
+        .collect()
+}
+
+struct Rule {
+    name: String,
+    condition: Node,
+    then: Vec<config::RuleAction>,
+    otherwise: Vec<config::RuleAction>,
+    /// Whether the condition was true at the last evaluation
+    active: bool,
+}
+
+/// All rules from the config
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Name of the device, for the webhook templates
+    device: String,
+    /// ntfy server and topic, for the ntfy actions
+    ntfy: Option<config::Ntfy>,
+    /// Whether actions that connect over TLS are run
+    tls: bool,
+}
+
+impl Rules {
+    pub fn new(rules: &[config::Rule], device: &str, ntfy: Option<config::Ntfy>) -> Self {
+        Self {
+            device: device.into(),
+            ntfy,
+            tls: true,
+            rules: rules
+                .iter()
+                .map(|rule| Rule {
+                    name: rule.name.clone(),
+                    condition: Node::new(&rule.when),
+                    then: rule.then.clone(),
+                    otherwise: rule.otherwise.clone(),
+                    active: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Skip the actions that connect over TLS (HTTPS webhooks and ntfy), e.g. in the safe mode
+    /// (see `power.rs`).
+    pub fn disable_tls(&mut self) {
+        self.tls = false;
+    }
+
+    /// Evaluate all rules, reading the metrics with `value`. Run the actions of the rules whose
+    /// condition changed, and return the events for these changes.
+    pub fn evaluate(
+        &mut self,
+        value: impl Fn(Metric) -> Option<f32>,
+        outputs: &Mutex<Outputs>,
+    ) -> Vec<Event> {
+        let now = Instant::now();
+        let mut events = Vec::new();
+        for rule in self.rules.iter_mut() {
+            let active = rule.condition.evaluate(&value, now);
+            if active == rule.active {
+                continue;
+            }
+            rule.active = active;
+            println!(":: Rule {}: {}", rule.name, active);
+            eventlog::record(
+                eventlog::Kind::Rule,
+                &format!(
+                    "{}: {}",
+                    rule.name,
+                    if active { "active" } else { "inactive" }
+                ),
+            );
+            let threshold = rule.condition.threshold();
+            let variable = |name: &str| match name {
+                "device" => Some(self.device.clone()),
+                "rule" => Some(rule.name.clone()),
+                "active" => Some(active.to_string()),
+                "metric" => threshold.map(|(metric, _)| metric.name().into()),
+                "value" => threshold
+                    .and_then(|(metric, _)| value(metric))
+                    .map(|value| format!("{:.2}", value)),
+                "threshold" => threshold.map(|(_, threshold)| format!("{:.2}", threshold)),
+                _ => None,
+            };
+            let actions = if active { &rule.then } else { &rule.otherwise };
+            for action in actions {
+                if !self.tls && needs_tls(action, self.ntfy.as_ref()) {
+                    println!("Rule {}: Skipping action over TLS in safe mode", rule.name);
+                    continue;
+                }
+                if let Err(e) = run(action, outputs, self.ntfy.as_ref(), &variable) {
+                    eprintln!("Rule {}: ERROR: {:#}", rule.name, e);
+                }
+            }
+            events.push(Event::Rule {
+                name: rule.name.clone(),
+                active,
+            });
+        }
+        events
+    }
+}
+
+/// Return whether an action connects over TLS.
+fn needs_tls(action: &config::RuleAction, ntfy_config: Option<&config::Ntfy>) -> bool {
+    match action {
+        config::RuleAction::Output { .. } => false,
+        config::RuleAction::Webhook { webhook, .. } => webhook.starts_with("https://"),
+        // The default server uses HTTPS
+        config::RuleAction::Ntfy { .. } => !matches!(
+            ntfy_config.and_then(|ntfy| ntfy.server.as_deref()),
+            Some(server) if server.starts_with("http://")
+        ),
+    }
+}
+
+/// Run a rule action. `variable` returns the values of the template variables.
+fn run(
+    action: &config::RuleAction,
+    outputs: &Mutex<Outputs>,
+    ntfy_config: Option<&config::Ntfy>,
+    variable: &dyn Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    match action {
+        config::RuleAction::Output { output, level } => outputs
+            .lock()
+            .expect("Failed to lock outputs mutex")
+            .set_level(output, *level),
+        config::RuleAction::Webhook {
+            webhook: url,
+            body,
+            content_type,
+            headers,
+        } => {
+            let body = webhook::render(body, variable);
+            let content_type = content_type.as_deref().unwrap_or("application/json");
+            let mut all_headers = vec![("content-type", content_type)];
+            all_headers.extend(headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            webhook::send(Method::Post, url, &all_headers, &body)
+        }
+        config::RuleAction::Ntfy {
+            ntfy: message,
+            title,
+            priority,
+        } => {
+            // The config validation ensures that ntfy is configured
+            let ntfy_config = match ntfy_config {
+                Some(ntfy_config) => ntfy_config,
+                None => return Ok(()),
+            };
+            let title = match title {
+                Some(title) => webhook::render(title, variable),
+                None => variable("rule").unwrap_or_default(),
+            };
+            let message = webhook::render(message, variable);
+            ntfy::publish(ntfy_config, Some(&title), &message, *priority)
+        }
+    }
+}
@@ -0,0 +1,44 @@
+//! Graceful shutdown before a restart.
+//!
+//! Requested restarts (through the `restart` console command or the HTTP API) are handled by the
+//! main loop, between two measurement cycles: It submits the pending batches once more, publishes
+//! `offline` to the MQTT broker and stops the fan, and then calls [`restart`].
+//!
+//! State that is owned by other tasks is handled by hooks, e.g. saving the gas sensor baselines and
+//! the S0 energy total, and turning the PWM outputs off. The hooks also run before the watchdog
+//! restarts the device, when the main loop can't be relied on. Since a hook may run while a hung
+//! task holds a lock, hooks must not block on locks (use `try_lock` and skip the hook if the lock
+//! is held).
+//!
+//! A panic aborts right away (the firmware is built with `panic_immediate_abort`), so no hooks run
+//! then.
+
+use std::sync::Mutex;
+
+type Hook = Box<dyn Fn() + Send>;
+
+static HOOKS: Mutex<Vec<Hook>> = Mutex::new(Vec::new());
+
+/// Register a hook that runs before every restart.
+pub fn add_hook(hook: impl Fn() + Send + 'static) {
+    HOOKS
+        .lock()
+        .expect("Failed to lock shutdown hooks mutex")
+        .push(Box::new(hook));
+}
+
+/// Run the hooks. Does nothing if the hooks are already running (e.g. if a hook restarts).
+fn run_hooks() {
+    if let Ok(hooks) = HOOKS.try_lock() {
+        for hook in hooks.iter() {
+            hook();
+        }
+    }
+}
+
+/// Run the hooks and restart the device.
+pub fn restart() -> ! {
+    run_hooks();
+    println!("Restarting");
+    unsafe { esp_idf_sys::esp_restart() }
+}
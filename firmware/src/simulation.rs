@@ -0,0 +1,162 @@
+//! Simulation mode, for testing the sinks, dashboards and alerts end-to-end on a bench device
+//! without sensors.
+//!
+//! With `simulation.enabled`, the sensors are not read. Instead, the metrics of
+//! `simulation.metrics` are generated every measurement cycle, with the measurement, field, type
+//! and sensor tag of the sensor that measures them, so that the queries of the dashboards pick them
+//! up, and the display, the rules and the history use them like real readings. Every line is
+//! tagged with `simulated=true`, so that the simulated data can be told apart (and deleted) later.
+//!
+//! There are three generators, depending on the settings of the metric:
+//!
+//! - `min`, `max` and `period_secs`: A sine wave, e.g. a daily temperature cycle
+//! - `min`, `max` and `step`: A random walk that starts in the middle
+//! - `values`: Recorded values, one per cycle, replayed in a loop (e.g. a column of a CSV export
+//!   of real readings)
+
+use std::{f32::consts::PI, time::Instant};
+
+use crate::{
+    config::{self, Metric, SimulatedMetric},
+    reading::{Reading, Unit},
+};
+
+/// Generator of a simulated metric
+struct Generator {
+    config: SimulatedMetric,
+    /// Current value of a random walk
+    value: f32,
+    /// Index of the next replayed value
+    index: usize,
+}
+
+pub struct Simulator {
+    start: Instant,
+    generators: Vec<Generator>,
+}
+
+impl Simulator {
+    pub fn new(config: &config::Simulation) -> Self {
+        let generators = config
+            .metrics
+            .iter()
+            .map(|metric| Generator {
+                config: metric.clone(),
+                value: match *metric {
+                    SimulatedMetric::RandomWalk { min, max, .. } => (min + max) / 2.0,
+                    _ => 0.0,
+                },
+                index: 0,
+            })
+            .collect();
+        Self {
+            start: Instant::now(),
+            generators,
+        }
+    }
+
+    /// Return the readings of the current measurement cycle.
+    pub fn readings(&mut self) -> Vec<Reading> {
+        let elapsed_secs = self.start.elapsed().as_secs_f32();
+        self.generators
+            .iter_mut()
+            .map(|generator| {
+                let value = generator.next(elapsed_secs);
+                reading(generator.config.metric(), value)
+            })
+            .collect()
+    }
+}
+
+impl Generator {
+    /// Return the next value, `elapsed_secs` after the start of the simulation.
+    fn next(&mut self, elapsed_secs: f32) -> f32 {
+        match self.config {
+            SimulatedMetric::Sine {
+                min,
+                max,
+                period_secs,
+                ..
+            } => {
+                let phase = 2.0 * PI * (elapsed_secs / period_secs as f32).fract();
+                (min + max) / 2.0 + (max - min) / 2.0 * phase.sin()
+            }
+            SimulatedMetric::RandomWalk { min, max, step, .. } => {
+                self.value = (self.value + step * (2.0 * random() - 1.0)).clamp(min, max);
+                self.value
+            }
+            SimulatedMetric::Replay { ref values, .. } => {
+                // Validated when loading the config, there's at least one value
+                let value = values[self.index % values.len()];
+                self.index = (self.index + 1) % values.len();
+                value
+            }
+        }
+    }
+}
+
+/// Return a random number between 0 and 1, from the hardware random number generator.
+fn random() -> f32 {
+    (unsafe { esp_idf_sys::esp_random() }) as f32 / u32::MAX as f32
+}
+
+/// Return the reading of a metric, as submitted by its sensor.
+fn reading(metric: Metric, value: f32) -> Reading {
+    // Measurement, field, decimals (`None` for unsigned readings), unit and sensor tag
+    let (measurement, field, decimals, unit, sensor) = match metric {
+        Metric::Temperature => ("temperature", "celsius", Some(2), Unit::Celsius, None),
+        Metric::Humidity => ("humidity", "percent", Some(2), Unit::Percent, None),
+        Metric::Illuminance => ("illumination", "lux", Some(2), Unit::Lux, None),
+        Metric::Co2 => ("co2", "ppm", None, Unit::Ppm, Some("scd4x")),
+        Metric::Co2eq => ("co2", "ppm", None, Unit::Ppm, Some("mox")),
+        Metric::Tvoc => ("tvoc", "ppb", None, Unit::Ppb, None),
+        Metric::Pressure => ("pressure", "station_hpa", Some(2), Unit::Hectopascal, None),
+        Metric::SeaLevelPressure => (
+            "pressure",
+            "sea_level_hpa",
+            Some(2),
+            Unit::Hectopascal,
+            None,
+        ),
+        Metric::Aqi => ("aqi", "uba", None, Unit::None, Some("ens160")),
+        Metric::Hcho => ("formaldehyde", "ppb", Some(1), Unit::Ppb, None),
+        Metric::Occupancy => (
+            "occupancy",
+            "percent",
+            Some(1),
+            Unit::Percent,
+            Some("apds9960"),
+        ),
+        Metric::DoseRate => (
+            "radiation",
+            "usvh",
+            Some(4),
+            Unit::MicrosievertsPerHour,
+            None,
+        ),
+        Metric::Power => ("power", "watts", Some(1), Unit::Watt, None),
+        Metric::Thermocouple => (
+            "temperature",
+            "celsius",
+            Some(2),
+            Unit::Celsius,
+            Some("max31855"),
+        ),
+        Metric::Rtd => (
+            "temperature",
+            "celsius",
+            Some(2),
+            Unit::Celsius,
+            Some("max31865"),
+        ),
+    };
+    let reading = match decimals {
+        Some(decimals) => Reading::float(measurement, field, value, decimals, unit),
+        None => Reading::unsigned(measurement, field, value.max(0.0).round() as u32, unit),
+    }
+    .metric(metric);
+    match sensor {
+        Some(sensor) => reading.sensor(sensor),
+        None => reading,
+    }
+}
@@ -0,0 +1,537 @@
+//! Minimal SNMP v2c agent, for facility management systems that only speak SNMP.
+//!
+//! The agent answers `GetRequest`, `GetNextRequest` and `GetBulkRequest` PDUs with the configured
+//! community. All objects are read-only, `SetRequest` PDUs are answered with `notWritable`.
+//! Requests with another version or community are dropped.
+//!
+//! Besides the system group of MIB-II (`sysDescr`, `sysObjectID`, `sysUpTime` and `sysName`), the
+//! device health and the latest value of every measured metric are exposed in the subtree
+//! `1.3.6.1.4.1.<enterprise>.1` (see the README for the layout). SNMP has no floating point
+//! type, so the values are scaled by 100.
+//!
+//! The BER encoding is implemented here, limited to the types that are used by SNMP.
+
+use std::{
+    io,
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    config::{Config, Metric},
+    history::History,
+    SENSILO_NAME, VERSION,
+};
+
+/// Stack size of the agent thread
+const STACK_SIZE: usize = 8192;
+
+/// Maximum size of a response, so that it fits into a single Ethernet frame
+const MAX_RESPONSE_SIZE: usize = 1400;
+
+/// Value of the version field for SNMP v2c
+const VERSION_2C: i64 = 1;
+
+// BER tags
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+// PDU types
+const PDU_GET: u8 = 0xa0;
+const PDU_GET_NEXT: u8 = 0xa1;
+const PDU_RESPONSE: u8 = 0xa2;
+const PDU_SET: u8 = 0xa3;
+const PDU_GET_BULK: u8 = 0xa5;
+
+// Error status values
+const ERROR_TOO_BIG: i64 = 1;
+const ERROR_NOT_WRITABLE: i64 = 17;
+
+/// OID of the system group of MIB-II
+const SYSTEM: [u32; 7] = [1, 3, 6, 1, 2, 1, 1];
+
+#[derive(Clone)]
+enum Value {
+    Integer(i64),
+    OctetString(String),
+    ObjectId(Vec<u32>),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Null,
+    NoSuchObject,
+    EndOfMibView,
+}
+
+impl Value {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Value::Integer(value) => tlv(TAG_INTEGER, &encode_integer(*value)),
+            Value::OctetString(value) => tlv(TAG_OCTET_STRING, value.as_bytes()),
+            Value::ObjectId(oid) => tlv(TAG_OID, &encode_oid(oid)),
+            Value::Gauge32(value) => tlv(TAG_GAUGE32, &encode_integer((*value).into())),
+            Value::TimeTicks(value) => tlv(TAG_TIMETICKS, &encode_integer((*value).into())),
+            Value::Null => tlv(TAG_NULL, &[]),
+            Value::NoSuchObject => tlv(TAG_NO_SUCH_OBJECT, &[]),
+            Value::EndOfMibView => tlv(TAG_END_OF_MIB_VIEW, &[]),
+        }
+    }
+}
+
+/// An object with its OID
+type Object = (Vec<u32>, Value);
+
+/// A decoded request
+struct Request<'a> {
+    version: i64,
+    community: &'a [u8],
+    pdu_type: u8,
+    request_id: i64,
+    /// Error status field, the number of non-repeaters in `GetBulkRequest` PDUs
+    non_repeaters: i64,
+    /// Error index field, the maximum number of repetitions in `GetBulkRequest` PDUs
+    max_repetitions: i64,
+    oids: Vec<Vec<u32>>,
+}
+
+impl<'a> Request<'a> {
+    /// Decode a request, return `None` if it is malformed.
+    fn decode(data: &'a [u8]) -> Option<Self> {
+        let (message, _) = expect(data, TAG_SEQUENCE)?;
+        let (version, rest) = expect(message, TAG_INTEGER)?;
+        let (community, rest) = expect(rest, TAG_OCTET_STRING)?;
+        let (pdu_type, pdu, _) = read_tlv(rest)?;
+        let (request_id, rest) = expect(pdu, TAG_INTEGER)?;
+        let (non_repeaters, rest) = expect(rest, TAG_INTEGER)?;
+        let (max_repetitions, rest) = expect(rest, TAG_INTEGER)?;
+        let (mut varbinds, _) = expect(rest, TAG_SEQUENCE)?;
+        let mut oids = Vec::new();
+        while !varbinds.is_empty() {
+            let (varbind, rest) = expect(varbinds, TAG_SEQUENCE)?;
+            let (oid, _) = expect(varbind, TAG_OID)?;
+            oids.push(decode_oid(oid)?);
+            varbinds = rest;
+        }
+        Some(Self {
+            version: decode_integer(version)?,
+            community,
+            pdu_type,
+            request_id: decode_integer(request_id)?,
+            non_repeaters: decode_integer(non_repeaters)?,
+            max_repetitions: decode_integer(max_repetitions)?,
+            oids,
+        })
+    }
+}
+
+/// Start the agent thread.
+pub fn spawn(
+    config: Arc<Config>,
+    serial: Option<String>,
+    history: Arc<Mutex<History>>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", config.snmp.port))?;
+    let agent = Agent {
+        config,
+        serial,
+        history,
+    };
+    thread::Builder::new()
+        .name("snmp".into())
+        .stack_size(STACK_SIZE)
+        .spawn(move || agent.run(socket))?;
+    Ok(())
+}
+
+struct Agent {
+    config: Arc<Config>,
+    serial: Option<String>,
+    history: Arc<Mutex<History>>,
+}
+
+impl Agent {
+    fn run(&self, socket: UdpSocket) {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (length, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("SNMP: ERROR: Could not receive request: {}", e);
+                    continue;
+                }
+            };
+            if let Some(response) = self.handle(&buf[..length]) {
+                if let Err(e) = socket.send_to(&response, peer) {
+                    eprintln!("SNMP: ERROR: Could not send response: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Handle a request and return the response, or `None` if the request is dropped.
+    fn handle(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let request = Request::decode(data)?;
+        let community = self.config.snmp.community.as_deref().unwrap_or_default();
+        if request.version != VERSION_2C || request.community != community.as_bytes() {
+            return None;
+        }
+
+        let objects = self.objects();
+        let mut error_status = 0;
+        let mut error_index = 0;
+        let mut varbinds: Vec<Object> = Vec::new();
+        match request.pdu_type {
+            PDU_GET => {
+                for oid in request.oids.iter() {
+                    varbinds.push((oid.clone(), get(&objects, oid)));
+                }
+            }
+            PDU_GET_NEXT => {
+                for oid in request.oids.iter() {
+                    varbinds.push(next(&objects, oid));
+                }
+            }
+            PDU_GET_BULK => {
+                let non_repeaters = request.non_repeaters.clamp(0, request.oids.len() as i64);
+                let (non_repeaters, repeaters) = request.oids.split_at(non_repeaters as usize);
+                for oid in non_repeaters {
+                    varbinds.push(next(&objects, oid));
+                }
+                // Continue every repeater where its previous repetition ended
+                let mut cursors = repeaters.to_vec();
+                for _ in 0..request.max_repetitions.max(0) {
+                    if cursors.is_empty() {
+                        break;
+                    }
+                    let mut finished = true;
+                    for cursor in cursors.iter_mut() {
+                        let (oid, value) = next(&objects, cursor);
+                        finished &= matches!(value, Value::EndOfMibView);
+                        *cursor = oid.clone();
+                        varbinds.push((oid, value));
+                    }
+                    if finished {
+                        break;
+                    }
+                }
+            }
+            PDU_SET => {
+                error_status = ERROR_NOT_WRITABLE;
+                error_index = 1;
+                for oid in request.oids.iter() {
+                    varbinds.push((oid.clone(), Value::Null));
+                }
+            }
+            _ => return None,
+        }
+
+        let mut encoded: Vec<Vec<u8>> = varbinds
+            .iter()
+            .map(|(oid, value)| {
+                let varbind = [tlv(TAG_OID, &encode_oid(oid)), value.encode()].concat();
+                tlv(TAG_SEQUENCE, &varbind)
+            })
+            .collect();
+        if request.pdu_type == PDU_GET_BULK {
+            // GetBulk responses may be truncated, other responses must be complete. The length
+            // fields of the enclosing TLVs grow by up to 2 bytes each.
+            let mut size = encode_response(&request, error_status, error_index, &[]).len() + 6;
+            let count = encoded
+                .iter()
+                .take_while(|varbind| {
+                    size += varbind.len();
+                    size <= MAX_RESPONSE_SIZE
+                })
+                .count();
+            encoded.truncate(count.max(1));
+        }
+        let response = encode_response(&request, error_status, error_index, &encoded);
+        if response.len() > MAX_RESPONSE_SIZE {
+            return Some(encode_response(&request, ERROR_TOO_BIG, 0, &[]));
+        }
+        Some(response)
+    }
+
+    /// Return all objects, sorted by OID.
+    fn objects(&self) -> Vec<Object> {
+        let name = self.config.name.as_deref().unwrap_or(SENSILO_NAME);
+        let root = [1, 3, 6, 1, 4, 1, self.config.snmp.enterprise, 1];
+        let oid = |suffix: &[u32]| [root.as_slice(), suffix].concat();
+        let system = |object: u32| [SYSTEM.as_slice(), &[object, 0]].concat();
+
+        // Hundredths of a second, wraps after 497 days
+        let uptime = (unsafe { esp_idf_sys::esp_timer_get_time() } / 10_000) as u32;
+        let free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+
+        let mut objects = vec![
+            (
+                system(1),
+                Value::OctetString(format!("Sensilo v{}", VERSION)),
+            ),
+            (system(2), Value::ObjectId(root.to_vec())),
+            (system(3), Value::TimeTicks(uptime)),
+            (system(5), Value::OctetString(name.into())),
+            (oid(&[1, 1, 0]), Value::OctetString(name.into())),
+            (oid(&[1, 3, 0]), Value::OctetString(VERSION.into())),
+            (oid(&[1, 4, 0]), Value::TimeTicks(uptime)),
+            (oid(&[1, 5, 0]), Value::Gauge32(free_heap)),
+        ];
+        if let Some(ref serial) = self.serial {
+            objects.push((oid(&[1, 2, 0]), Value::OctetString(serial.clone())));
+        }
+
+        // Metric table, indexed by the position of the metric in `Metric::ALL` (starting at 1)
+        let history = self.history.lock().expect("Failed to lock history mutex");
+        for (i, metric) in Metric::ALL.into_iter().enumerate() {
+            let value = match history.latest(metric) {
+                Some(value) if value.is_finite() => value,
+                _ => continue,
+            };
+            let index = i as u32 + 1;
+            objects.push((
+                oid(&[2, 1, 1, index]),
+                Value::OctetString(metric.name().into()),
+            ));
+            let scaled = (value * 100.0)
+                .round()
+                .clamp(i32::MIN as f32, i32::MAX as f32);
+            objects.push((oid(&[2, 1, 2, index]), Value::Integer(scaled as i64)));
+        }
+        drop(history);
+
+        objects.sort_by(|a, b| a.0.cmp(&b.0));
+        objects
+    }
+}
+
+/// Return the value of the object with the OID.
+fn get(objects: &[Object], oid: &[u32]) -> Value {
+    match objects.binary_search_by(|(object, _)| object.as_slice().cmp(oid)) {
+        Ok(index) => objects[index].1.clone(),
+        Err(_) => Value::NoSuchObject,
+    }
+}
+
+/// Return the first object after the OID (in lexicographic order).
+fn next(objects: &[Object], oid: &[u32]) -> Object {
+    objects
+        .iter()
+        .find(|(object, _)| object.as_slice() > oid)
+        .cloned()
+        .unwrap_or_else(|| (oid.to_vec(), Value::EndOfMibView))
+}
+
+fn encode_response(
+    request: &Request,
+    error_status: i64,
+    error_index: i64,
+    varbinds: &[Vec<u8>],
+) -> Vec<u8> {
+    let pdu = [
+        tlv(TAG_INTEGER, &encode_integer(request.request_id)),
+        tlv(TAG_INTEGER, &encode_integer(error_status)),
+        tlv(TAG_INTEGER, &encode_integer(error_index)),
+        tlv(TAG_SEQUENCE, &varbinds.concat()),
+    ]
+    .concat();
+    let message = [
+        tlv(TAG_INTEGER, &encode_integer(VERSION_2C)),
+        tlv(TAG_OCTET_STRING, request.community),
+        tlv(PDU_RESPONSE, &pdu),
+    ]
+    .concat();
+    tlv(TAG_SEQUENCE, &message)
+}
+
+/// Read a TLV, return the tag, the content and the remaining data.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first & 0x80 == 0 {
+        (usize::from(first), rest)
+    } else {
+        // Long form, at most 2 length bytes are needed for a datagram
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 2 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count]
+            .iter()
+            .fold(0, |length, byte| length << 8 | usize::from(*byte));
+        (length, &rest[count..])
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+/// Read a TLV with the expected tag, return the content and the remaining data.
+fn expect(data: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (actual, content, rest) = read_tlv(data)?;
+    (actual == tag).then_some((content, rest))
+}
+
+/// Encode a TLV.
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let length = content.len();
+    let mut out = Vec::with_capacity(length + 4);
+    out.push(tag);
+    if length < 0x80 {
+        out.push(length as u8);
+    } else if length < 0x100 {
+        out.extend_from_slice(&[0x81, length as u8]);
+    } else {
+        out.extend_from_slice(&[0x82, (length >> 8) as u8, length as u8]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn decode_integer(content: &[u8]) -> Option<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return None;
+    }
+    // Sign extension
+    let initial = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(
+        content
+            .iter()
+            .fold(initial, |value, byte| value << 8 | i64::from(*byte)),
+    )
+}
+
+/// Encode an integer in the minimal number of bytes (two's complement).
+fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = match bytes[start] {
+            0x00 => bytes[start + 1] & 0x80 == 0,
+            0xff => bytes[start + 1] & 0x80 != 0,
+            _ => false,
+        };
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn decode_oid(content: &[u8]) -> Option<Vec<u32>> {
+    let (&first, rest) = content.split_first()?;
+    if rest.last().map(|byte| byte & 0x80 != 0).unwrap_or(false) {
+        return None;
+    }
+    let mut oid = vec![u32::from(first / 40), u32::from(first % 40)];
+    let mut arc: u32 = 0;
+    for byte in rest {
+        arc = arc.checked_mul(128)? | u32::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            oid.push(arc);
+            arc = 0;
+        }
+    }
+    Some(oid)
+}
+
+/// Encode an OID. It must have at least two arcs, which is the case for all decoded OIDs.
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = vec![(oid[0] * 40 + oid[1]) as u8];
+    for arc in oid[2..].iter() {
+        let mut bytes = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            bytes.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        out.extend(bytes.iter().rev());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `GetRequest` for `sysName.0` with the community `public` and the request ID 42
+    const GET_SYS_NAME: [u8; 40] = [
+        0x30, 0x26, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa0, 0x19,
+        0x02, 0x01, 0x2a, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c, 0x06, 0x08,
+        0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x05, 0x00, 0x05, 0x00,
+    ];
+
+    #[test]
+    fn get_request_is_decoded() {
+        let request = Request::decode(&GET_SYS_NAME).unwrap();
+        assert_eq!(request.version, VERSION_2C);
+        assert_eq!(request.community, b"public");
+        assert_eq!(request.pdu_type, PDU_GET);
+        assert_eq!(request.request_id, 42);
+        assert_eq!(request.oids, [vec![1, 3, 6, 1, 2, 1, 1, 5, 0]]);
+    }
+
+    #[test]
+    fn truncated_request_is_dropped() {
+        for length in 0..GET_SYS_NAME.len() {
+            assert!(Request::decode(&GET_SYS_NAME[..length]).is_none());
+        }
+    }
+
+    #[test]
+    fn malformed_lengths_are_rejected() {
+        // Length longer than the data
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x03, 0x00]).is_none());
+        // Long form without length bytes, with too many and with missing ones
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x80]).is_none());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x83, 0x00, 0x00, 0x01, 0x00]).is_none());
+        assert!(read_tlv(&[TAG_SEQUENCE, 0x82, 0x01]).is_none());
+        // Wrong tag
+        assert!(expect(&[TAG_INTEGER, 0x01, 0x00], TAG_SEQUENCE).is_none());
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        assert!(decode_integer(&[]).is_none());
+        assert!(decode_integer(&[0x01; 9]).is_none());
+        assert!(decode_oid(&[]).is_none());
+        // Unterminated arc
+        assert!(decode_oid(&[0x2b, 0x86]).is_none());
+        // Arc that doesn't fit into 32 bits
+        assert!(decode_oid(&[0x2b, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).is_none());
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        for value in [0, 1, 127, 128, 256, -1, -128, -129, i64::MAX, i64::MIN] {
+            assert_eq!(decode_integer(&encode_integer(value)), Some(value));
+        }
+        assert_eq!(encode_integer(128), [0x00, 0x80]);
+        assert_eq!(encode_integer(-128), [0x80]);
+    }
+
+    #[test]
+    fn oids_round_trip() {
+        let oid = [1, 3, 6, 1, 4, 1, u32::MAX, 1, 0];
+        assert_eq!(decode_oid(&encode_oid(&oid)).unwrap(), oid);
+    }
+
+    #[test]
+    fn long_content_round_trips() {
+        let content = [0x55; 300];
+        let encoded = tlv(TAG_OCTET_STRING, &content);
+        assert_eq!(&encoded[..4], [TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+        assert_eq!(
+            read_tlv(&encoded),
+            Some((TAG_OCTET_STRING, content.as_slice(), [].as_slice()))
+        );
+    }
+}
@@ -0,0 +1,43 @@
+//! Shared SPI bus for sensors that don't speak I²C.
+//!
+//! All SPI sensors share SCLK, SDO (MOSI) and SDI (MISO), every sensor has its own chip select
+//! pin. Since SPI sensors are often used for several measurement points (e.g. one thermocouple in
+//! the oven and one in the boiler), every sensor is wrapped in a [`Channel`] with a tag that
+//! identifies it in the submitted measurements.
+//!
+//! [`Channel`]: crate::channel::Channel
+
+use std::sync::Arc;
+
+use esp_idf_hal::{
+    gpio::{AnyOutputPin, InputPin, OutputPin},
+    peripheral::Peripheral,
+    spi::{config::Config, Dma, SpiAnyPins, SpiDeviceDriver, SpiDriver},
+};
+use esp_idf_sys::EspError;
+
+/// An SPI device on the shared bus
+pub type SpiDevice<'d> = SpiDeviceDriver<'d, Arc<SpiDriver<'d>>>;
+
+pub struct SpiBus<'d> {
+    driver: Arc<SpiDriver<'d>>,
+}
+
+impl<'d> SpiBus<'d> {
+    pub fn new<SPI: SpiAnyPins>(
+        spi: impl Peripheral<P = SPI> + 'd,
+        sclk: impl Peripheral<P = impl OutputPin> + 'd,
+        sdo: impl Peripheral<P = impl OutputPin> + 'd,
+        sdi: impl Peripheral<P = impl InputPin + OutputPin> + 'd,
+    ) -> Result<Self, EspError> {
+        let driver = SpiDriver::new(spi, sclk, sdo, Some(sdi), Dma::Disabled)?;
+        Ok(Self {
+            driver: Arc::new(driver),
+        })
+    }
+
+    /// Create a device on this bus, with the specified chip select pin.
+    pub fn device(&self, cs: AnyOutputPin, config: &Config) -> Result<SpiDevice<'d>, EspError> {
+        SpiDeviceDriver::new(self.driver.clone(), Some(cs), config)
+    }
+}
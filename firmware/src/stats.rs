@@ -0,0 +1,90 @@
+//! FreeRTOS task and heap statistics, for diagnosing stack overflows and memory exhaustion.
+//!
+//! The task list needs `CONFIG_FREERTOS_USE_TRACE_FACILITY` (see `sdkconfig.defaults`).
+
+use std::ffi::CStr;
+
+use esp_idf_sys::{
+    eTaskState, eTaskState_eBlocked, eTaskState_eDeleted, eTaskState_eReady, eTaskState_eRunning,
+    eTaskState_eSuspended, TaskStatus_t, MALLOC_CAP_8BIT,
+};
+
+/// Statistics of a task
+pub struct Task {
+    pub name: String,
+    pub state: &'static str,
+    pub priority: u32,
+    /// Minimum amount of free stack since the task was started, in bytes
+    pub stack_high_water_mark: u32,
+}
+
+/// Return the statistics of all tasks, sorted by the free stack (least first).
+pub fn tasks() -> Vec<Task> {
+    // Reserve some room for tasks that are created in the meantime
+    let capacity = unsafe { esp_idf_sys::uxTaskGetNumberOfTasks() } + 4;
+    let mut statuses: Vec<TaskStatus_t> = Vec::with_capacity(capacity as usize);
+    // Safety: The buffer has room for `capacity` entries, the returned count is never larger.
+    let count = unsafe {
+        esp_idf_sys::uxTaskGetSystemState(statuses.as_mut_ptr(), capacity, std::ptr::null_mut())
+    };
+    unsafe { statuses.set_len(count as usize) };
+
+    let mut tasks: Vec<Task> = statuses
+        .iter()
+        .map(|status| Task {
+            // Safety: Task names are null-terminated and live as long as the task
+            name: unsafe { CStr::from_ptr(status.pcTaskName) }
+                .to_string_lossy()
+                .into_owned(),
+            state: state_name(status.eCurrentState),
+            priority: status.uxCurrentPriority,
+            stack_high_water_mark: status.usStackHighWaterMark,
+        })
+        .collect();
+    tasks.sort_by_key(|task| task.stack_high_water_mark);
+    tasks
+}
+
+#[allow(non_upper_case_globals)]
+fn state_name(state: eTaskState) -> &'static str {
+    match state {
+        eTaskState_eRunning => "running",
+        eTaskState_eReady => "ready",
+        eTaskState_eBlocked => "blocked",
+        eTaskState_eSuspended => "suspended",
+        eTaskState_eDeleted => "deleted",
+        _ => "invalid",
+    }
+}
+
+/// Heap statistics in bytes
+pub struct Heap {
+    pub total: usize,
+    pub free: usize,
+    /// Minimum amount of free heap since boot
+    pub minimum_free: usize,
+    pub largest_free_block: usize,
+}
+
+impl Heap {
+    /// Return the fragmentation in percent: The share of the free heap that is not part of the
+    /// largest free block, i.e. that can't be used for a single large allocation.
+    pub fn fragmentation(&self) -> f32 {
+        if self.free == 0 {
+            return 0.0;
+        }
+        (1.0 - self.largest_free_block as f32 / self.free as f32) * 100.0
+    }
+}
+
+/// Return the statistics of the byte-addressable heap.
+pub fn heap() -> Heap {
+    unsafe {
+        Heap {
+            total: esp_idf_sys::heap_caps_get_total_size(MALLOC_CAP_8BIT),
+            free: esp_idf_sys::heap_caps_get_free_size(MALLOC_CAP_8BIT),
+            minimum_free: esp_idf_sys::heap_caps_get_minimum_free_size(MALLOC_CAP_8BIT),
+            largest_free_block: esp_idf_sys::heap_caps_get_largest_free_block(MALLOC_CAP_8BIT),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Demand-controlled ventilation: A fan is driven through a PWM output, with a PI controller that
+//! keeps a measured value (e.g. the CO₂ concentration or the humidity) at a setpoint.
+//!
+//! The controller is updated once per measurement cycle, with the latest value. If the value is
+//! missing (e.g. because the sensor could not be read), the output is kept. The fan speed is
+//! submitted with the measurements, so the controller can be tuned from the logged data.
+
+use std::time::Instant;
+
+use esp_idf_hal::{
+    gpio::AnyOutputPin,
+    ledc::{config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver},
+    peripheral::Peripheral,
+    units::FromValueType,
+};
+use esp_idf_sys::EspError;
+
+use crate::config;
+
+/// A controller update
+#[derive(Debug, Copy, Clone)]
+pub struct Measurement {
+    /// Controlled value (in the unit of the metric)
+    pub value: f32,
+    /// Setpoint (in the unit of the metric)
+    pub setpoint: f32,
+    /// Fan speed in percent
+    pub output: f32,
+}
+
+/// A PI controller with output limits.
+///
+/// The controller is direct-acting: The output increases while the value is above the setpoint.
+/// The integral is only accumulated while the output is not limited, so that it doesn't wind up
+/// while the fan is already running at full speed (or is stopped).
+struct PiController {
+    setpoint: f32,
+    kp: f32,
+    ki: f32,
+    min_output: f32,
+    max_output: f32,
+    /// Integral term, in percent
+    integral: f32,
+}
+
+impl PiController {
+    fn new(config: &config::Ventilation) -> Self {
+        Self {
+            setpoint: config.setpoint,
+            kp: config.kp,
+            ki: config.ki,
+            min_output: config.min_output,
+            max_output: config.max_output,
+            integral: 0.0,
+        }
+    }
+
+    /// Update the controller with a new value, `dt_secs` seconds after the previous one. Return
+    /// the new output in percent.
+    fn update(&mut self, value: f32, dt_secs: f32) -> f32 {
+        let error = value - self.setpoint;
+        let integral = self.integral + self.ki * error * dt_secs;
+        let output = self.kp * error + integral;
+        if (self.min_output..=self.max_output).contains(&output) {
+            self.integral = integral;
+        }
+        (self.kp * error + self.integral).clamp(self.min_output, self.max_output)
+    }
+}
+
+pub struct Ventilation<'d> {
+    /// PWM output, the duty cycle is the fan speed
+    pwm: LedcDriver<'d>,
+    controller: PiController,
+    metric: config::Metric,
+    /// Time of the last controller update
+    last_update: Option<Instant>,
+}
+
+impl<'d> Ventilation<'d> {
+    /// Create a new instance. The fan is stopped (or at the minimal speed) until the first update.
+    pub fn new<T: LedcTimer, C: LedcChannel>(
+        timer: impl Peripheral<P = T> + 'd,
+        channel: impl Peripheral<P = C> + 'd,
+        pin: AnyOutputPin,
+        config: &config::Ventilation,
+    ) -> Result<Self, EspError> {
+        let timer = LedcTimerDriver::new(
+            timer,
+            &TimerConfig::new().frequency(config.pwm_frequency.Hz()),
+        )?;
+        let pwm = LedcDriver::new(channel, timer, pin)?;
+        let mut ventilation = Self {
+            pwm,
+            controller: PiController::new(config),
+            metric: config.metric,
+            last_update: None,
+        };
+        ventilation.set_output(config.min_output)?;
+        Ok(ventilation)
+    }
+
+    /// Return the metric that is controlled.
+    pub fn metric(&self) -> config::Metric {
+        self.metric
+    }
+
+    /// Update the controller with the latest value and set the fan speed.
+    pub fn update(&mut self, value: f32) -> Result<Measurement, EspError> {
+        let now = Instant::now();
+        // The first update only uses the proportional term
+        let dt_secs = self
+            .last_update
+            .map(|last| now.duration_since(last).as_secs_f32())
+            .unwrap_or(0.0);
+        self.last_update = Some(now);
+        let output = self.controller.update(value, dt_secs);
+        self.set_output(output)?;
+        Ok(Measurement {
+            value,
+            setpoint: self.controller.setpoint,
+            output,
+        })
+    }
+
+    /// Stop the fan, e.g. before a restart.
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        self.set_output(0.0)
+    }
+
+    /// Set the fan speed in percent.
+    fn set_output(&mut self, percent: f32) -> Result<(), EspError> {
+        let duty = self.pwm.get_max_duty() as f32 * percent / 100.0;
+        self.pwm.set_duty(duty.round() as u32)
+    }
+}
@@ -0,0 +1,47 @@
+//! Suppression of the first readings after a sensor was powered up.
+//!
+//! Some sensors (e.g. the VEML7700 and the SHTC3) frequently return garbage in the first one or
+//! two readings after being enabled. The warm-up state machine discards a configurable number of
+//! samples before readings are accepted.
+
+/// Warm-up state of a sensor
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    /// The sensor was (re-)powered, the specified number of samples still needs to be discarded
+    WarmingUp { remaining: u8 },
+    /// The sensor is warmed up, all readings are accepted
+    Ready,
+}
+
+pub struct Warmup {
+    state: State,
+}
+
+impl Warmup {
+    /// Create a new instance for a sensor that was just powered up, discarding the first
+    /// `discard` samples.
+    pub fn new(discard: u8) -> Self {
+        let state = match discard {
+            0 => State::Ready,
+            remaining => State::WarmingUp { remaining },
+        };
+        Self { state }
+    }
+
+    /// Process a new sample. Return whether it should be used, or discarded because the sensor is
+    /// still warming up.
+    pub fn accept(&mut self) -> bool {
+        match self.state {
+            State::Ready => true,
+            State::WarmingUp { remaining } => {
+                self.state = match remaining {
+                    1 => State::Ready,
+                    _ => State::WarmingUp {
+                        remaining: remaining - 1,
+                    },
+                };
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Software watchdog for the main loop and the gas sensor timer task.
+//!
+//! Every monitored task owns a [`Heartbeat`] with a monotonically increasing counter. The task
+//! beats whenever it made progress, and announces until when it will beat again. A periodic timer
+//! task checks all heartbeats and restarts the device if one of them is overdue, e.g. if the main
+//! loop hangs in a network call while the timer tasks are still alive. The shutdown hooks run
+//! before the restart (see [`crate::shutdown`]).
+//!
+//! The counters are also submitted in the `diagnostics` measurement, so that a partially hung
+//! device can be detected server-side as well.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+use esp_idf_sys::EspError;
+
+use crate::{eventlog, shutdown};
+
+/// Interval at which the heartbeats are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct State {
+    count: u32,
+    /// Time by which the next beat is expected
+    deadline: Instant,
+}
+
+pub struct Heartbeat {
+    name: &'static str,
+    state: Mutex<State>,
+}
+
+impl Heartbeat {
+    /// Create a new heartbeat. The first beat is expected within `timeout`.
+    pub fn new(name: &'static str, timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            state: Mutex::new(State {
+                count: 0,
+                deadline: Instant::now() + timeout,
+            }),
+        })
+    }
+
+    /// Signal progress. The next beat is expected within `timeout`.
+    pub fn beat(&self, timeout: Duration) {
+        let mut state = self.state.lock().expect("Failed to lock heartbeat mutex");
+        state.count = state.count.wrapping_add(1);
+        state.deadline = Instant::now() + timeout;
+    }
+
+    /// Return the number of beats since the start.
+    pub fn count(&self) -> u32 {
+        self.state
+            .lock()
+            .expect("Failed to lock heartbeat mutex")
+            .count
+    }
+
+    /// Return by how much the next beat is overdue, or `None` if it's not overdue.
+    fn overdue(&self) -> Option<Duration> {
+        let state = self.state.lock().expect("Failed to lock heartbeat mutex");
+        Instant::now().checked_duration_since(state.deadline)
+    }
+}
+
+/// Start checking the heartbeats in a periodic timer task. The task is cancelled when the returned
+/// timer is dropped.
+pub fn start(heartbeats: Vec<Arc<Heartbeat>>) -> Result<EspTimer, EspError> {
+    let timer = EspTaskTimerService::new()?.timer(move || {
+        for heartbeat in heartbeats.iter() {
+            if let Some(overdue) = heartbeat.overdue() {
+                eprintln!(
+                    "Watchdog: {} is overdue by {}s (after {} beats), restarting",
+                    heartbeat.name,
+                    overdue.as_secs(),
+                    heartbeat.count()
+                );
+                eventlog::record(
+                    eventlog::Kind::Watchdog,
+                    &format!("{} overdue by {}s", heartbeat.name, overdue.as_secs()),
+                );
+                shutdown::restart();
+            }
+        }
+    })?;
+    timer.every(CHECK_INTERVAL)?;
+    Ok(timer)
+}
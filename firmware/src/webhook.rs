@@ -0,0 +1,105 @@
+//! Outgoing HTTP requests to notification services (e.g. ntfy.sh, Pushover, Slack or Telegram
+//! bots), with a minimal template syntax for the request body.
+//!
+//! Placeholders have the form `{{name}}` (double braces, so that they don't clash with JSON) and
+//! are replaced with the value of the variable. See [`VARIABLES`] for the available variables.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use embedded_svc::{
+    http::{client::Client as HttpClient, Method, Status},
+    io::{Read, Write},
+};
+use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+
+/// Template variables
+pub const VARIABLES: [&str; 6] = ["device", "rule", "active", "metric", "value", "threshold"];
+
+/// Replace the placeholders in a template. `value` returns the value of a variable.
+///
+/// Unknown placeholders are kept as they are (the config validation rejects them, see
+/// [`placeholders`]).
+pub fn render(template: &str, value: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match value(name) {
+                    Some(value) => rendered.push_str(&value),
+                    None => rendered.push_str(&rest[start..start + 2 + end + 2]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Return the names of all placeholders in a template.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                names.push(after[..end].trim());
+                rest = &after[end + 2..];
+            }
+            None => break,
+        }
+    }
+    names
+}
+
+/// Certificate bundle hook of the HTTP client, needed for HTTPS support. The minimal build has no
+/// certificate bundle (see `sdkconfig.minimal`).
+pub fn crt_bundle_attach(
+) -> Option<unsafe extern "C" fn(*mut core::ffi::c_void) -> esp_idf_sys::esp_err_t> {
+    #[cfg(not(feature = "minimal"))]
+    return Some(esp_idf_sys::esp_crt_bundle_attach);
+    #[cfg(feature = "minimal")]
+    return None;
+}
+
+/// Send an HTTP request. Fail if the server doesn't respond with a success status (2xx).
+pub fn send(method: Method, url: &str, headers: &[(&str, &str)], body: &str) -> anyhow::Result<()> {
+    #[cfg(feature = "faults")]
+    crate::faults::http()?;
+    let mut client = HttpClient::wrap(EspHttpConnection::new(&HttpConfiguration {
+        timeout: Some(Duration::from_secs(10)),
+        crt_bundle_attach: crt_bundle_attach(),
+        ..Default::default()
+    })?);
+
+    let content_length_header = format!("{}", body.len());
+    let mut all_headers = vec![
+        ("content-length", content_length_header.as_str()),
+        ("connection", "close"),
+    ];
+    all_headers.extend_from_slice(headers);
+
+    let mut request = client.request(method, url, &all_headers)?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+
+    let mut response = request.submit()?;
+    let status = response.status();
+    // Drain the response
+    let mut buf = [0u8; 256];
+    while response.read(&mut buf)? > 0 {}
+    if !(200..300).contains(&status) {
+        bail!("Server returned HTTP {}", status);
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+//! Commission a fleet of devices with one command.
+//!
+//! The devices are listed in a CSV file with a header row. The `name` column is required, the
+//! optional `serial` column holds the serial numbers. All other columns are dotted config keys
+//! (e.g. `api.token` or `sinks.influxdb.api_token`) with a per-device value:
+//!
+//! ```text
+//! name,serial,api.token
+//! livingroom,SN-0001,secret1
+//! kitchen,SN-0002,secret2
+//! ```
+//!
+//! Quoting is not supported, values must not contain commas. Empty lines and lines starting with
+//! `#` are ignored. The values are strings, empty values keep the value of the shared config.
+//!
+//! For every device, an NVS image is generated and flashed with esptool (together with the
+//! firmware, if given). The devices are flashed one after the other, the user is prompted to
+//! connect the next one. Finally, the MAC addresses reported by esptool are listed, e.g. for
+//! DHCP reservations.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, ensure, Context};
+
+use crate::{image, load_config, parse_size, set, DEFAULT_PARTITION_SIZE};
+
+/// Flash offset of the NVS partition in the default partition table
+const NVS_OFFSET: &str = "0x9000";
+
+/// Flash offset of a merged firmware image (bootloader, partition table and app)
+const FIRMWARE_OFFSET: &str = "0x0";
+
+pub struct Args {
+    config: PathBuf,
+    devices: PathBuf,
+    secrets: Option<PathBuf>,
+    size: usize,
+    firmware: Option<PathBuf>,
+    port: Option<String>,
+    esptool: String,
+    out: PathBuf,
+}
+
+struct Device {
+    name: String,
+    serial: Option<String>,
+    /// Config keys and values
+    values: Vec<(String, String)>,
+}
+
+/// Result of flashing a device
+enum Outcome {
+    Flashed { mac: Option<String> },
+    Failed(anyhow::Error),
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut paths = Vec::new();
+    let mut secrets = None;
+    let mut size = DEFAULT_PARTITION_SIZE;
+    let mut firmware = None;
+    let mut port = None;
+    let mut esptool = "esptool.py".to_string();
+    let mut out = PathBuf::from("fleet");
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--secrets" => secrets = Some(PathBuf::from(value()?)),
+            "--size" => size = parse_size(&value()?)?,
+            "--firmware" => firmware = Some(PathBuf::from(value()?)),
+            "--port" => port = Some(value()?),
+            "--esptool" => esptool = value()?,
+            "--out" => out = PathBuf::from(value()?),
+            _ if arg.starts_with('-') => bail!("Unknown option: {}", arg),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let (config, devices) = match <[PathBuf; 2]>::try_from(paths) {
+        Ok([config, devices]) => (config, devices),
+        Err(_) => bail!("Expected a config file and a devices file"),
+    };
+    Ok(Args {
+        config,
+        devices,
+        secrets,
+        size,
+        firmware,
+        port,
+        esptool,
+        out,
+    })
+}
+
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let config = load_config(&args.config, args.secrets.as_deref())?;
+    let devices = read_devices(&args.devices)?;
+
+    // Generate all images first, so that an invalid config is detected before anything is flashed
+    fs::create_dir_all(&args.out)
+        .with_context(|| format!("Could not create {}", args.out.display()))?;
+    let mut images = Vec::with_capacity(devices.len());
+    for device in devices.iter() {
+        let mut config = config.clone();
+        set(&mut config, "name", &device.name)?;
+        for (key, value) in device.values.iter() {
+            set(&mut config, key, value)
+                .with_context(|| format!("Invalid value for {}", device.name))?;
+        }
+        let (bytes, _) = image(&config, device.serial.as_deref(), args.size)
+            .with_context(|| format!("Invalid config for {}", device.name))?;
+        let path = args.out.join(format!("{}.bin", device.name));
+        fs::write(&path, bytes).with_context(|| format!("Could not write {}", path.display()))?;
+        images.push(path);
+    }
+    println!(
+        "Generated {} images in {}",
+        images.len(),
+        args.out.display()
+    );
+
+    let mut outcomes = Vec::with_capacity(devices.len());
+    for (i, (device, image)) in devices.iter().zip(images.iter()).enumerate() {
+        prompt(&format!(
+            "\n[{}/{}] Connect {} and press Enter",
+            i + 1,
+            devices.len(),
+            device.name
+        ))?;
+        let outcome = match flash(args, image) {
+            Ok(mac) => {
+                println!("Flashed {}", device.name);
+                Outcome::Flashed { mac }
+            }
+            Err(e) => {
+                eprintln!("Error: Could not flash {}: {:#}", device.name, e);
+                Outcome::Failed(e)
+            }
+        };
+        outcomes.push(outcome);
+    }
+
+    println!("\nSummary:");
+    let mut failed = 0;
+    for (device, outcome) in devices.iter().zip(outcomes.iter()) {
+        match outcome {
+            Outcome::Flashed { mac } => println!(
+                "  {:20} {}",
+                device.name,
+                mac.as_deref().unwrap_or("unknown MAC")
+            ),
+            Outcome::Failed(e) => {
+                failed += 1;
+                println!("  {:20} failed: {}", device.name, e);
+            }
+        }
+    }
+    ensure!(
+        failed == 0,
+        "{} of {} devices failed",
+        failed,
+        devices.len()
+    );
+    Ok(())
+}
+
+fn read_devices(path: &Path) -> anyhow::Result<Vec<Device>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    let header: Vec<&str> = match lines.next() {
+        Some((_, line)) => line.split(',').map(str::trim).collect(),
+        None => bail!("{} is empty", path.display()),
+    };
+    let name_column = header
+        .iter()
+        .position(|column| *column == "name")
+        .with_context(|| format!("{}: Missing name column", path.display()))?;
+    let serial_column = header.iter().position(|column| *column == "serial");
+
+    let mut devices: Vec<Device> = Vec::new();
+    for (number, line) in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        ensure!(
+            fields.len() == header.len(),
+            "{}:{}: Expected {} fields, found {}",
+            path.display(),
+            number,
+            header.len(),
+            fields.len()
+        );
+        let name = fields[name_column];
+        ensure!(
+            !name.is_empty(),
+            "{}:{}: Empty name",
+            path.display(),
+            number
+        );
+        ensure!(
+            devices.iter().all(|device| device.name != name),
+            "{}:{}: Duplicate name {}",
+            path.display(),
+            number,
+            name
+        );
+        let values = header
+            .iter()
+            .zip(fields.iter())
+            .enumerate()
+            // Empty fields keep the value of the shared config
+            .filter(|(i, (_, value))| {
+                *i != name_column && Some(*i) != serial_column && !value.is_empty()
+            })
+            .map(|(_, (key, value))| (key.to_string(), value.to_string()))
+            .collect();
+        let serial = serial_column
+            .map(|column| fields[column])
+            .filter(|serial| !serial.is_empty());
+        if let Some(serial) = serial {
+            ensure!(
+                devices
+                    .iter()
+                    .all(|device| device.serial.as_deref() != Some(serial)),
+                "{}:{}: Duplicate serial number {}",
+                path.display(),
+                number,
+                serial
+            );
+        }
+        devices.push(Device {
+            name: name.to_string(),
+            serial: serial.map(str::to_string),
+            values,
+        });
+    }
+    ensure!(!devices.is_empty(), "{}: No devices", path.display());
+    Ok(devices)
+}
+
+/// Print a message and wait for Enter.
+fn prompt(message: &str) -> anyhow::Result<()> {
+    print!("{} ", message);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line)?;
+    ensure!(read > 0, "Aborted");
+    Ok(())
+}
+
+/// Flash an NVS image (and the firmware) with esptool, return the MAC address of the device.
+fn flash(args: &Args, image: &Path) -> anyhow::Result<Option<String>> {
+    let mut command = Command::new(&args.esptool);
+    if let Some(ref port) = args.port {
+        command.args(["--port", port]);
+    }
+    command.args(["--chip", "esp32c3", "write_flash"]);
+    if let Some(ref firmware) = args.firmware {
+        command.arg(FIRMWARE_OFFSET).arg(firmware);
+    }
+    command.arg(NVS_OFFSET).arg(image);
+
+    let output = command
+        .output()
+        .with_context(|| format!("Could not run {}", args.esptool))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // The last line is usually the error message, in stderr or stdout depending on the version
+        let last_line = |text: &str| {
+            text.lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .map(str::to_string)
+        };
+        let message = last_line(&stderr)
+            .or_else(|| last_line(&stdout))
+            .unwrap_or_else(|| "no output".to_string());
+        bail!("esptool failed ({}): {}", output.status, message.trim());
+    }
+    Ok(stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("MAC: "))
+        .map(str::to_string))
+}
@@ -0,0 +1,262 @@
+//! Generate an NVS partition image with the config of a Sensilo device.
+//!
+//! The config is validated against the config schema of the firmware, merged with an optional
+//! secrets file (so that the config itself can be shared and versioned) and written to the
+//! `config` NVS namespace, where the firmware loads it from at boot. The image can be flashed
+//! together with the firmware, e.g. with `espflash write-bin 0x9000 nvs.bin`.
+//!
+//! In the `flash-fleet` mode, an image is generated for every device of a CSV file and flashed
+//! with esptool, one device after the other (see [`fleet`]).
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, ensure, Context};
+
+mod fleet;
+mod nvs;
+
+#[allow(dead_code)]
+#[path = "../../firmware/src/config/schema.rs"]
+mod schema;
+
+/// NVS namespace and key of the config, as used by the firmware
+const NAMESPACE: &str = "config";
+const KEY: &str = "toml";
+
+/// NVS namespace and key of the serial number, as used by the firmware
+const SERIAL_NAMESPACE: &str = "device";
+const SERIAL_KEY: &str = "serial";
+
+/// Maximum length of a serial number, as accepted by the firmware
+const MAX_SERIAL_LENGTH: usize = 32;
+
+/// Maximum size of the config in bytes, as accepted by the firmware
+const MAX_SIZE: usize = 4096;
+
+/// Size of the NVS partition in the default partition table
+const DEFAULT_PARTITION_SIZE: usize = 0x6000;
+
+const USAGE: &str = "\
+Usage: sensilo-provision [options] <config.toml> <nvs.bin>
+       sensilo-provision flash-fleet [options] [fleet options] <config.toml> <devices.csv>
+
+Options:
+  --secrets <file>  TOML file with secrets (e.g. API tokens), merged into the config
+  --name <name>     Device name, overrides the name in the config
+  --serial <id>     Serial number (asset tag) of the device
+  --size <bytes>    Size of the NVS partition (default: 0x6000)
+
+Fleet options:
+  --firmware <bin>  Merged firmware image, flashed at 0x0 (default: only the config is flashed)
+  --port <port>     Serial port of the devices (default: detected by esptool)
+  --esptool <cmd>   esptool command (default: esptool.py)
+  --out <dir>       Directory for the generated images (default: fleet)";
+
+struct Args {
+    config: PathBuf,
+    output: PathBuf,
+    secrets: Option<PathBuf>,
+    name: Option<String>,
+    serial: Option<String>,
+    size: usize,
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    let result = if args.peek().map(String::as_str) == Some("flash-fleet") {
+        args.next();
+        fleet::parse_args(args).map(|args| fleet::run(&args))
+    } else {
+        parse_args(args).map(|args| run(&args))
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error: {:#}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut paths = Vec::new();
+    let mut secrets = None;
+    let mut name = None;
+    let mut serial = None;
+    let mut size = DEFAULT_PARTITION_SIZE;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--secrets" => secrets = Some(PathBuf::from(value()?)),
+            "--name" => name = Some(value()?),
+            "--serial" => serial = Some(value()?),
+            "--size" => size = parse_size(&value()?)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => bail!("Unknown option: {}", arg),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let (config, output) = match <[PathBuf; 2]>::try_from(paths) {
+        Ok([config, output]) => (config, output),
+        Err(_) => bail!("Expected a config file and an output file"),
+    };
+    Ok(Args {
+        config,
+        output,
+        secrets,
+        name,
+        serial,
+        size,
+    })
+}
+
+/// Parse a size in bytes, decimal or hexadecimal (`0x` prefix).
+fn parse_size(text: &str) -> anyhow::Result<usize> {
+    let size = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    size.with_context(|| format!("Invalid size: {}", text))
+}
+
+fn run(args: &Args) -> anyhow::Result<()> {
+    let mut config = load_config(&args.config, args.secrets.as_deref())?;
+    if let Some(ref name) = args.name {
+        set(&mut config, "name", name)?;
+    }
+    let (bytes, config_size) = image(&config, args.serial.as_deref(), args.size)?;
+    fs::write(&args.output, bytes)
+        .with_context(|| format!("Could not write {}", args.output.display()))?;
+    println!(
+        "Wrote {} ({} bytes config, {} bytes partition)",
+        args.output.display(),
+        config_size,
+        args.size
+    );
+    Ok(())
+}
+
+/// Read a config and merge the secrets into it.
+fn load_config(path: &Path, secrets: Option<&Path>) -> anyhow::Result<toml::Value> {
+    let mut config = read_toml(path)?;
+    if let Some(secrets) = secrets {
+        merge(&mut config, read_toml(secrets)?);
+    }
+    Ok(config)
+}
+
+/// Set the value at a dotted key path (e.g. `api.token`), creating the tables on the way.
+fn set(config: &mut toml::Value, path: &str, value: &str) -> anyhow::Result<()> {
+    let mut table = config
+        .as_table_mut()
+        .context("The config must be a table")?;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if keys.peek().is_none() {
+            table.insert(key.into(), value.into());
+            break;
+        }
+        table = table
+            .entry(key.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .with_context(|| format!("{} is not a table", key))?;
+    }
+    Ok(())
+}
+
+/// Validate a config and generate an NVS image of `size` bytes with it (and the serial number, if
+/// set). Returns the image and the size of the serialized config.
+fn image(
+    config: &toml::Value,
+    serial: Option<&str>,
+    size: usize,
+) -> anyhow::Result<(Vec<u8>, usize)> {
+    // Check against the schema. The remaining validation (e.g. value ranges) happens on the
+    // device, which falls back to the defaults if the config is invalid.
+    let version = config
+        .get("version")
+        .and_then(|version| version.as_integer());
+    ensure!(
+        version == Some(schema::VERSION.into()),
+        "Invalid config: version: Must be the current version {}",
+        schema::VERSION
+    );
+    config
+        .clone()
+        .try_into::<schema::Config>()
+        .context("Invalid config")?;
+
+    let text = toml::to_string(config).context("Could not serialize config")?;
+    ensure!(
+        text.len() <= MAX_SIZE,
+        "Config is too large ({} bytes, max {})",
+        text.len(),
+        MAX_SIZE
+    );
+
+    let mut image = nvs::Image::new();
+    let namespace = image.namespace(NAMESPACE)?;
+    image.blob(namespace, KEY, text.as_bytes())?;
+    if let Some(serial) = serial {
+        check_serial(serial)?;
+        let namespace = image.namespace(SERIAL_NAMESPACE)?;
+        image.blob(namespace, SERIAL_KEY, serial.as_bytes())?;
+    }
+    Ok((image.to_bytes(size)?, text.len()))
+}
+
+/// Check that a serial number can be used as tag value, like the firmware does.
+fn check_serial(serial: &str) -> anyhow::Result<()> {
+    ensure!(
+        !serial.is_empty() && serial.len() <= MAX_SERIAL_LENGTH,
+        "Invalid serial number: Must be 1-{} characters long",
+        MAX_SERIAL_LENGTH
+    );
+    ensure!(
+        serial
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !b",=\\".contains(&byte)),
+        "Invalid serial number: Only printable ASCII characters without spaces, commas, equal \
+        signs and backslashes are allowed"
+    );
+    Ok(())
+}
+
+fn read_toml(path: &Path) -> anyhow::Result<toml::Value> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    text.parse::<toml::Value>()
+        .with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Merge `overlay` into `base`. Tables are merged recursively, other values are replaced.
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
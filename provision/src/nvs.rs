@@ -0,0 +1,234 @@
+//! Writer for ESP-IDF NVS partition images (format version 2, as written by
+//! `nvs_partition_gen.py`).
+//!
+//! A partition consists of 4 KiB pages. Every page starts with a 32 byte header and a 32 byte
+//! bitmap with the state of its 126 entries, followed by the entries (32 bytes each). Values
+//! that don't fit into an entry (strings and blobs) are stored in the entries directly following
+//! the header entry. Blobs are split into chunks that fit into a page, followed by a blob index
+//! entry.
+
+use anyhow::{bail, ensure};
+
+/// Size of a flash page
+const PAGE_SIZE: usize = 4096;
+
+/// Size of an entry
+const ENTRY_SIZE: usize = 32;
+
+/// Number of entries per page
+const ENTRIES_PER_PAGE: usize = 126;
+
+/// Page states
+const PAGE_STATE_ACTIVE: u32 = 0xffff_fffe;
+const PAGE_STATE_FULL: u32 = 0xffff_fffc;
+
+/// Page format version 2 (multi-page blobs)
+const PAGE_VERSION: u8 = 0xfe;
+
+/// Entry types
+const TYPE_U8: u8 = 0x01;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_INDEX: u8 = 0x48;
+
+/// Chunk index of entries that are not blob chunks
+const CHUNK_NONE: u8 = 0xff;
+
+/// Maximum length of a key (without the terminating null byte)
+const MAX_KEY_LENGTH: usize = 15;
+
+struct Page {
+    entries: Vec<[u8; ENTRY_SIZE]>,
+}
+
+impl Page {
+    fn free(&self) -> usize {
+        ENTRIES_PER_PAGE - self.entries.len()
+    }
+}
+
+/// An NVS partition image
+pub struct Image {
+    pages: Vec<Page>,
+    namespaces: Vec<String>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self {
+            pages: vec![Page {
+                entries: Vec::new(),
+            }],
+            namespaces: Vec::new(),
+        }
+    }
+
+    /// Add a namespace and return its index.
+    pub fn namespace(&mut self, name: &str) -> anyhow::Result<u8> {
+        if let Some(index) = self.namespaces.iter().position(|ns| ns == name) {
+            return Ok(index as u8 + 1);
+        }
+        ensure!(self.namespaces.len() < 254, "Too many namespaces");
+        self.namespaces.push(name.to_string());
+        let index = self.namespaces.len() as u8;
+        let mut data = [0xff; 8];
+        data[0] = index;
+        let entry = header(0, TYPE_U8, 1, CHUNK_NONE, name, data)?;
+        self.reserve(1);
+        self.push(entry);
+        Ok(index)
+    }
+
+    /// Add a blob.
+    pub fn blob(&mut self, namespace: u8, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        let mut rest = value;
+        let mut chunk_count = 0u8;
+        loop {
+            // A chunk needs a header entry and at least one data entry
+            self.reserve(2);
+            let capacity = (self.current().free() - 1) * ENTRY_SIZE;
+            let (chunk, remaining) = rest.split_at(rest.len().min(capacity));
+            let mut data = [0xff; 8];
+            data[0..2].copy_from_slice(&(chunk.len() as u16).to_le_bytes());
+            data[4..8].copy_from_slice(&crc32(chunk).to_le_bytes());
+            let span = 1 + chunk.len().div_ceil(ENTRY_SIZE);
+            let entry = header(
+                namespace,
+                TYPE_BLOB_DATA,
+                span as u8,
+                chunk_count,
+                key,
+                data,
+            )?;
+            self.push(entry);
+            for data_entry in chunk.chunks(ENTRY_SIZE) {
+                let mut padded = [0xff; ENTRY_SIZE];
+                padded[..data_entry.len()].copy_from_slice(data_entry);
+                self.push(padded);
+            }
+            chunk_count = chunk_count
+                .checked_add(1)
+                .filter(|count| *count < 128)
+                .ok_or_else(|| anyhow::anyhow!("Blob {} is too large", key))?;
+            rest = remaining;
+            if rest.is_empty() {
+                break;
+            }
+        }
+
+        let mut data = [0xff; 8];
+        data[0..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        data[4] = chunk_count;
+        data[5] = 0; // Chunk start (version 0)
+        let entry = header(namespace, TYPE_BLOB_INDEX, 1, CHUNK_NONE, key, data)?;
+        self.reserve(1);
+        self.push(entry);
+        Ok(())
+    }
+
+    /// Return the image of a partition of `size` bytes.
+    pub fn to_bytes(&self, size: usize) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            size.is_multiple_of(PAGE_SIZE),
+            "The partition size must be a multiple of {} bytes",
+            PAGE_SIZE
+        );
+        // NVS needs a free page for garbage collection
+        let pages = size / PAGE_SIZE;
+        if self.pages.len() + 1 > pages {
+            bail!(
+                "The data needs {} pages, the partition only has {} (including one free page)",
+                self.pages.len() + 1,
+                pages
+            );
+        }
+
+        let mut image = vec![0xff; size];
+        for (i, page) in self.pages.iter().enumerate() {
+            let bytes = &mut image[i * PAGE_SIZE..(i + 1) * PAGE_SIZE];
+
+            // Header
+            let state = if i + 1 == self.pages.len() {
+                PAGE_STATE_ACTIVE
+            } else {
+                PAGE_STATE_FULL
+            };
+            bytes[0..4].copy_from_slice(&state.to_le_bytes());
+            bytes[4..8].copy_from_slice(&(i as u32).to_le_bytes());
+            bytes[8] = PAGE_VERSION;
+            let crc = crc32(&bytes[4..28]);
+            bytes[28..32].copy_from_slice(&crc.to_le_bytes());
+
+            // Entry state bitmap (2 bits per entry, 0b10 means written) and entries
+            for (j, entry) in page.entries.iter().enumerate() {
+                bytes[32 + j / 4] &= !(1 << (j % 4 * 2));
+                let offset = 64 + j * ENTRY_SIZE;
+                bytes[offset..offset + ENTRY_SIZE].copy_from_slice(entry);
+            }
+        }
+        Ok(image)
+    }
+
+    fn current(&self) -> &Page {
+        self.pages.last().expect("No pages")
+    }
+
+    /// Start a new page if the current one has less than `entries` free entries.
+    fn reserve(&mut self, entries: usize) {
+        if self.current().free() < entries {
+            self.pages.push(Page {
+                entries: Vec::new(),
+            });
+        }
+    }
+
+    fn push(&mut self, entry: [u8; ENTRY_SIZE]) {
+        self.pages.last_mut().expect("No pages").entries.push(entry);
+    }
+}
+
+/// Return a header entry.
+fn header(
+    namespace: u8,
+    entry_type: u8,
+    span: u8,
+    chunk_index: u8,
+    key: &str,
+    data: [u8; 8],
+) -> anyhow::Result<[u8; ENTRY_SIZE]> {
+    ensure!(
+        key.len() <= MAX_KEY_LENGTH,
+        "Key {} is longer than {} bytes",
+        key,
+        MAX_KEY_LENGTH
+    );
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = namespace;
+    entry[1] = entry_type;
+    entry[2] = span;
+    entry[3] = chunk_index;
+    entry[8..8 + key.len()].copy_from_slice(key.as_bytes());
+    entry[24..32].copy_from_slice(&data);
+
+    // The CRC covers everything but the CRC itself
+    let mut crc_data = [0; 28];
+    crc_data[0..4].copy_from_slice(&entry[0..4]);
+    crc_data[4..28].copy_from_slice(&entry[8..32]);
+    entry[4..8].copy_from_slice(&crc32(&crc_data).to_le_bytes());
+    Ok(entry)
+}
+
+/// CRC-32 as used by NVS (`esp_rom_crc32_le` with an initial value of 0xffffffff).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,181 @@
+//! Build options and builds of the firmware, shared by the tasks that build it.
+
+use std::{fs, path::PathBuf, process::Command};
+
+use anyhow::Context;
+
+use crate::{firmware_cargo, firmware_dir, run_command, TARGET};
+
+/// Feature, profile and sdkconfig defaults of the minimal build (see the firmware README)
+const MINIMAL_FEATURE: &str = "minimal";
+const MINIMAL_PROFILE: &str = "minimal";
+const MINIMAL_SDKCONFIG: &str = "sdkconfig.minimal";
+
+/// Features with additional sdkconfig defaults
+const SDKCONFIG_FEATURES: [(&str, &str); 2] = [
+    (MINIMAL_FEATURE, MINIMAL_SDKCONFIG),
+    ("bthome", "sdkconfig.bthome"),
+];
+
+/// Profile of the builds, except for the minimal build
+const DEFAULT_PROFILE: &str = "release";
+
+/// Partition table of the firmware, relative to the firmware directory
+pub const PARTITION_TABLE: &str = "partitions.csv";
+
+pub struct BuildArgs {
+    /// Cargo profile, by default `release` (`minimal` for the minimal build)
+    profile: Option<String>,
+    features: Vec<String>,
+    default_features: bool,
+    minimal: bool,
+}
+
+impl Default for BuildArgs {
+    fn default() -> Self {
+        Self {
+            profile: None,
+            features: Vec::new(),
+            default_features: true,
+            minimal: false,
+        }
+    }
+}
+
+impl BuildArgs {
+    /// Apply a build option, with `args` for its value. Return `false` if `arg` is no build
+    /// option.
+    pub fn parse_option(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> anyhow::Result<bool> {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg {
+            "--profile" => self.profile = Some(value()?),
+            "--features" => self.features.extend(
+                value()?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|feature| !feature.is_empty())
+                    .map(String::from),
+            ),
+            "--no-default-features" => self.default_features = false,
+            "--minimal" => self.minimal = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn profile(&self) -> &str {
+        match self.profile {
+            Some(ref profile) => profile,
+            None if self.is_minimal() => MINIMAL_PROFILE,
+            None => DEFAULT_PROFILE,
+        }
+    }
+
+    pub fn is_minimal(&self) -> bool {
+        self.minimal
+            || self
+                .features
+                .iter()
+                .any(|feature| feature == MINIMAL_FEATURE)
+    }
+
+    /// Return all enabled features explicitly, including the default features.
+    pub fn features(&self, manifest: &toml::Value) -> Vec<String> {
+        let mut features = Vec::new();
+        if self.minimal {
+            // The minimal build can't be combined with other features
+            features.push(MINIMAL_FEATURE.to_string());
+        } else if self.default_features {
+            features.extend(default_features(manifest));
+        }
+        for feature in self.features.iter() {
+            if !features.contains(feature) {
+                features.push(feature.clone());
+            }
+        }
+        features
+    }
+}
+
+/// Build the firmware with exactly `features` and return the path of the ELF file.
+pub fn build(profile: &str, features: &[String]) -> anyhow::Result<PathBuf> {
+    run_command(&mut cargo("build", profile, features)?)?;
+
+    // The dev profile is built into `debug`
+    let profile_dir = if profile == "dev" { "debug" } else { profile };
+    Ok(firmware_dir()
+        .join("target")
+        .join(TARGET)
+        .join(profile_dir)
+        .join("sensilo"))
+}
+
+/// Return the default profile of a build with `features`.
+pub fn default_profile(features: &[String]) -> &'static str {
+    if features.iter().any(|feature| feature == MINIMAL_FEATURE) {
+        MINIMAL_PROFILE
+    } else {
+        DEFAULT_PROFILE
+    }
+}
+
+/// Check the firmware with exactly `features`, without building it.
+pub fn check(profile: &str, features: &[String]) -> anyhow::Result<()> {
+    run_command(&mut cargo("check", profile, features)?)
+}
+
+/// Return a cargo command for the firmware with exactly `features`.
+fn cargo(subcommand: &str, profile: &str, features: &[String]) -> anyhow::Result<Command> {
+    let mut command = firmware_cargo()?;
+    command.args([subcommand, "--profile", profile, "--no-default-features"]);
+    if !features.is_empty() {
+        command.args(["--features", &features.join(",")]);
+    }
+    let mut sdkconfig = vec!["sdkconfig.defaults"];
+    for (feature, defaults) in SDKCONFIG_FEATURES {
+        if features.iter().any(|enabled| enabled == feature) {
+            sdkconfig.push(defaults);
+        }
+    }
+    if sdkconfig.len() > 1 {
+        command.env("ESP_IDF_SDKCONFIG_DEFAULTS", sdkconfig.join(";"));
+    }
+    Ok(command)
+}
+
+/// Build the firmware with the enabled features of `args`.
+pub fn build_enabled(args: &BuildArgs) -> anyhow::Result<PathBuf> {
+    let features = args.features(&read_manifest()?);
+    build(args.profile(), &features)
+}
+
+pub fn read_manifest() -> anyhow::Result<toml::Value> {
+    let path = firmware_dir().join("Cargo.toml");
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    text.parse::<toml::Value>()
+        .with_context(|| format!("Could not parse {}", path.display()))
+}
+
+/// Return the default features of the firmware.
+fn default_features(manifest: &toml::Value) -> Vec<String> {
+    manifest
+        .get("features")
+        .and_then(|features| features.get("default"))
+        .and_then(|default| default.as_array())
+        .map(|default| {
+            default
+                .iter()
+                .filter_map(|feature| feature.as_str())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
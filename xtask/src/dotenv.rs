@@ -0,0 +1,68 @@
+//! Build-time settings of the firmware (WiFi credentials, device name, InfluxDB server) from
+//! `firmware/.env` and `firmware/.env.local`. The firmware migrates them into its NVS config at
+//! the first boot.
+//!
+//! `.env` is versioned with placeholder values, `.env.local` is ignored by git and meant for the
+//! actual credentials. Both contain lines like `export SENSILO_NAME="livingroom"`, so they can
+//! also be sourced by a shell. Variables that are set in the environment take precedence.
+
+use std::{env, fs, io};
+
+use anyhow::{bail, Context};
+
+use crate::firmware_dir;
+
+/// Files with the settings, later ones override earlier ones
+const FILES: [&str; 2] = [".env", ".env.local"];
+
+/// Return the settings of the files that are not set in the environment.
+pub fn load() -> anyhow::Result<Vec<(String, String)>> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for file in FILES {
+        let path = firmware_dir().join(file);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+        for (i, line) in text.lines().enumerate() {
+            let (key, value) = match parse_line(line)
+                .with_context(|| format!("{}:{}: Invalid line", path.display(), i + 1))?
+            {
+                Some((key, _)) if env::var_os(&key).is_some() => continue,
+                Some(var) => var,
+                None => continue,
+            };
+            match vars.iter_mut().find(|(other, _)| *other == key) {
+                Some((_, old)) => *old = value,
+                None => vars.push((key, value)),
+            }
+        }
+    }
+    Ok(vars)
+}
+
+/// Parse `[export] KEY=value`, where the value may be quoted. Return `None` for empty lines and
+/// comments.
+fn parse_line(line: &str) -> anyhow::Result<Option<(String, String)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=').context("Expected KEY=value")?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Invalid variable name {:?}", key);
+    }
+    let value = value.trim();
+    let value = ['"', '\'']
+        .iter()
+        .find_map(|quote| {
+            value
+                .strip_prefix(*quote)
+                .and_then(|value| value.strip_suffix(*quote))
+        })
+        .unwrap_or(value);
+    Ok(Some((key.to_string(), value.to_string())))
+}
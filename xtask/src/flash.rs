@@ -0,0 +1,105 @@
+//! Flashing of the firmware and the serial monitor, with [espflash](https://github.com/esp-rs/espflash)
+//! 2.x.
+
+use std::process::Command;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    build::{self, BuildArgs, PARTITION_TABLE},
+    firmware_dir, run_command,
+};
+
+#[derive(Default)]
+pub struct Args {
+    build: BuildArgs,
+    espflash: Espflash,
+    monitor: bool,
+}
+
+/// Options of the espflash commands
+pub struct Espflash {
+    /// Serial port, detected by espflash if not set
+    port: Option<String>,
+    command: String,
+}
+
+impl Default for Espflash {
+    fn default() -> Self {
+        Self {
+            port: None,
+            command: "espflash".into(),
+        }
+    }
+}
+
+impl Espflash {
+    /// Apply an espflash option, with `args` for its value. Return `false` if `arg` is no
+    /// espflash option.
+    pub fn parse_option(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> anyhow::Result<bool> {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg {
+            "--port" => self.port = Some(value()?),
+            "--espflash" => self.command = value()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Return an espflash command with the subcommand and the port.
+    pub fn command(&self, subcommand: &str) -> Command {
+        let mut command = Command::new(&self.command);
+        command.current_dir(firmware_dir()).arg(subcommand);
+        if let Some(ref port) = self.port {
+            command.args(["--port", port]);
+        }
+        command
+    }
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--monitor" => parsed.monitor = true,
+            _ if parsed.build.parse_option(&arg, &mut args)? => {}
+            _ if parsed.espflash.parse_option(&arg, &mut args)? => {}
+            _ => bail!("Unknown option: {}", arg),
+        }
+    }
+    Ok(parsed)
+}
+
+pub fn parse_monitor_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Espflash> {
+    let mut parsed = Espflash::default();
+    while let Some(arg) = args.next() {
+        if !parsed.parse_option(&arg, &mut args)? {
+            bail!("Unknown option: {}", arg);
+        }
+    }
+    Ok(parsed)
+}
+
+/// Build and flash the firmware, and optionally open the serial monitor.
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let elf = build::build_enabled(&args.build)?;
+    let mut command = args.espflash.command("flash");
+    command.args(["--partition-table", PARTITION_TABLE]);
+    if args.monitor {
+        command.arg("--monitor");
+    }
+    command.arg(&elf);
+    run_command(&mut command)
+}
+
+/// Open the serial monitor.
+pub fn monitor(espflash: &Espflash) -> anyhow::Result<()> {
+    run_command(&mut espflash.command("monitor"))
+}
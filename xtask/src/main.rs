@@ -0,0 +1,147 @@
+//! Developer tasks for the firmware, run with `cargo xtask <task>` from the repository root.
+//!
+//! The tasks call cargo in the `firmware` directory, so that its toolchain and target settings
+//! apply, with the build-time settings of `firmware/.env` and `firmware/.env.local` (see
+//! [`dotenv`]).
+
+use std::{
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use anyhow::{bail, Context};
+
+mod build;
+mod dotenv;
+mod flash;
+mod matrix;
+mod provision;
+mod size;
+
+#[allow(dead_code)]
+#[path = "../../firmware/build/image.rs"]
+mod image;
+
+/// Target of the firmware, as set in `firmware/.cargo/config.toml`
+const TARGET: &str = "riscv32imc-esp-espidf";
+
+const USAGE: &str = "\
+Usage: cargo xtask <task> [options]
+
+Tasks:
+  build [build options]             Build the firmware
+  flash [build options] [options]   Build and flash the firmware
+  matrix [options]                  Check the firmware with the feature combinations of the
+                                    firmware manifest
+  monitor [espflash options]        Open the serial monitor
+  provision [options] <config.toml> Generate an NVS image with the config (and flash it)
+  size [build options] [--total]    Build the firmware and report its flash and static RAM usage,
+                                    in total and per enabled feature. Fails if a budget is
+                                    exceeded. With --total, only the total is reported.
+  help                              Show this help
+
+Build options:
+  --profile <name>         Cargo profile (default: release, minimal for --minimal)
+  --features <features>    Comma separated features, in addition to the default features
+  --no-default-features    Don't enable the default features
+  --minimal                Minimal build for 4 MB modules, with its sdkconfig and profile
+
+Espflash options:
+  --port <port>            Serial port (default: detected by espflash)
+  --espflash <cmd>         espflash command (default: espflash)
+
+Flash options:
+  --monitor                Open the serial monitor after flashing
+  (and the espflash options)
+
+Matrix options:
+  --only <names>           Comma separated combinations to check (default: all)
+  --build                  Build instead of only checking, to catch link errors as well
+  --profile <name>         Cargo profile (default: release, minimal for the minimal build)
+  --fail-fast              Stop at the first failing combination
+
+Provision options:
+  --secrets <file>         TOML file with secrets, merged into the config
+  --name <name>            Device name, overrides the name in the config
+  --serial <id>            Serial number (asset tag) of the device
+  --size <bytes>           Size of the NVS partition (default: 0x6000)
+  --out <nvs.bin>          Path of the image (default: firmware/target/nvs.bin)
+  --flash                  Flash the image to the NVS partition
+  (and the espflash options)";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("build") => parse_build_args(args).map(|args| build::build_enabled(&args).map(drop)),
+        Some("flash") => flash::parse_args(args).map(|args| flash::run(&args)),
+        Some("matrix") => matrix::parse_args(args).map(|args| matrix::run(&args)),
+        Some("monitor") => flash::parse_monitor_args(args).map(|args| flash::monitor(&args)),
+        Some("provision") => provision::parse_args(args).map(|args| provision::run(&args)),
+        Some("size") => size::parse_args(args).map(|args| size::run(&args)),
+        Some("help") | Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        Some(task) => Err(anyhow::anyhow!("Unknown task: {}", task)),
+        None => Err(anyhow::anyhow!("Missing task")),
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Error: {:#}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_build_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<build::BuildArgs> {
+    let mut parsed = build::BuildArgs::default();
+    while let Some(arg) = args.next() {
+        if !parsed.parse_option(&arg, &mut args)? {
+            bail!("Unknown option: {}", arg);
+        }
+    }
+    Ok(parsed)
+}
+
+/// Return the path of the repository root.
+fn repository_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("The xtask crate is in the repository root")
+        .to_path_buf()
+}
+
+/// Return the path of the firmware crate.
+fn firmware_dir() -> PathBuf {
+    repository_dir().join("firmware")
+}
+
+/// Return a cargo command that runs in the firmware directory, with the build-time settings.
+///
+/// Note: The toolchain of the xtask is not passed on, so that the one of the firmware (see
+/// `firmware/rust-toolchain.toml`) is used.
+fn firmware_cargo() -> anyhow::Result<Command> {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(firmware_dir())
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .env_remove("CARGO_TARGET_DIR")
+        .envs(dotenv::load()?);
+    Ok(command)
+}
+
+/// Run a command, fail if it doesn't exit successfully.
+fn run_command(command: &mut Command) -> anyhow::Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("Could not run {:?}", command.get_program()))?;
+    if !status.success() {
+        bail!("{:?} failed ({})", command.get_program(), status);
+    }
+    Ok(())
+}
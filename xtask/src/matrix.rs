@@ -0,0 +1,167 @@
+//! Check the firmware with a curated matrix of feature combinations.
+//!
+//! The sensor drivers and their code are only compiled with the features of the sensors
+//! (`#[cfg(feature = ...)]`), and the default build enables a few features only, so code that
+//! breaks with other combinations (e.g. a `#[cfg]` item that is used without the same condition,
+//! or an import that is unused without a feature) easily goes unnoticed. The
+//! combinations are set in the firmware manifest, so that they are maintained together with the
+//! features:
+//!
+//! ```toml
+//! [[package.metadata.sensilo.matrix]]
+//! name = "climate"
+//! features = ["temp_humi", "pressure", "scd4x"]
+//! ```
+//!
+//! Every combination is checked without the default features. Sinks and power modes are runtime
+//! settings, so they are compiled into every combination.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+
+use crate::build;
+
+#[derive(Default)]
+pub struct Args {
+    /// Names of the combinations to check, all if empty
+    only: Vec<String>,
+    /// Build instead of only checking, to also catch link errors
+    build: bool,
+    profile: Option<String>,
+    /// Stop at the first failure
+    fail_fast: bool,
+}
+
+struct Combination {
+    name: String,
+    features: Vec<String>,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--only" => parsed.only.extend(
+                value()?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from),
+            ),
+            "--build" => parsed.build = true,
+            "--profile" => parsed.profile = Some(value()?),
+            "--fail-fast" => parsed.fail_fast = true,
+            _ => bail!("Unknown option: {}", arg),
+        }
+    }
+    Ok(parsed)
+}
+
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let mut combinations = combinations(&build::read_manifest()?)?;
+    for name in args.only.iter() {
+        if !combinations
+            .iter()
+            .any(|combination| combination.name == *name)
+        {
+            bail!("Unknown combination: {}", name);
+        }
+    }
+    if !args.only.is_empty() {
+        combinations.retain(|combination| args.only.contains(&combination.name));
+    }
+
+    let mut results: Vec<(&Combination, Duration, bool)> = Vec::new();
+    for combination in combinations.iter() {
+        println!(
+            "{} {} ({})",
+            if args.build { "Building" } else { "Checking" },
+            combination.name,
+            if combination.features.is_empty() {
+                "no features".to_string()
+            } else {
+                combination.features.join(",")
+            }
+        );
+        let profile = args
+            .profile
+            .as_deref()
+            .unwrap_or_else(|| build::default_profile(&combination.features));
+        let start = Instant::now();
+        let result = if args.build {
+            build::build(profile, &combination.features).map(drop)
+        } else {
+            build::check(profile, &combination.features)
+        };
+        if let Err(ref e) = result {
+            eprintln!("Error: {:#}", e);
+        }
+        results.push((combination, start.elapsed(), result.is_ok()));
+        if result.is_err() && args.fail_fast {
+            break;
+        }
+    }
+
+    println!();
+    println!("{:<16} {:>8}  Result", "Combination", "Time");
+    for (combination, duration, ok) in results.iter() {
+        println!(
+            "{:<16} {:>7}s  {}",
+            combination.name,
+            duration.as_secs(),
+            if *ok { "ok" } else { "FAILED" }
+        );
+    }
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|(_, _, ok)| !ok)
+        .map(|(combination, _, _)| combination.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        bail!("Failed combinations: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Return the combinations of `package.metadata.sensilo.matrix`, in their order.
+fn combinations(manifest: &toml::Value) -> anyhow::Result<Vec<Combination>> {
+    let entries = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("sensilo"))
+        .and_then(|sensilo| sensilo.get("matrix"))
+        .and_then(|matrix| matrix.as_array())
+        .context("No combinations in package.metadata.sensilo.matrix of the firmware manifest")?;
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let field = |key: &str| format!("package.metadata.sensilo.matrix[{}].{}", i, key);
+            let name = entry
+                .get("name")
+                .and_then(|name| name.as_str())
+                .with_context(|| format!("{}: Must be set", field("name")))?;
+            let features = entry
+                .get("features")
+                .and_then(|features| features.as_array())
+                .with_context(|| format!("{}: Must be set", field("features")))?
+                .iter()
+                .map(|feature| {
+                    feature
+                        .as_str()
+                        .map(String::from)
+                        .with_context(|| format!("{}: Must be strings", field("features")))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(Combination {
+                name: name.to_string(),
+                features,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,93 @@
+//! Generation of an NVS image with the [provisioning tool](../../provision/), and flashing of it.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{firmware_dir, flash::Espflash, repository_dir, run_command};
+
+/// Offset of the NVS partition, the same in the default and the minimal partition table
+const NVS_OFFSET: &str = "0x9000";
+
+pub struct Args {
+    /// Options of the provisioning tool, passed on
+    options: Vec<String>,
+    config: PathBuf,
+    output: PathBuf,
+    flash: bool,
+    espflash: Espflash,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut options = Vec::new();
+    let mut config = None;
+    let mut output = firmware_dir().join("target").join("nvs.bin");
+    let mut flash = false;
+    let mut espflash = Espflash::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--flash" => flash = true,
+            "--out" => output = absolute(value()?)?,
+            "--secrets" => {
+                let value = absolute(value()?)?;
+                options.extend([arg, value.display().to_string()]);
+            }
+            "--name" | "--serial" | "--size" => {
+                let value = value()?;
+                options.extend([arg, value]);
+            }
+            _ if espflash.parse_option(&arg, &mut args)? => {}
+            _ if arg.starts_with('-') => bail!("Unknown option: {}", arg),
+            _ if config.is_none() => config = Some(absolute(arg)?),
+            _ => bail!("Unexpected argument: {}", arg),
+        }
+    }
+    Ok(Args {
+        options,
+        config: config.context("Missing config file")?,
+        output,
+        flash,
+        espflash,
+    })
+}
+
+/// Generate the NVS image, and flash it to the NVS partition if requested.
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(repository_dir())
+        .args([
+            "run",
+            "--quiet",
+            "--manifest-path",
+            "provision/Cargo.toml",
+            "--",
+        ])
+        .args(&args.options)
+        .arg(&args.config)
+        .arg(&args.output);
+    run_command(&mut command)?;
+
+    if args.flash {
+        let mut command = args.espflash.command("write-bin");
+        command.arg(NVS_OFFSET).arg(&args.output);
+        run_command(&mut command)?;
+    }
+    Ok(())
+}
+
+/// Return a path relative to the working directory as absolute path, since the provisioning tool
+/// runs in the repository root.
+fn absolute(path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    Ok(env::current_dir()
+        .context("Could not get the working directory")?
+        .join(path))
+}
@@ -0,0 +1,148 @@
+//! Report the flash and static RAM usage of the firmware, and check it against the budgets.
+//!
+//! The budgets are set in the firmware manifest, in KiB:
+//!
+//! ```toml
+//! [package.metadata.sensilo.budget]
+//! flash_kib = 3968
+//! ram_kib = 160
+//! ```
+//!
+//! The usage of a feature is the difference to a build without it. To get it, the firmware is
+//! built once without every enabled feature (incremental builds, but still slow), and finally
+//! with all enabled features, so that the last build is the complete firmware.
+
+use anyhow::{bail, Context};
+
+use crate::{
+    build::{self, BuildArgs},
+    image,
+};
+
+/// Features that are not measured separately, since they don't add code on their own
+const UNMEASURED_FEATURES: [&str; 1] = ["minimal"];
+
+#[derive(Default)]
+pub struct Args {
+    build: BuildArgs,
+    total: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    flash_kib: u64,
+    ram_kib: u64,
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--total" => parsed.total = true,
+            _ if parsed.build.parse_option(&arg, &mut args)? => {}
+            _ => bail!("Unknown option: {}", arg),
+        }
+    }
+    Ok(parsed)
+}
+
+pub fn run(args: &Args) -> anyhow::Result<()> {
+    let manifest = build::read_manifest()?;
+    let budget = budget(&manifest)?;
+    let profile = args.build.profile();
+
+    // All enabled features, explicitly (the builds per feature are without the default features)
+    let features = args.build.features(&manifest);
+
+    let mut deltas = Vec::new();
+    if !args.total {
+        for feature in features
+            .iter()
+            .filter(|feature| !UNMEASURED_FEATURES.contains(&feature.as_str()))
+        {
+            let without: Vec<String> = features
+                .iter()
+                .filter(|other| *other != feature)
+                .cloned()
+                .collect();
+            println!("Building without {}", feature);
+            deltas.push((feature.clone(), usage(profile, &without)?));
+        }
+    }
+    println!("Building with all features");
+    let total = usage(profile, &features)?;
+
+    println!();
+    println!("{:<16} {:>10} {:>10}", "Feature", "Flash", "RAM");
+    for (feature, without) in deltas.iter() {
+        println!(
+            "{:<16} {:>10} {:>10}",
+            feature,
+            format_delta(total.flash, without.flash),
+            format_delta(total.ram, without.ram)
+        );
+    }
+    println!(
+        "{:<16} {:>6} KiB {:>6} KiB",
+        "Total",
+        total.flash / 1024,
+        total.ram / 1024
+    );
+    println!(
+        "{:<16} {:>6} KiB {:>6} KiB",
+        "Budget", budget.flash_kib, budget.ram_kib
+    );
+
+    let mut exceeded = Vec::new();
+    if total.flash > budget.flash_kib * 1024 {
+        exceeded.push(format!(
+            "flash {} of {} KiB",
+            total.flash / 1024,
+            budget.flash_kib
+        ));
+    }
+    if total.ram > budget.ram_kib * 1024 {
+        exceeded.push(format!(
+            "static RAM {} of {} KiB",
+            total.ram / 1024,
+            budget.ram_kib
+        ));
+    }
+    if !exceeded.is_empty() {
+        bail!("Budget exceeded: {}", exceeded.join(", "));
+    }
+    Ok(())
+}
+
+/// Build the firmware with exactly `features` and return its usage.
+fn usage(profile: &str, features: &[String]) -> anyhow::Result<image::Usage> {
+    let elf = build::build(profile, features)?;
+    image::usage(&elf).with_context(|| format!("Could not read {}", elf.display()))
+}
+
+/// Format the usage of a feature in KiB, e.g. `+12.5 KiB`.
+fn format_delta(with: u64, without: u64) -> String {
+    let delta = with as f64 - without as f64;
+    format!("{:+.1} KiB", delta / 1024.0)
+}
+
+/// Return the budgets of `package.metadata.sensilo.budget`.
+fn budget(manifest: &toml::Value) -> anyhow::Result<Budget> {
+    let table = manifest
+        .get("package")
+        .and_then(|package| package.get("metadata"))
+        .and_then(|metadata| metadata.get("sensilo"))
+        .and_then(|sensilo| sensilo.get("budget"))
+        .context("No budget in package.metadata.sensilo.budget of the firmware manifest")?;
+    let get = |key: &str| {
+        table
+            .get(key)
+            .and_then(|value| value.as_integer())
+            .and_then(|value| u64::try_from(value).ok())
+            .with_context(|| format!("package.metadata.sensilo.budget.{}: Must be set", key))
+    };
+    Ok(Budget {
+        flash_kib: get("flash_kib")?,
+        ram_kib: get("ram_kib")?,
+    })
+}